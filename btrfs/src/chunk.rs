@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use btrfs_diskformat::{
+    Chunk, Key, Stripe, BTRFS_BLOCK_GROUP_RAID0, BTRFS_BLOCK_GROUP_RAID10, BTRFS_BLOCK_GROUP_RAID5,
+    BTRFS_BLOCK_GROUP_RAID6,
+};
+use core::mem::size_of;
+use syscall::{Error, EIO, EOPNOTSUPP};
+
+use super::tree::{read, CHUNK_ITEM_KEY};
+
+// Everything above the superblock addresses things by logical address, which chunks map onto
+// stripes of one or more devices. The chunks the chunk tree itself lives in are listed in the
+// superblock's sys_chunk_array, so that's enough to read the chunk tree, which lists every
+// chunk.
+//
+// Single, DUP and the RAID1 profiles keep a whole copy of the chunk in each stripe. RAID0 and
+// RAID10 spread it across stripes `stripe_len` bytes at a time, RAID10 keeping `sub_stripes`
+// copies of each. RAID5 and RAID6 aren't supported.
+
+/// Where one copy of some logical addresses starts on disk
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Placement {
+    pub devid: u64,
+    pub physical: u64,
+}
+
+/// One chunk, starting at the logical address it's kept under in `ChunkMap`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChunkMapping {
+    pub length: u64,
+    pub stripe_len: u64,
+    /// `BTRFS_BLOCK_GROUP_*` flags: what the chunk holds and how it's replicated
+    pub flags: u64,
+    pub sub_stripes: u16,
+    /// Where each stripe starts
+    pub stripes: Vec<Placement>,
+}
+
+impl ChunkMapping {
+    /// Parses a chunk item, returning it and how many bytes of `data` it took up
+    pub fn parse(data: &[u8]) -> syscall::Result<(Self, usize)> {
+        let chunk = read::<Chunk>(data)?;
+        let count = chunk.num_stripes.get() as usize;
+        if count == 0 {
+            return Err(Error::new(EIO));
+        }
+
+        // the item ends with every stripe, the first of which is part of `Chunk`
+        let len = size_of::<Chunk>() + (count - 1) * size_of::<Stripe>();
+        let stripes = data
+            .get(size_of::<Chunk>() - size_of::<Stripe>()..len)
+            .ok_or(Error::new(EIO))?
+            .chunks_exact(size_of::<Stripe>())
+            .map(|stripe| {
+                let stripe = read::<Stripe>(stripe)?;
+                Ok(Placement {
+                    devid: stripe.devid.get(),
+                    physical: stripe.offset.get(),
+                })
+            })
+            .collect::<syscall::Result<Vec<_>>>()?;
+
+        let mapping = Self {
+            length: chunk.length.get(),
+            stripe_len: chunk.stripe_len.get(),
+            flags: chunk.r#type.get(),
+            sub_stripes: chunk.sub_stripes.get(),
+            stripes,
+        };
+
+        let striped = mapping.flags & (BTRFS_BLOCK_GROUP_RAID0 | BTRFS_BLOCK_GROUP_RAID10) != 0;
+        let sub_stripes = mapping.sub_stripes as usize;
+        let bad_raid10 = mapping.flags & BTRFS_BLOCK_GROUP_RAID10 != 0
+            && (sub_stripes == 0 || !count.is_multiple_of(sub_stripes));
+        if mapping.length == 0 || (striped && mapping.stripe_len == 0) || bad_raid10 {
+            return Err(Error::new(EIO));
+        }
+
+        Ok((mapping, len))
+    }
+}
+
+/// A run of logical addresses, and where it is on disk
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Mapped {
+    /// Every copy, any of which can be read
+    pub copies: Vec<Placement>,
+    /// Bytes from the start that are contiguous in every copy
+    pub len: u64,
+}
+
+/// The chunks of a volume by the logical address each starts at
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ChunkMap {
+    chunks: BTreeMap<u64, ChunkMapping>,
+}
+
+impl ChunkMap {
+    /// The chunks in a superblock's sys_chunk_array, which is a key and then a chunk item,
+    /// stripes and all, after another
+    pub fn from_sys_chunk_array(mut array: &[u8]) -> syscall::Result<Self> {
+        let mut map = Self::default();
+
+        while !array.is_empty() {
+            let key = read::<Key>(array)?;
+            if key.r#type != CHUNK_ITEM_KEY {
+                return Err(Error::new(EIO));
+            }
+
+            array = &array[size_of::<Key>()..];
+            let len = map.insert(key.offset.get(), array)?;
+            array = &array[len..];
+        }
+
+        Ok(map)
+    }
+    /// Adds the chunk item `data`, which starts at `logical`, returning how many bytes of
+    /// `data` it took up
+    pub fn insert(&mut self, logical: u64, data: &[u8]) -> syscall::Result<usize> {
+        let (mapping, len) = ChunkMapping::parse(data)?;
+        self.chunks.insert(logical, mapping);
+        Ok(len)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &ChunkMapping)> + '_ {
+        self.chunks
+            .iter()
+            .map(|(&logical, mapping)| (logical, mapping))
+    }
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+    /// The chunk the logical address `logical` is in and the address it starts at, failing
+    /// with `EIO` if there's none
+    pub fn chunk(&self, logical: u64) -> syscall::Result<(u64, &ChunkMapping)> {
+        self.chunks
+            .range(..=logical)
+            .next_back()
+            .filter(|(&start, chunk)| logical - start < chunk.length)
+            .map(|(&start, chunk)| (start, chunk))
+            .ok_or(Error::new(EIO))
+    }
+    /// Where the logical address `logical` is on disk, failing with `EIO` if no chunk has it
+    /// and `EOPNOTSUPP` if it's in a RAID5 or RAID6 chunk
+    pub fn map(&self, logical: u64) -> syscall::Result<Mapped> {
+        let (start, chunk) = self.chunk(logical)?;
+
+        let offset = logical - start;
+        if chunk.flags & (BTRFS_BLOCK_GROUP_RAID5 | BTRFS_BLOCK_GROUP_RAID6) != 0 {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+
+        // which stripes hold `offset`, and how far into each
+        let (stripes, within, len) = if chunk.flags & BTRFS_BLOCK_GROUP_RAID0 != 0 {
+            let (nr, within) = (offset / chunk.stripe_len, offset % chunk.stripe_len);
+            let count = chunk.stripes.len() as u64;
+            let index = (nr % count) as usize;
+            let row = nr / count;
+
+            (
+                &chunk.stripes[index..=index],
+                row * chunk.stripe_len + within,
+                chunk.stripe_len - within,
+            )
+        } else if chunk.flags & BTRFS_BLOCK_GROUP_RAID10 != 0 {
+            let (nr, within) = (offset / chunk.stripe_len, offset % chunk.stripe_len);
+            let sub = chunk.sub_stripes as usize;
+            let factor = (chunk.stripes.len() / sub) as u64;
+            let index = (nr % factor) as usize * sub;
+            let row = nr / factor;
+
+            (
+                &chunk.stripes[index..index + sub],
+                row * chunk.stripe_len + within,
+                chunk.stripe_len - within,
+            )
+        } else {
+            (&chunk.stripes[..], offset, chunk.length - offset)
+        };
+
+        Ok(Mapped {
+            len: len.min(chunk.length - offset),
+            copies: stripes
+                .iter()
+                .map(|stripe| Placement {
+                    devid: stripe.devid,
+                    physical: stripe.physical + within,
+                })
+                .collect(),
+        })
+    }
+}
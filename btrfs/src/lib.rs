@@ -0,0 +1,120 @@
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use btrfs_diskformat::{
+    constants::{MAGIC, SUPERBLOCK_ADDRS},
+    *,
+};
+use core::mem::size_of;
+use log::warn;
+use syscall::{Error, EINVAL, EIO, EOPNOTSUPP};
+
+pub mod chunk;
+pub mod crc32c;
+pub mod subvolume;
+pub mod tree;
+pub mod volume;
+
+pub use self::subvolume::{DirEntry, Subvolume};
+pub use self::volume::BtrfsVolume;
+
+/// Something a Btrfs volume can be read from, a byte at a time as far as callers are concerned
+pub trait Disk {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> syscall::Result<()>;
+    /// How many bytes there are to read
+    fn size(&mut self) -> syscall::Result<u64>;
+}
+
+/// Reads the superblock at `addr` and checks it: `EINVAL` if it isn't a Btrfs superblock at
+/// all, `EOPNOTSUPP` if it's checksummed with something other than CRC-32C, and `EIO` if the
+/// checksum doesn't match or it says it belongs somewhere else
+pub fn superblock_at(disk: &mut dyn Disk, addr: u64) -> syscall::Result<SuperBlock> {
+    let mut bytes = vec![0; size_of::<SuperBlock>()];
+    disk.read_at(addr, &mut bytes)?;
+    let superblock = tree::read::<SuperBlock>(&bytes)?;
+
+    if superblock.magic.get() != MAGIC {
+        return Err(Error::new(EINVAL));
+    }
+    if superblock.csum_type.get() != BTRFS_CSUM_TYPE_CRC32 {
+        return Err(Error::new(EOPNOTSUPP));
+    }
+    if !crc32c::matches(&superblock.csum, &bytes[superblock.csum.len()..])
+        || superblock.bytenr.get() != addr
+    {
+        return Err(Error::new(EIO));
+    }
+
+    Ok(superblock)
+}
+
+/// Reads the primary superblock of `disk` and whichever of its mirrors at 64 MiB and 256 GiB
+/// the device is big enough for, returning the valid one with the highest generation, or the
+/// primary's error if none is valid
+///
+/// Copies left by another volume, as after reformatting a disk without wiping it, aren't
+/// considered. With the primary valid, that's any mirror with another fsid; without it, the
+/// valid mirrors have to agree on one, and if they don't this fails with `EIO`, there being no
+/// telling which volume is the one on the device now. Mirrors that fail their checks or are at
+/// another generation are logged.
+pub fn newest_superblock(disk: &mut dyn Disk) -> syscall::Result<SuperBlock> {
+    let size = disk.size()?;
+    let mut copies = SUPERBLOCK_ADDRS
+        .iter()
+        .filter(|&&addr| addr + size_of::<SuperBlock>() as u64 <= size)
+        .map(|&addr| (addr, superblock_at(disk, addr)))
+        .collect::<Vec<_>>();
+    if copies.is_empty() {
+        return Err(Error::new(EINVAL));
+    }
+
+    let fsid = match &copies[0] {
+        (_, Ok(primary)) => primary.fsid,
+        _ => {
+            let mut mirrors = copies[1..]
+                .iter()
+                .filter_map(|(_, copy)| copy.as_ref().ok());
+            match mirrors.next() {
+                Some(first) if mirrors.all(|copy| copy.fsid == first.fsid) => first.fsid,
+                Some(_) => {
+                    warn!("Btrfs: primary superblock unusable, and its mirrors are of different volumes");
+                    return Err(Error::new(EIO));
+                }
+                None => return copies.swap_remove(0).1,
+            }
+        }
+    };
+    for (addr, copy) in &mut copies[1..] {
+        if copy.as_ref().is_ok_and(|copy| copy.fsid != fsid) {
+            warn!("Btrfs: superblock at {:#x} belongs to another volume", addr);
+            *copy = Err(Error::new(EINVAL));
+        }
+    }
+
+    // the earliest wins a tie, so the primary is preferred
+    let newest = copies
+        .iter()
+        .filter_map(|(_, copy)| copy.as_ref().ok())
+        .rev()
+        .max_by_key(|copy| copy.generation.get())
+        .copied()
+        .ok_or(Error::new(EIO))?;
+
+    for (addr, copy) in &copies {
+        match copy {
+            Err(err) => warn!("Btrfs: superblock at {:#x} is unusable: {}", addr, err),
+            Ok(copy) if copy.generation.get() != newest.generation.get() => warn!(
+                "Btrfs: superblock at {:#x} is at generation {}, not {}",
+                addr,
+                copy.generation.get(),
+                newest.generation.get()
+            ),
+            Ok(_) => {}
+        }
+    }
+
+    Ok(newest)
+}
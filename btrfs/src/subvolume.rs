@@ -0,0 +1,493 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use btrfs_diskformat::{InodeItem, Key, RootItem};
+use byteorder::LE;
+use core::mem::size_of;
+use core2::io::Read;
+use libflate::zlib::Decoder;
+use syscall::{Error, Stat, EIO, ENOENT, ENOTDIR, EOPNOTSUPP};
+use zerocopy::{FromBytes, Unaligned, U16, U64};
+
+use super::crc32c::name_hash;
+use super::tree::{
+    key_tuple, read, KeyTuple, DIR_INDEX_KEY, DIR_ITEM_KEY, EXTENT_DATA_KEY, FS_TREE_OBJECTID,
+    INODE_ITEM_KEY, ROOT_ITEM_KEY,
+};
+use super::{BtrfsVolume, Disk};
+
+// Files live in subvolumes, each a tree of its own found through its ROOT_ITEM in the root
+// tree. In a subvolume's tree, an inode's items all have its number as their object ID: an
+// INODE_ITEM with its metadata, a DIR_ITEM (keyed by name hash) and a DIR_INDEX (keyed by the
+// order the entry was made in) for each entry of a directory, and for a file an EXTENT_DATA per
+// run of contents, keyed by where in the file the run starts.
+//
+// Contents are either inline in the EXTENT_DATA item or in an extent elsewhere, which is read
+// through the chunk map like anything else. Either may be zlib-compressed; LZO and zstd aren't
+// supported. Data checksums aren't checked, and runs no EXTENT_DATA covers read as zeros, as do
+// holes and preallocated extents.
+
+/// `BTRFS_FT_DIR`, the type of a directory entry that's a directory
+pub const FT_DIR: u8 = 2;
+
+/// The longest an extent's run of file contents can be
+const MAX_EXTENT_SIZE: u64 = 128 << 20;
+
+/// The most a compressed extent takes up on disk, `BTRFS_MAX_COMPRESSED`
+const MAX_COMPRESSED: u64 = 128 << 10;
+
+/// Deepest a path can be, which stops a loop of subvolumes
+const MAX_DEPTH: usize = 256;
+
+const COMPRESS_NONE: u8 = 0;
+const COMPRESS_ZLIB: u8 = 1;
+const COMPRESS_LZO: u8 = 2;
+const COMPRESS_ZSTD: u8 = 3;
+
+const EXTENT_INLINE: u8 = 0;
+const EXTENT_REG: u8 = 1;
+const EXTENT_PREALLOC: u8 = 2;
+
+/// What DIR_ITEM and DIR_INDEX items hold for each entry, followed by its name
+#[derive(FromBytes, Unaligned, Clone)]
+#[repr(C, packed)]
+struct DirItem {
+    location: Key,
+    transid: U64<LE>,
+    data_len: U16<LE>,
+    name_len: U16<LE>,
+    r#type: u8,
+}
+
+/// The start of every EXTENT_DATA item, followed by the contents for an inline extent and by
+/// an `ExtentPointer` otherwise
+#[derive(FromBytes, Unaligned, Clone)]
+#[repr(C, packed)]
+struct FileExtentItem {
+    generation: U64<LE>,
+    ram_bytes: U64<LE>,
+    compression: u8,
+    encryption: u8,
+    other_encoding: U16<LE>,
+    r#type: u8,
+}
+
+/// Where a run of contents that isn't inline is
+#[derive(FromBytes, Unaligned, Clone)]
+#[repr(C, packed)]
+struct ExtentPointer {
+    /// Logical address of the extent, or 0 for a hole
+    disk_bytenr: U64<LE>,
+    disk_num_bytes: U64<LE>,
+    /// Where in the extent, once decompressed, the run starts
+    offset: U64<LE>,
+    num_bytes: U64<LE>,
+}
+
+/// A subvolume: where its tree is, and its root directory
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Subvolume {
+    pub id: u64,
+    pub root: u64,
+    pub level: u8,
+    pub root_dir: u64,
+}
+
+/// One entry of a directory
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DirEntry {
+    /// The name, with anything that isn't UTF-8 replaced
+    pub name: String,
+    /// Where the entry leads: an INODE_ITEM in the same subvolume, or the ROOT_ITEM of another
+    pub location: KeyTuple,
+    /// One of the `BTRFS_FT_*` types
+    pub file_type: u8,
+}
+
+impl DirEntry {
+    /// Parses every entry in a DIR_ITEM or DIR_INDEX item, which can hold more than one when
+    /// names' hashes collide
+    fn parse_all(mut data: &[u8]) -> syscall::Result<Vec<(Self, &[u8])>> {
+        let mut entries = Vec::new();
+        while !data.is_empty() {
+            let item = read::<DirItem>(data)?;
+            let start = size_of::<DirItem>();
+            let end = start + item.name_len.get() as usize + item.data_len.get() as usize;
+            let name = data
+                .get(start..start + item.name_len.get() as usize)
+                .ok_or(Error::new(EIO))?;
+            if end > data.len() {
+                return Err(Error::new(EIO));
+            }
+
+            let entry = Self {
+                name: String::from_utf8_lossy(name).into_owned(),
+                location: key_tuple(&item.location),
+                file_type: item.r#type,
+            };
+            entries.push((entry, name));
+            data = &data[end..];
+        }
+        Ok(entries)
+    }
+    /// The subvolume the entry leads to the root directory of, if it does
+    pub fn subvolume(&self) -> Option<u64> {
+        match self.location {
+            (id, ROOT_ITEM_KEY, _) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// One EXTENT_DATA item
+enum FileExtent {
+    Inline {
+        compression: u8,
+        ram_bytes: u64,
+        data: Vec<u8>,
+    },
+    Regular {
+        compression: u8,
+        ram_bytes: u64,
+        pointer: ExtentPointer,
+    },
+    Prealloc {
+        num_bytes: u64,
+    },
+}
+
+impl FileExtent {
+    fn parse(data: &[u8]) -> syscall::Result<Self> {
+        let item = read::<FileExtentItem>(data)?;
+        if item.encryption != 0 || item.other_encoding.get() != 0 {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+
+        let rest = &data[size_of::<FileExtentItem>()..];
+        let (compression, ram_bytes) = (item.compression, item.ram_bytes.get());
+        match item.r#type {
+            EXTENT_INLINE => Ok(Self::Inline {
+                compression,
+                ram_bytes,
+                data: rest.to_vec(),
+            }),
+            EXTENT_REG => Ok(Self::Regular {
+                compression,
+                ram_bytes,
+                pointer: read::<ExtentPointer>(rest)?,
+            }),
+            EXTENT_PREALLOC => Ok(Self::Prealloc {
+                num_bytes: read::<ExtentPointer>(rest)?.num_bytes.get(),
+            }),
+            _ => Err(Error::new(EIO)),
+        }
+    }
+    /// How many bytes of the file it covers
+    fn len(&self) -> u64 {
+        match self {
+            Self::Inline { ram_bytes, .. } => *ram_bytes,
+            Self::Regular { pointer, .. } => pointer.num_bytes.get(),
+            Self::Prealloc { num_bytes } => *num_bytes,
+        }
+    }
+}
+
+/// `data` as it was before being compressed with `compression` from `ram_bytes` bytes
+fn decompress(compression: u8, data: &[u8], ram_bytes: u64) -> syscall::Result<Vec<u8>> {
+    match compression {
+        COMPRESS_NONE => Ok(data.to_vec()),
+        COMPRESS_ZLIB => {
+            // the extent is padded out to a whole sector after the stream, which the decoder
+            // never gets to
+            let mut plain = Vec::new();
+            Decoder::new(data)
+                .map_err(|_| Error::new(EIO))?
+                .take(ram_bytes)
+                .read_to_end(&mut plain)
+                .map_err(|_| Error::new(EIO))?;
+            Ok(plain)
+        }
+        COMPRESS_LZO | COMPRESS_ZSTD => Err(Error::new(EOPNOTSUPP)),
+        _ => Err(Error::new(EIO)),
+    }
+}
+
+/// Copies what's in `plain` from `skip` on into `buf`, leaving whatever it doesn't reach
+fn copy_from(plain: &[u8], skip: u64, buf: &mut [u8]) {
+    let plain = plain.get(skip as usize..).unwrap_or_default();
+    let len = plain.len().min(buf.len());
+    buf[..len].copy_from_slice(&plain[..len]);
+}
+
+impl<D: Disk> BtrfsVolume<D> {
+    /// The data of the one item with the key `key` in the tree at `logical` and `level`,
+    /// failing with `ENOENT` if there's none
+    fn item(&mut self, logical: u64, level: u8, key: KeyTuple) -> syscall::Result<Vec<u8>> {
+        let mut found = None;
+        self.for_each_item(logical, level, &(key..=key), &mut |_, data| {
+            found = Some(data.to_vec());
+            Ok(())
+        })?;
+        found.ok_or(Error::new(ENOENT))
+    }
+    /// The subvolume with the ID `id`, failing with `ENOENT` if there's none
+    pub fn subvolume(&mut self, id: u64) -> syscall::Result<Subvolume> {
+        let root = self.superblock().root.get();
+        let level = self.superblock().root_level;
+        let keys = (id, ROOT_ITEM_KEY, 0)..=(id, ROOT_ITEM_KEY, u64::MAX);
+
+        // a snapshot's ROOT_ITEM is keyed by the transaction it was made in, so there can be
+        // more than one, the last of which is the current one
+        let mut found = None;
+        self.for_each_item(root, level, &keys, &mut |_, data| {
+            found = Some(read::<RootItem>(data)?);
+            Ok(())
+        })?;
+        let item = found.ok_or(Error::new(ENOENT))?;
+
+        Ok(Subvolume {
+            id,
+            root: item.bytenr.get(),
+            level: item.level,
+            root_dir: item.root_dirid.get(),
+        })
+    }
+    /// The subvolume that's mounted unless another is asked for: the one the "default" entry
+    /// of the root tree's directory names, or the top-level one if it has none
+    pub fn default_subvolume(&mut self) -> syscall::Result<Subvolume> {
+        let root = self.superblock().root.get();
+        let level = self.superblock().root_level;
+        let dir = self.superblock().root_dir_objectid.get();
+
+        let id = match self.dir_item(root, level, dir, "default")? {
+            Some(entry) => entry.location.0,
+            None => FS_TREE_OBJECTID,
+        };
+        self.subvolume(id)
+    }
+    // the entry called `name` in the directory `dir` of the tree at `logical` and `level`
+    fn dir_item(
+        &mut self,
+        logical: u64,
+        level: u8,
+        dir: u64,
+        name: &str,
+    ) -> syscall::Result<Option<DirEntry>> {
+        let data = match self.item(
+            logical,
+            level,
+            (dir, DIR_ITEM_KEY, name_hash(name.as_bytes())),
+        ) {
+            Ok(data) => data,
+            Err(err) if err.errno == ENOENT => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        Ok(DirEntry::parse_all(&data)?
+            .into_iter()
+            .find(|(_, stored)| *stored == name.as_bytes())
+            .map(|(entry, _)| entry))
+    }
+    /// The INODE_ITEM of `inode` in `subvolume`
+    pub fn inode(&mut self, subvolume: &Subvolume, inode: u64) -> syscall::Result<InodeItem> {
+        let data = self.item(subvolume.root, subvolume.level, (inode, INODE_ITEM_KEY, 0))?;
+        read::<InodeItem>(&data)
+    }
+    /// Fills `stat` in for `inode` in `subvolume`
+    pub fn stat(
+        &mut self,
+        subvolume: &Subvolume,
+        inode: u64,
+        stat: &mut Stat,
+    ) -> syscall::Result<()> {
+        let item = self.inode(subvolume, inode)?;
+
+        *stat = Stat {
+            st_dev: subvolume.id,
+            st_ino: inode,
+            st_mode: item.mode.get() as u16,
+            st_nlink: item.nlink.get(),
+            st_uid: item.uid.get(),
+            st_gid: item.gid.get(),
+            st_size: item.size.get(),
+            st_blksize: self.superblock().sectorsize.get(),
+            st_blocks: item.nbytes.get().div_ceil(512),
+            st_mtime: item.mtime.timestamp.get() as u64,
+            st_mtime_nsec: item.mtime.nanoseconds.get(),
+            st_atime: item.atime.timestamp.get() as u64,
+            st_atime_nsec: item.atime.nanoseconds.get(),
+            st_ctime: item.ctime.timestamp.get() as u64,
+            st_ctime_nsec: item.ctime.nanoseconds.get(),
+        };
+        Ok(())
+    }
+    /// The entries of the directory `dir` in `subvolume`, in the order they were made
+    pub fn read_dir(&mut self, subvolume: &Subvolume, dir: u64) -> syscall::Result<Vec<DirEntry>> {
+        let keys = (dir, DIR_INDEX_KEY, 0)..=(dir, DIR_INDEX_KEY, u64::MAX);
+
+        let mut entries = Vec::new();
+        self.for_each_item(subvolume.root, subvolume.level, &keys, &mut |_, data| {
+            for (entry, _) in DirEntry::parse_all(data)? {
+                entries.push(entry);
+            }
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+    /// The entry called `name` in the directory `dir` of `subvolume`, failing with `ENOENT` if
+    /// there's none
+    pub fn lookup(
+        &mut self,
+        subvolume: &Subvolume,
+        dir: u64,
+        name: &str,
+    ) -> syscall::Result<DirEntry> {
+        self.dir_item(subvolume.root, subvolume.level, dir, name)?
+            .ok_or(Error::new(ENOENT))
+    }
+    /// The subvolume and inode `path` leads to from the root directory of `subvolume`, going
+    /// into other subvolumes along the way; symlinks aren't followed
+    pub fn resolve(
+        &mut self,
+        subvolume: &Subvolume,
+        path: &str,
+    ) -> syscall::Result<(Subvolume, u64)> {
+        let mut at = vec![(*subvolume, subvolume.root_dir, FT_DIR)];
+
+        for name in path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+        {
+            let &(subvolume, dir, file_type) = at.last().unwrap();
+            if file_type != FT_DIR {
+                return Err(Error::new(ENOTDIR));
+            }
+            if name == ".." {
+                if at.len() > 1 {
+                    at.pop();
+                }
+                continue;
+            }
+            if at.len() >= MAX_DEPTH {
+                return Err(Error::new(ENOENT));
+            }
+
+            let entry = self.lookup(&subvolume, dir, name)?;
+            at.push(match entry.subvolume() {
+                Some(id) => {
+                    let inner = self.subvolume(id)?;
+                    (inner, inner.root_dir, FT_DIR)
+                }
+                None => (subvolume, entry.location.0, entry.file_type),
+            });
+        }
+
+        let &(subvolume, inode, _) = at.last().unwrap();
+        Ok((subvolume, inode))
+    }
+    /// Reads the contents of `inode` in `subvolume` from `offset` into `buf`, returning how
+    /// many bytes there were before the end of the file
+    pub fn read(
+        &mut self,
+        subvolume: &Subvolume,
+        inode: u64,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> syscall::Result<usize> {
+        let size = self.inode(subvolume, inode)?.size.get();
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = (size - offset).min(buf.len() as u64) as usize;
+        let buf = &mut buf[..len];
+        buf.fill(0);
+
+        // no run is longer than `MAX_EXTENT_SIZE`, so one starting further back than that
+        // can't reach `offset`
+        let end = offset + len as u64;
+        let keys = (
+            inode,
+            EXTENT_DATA_KEY,
+            offset.saturating_sub(MAX_EXTENT_SIZE),
+        )..=(inode, EXTENT_DATA_KEY, end - 1);
+        let mut extents = Vec::new();
+        self.for_each_item(subvolume.root, subvolume.level, &keys, &mut |key, data| {
+            extents.push((key.2, FileExtent::parse(data)?));
+            Ok(())
+        })?;
+
+        for (start, extent) in extents {
+            let from = start.max(offset);
+            let to = start
+                .checked_add(extent.len())
+                .ok_or(Error::new(EIO))?
+                .min(end);
+            if from >= to {
+                continue;
+            }
+
+            let dest = &mut buf[(from - offset) as usize..(to - offset) as usize];
+            self.read_extent(&extent, from - start, dest)?;
+        }
+        Ok(len)
+    }
+    // reads what `extent` has from `skip` bytes into the run it covers into `buf`
+    fn read_extent(
+        &mut self,
+        extent: &FileExtent,
+        skip: u64,
+        buf: &mut [u8],
+    ) -> syscall::Result<()> {
+        match extent {
+            FileExtent::Inline {
+                compression,
+                ram_bytes,
+                data,
+            } => {
+                let plain = decompress(*compression, data, *ram_bytes)?;
+                copy_from(&plain, skip, buf);
+            }
+            FileExtent::Regular { pointer, .. } if pointer.disk_bytenr.get() == 0 => {}
+            FileExtent::Regular {
+                compression: COMPRESS_NONE,
+                pointer,
+                ..
+            } => {
+                let at = pointer
+                    .disk_bytenr
+                    .get()
+                    .checked_add(pointer.offset.get())
+                    .and_then(|at| at.checked_add(skip))
+                    .ok_or(Error::new(EIO))?;
+                self.read_logical(at, buf)?;
+            }
+            FileExtent::Regular {
+                compression,
+                ram_bytes,
+                pointer,
+            } => {
+                // what's on disk is read whole, so it's held to what a compressed extent can
+                // be and to the chunk it starts in before anything is allocated for it
+                let (logical, len) = (pointer.disk_bytenr.get(), pointer.disk_num_bytes.get());
+                let (start, chunk) = self.chunks().chunk(logical)?;
+                if len > MAX_COMPRESSED || len > chunk.length - (logical - start) {
+                    return Err(Error::new(EIO));
+                }
+                let skip = pointer
+                    .offset
+                    .get()
+                    .checked_add(skip)
+                    .ok_or(Error::new(EIO))?;
+
+                let mut data = vec![0; len as usize];
+                self.read_logical(logical, &mut data)?;
+                let plain = decompress(*compression, &data, *ram_bytes)?;
+                copy_from(&plain, skip, buf);
+            }
+            FileExtent::Prealloc { .. } => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use alloc::vec::Vec;
+use btrfs_diskformat::{Header, Item, Key, KeyPointer};
+use core::mem::size_of;
+use syscall::{Error, EIO};
+use zerocopy::{FromBytes, LayoutVerified, Unaligned};
+
+use super::crc32c;
+
+/// A key as a tuple of object ID, item type and offset, which sorts the way Btrfs sorts keys
+pub type KeyTuple = (u64, u8, u64);
+
+/// Type of the items that hold an inode's metadata
+pub const INODE_ITEM_KEY: u8 = 1;
+
+/// Type of the items that name a directory entry by the hash of its name
+pub const DIR_ITEM_KEY: u8 = 84;
+
+/// Type of the items that name a directory entry by its index in the directory
+pub const DIR_INDEX_KEY: u8 = 96;
+
+/// Type of the items that say where a run of a file's contents is
+pub const EXTENT_DATA_KEY: u8 = 108;
+
+/// Type of the items in the root tree that say where another tree's root is
+pub const ROOT_ITEM_KEY: u8 = 132;
+
+/// Type of the items that map a range of logical addresses
+pub const CHUNK_ITEM_KEY: u8 = 228;
+
+/// Object ID of the top-level subvolume's tree
+pub const FS_TREE_OBJECTID: u64 = 5;
+
+/// Object ID of the chunk items in the chunk tree
+pub const FIRST_CHUNK_TREE_OBJECTID: u64 = 256;
+
+pub fn key_tuple(key: &Key) -> KeyTuple {
+    (key.objectid.get(), key.r#type, key.offset.get())
+}
+
+/// Reads a `T` from the front of `bytes`, failing with `EIO` if there isn't room for one
+pub fn read<T: FromBytes + Unaligned + Clone>(bytes: &[u8]) -> syscall::Result<T> {
+    LayoutVerified::<_, T>::new_unaligned_from_prefix(bytes)
+        .map(|(value, _)| T::clone(&value))
+        .ok_or(Error::new(EIO))
+}
+
+/// One node of a tree, as read from the volume and checked
+///
+/// Leaves (level 0) hold items; every other level holds pointers to the nodes below, each with
+/// the lowest key found under it.
+pub struct Node {
+    bytes: Vec<u8>,
+    header: Header,
+}
+
+impl Node {
+    /// Checks the node in `bytes`, read from logical address `logical` of the volume with the
+    /// filesystem ID `fsid`, failing with `EIO` if its checksum doesn't match, or it says it's
+    /// somewhere else, in another volume, or at another level than `level`
+    pub fn parse(
+        bytes: Vec<u8>,
+        logical: u64,
+        fsid: &[u8; 16],
+        level: u8,
+    ) -> syscall::Result<Self> {
+        let header = read::<Header>(&bytes)?;
+
+        if !crc32c::matches(&header.csum, &bytes[header.csum.len()..])
+            || header.logical_address.get() != logical
+            || &header.fs_uuid != fsid
+            || header.level != level
+        {
+            return Err(Error::new(EIO));
+        }
+
+        let entry = match level {
+            0 => size_of::<Item>(),
+            _ => size_of::<KeyPointer>(),
+        };
+        let len = size_of::<Header>() + header.num_items.get() as usize * entry;
+        if len > bytes.len() {
+            return Err(Error::new(EIO));
+        }
+
+        Ok(Self { bytes, header })
+    }
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+    pub fn level(&self) -> u8 {
+        self.header.level
+    }
+    fn entries<T: FromBytes + Unaligned + Clone>(&self) -> impl Iterator<Item = T> + '_ {
+        let body = &self.bytes[size_of::<Header>()..];
+        (0..self.header.num_items.get() as usize)
+            .map(move |i| read::<T>(&body[i * size_of::<T>()..]).unwrap())
+    }
+    /// The items of a leaf in key order, each with its data, failing with `EIO` for an item
+    /// whose data isn't inside the node
+    pub fn items(&self) -> impl Iterator<Item = syscall::Result<(KeyTuple, &[u8])>> + '_ {
+        let body = &self.bytes[size_of::<Header>()..];
+        self.entries::<Item>().map(move |item| {
+            let start = item.offset.get() as usize;
+            let end = start + item.size.get() as usize;
+            let data = body.get(start..end).ok_or(Error::new(EIO))?;
+            Ok((key_tuple(&item.key), data))
+        })
+    }
+    /// The pointers of an internal node in key order, as the lowest key below each and its
+    /// logical address
+    pub fn children(&self) -> impl Iterator<Item = (KeyTuple, u64)> + '_ {
+        self.entries::<KeyPointer>()
+            .map(|pointer| (key_tuple(&pointer.key), pointer.block_pointer.get()))
+    }
+}
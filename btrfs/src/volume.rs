@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use alloc::vec;
+use alloc::vec::Vec;
+use btrfs_diskformat::constants::MAX_SYSTEM_CHUNK_ARRAY_SIZE;
+use btrfs_diskformat::SuperBlock;
+use core::ops::RangeInclusive;
+use syscall::{Error, EIO, ENODEV};
+use zerocopy::AsBytes;
+
+use super::chunk::ChunkMap;
+use super::tree::{KeyTuple, Node, CHUNK_ITEM_KEY, FIRST_CHUNK_TREE_OBJECTID};
+use super::{newest_superblock, Disk};
+
+/// Incompat flag for volumes whose tree nodes carry a metadata UUID other than the fsid
+const INCOMPAT_METADATA_UUID: u64 = 1 << 10;
+
+/// Deepest a tree can be; level 0 is the leaves
+const MAX_LEVEL: u8 = 8;
+
+/// A Btrfs volume on a single device, opened read-only
+///
+/// Opening one reads the newest valid superblock, then the chunk tree through the chunks in the
+/// superblock's sys_chunk_array, which gives the whole map from logical addresses to where they
+/// are on the device. Trees are read through that map. A volume spread over more than one
+/// device opens, but anything on the others fails with `ENODEV`.
+pub struct BtrfsVolume<D: Disk> {
+    disk: D,
+    superblock: SuperBlock,
+    chunks: ChunkMap,
+}
+
+impl<D: Disk> BtrfsVolume<D> {
+    pub fn open(mut disk: D) -> syscall::Result<Self> {
+        let superblock = newest_superblock(&mut disk)?;
+        let len = superblock.sys_chunk_array_size.get() as usize;
+        if len > MAX_SYSTEM_CHUNK_ARRAY_SIZE {
+            return Err(Error::new(EIO));
+        }
+        let chunks = ChunkMap::from_sys_chunk_array(&superblock.sys_chunk_array[..len])?;
+
+        let mut volume = Self {
+            disk,
+            superblock,
+            chunks,
+        };
+        volume.read_chunk_tree()?;
+        Ok(volume)
+    }
+    // adds every chunk in the chunk tree to the map, which so far only has the system chunks
+    // the chunk tree is in
+    fn read_chunk_tree(&mut self) -> syscall::Result<()> {
+        let root = self.superblock.chunk_root.get();
+        let level = self.superblock.chunk_root_level;
+        let keys = (FIRST_CHUNK_TREE_OBJECTID, CHUNK_ITEM_KEY, 0)
+            ..=(FIRST_CHUNK_TREE_OBJECTID, CHUNK_ITEM_KEY, u64::MAX);
+
+        let mut chunks = self.chunks.clone();
+        self.for_each_item(root, level, &keys, &mut |(_, _, logical), data| {
+            chunks.insert(logical, data).map(drop)
+        })?;
+        self.chunks = chunks;
+        Ok(())
+    }
+    pub fn superblock(&self) -> &SuperBlock {
+        &self.superblock
+    }
+    pub fn chunks(&self) -> &ChunkMap {
+        &self.chunks
+    }
+    /// The ID of the device the volume was opened from
+    pub fn devid(&self) -> u64 {
+        self.superblock.dev_item.devid.get()
+    }
+    /// The filesystem ID every tree node carries
+    pub fn metadata_fsid(&self) -> [u8; 16] {
+        let mut fsid = self.superblock.fsid;
+        if self.superblock.incompat_flags.get() & INCOMPAT_METADATA_UUID != 0 {
+            // the first thing after uuid_tree_generation
+            fsid.copy_from_slice(&self.superblock._reserved.as_bytes()[..16]);
+        }
+        fsid
+    }
+    // where each copy of the logical address `logical` on this device is, along with how many
+    // bytes from there are contiguous
+    fn locate(&self, logical: u64) -> syscall::Result<(Vec<u64>, u64)> {
+        let mapped = self.chunks.map(logical)?;
+        let copies = mapped
+            .copies
+            .iter()
+            .filter(|copy| copy.devid == self.devid())
+            .map(|copy| copy.physical)
+            .collect::<Vec<_>>();
+
+        match copies.is_empty() {
+            true => Err(Error::new(ENODEV)),
+            false => Ok((copies, mapped.len)),
+        }
+    }
+    /// Reads `buf.len()` bytes from logical address `logical`
+    pub fn read_logical(&mut self, logical: u64, buf: &mut [u8]) -> syscall::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = logical + done as u64;
+            let (copies, len) = self.locate(at)?;
+            let len = (len as usize).min(buf.len() - done);
+
+            self.disk.read_at(copies[0], &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+    /// Reads and checks the tree node at logical address `logical`, which should be at `level`
+    ///
+    /// A copy that fails its checks is passed over for the next one on this device, if there
+    /// is one, as with DUP metadata.
+    pub fn read_node(&mut self, logical: u64, level: u8) -> syscall::Result<Node> {
+        let size = self.superblock.nodesize.get() as usize;
+        let (copies, len) = self.locate(logical)?;
+        if len < size as u64 {
+            return Err(Error::new(EIO));
+        }
+
+        let fsid = self.metadata_fsid();
+        let mut last = Error::new(EIO);
+        for physical in copies {
+            let mut bytes = vec![0; size];
+            let node = self
+                .disk
+                .read_at(physical, &mut bytes)
+                .and_then(|()| Node::parse(bytes, logical, &fsid, level));
+
+            match node {
+                Ok(node) => return Ok(node),
+                Err(err) => last = err,
+            }
+        }
+        Err(last)
+    }
+    /// Runs `f` on every item with a key in `keys` in the tree whose root is at `logical` and
+    /// `level`, in key order, only reading the nodes that could hold one
+    pub fn for_each_item(
+        &mut self,
+        logical: u64,
+        level: u8,
+        keys: &RangeInclusive<KeyTuple>,
+        f: &mut dyn FnMut(KeyTuple, &[u8]) -> syscall::Result<()>,
+    ) -> syscall::Result<()> {
+        if level >= MAX_LEVEL {
+            return Err(Error::new(EIO));
+        }
+
+        let node = self.read_node(logical, level)?;
+        if level == 0 {
+            for item in node.items() {
+                let (key, data) = item?;
+                if keys.contains(&key) {
+                    f(key, data)?;
+                }
+            }
+            return Ok(());
+        }
+
+        // each child holds the keys from its own up to the next child's
+        let children = node.children().collect::<Vec<_>>();
+        for (i, &(first, child)) in children.iter().enumerate() {
+            if first > *keys.end() {
+                break;
+            }
+            let next = children.get(i + 1).map(|&(next, _)| next);
+            if next.is_some_and(|next| next <= *keys.start()) {
+                continue;
+            }
+
+            self.for_each_item(child, level - 1, keys, f)?;
+        }
+        Ok(())
+    }
+}
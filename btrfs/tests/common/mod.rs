@@ -0,0 +1,164 @@
+// Helpers shared by the test files; not every file uses all of them
+#![allow(dead_code)]
+
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use btrfs::crc32c;
+use btrfs::tree::KeyTuple;
+use btrfs::{BtrfsVolume, Disk};
+use syscall::{Error, EIO};
+
+/// How big the images are made; sparse, so only what mkfs.btrfs writes takes up room
+const IMAGE_SIZE: u64 = 256 << 20;
+
+/// A volume in an image file
+pub struct FileDisk(pub File);
+
+impl Disk for FileDisk {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> syscall::Result<()> {
+        self.0
+            .read_exact_at(buf, offset)
+            .map_err(|_| Error::new(EIO))
+    }
+    fn size(&mut self) -> syscall::Result<u64> {
+        self.0
+            .metadata()
+            .map(|metadata| metadata.len())
+            .map_err(|_| Error::new(EIO))
+    }
+}
+
+/// A directory of a test's own, removed along with everything in it when dropped
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("btrfs-test-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Makes an image in `scratch` with `mkfs.btrfs --rootdir`, holding whatever `populate` puts in
+/// the directory it's given, with `args` passed on as well; `None` if mkfs.btrfs isn't
+/// installed, which the tests take as a reason to skip
+pub fn mkfs(scratch: &Scratch, args: &[&str], populate: impl FnOnce(&Path)) -> Option<PathBuf> {
+    let root = scratch.path().join("root");
+    fs::create_dir(&root).unwrap();
+    populate(&root);
+
+    let image = scratch.path().join("image");
+    File::create(&image).unwrap().set_len(IMAGE_SIZE).unwrap();
+
+    let status = Command::new("mkfs.btrfs")
+        .arg("--quiet")
+        .arg("--force")
+        .arg("--rootdir")
+        .arg(&root)
+        .args(args)
+        .arg(&image)
+        .status();
+    match status {
+        Ok(status) => assert!(status.success(), "mkfs.btrfs failed: {status}"),
+        Err(_) => {
+            eprintln!("mkfs.btrfs isn't installed; skipping");
+            return None;
+        }
+    }
+    Some(image)
+}
+
+pub fn open(image: &Path) -> BtrfsVolume<FileDisk> {
+    BtrfsVolume::open(FileDisk(File::open(image).unwrap())).unwrap()
+}
+
+/// `len` bytes that don't repeat for a long while, so don't compress
+pub fn noise(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed | 1;
+    (0..len)
+        .map(|_| {
+            // xorshift
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// `len` bytes of text, which compress well
+pub fn text(len: usize) -> Vec<u8> {
+    b"all work and no play makes jack a dull boy\n"
+        .iter()
+        .copied()
+        .cycle()
+        .take(len)
+        .collect()
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// Has `f` change the item with the key `key`, given its size and its data as it was, in every
+/// copy of the leaf it's in, which is then checksummed again
+///
+/// Leaves are found by reading the whole image a node at a time, which is slow but doesn't
+/// trust the reader being tested to say where they are.
+pub fn patch_item(image: &Path, key: KeyTuple, mut f: impl FnMut(&mut u32, &mut [u8])) {
+    const HEADER: usize = 101;
+    const ITEM: usize = 25;
+
+    let nodesize = open(image).superblock().nodesize.get() as usize;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image)
+        .unwrap();
+    let mut node = vec![0; nodesize];
+    let mut patched = 0;
+
+    for at in (0..IMAGE_SIZE).step_by(nodesize) {
+        file.read_exact_at(&mut node, at).unwrap();
+        if node[HEADER - 1] != 0 || !crc32c::matches(&node[..32], &node[32..]) {
+            continue;
+        }
+
+        let count = u32_at(&node, HEADER - 5) as usize;
+        let Some(item) = (0..count)
+            .map(|i| HEADER + i * ITEM)
+            .find(|&item| (u64_at(&node, item), node[item + 8], u64_at(&node, item + 9)) == key)
+        else {
+            continue;
+        };
+
+        let start = HEADER + u32_at(&node, item + 17) as usize;
+        let mut size = u32_at(&node, item + 21);
+        let end = start + size as usize;
+        f(&mut size, &mut node[start..end]);
+        node[item + 21..item + 25].copy_from_slice(&size.to_le_bytes());
+
+        let csum = crc32c::crc32c(&node[32..]);
+        node[..4].copy_from_slice(&csum.to_le_bytes());
+        file.write_all_at(&node, at).unwrap();
+        patched += 1;
+    }
+    assert!(patched > 0, "no leaf has {key:?}");
+}
@@ -0,0 +1,202 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+
+use btrfs::{crc32c, newest_superblock, Disk};
+use btrfs_diskformat::constants::{MAGIC, SUPERBLOCK_ADDRS};
+use btrfs_diskformat::SuperBlock;
+use syscall::{Error, EINVAL, EIO};
+use zerocopy::LayoutVerified;
+
+use common::*;
+
+const PRIMARY: u64 = SUPERBLOCK_ADDRS[0];
+const MIRROR_64M: u64 = SUPERBLOCK_ADDRS[1];
+const MIRROR_256G: u64 = SUPERBLOCK_ADDRS[2];
+const SUPERBLOCK_SIZE: usize = 4096;
+
+/// A device of `size` bytes that's zeroes but for the superblocks put on it, keeping track of
+/// where it was read
+struct Sparse {
+    size: u64,
+    blocks: BTreeMap<u64, Vec<u8>>,
+    reads: Vec<u64>,
+}
+
+impl Sparse {
+    fn new(size: u64) -> Self {
+        Self {
+            size,
+            blocks: BTreeMap::new(),
+            reads: Vec::new(),
+        }
+    }
+    /// Puts a superblock at `addr` of the volume `fsid`, at `generation`
+    fn with(mut self, addr: u64, generation: u64, fsid: u8) -> Self {
+        let mut block = vec![0; SUPERBLOCK_SIZE];
+        {
+            let superblock = LayoutVerified::<_, SuperBlock>::new_unaligned(&mut block[..])
+                .unwrap()
+                .into_mut();
+            superblock.fsid = [fsid; 16];
+            superblock.bytenr.set(addr);
+            superblock.magic.set(MAGIC);
+            superblock.generation.set(generation);
+            superblock.dev_item.total_bytes.set(self.size);
+        }
+        let csum = crc32c::crc32c(&block[32..]);
+        block[..4].copy_from_slice(&csum.to_le_bytes());
+        self.blocks.insert(addr, block);
+        self
+    }
+    /// Flips a bit of the superblock at `addr`, so it fails its checksum
+    fn corrupt(mut self, addr: u64) -> Self {
+        self.blocks.get_mut(&addr).unwrap()[300] ^= 1;
+        self
+    }
+}
+
+impl Disk for Sparse {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> syscall::Result<()> {
+        self.reads.push(offset);
+        if offset + buf.len() as u64 > self.size {
+            return Err(Error::new(EIO));
+        }
+        buf.fill(0);
+        if let Some(block) = self.blocks.get(&offset) {
+            buf.copy_from_slice(&block[..buf.len()]);
+        }
+        Ok(())
+    }
+    fn size(&mut self) -> syscall::Result<u64> {
+        Ok(self.size)
+    }
+}
+
+/// The generation and fsid of the superblock picked, or the errno
+fn newest(disk: &mut Sparse) -> Result<(u64, u8), i32> {
+    newest_superblock(disk)
+        .map(|superblock| (superblock.generation.get(), superblock.fsid[0]))
+        .map_err(|err| err.errno)
+}
+
+/// Big enough for the mirror at 64 MiB but not the one at 256 GiB
+const SMALL: u64 = 128 << 20;
+/// Big enough for every mirror
+const LARGE: u64 = 512 << 30;
+
+#[test]
+fn newest_generation_wins() {
+    let mut disk = Sparse::new(SMALL)
+        .with(PRIMARY, 5, 1)
+        .with(MIRROR_64M, 5, 1);
+    assert_eq!(newest(&mut disk), Ok((5, 1)));
+
+    let mut disk = Sparse::new(SMALL)
+        .with(PRIMARY, 5, 1)
+        .with(MIRROR_64M, 7, 1);
+    assert_eq!(newest(&mut disk), Ok((7, 1)));
+
+    let mut disk = Sparse::new(LARGE)
+        .with(PRIMARY, 9, 1)
+        .with(MIRROR_64M, 7, 1)
+        .with(MIRROR_256G, 8, 1);
+    assert_eq!(newest(&mut disk), Ok((9, 1)));
+}
+
+#[test]
+fn mirrors_past_the_end_are_not_read() {
+    let mut disk = Sparse::new(SMALL)
+        .with(PRIMARY, 5, 1)
+        .with(MIRROR_64M, 5, 1);
+    assert_eq!(newest(&mut disk), Ok((5, 1)));
+    assert_eq!(disk.reads, [PRIMARY, MIRROR_64M]);
+
+    // too small for even the mirror at 64 MiB
+    let mut disk = Sparse::new(MIRROR_64M).with(PRIMARY, 5, 1);
+    assert_eq!(newest(&mut disk), Ok((5, 1)));
+    assert_eq!(disk.reads, [PRIMARY]);
+
+    // a corrupt primary is then the end of it
+    let mut disk = Sparse::new(MIRROR_64M).with(PRIMARY, 5, 1).corrupt(PRIMARY);
+    assert_eq!(newest(&mut disk), Err(EIO));
+    assert_eq!(disk.reads, [PRIMARY]);
+
+    // too small for a superblock at all
+    let mut disk = Sparse::new(PRIMARY + 100);
+    assert_eq!(newest(&mut disk), Err(EINVAL));
+    assert!(disk.reads.is_empty());
+}
+
+#[test]
+fn corrupt_primary_falls_back_to_a_mirror() {
+    let mut disk = Sparse::new(SMALL)
+        .with(PRIMARY, 5, 1)
+        .with(MIRROR_64M, 4, 1)
+        .corrupt(PRIMARY);
+    assert_eq!(newest(&mut disk), Ok((4, 1)));
+
+    let mut disk = Sparse::new(LARGE)
+        .with(PRIMARY, 5, 1)
+        .with(MIRROR_64M, 4, 1)
+        .with(MIRROR_256G, 3, 1)
+        .corrupt(PRIMARY);
+    assert_eq!(newest(&mut disk), Ok((4, 1)));
+
+    let mut disk = Sparse::new(SMALL).with(PRIMARY, 5, 1).corrupt(PRIMARY);
+    assert_eq!(newest(&mut disk), Err(EIO));
+}
+
+#[test]
+fn mirrors_of_other_volumes_are_passed_over() {
+    // the primary says which volume this is
+    let mut disk = Sparse::new(SMALL)
+        .with(PRIMARY, 5, 1)
+        .with(MIRROR_64M, 70, 2);
+    assert_eq!(newest(&mut disk), Ok((5, 1)));
+
+    let mut disk = Sparse::new(LARGE)
+        .with(PRIMARY, 5, 1)
+        .with(MIRROR_64M, 6, 1)
+        .with(MIRROR_256G, 70, 2);
+    assert_eq!(newest(&mut disk), Ok((6, 1)));
+
+    // without it, mirrors that disagree can't be told apart
+    let mut disk = Sparse::new(LARGE)
+        .with(PRIMARY, 5, 1)
+        .with(MIRROR_64M, 4, 1)
+        .with(MIRROR_256G, 70, 2)
+        .corrupt(PRIMARY);
+    assert_eq!(newest(&mut disk), Err(EIO));
+
+    // but ones that agree can
+    let mut disk = Sparse::new(LARGE)
+        .with(PRIMARY, 5, 2)
+        .with(MIRROR_64M, 4, 1)
+        .with(MIRROR_256G, 6, 1)
+        .corrupt(PRIMARY);
+    assert_eq!(newest(&mut disk), Ok((6, 1)));
+}
+
+#[test]
+fn volume_opens_from_a_mirror() {
+    let scratch = Scratch::new("mirror");
+    let Some(image) = mkfs(&scratch, &[], |root| {
+        std::fs::write(root.join("file"), text(5000)).unwrap();
+    }) else {
+        return;
+    };
+
+    let file = OpenOptions::new().write(true).open(&image).unwrap();
+    file.write_all_at(&[0xff; 64], PRIMARY + 300).unwrap();
+
+    let mut volume = open(&image);
+    assert_eq!(volume.superblock().bytenr.get(), MIRROR_64M);
+    let subvolume = volume.default_subvolume().unwrap();
+    let (subvolume, inode) = volume.resolve(&subvolume, "file").unwrap();
+    let mut buf = vec![0; 5010];
+    assert_eq!(volume.read(&subvolume, inode, 0, &mut buf), Ok(5000));
+    assert_eq!(buf[..5000], text(5000));
+}
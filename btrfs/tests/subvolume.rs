@@ -0,0 +1,440 @@
+mod common;
+
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::{symlink, FileExt, MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+
+use btrfs::subvolume::FT_DIR;
+use btrfs::tree::EXTENT_DATA_KEY;
+use btrfs::{BtrfsVolume, Subvolume};
+use syscall::{Stat, EIO, ENOENT, ENOTDIR};
+
+use common::*;
+
+const FT_REG_FILE: u8 = 1;
+const FT_SYMLINK: u8 = 7;
+
+const COMPRESS_NONE: u8 = 0;
+const COMPRESS_ZLIB: u8 = 1;
+
+const EXTENT_INLINE: u8 = 0;
+const EXTENT_REG: u8 = 1;
+const EXTENT_PREALLOC: u8 = 2;
+
+// where things are in an EXTENT_DATA item
+const COMPRESSION: usize = 16;
+const TYPE: usize = 20;
+const DISK_BYTENR: usize = 21;
+const DISK_NUM_BYTES: usize = 29;
+const OFFSET: usize = 37;
+const NUM_BYTES: usize = 45;
+
+fn set_u64(data: &mut [u8], at: usize, value: u64) {
+    data[at..at + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+/// The inode at `path` from the root directory of the default subvolume
+fn inode(volume: &mut BtrfsVolume<FileDisk>, path: &str) -> (Subvolume, u64) {
+    let subvolume = volume.default_subvolume().unwrap();
+    volume.resolve(&subvolume, path).unwrap()
+}
+
+fn read_all(volume: &mut BtrfsVolume<FileDisk>, path: &str) -> syscall::Result<Vec<u8>> {
+    let (subvolume, inode) = inode(volume, path);
+    let mut stat = Stat::default();
+    volume.stat(&subvolume, inode, &mut stat)?;
+
+    let mut buf = vec![0xaa; stat.st_size as usize + 10];
+    let len = volume.read(&subvolume, inode, 0, &mut buf)?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// The EXTENT_DATA items of the file at `path`, keyed by where in the file each starts
+fn extents(volume: &mut BtrfsVolume<FileDisk>, path: &str) -> Vec<(u64, Vec<u8>)> {
+    let (subvolume, inode) = inode(volume, path);
+    let keys = (inode, EXTENT_DATA_KEY, 0)..=(inode, EXTENT_DATA_KEY, u64::MAX);
+
+    let mut extents = Vec::new();
+    volume
+        .for_each_item(subvolume.root, subvolume.level, &keys, &mut |key, data| {
+            extents.push((key.2, data.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+    extents
+}
+
+/// A volume with a file of each kind of extent mkfs.btrfs makes: plain and zlib-compressed
+/// ones, each inline and not
+fn contents(scratch: &Scratch) -> Option<PathBuf> {
+    mkfs(scratch, &["--compress", "zlib"], |root| {
+        fs::write(root.join("inline"), b"hello, world\n").unwrap();
+        fs::write(root.join("inline-zlib"), text(3000)).unwrap();
+        fs::write(root.join("regular"), noise(300_000, 1)).unwrap();
+        fs::write(root.join("regular-zlib"), text(300_000)).unwrap();
+        fs::write(root.join("empty"), b"").unwrap();
+        File::create(root.join("sparse"))
+            .unwrap()
+            .write_all_at(&noise(8192, 3), 1 << 20)
+            .unwrap();
+    })
+}
+
+#[test]
+fn read_dir_and_lookup() {
+    let scratch = Scratch::new("read_dir_and_lookup");
+    let Some(image) = mkfs(&scratch, &[], |root| {
+        fs::create_dir(root.join("docs")).unwrap();
+        fs::write(root.join("docs/a"), b"a").unwrap();
+        fs::write(root.join("docs/b"), b"b").unwrap();
+        fs::write(root.join("top"), b"top").unwrap();
+        symlink("docs/a", root.join("link")).unwrap();
+    }) else {
+        return;
+    };
+    let mut volume = open(&image);
+    let subvolume = volume.default_subvolume().unwrap();
+
+    let mut entries = volume
+        .read_dir(&subvolume, subvolume.root_dir)
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.name, entry.file_type))
+        .collect::<Vec<_>>();
+    entries.sort();
+    assert_eq!(
+        entries,
+        [
+            ("docs".into(), FT_DIR),
+            ("link".into(), FT_SYMLINK),
+            ("top".into(), FT_REG_FILE),
+        ]
+    );
+
+    let docs = volume
+        .lookup(&subvolume, subvolume.root_dir, "docs")
+        .unwrap();
+    assert_eq!(docs.file_type, FT_DIR);
+    assert_eq!(docs.subvolume(), None);
+    let mut names = volume
+        .read_dir(&subvolume, docs.location.0)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["a", "b"]);
+
+    // lookups are by the hash of the name, then the name itself
+    let a = volume.lookup(&subvolume, docs.location.0, "a").unwrap();
+    assert_eq!(a.file_type, FT_REG_FILE);
+    assert_eq!(
+        volume.resolve(&subvolume, "/docs/./a").unwrap(),
+        (subvolume, a.location.0)
+    );
+    let top = volume
+        .lookup(&subvolume, subvolume.root_dir, "top")
+        .unwrap();
+    assert_eq!(
+        volume.resolve(&subvolume, "docs/../top").unwrap().1,
+        top.location.0
+    );
+    assert_eq!(
+        volume.resolve(&subvolume, "..").unwrap().1,
+        subvolume.root_dir
+    );
+
+    assert_eq!(
+        volume
+            .lookup(&subvolume, subvolume.root_dir, "missing")
+            .unwrap_err()
+            .errno,
+        ENOENT
+    );
+    assert_eq!(
+        volume
+            .lookup(&subvolume, docs.location.0, "top")
+            .unwrap_err()
+            .errno,
+        ENOENT
+    );
+    assert_eq!(
+        volume.resolve(&subvolume, "docs/c").unwrap_err().errno,
+        ENOENT
+    );
+    assert_eq!(
+        volume.resolve(&subvolume, "top/a").unwrap_err().errno,
+        ENOTDIR
+    );
+}
+
+#[test]
+fn stat() {
+    let scratch = Scratch::new("stat");
+    let Some(image) = mkfs(&scratch, &[], |root| {
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::set_permissions(root.join("dir"), fs::Permissions::from_mode(0o750)).unwrap();
+        fs::write(root.join("file"), noise(5000, 2)).unwrap();
+        fs::set_permissions(root.join("file"), fs::Permissions::from_mode(0o640)).unwrap();
+        File::options()
+            .write(true)
+            .open(root.join("file"))
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + std::time::Duration::new(1_000_000, 5))
+            .unwrap();
+        fs::hard_link(root.join("file"), root.join("dir/again")).unwrap();
+    }) else {
+        return;
+    };
+    let root = scratch.path().join("root");
+    let mut volume = open(&image);
+
+    for path in ["file", "dir", "dir/again"] {
+        let (subvolume, inode) = inode(&mut volume, path);
+        let mut stat = Stat::default();
+        volume.stat(&subvolume, inode, &mut stat).unwrap();
+
+        let source = fs::symlink_metadata(root.join(path)).unwrap();
+        assert_eq!(stat.st_ino, inode, "{path}");
+        assert_eq!(stat.st_dev, subvolume.id, "{path}");
+        assert_eq!(stat.st_mode as u32, source.mode(), "{path}");
+        assert_eq!(stat.st_uid, source.uid(), "{path}");
+        assert_eq!(stat.st_gid, source.gid(), "{path}");
+        assert_eq!(
+            stat.st_blksize,
+            volume.superblock().sectorsize.get(),
+            "{path}"
+        );
+        if !source.is_dir() {
+            assert_eq!(stat.st_size, source.size(), "{path}");
+            assert_eq!(stat.st_nlink, 2, "{path}");
+            assert!(stat.st_blocks * 512 >= stat.st_size, "{path}");
+            assert_eq!(
+                (stat.st_mtime, stat.st_mtime_nsec),
+                (1_000_000, 5),
+                "{path}"
+            );
+        }
+    }
+
+    // both names are the one inode
+    assert_eq!(inode(&mut volume, "file"), inode(&mut volume, "dir/again"));
+
+    let subvolume = volume.default_subvolume().unwrap();
+    let mut stat = Stat::default();
+    assert_eq!(
+        volume
+            .stat(&subvolume, u64::MAX - 1, &mut stat)
+            .unwrap_err()
+            .errno,
+        ENOENT
+    );
+}
+
+#[test]
+fn read() {
+    let scratch = Scratch::new("read");
+    let Some(image) = contents(&scratch) else {
+        return;
+    };
+    let mut volume = open(&image);
+
+    // the fixture has what it's meant to
+    for (path, kind, compression) in [
+        ("inline", EXTENT_INLINE, COMPRESS_NONE),
+        ("inline-zlib", EXTENT_INLINE, COMPRESS_ZLIB),
+        ("regular", EXTENT_REG, COMPRESS_NONE),
+        ("regular-zlib", EXTENT_REG, COMPRESS_ZLIB),
+    ] {
+        let extents = extents(&mut volume, path);
+        assert!(!extents.is_empty(), "{path}");
+        for (_, data) in extents {
+            assert_eq!(
+                (data[TYPE], data[COMPRESSION]),
+                (kind, compression),
+                "{path}"
+            );
+        }
+    }
+
+    let root = scratch.path().join("root");
+    for path in [
+        "inline",
+        "inline-zlib",
+        "regular",
+        "regular-zlib",
+        "empty",
+        "sparse",
+    ] {
+        let source = fs::read(root.join(path)).unwrap();
+        assert_eq!(read_all(&mut volume, path).unwrap(), source, "{path}");
+    }
+
+    // reads from the middle, across where extents would be split, and past the end
+    let source = fs::read(root.join("regular-zlib")).unwrap();
+    let (subvolume, inode) = inode(&mut volume, "regular-zlib");
+    for (offset, len) in [(1, 10), (131_000, 10_000), (299_990, 100), (300_000, 10)] {
+        let mut buf = vec![0; len];
+        let read = volume.read(&subvolume, inode, offset, &mut buf).unwrap();
+        let expected = source.get(offset as usize..).unwrap_or_default();
+        let expected = &expected[..expected.len().min(len)];
+        assert_eq!(&buf[..read], expected, "{offset}");
+    }
+}
+
+#[test]
+fn prealloc() {
+    let scratch = Scratch::new("prealloc");
+    let Some(image) = contents(&scratch) else {
+        return;
+    };
+
+    // mkfs.btrfs only writes what's there, so the extent is made a preallocated one after
+    let (_, inode) = inode(&mut open(&image), "regular");
+    let mut found = 0;
+    patch_item(&image, (inode, EXTENT_DATA_KEY, 0), |_, data| {
+        data[TYPE] = EXTENT_PREALLOC;
+        found = u64_at(data, NUM_BYTES);
+    });
+    assert!(found >= 300_000);
+
+    // preallocated but never written, so it reads as zeros, whatever's on disk
+    let mut volume = open(&image);
+    let contents = read_all(&mut volume, "regular").unwrap();
+    assert_eq!(contents.len(), 300_000);
+    assert!(contents.iter().all(|&byte| byte == 0));
+}
+
+/// Damages the extent of the file at `path` that starts at `at` with `f`, then reads it back
+fn read_damaged(
+    path: &str,
+    at: u64,
+    f: impl FnMut(&mut u32, &mut [u8]),
+) -> syscall::Result<Vec<u8>> {
+    let scratch = Scratch::new(&format!("damaged-{path}"));
+    let Some(image) = contents(&scratch) else {
+        return Err(syscall::Error::new(EIO));
+    };
+
+    let (_, inode) = inode(&mut open(&image), path);
+    patch_item(&image, (inode, EXTENT_DATA_KEY, at), f);
+    read_all(&mut open(&image), path)
+}
+
+#[test]
+fn damaged_extents() {
+    let errno = |result: syscall::Result<Vec<u8>>| result.map(drop).map_err(|err| err.errno);
+
+    // cut off before the pointer to the extent
+    assert_eq!(
+        errno(read_damaged("regular", 0, |size, _| *size = DISK_BYTENR as u32 + 8)),
+        Err(EIO)
+    );
+    // a compressed extent bigger than one can be, which isn't allocated for
+    assert_eq!(
+        errno(read_damaged("regular-zlib", 0, |_, data| {
+            set_u64(data, DISK_NUM_BYTES, 1 << 40)
+        })),
+        Err(EIO)
+    );
+    // offsets that wrap around
+    assert_eq!(
+        errno(read_damaged("regular", 0, |_, data| {
+            set_u64(data, OFFSET, u64::MAX - 10)
+        })),
+        Err(EIO)
+    );
+    assert_eq!(
+        errno(read_damaged("sparse", 1 << 20, |_, data| {
+            set_u64(data, NUM_BYTES, u64::MAX)
+        })),
+        Err(EIO)
+    );
+    // a zlib stream that isn't one
+    assert_eq!(
+        errno(read_damaged("inline-zlib", 0, |_, data| {
+            data[TYPE + 1..].fill(0xff)
+        })),
+        Err(EIO)
+    );
+}
+
+#[test]
+fn compressed_extent_past_its_chunk() {
+    let scratch = Scratch::new("compressed_extent_past_its_chunk");
+    let Some(image) = contents(&scratch) else {
+        return;
+    };
+
+    // a chunk another one starts right after, so reading on past its end would work
+    let mut volume = open(&image);
+    let chunks = volume
+        .chunks()
+        .iter()
+        .map(|(start, chunk)| (start, start + chunk.length))
+        .collect::<Vec<_>>();
+    let boundary = chunks
+        .iter()
+        .map(|&(_, end)| end)
+        .find(|&end| chunks.iter().any(|&(start, _)| start == end))
+        .expect("no chunk follows on from another");
+
+    // the extent's stream, moved to just before the end of that chunk, and said to run on
+    // into the next one; the stream is whole, so only its length is wrong
+    let (_, data) = extents(&mut volume, "regular-zlib").remove(0);
+    let (from, len) = (u64_at(&data, DISK_BYTENR), u64_at(&data, DISK_NUM_BYTES));
+    let mut stream = vec![0; len as usize];
+    volume.read_logical(from, &mut stream).unwrap();
+    let to = boundary - len;
+    let file = OpenOptions::new().write(true).open(&image).unwrap();
+    for copy in volume.chunks().map(to).unwrap().copies {
+        file.write_all_at(&stream, copy.physical).unwrap();
+    }
+    drop(file);
+
+    let (_, inode) = inode(&mut volume, "regular-zlib");
+    patch_item(&image, (inode, EXTENT_DATA_KEY, 0), |_, data| {
+        set_u64(data, DISK_BYTENR, to);
+        set_u64(data, DISK_NUM_BYTES, len + 4096);
+    });
+    assert_eq!(
+        read_all(&mut open(&image), "regular-zlib")
+            .unwrap_err()
+            .errno,
+        EIO
+    );
+}
+
+#[test]
+fn corrupt_compressed_data() {
+    let scratch = Scratch::new("corrupt_compressed_data");
+    let Some(image) = contents(&scratch) else {
+        return;
+    };
+
+    let mut volume = open(&image);
+    let (_, data) = extents(&mut volume, "regular-zlib").remove(0);
+    let mapped = volume.chunks().map(u64_at(&data, DISK_BYTENR)).unwrap();
+    let file = OpenOptions::new().write(true).open(&image).unwrap();
+    for copy in mapped.copies {
+        file.write_all_at(&[0xff; 64], copy.physical).unwrap();
+    }
+    drop(file);
+
+    assert_eq!(
+        read_all(&mut open(&image), "regular-zlib")
+            .unwrap_err()
+            .errno,
+        EIO
+    );
+    // the rest of the volume is still there
+    assert_eq!(
+        read_all(&mut open(&image), "regular").unwrap(),
+        fs::read(scratch.path().join("root/regular")).unwrap()
+    );
+}
@@ -0,0 +1,304 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+use core::ops::RangeBounds;
+use syscall::{Error, Result, EFBIG};
+
+use super::checksum::{checksum_of, Checksum};
+
+// File contents are split into fixed-size blocks, each checksummed on its own and held behind
+// an `Arc`, so changing a few bytes of a large file copies and rehashes one block rather than the
+// whole file, and snapshots keep sharing every block that hasn't changed. Blocks that were never
+// written (or were written as zeroes) aren't stored at all and read back as zeroes.
+
+/// Bytes of file contents per block; a block only holds up to the last non-hole byte written
+pub const BLOCK_SIZE: u64 = 4096;
+
+/// How a block's contents are stored, so encryption can happen a block at a time
+pub trait BlockCodec {
+    /// Turns the contents of block `index` into the bytes that get stored
+    fn encode(&self, index: u64, plain: Vec<u8>) -> Vec<u8>;
+    /// Turns stored bytes back into the contents of block `index`
+    fn decode(&self, index: u64, stored: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Blocks stored as they are
+pub struct Plain;
+
+impl BlockCodec for Plain {
+    fn encode(&self, _: u64, plain: Vec<u8>) -> Vec<u8> {
+        plain
+    }
+    fn decode(&self, _: u64, stored: &[u8]) -> Result<Vec<u8>> {
+        Ok(stored.to_vec())
+    }
+}
+
+/// Checksum of a block's stored bytes, as kept in `Block::checksum`
+pub fn block_checksum(stored: &[u8]) -> Checksum {
+    struct Stored<'b>(&'b [u8]);
+
+    impl Hash for Stored<'_> {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            state.write(&(self.0.len() as u64).to_le_bytes());
+            state.write(self.0);
+        }
+    }
+
+    checksum_of(&Stored(stored))
+}
+
+/// Feeds a hasher a file's length and the index and checksum of each stored block, in index
+/// order; what a file's entry checksum covers in place of its contents
+pub fn hash_file<H: Hasher>(
+    len: u64,
+    blocks: impl ExactSizeIterator<Item = (u64, Checksum)>,
+    state: &mut H,
+) {
+    state.write(&len.to_le_bytes());
+    state.write(&(blocks.len() as u64).to_le_bytes());
+    for (index, checksum) in blocks {
+        state.write(&index.to_le_bytes());
+        state.write(checksum.as_bytes());
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Block {
+    data: Vec<u8>,
+    checksum: Checksum,
+}
+
+impl Block {
+    pub fn new(data: Vec<u8>) -> Self {
+        let checksum = block_checksum(&data);
+        Self { data, checksum }
+    }
+    /// A block read back along with the checksum it was stored with, which `is_intact` holds
+    /// the data up against
+    pub fn with_checksum(data: Vec<u8>, checksum: Checksum) -> Self {
+        Self { data, checksum }
+    }
+    /// The bytes as stored, i.e. encrypted if the file is
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+    /// Whether the data still matches its checksum
+    pub fn is_intact(&self) -> bool {
+        block_checksum(&self.data) == self.checksum
+    }
+}
+
+/// A file's contents as a sparse map of blocks
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FileBlocks {
+    len: u64,
+    blocks: BTreeMap<u64, Arc<Block>>,
+}
+
+impl Hash for FileBlocks {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_file(
+            self.len,
+            self.blocks
+                .iter()
+                .map(|(&index, block)| (index, block.checksum)),
+            state,
+        );
+    }
+}
+
+impl FileBlocks {
+    /// Splits unencrypted contents into blocks
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut file = Self::default();
+        file.write_at(0, data, &Plain)
+            .expect("plain blocks always decode");
+        file
+    }
+    /// Reassembles a file from blocks read off disk, which have to be in index order and within
+    /// `len`
+    pub fn from_blocks(
+        len: u64,
+        blocks: impl IntoIterator<Item = (u64, Arc<Block>)>,
+    ) -> Option<Self> {
+        let mut file = Self {
+            len,
+            blocks: BTreeMap::new(),
+        };
+
+        for (index, block) in blocks {
+            if index >= len.div_ceil(BLOCK_SIZE)
+                || file
+                    .blocks
+                    .last_key_value()
+                    .is_some_and(|(&last, _)| last >= index)
+            {
+                return None;
+            }
+            file.blocks.insert(index, block);
+        }
+
+        Some(file)
+    }
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Stored blocks in index order; anything missing is a hole
+    pub fn blocks(&self) -> impl ExactSizeIterator<Item = (u64, &Arc<Block>)> {
+        self.blocks.iter().map(|(&index, block)| (index, block))
+    }
+    // contents of block `index`, which may be shorter than a block if the rest is a hole
+    fn read_block(&self, index: u64, codec: &dyn BlockCodec) -> Result<Vec<u8>> {
+        match self.blocks.get(&index) {
+            Some(block) => codec.decode(index, &block.data),
+            None => Ok(Vec::new()),
+        }
+    }
+    fn store_block(&mut self, index: u64, mut plain: Vec<u8>, codec: &dyn BlockCodec) {
+        // trailing zeroes read back the same as a hole, so there's no need to keep them
+        let used = plain
+            .iter()
+            .rposition(|&byte| byte != 0)
+            .map_or(0, |last| last + 1);
+        plain.truncate(used);
+
+        if plain.is_empty() {
+            self.blocks.remove(&index);
+        } else {
+            let stored = codec.encode(index, plain);
+            self.blocks.insert(index, Arc::new(Block::new(stored)));
+        }
+    }
+    /// Reads up to `buf.len()` bytes starting at `offset`, returning how many there were
+    pub fn read_at(&self, offset: u64, buf: &mut [u8], codec: &dyn BlockCodec) -> Result<usize> {
+        if offset >= self.len {
+            return Ok(0);
+        }
+
+        let end = self.len.min(offset.saturating_add(buf.len() as u64));
+        let buf = &mut buf[..(end - offset) as usize];
+        buf.fill(0);
+
+        for (&index, block) in self
+            .blocks
+            .range(offset / BLOCK_SIZE..=(end - 1) / BLOCK_SIZE)
+        {
+            let plain = codec.decode(index, &block.data)?;
+            let start = index * BLOCK_SIZE;
+
+            let from = start.max(offset);
+            let to = (start + (plain.len() as u64).min(BLOCK_SIZE)).min(end);
+            if from < to {
+                buf[(from - offset) as usize..(to - offset) as usize]
+                    .copy_from_slice(&plain[(from - start) as usize..(to - start) as usize]);
+            }
+        }
+
+        Ok(buf.len())
+    }
+    /// Writes `buf` at `offset`, growing the file if it ends past the current end; anything
+    /// skipped over becomes a hole. Writing nothing leaves the file as it is, wherever it's at.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8], codec: &dyn BlockCodec) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(Error::new(EFBIG))?;
+
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / BLOCK_SIZE;
+            let within = (pos - index * BLOCK_SIZE) as usize;
+            let count = (BLOCK_SIZE - within as u64).min(end - pos) as usize;
+            let src = &buf[(pos - offset) as usize..][..count];
+
+            // a write covering the whole block doesn't need what was there
+            let mut plain = if count as u64 == BLOCK_SIZE {
+                vec![0; BLOCK_SIZE as usize]
+            } else {
+                self.read_block(index, codec)?
+            };
+            if plain.len() < within + count {
+                plain.resize(within + count, 0);
+            }
+            plain[within..within + count].copy_from_slice(src);
+
+            self.store_block(index, plain, codec);
+            pos += count as u64;
+        }
+
+        self.len = self.len.max(end);
+        Ok(buf.len())
+    }
+    /// Cuts the file down to `len` bytes, or extends it with a hole
+    pub fn truncate(&mut self, len: u64, codec: &dyn BlockCodec) -> Result<()> {
+        if len < self.len {
+            drop(self.blocks.split_off(&len.div_ceil(BLOCK_SIZE)));
+
+            // the block that now holds the end can't keep bytes past it, or growing the file
+            // again would bring them back
+            let index = len / BLOCK_SIZE;
+            let within = (len % BLOCK_SIZE) as usize;
+            if within != 0 && self.blocks.contains_key(&index) {
+                let mut plain = self.read_block(index, codec)?;
+                if plain.len() > within {
+                    plain.truncate(within);
+                    self.store_block(index, plain, codec);
+                }
+            }
+        }
+
+        self.len = len;
+        Ok(())
+    }
+    /// The whole file as one buffer
+    pub fn to_vec(&self, codec: &dyn BlockCodec) -> Result<Vec<u8>> {
+        let len = usize::try_from(self.len).map_err(|_| Error::new(EFBIG))?;
+        let mut out = vec![0; len];
+        self.read_at(0, &mut out, codec)?;
+        Ok(out)
+    }
+    /// Swaps each stored block with an index in `range` for whatever `share` gives back for it,
+    /// which has to hold the same bytes; how identical blocks come to be stored once. Returns
+    /// how many were swapped.
+    pub fn share_blocks(
+        &mut self,
+        range: impl RangeBounds<u64>,
+        share: &mut dyn FnMut(&Arc<Block>) -> Option<Arc<Block>>,
+    ) -> u64 {
+        let mut swapped = 0;
+        for (_, block) in self.blocks.range_mut(range) {
+            if let Some(shared) = share(block) {
+                debug_assert_eq!(shared.data, block.data);
+                *block = shared;
+                swapped += 1;
+            }
+        }
+        swapped
+    }
+    /// The same contents stored with a different codec, e.g. when moving into an encrypted
+    /// directory
+    pub fn recode(&self, from: &dyn BlockCodec, to: &dyn BlockCodec) -> Result<Self> {
+        let mut out = Self {
+            len: self.len,
+            blocks: BTreeMap::new(),
+        };
+
+        for (&index, block) in self.blocks.iter() {
+            let plain = from.decode(index, &block.data)?;
+            out.store_block(index, plain, to);
+        }
+
+        Ok(out)
+    }
+}
@@ -0,0 +1,61 @@
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use sha3::{Digest, Sha3_512};
+
+/// Bytes in a checksum: a whole SHA3-512 digest
+pub const CHECKSUM_LEN: usize = 64;
+
+/// SHA3-512 digest of an entry or block, as kept in memory and on disk
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Checksum(pub [u8; CHECKSUM_LEN]);
+
+impl Checksum {
+    pub fn as_bytes(&self) -> &[u8; CHECKSUM_LEN] {
+        &self.0
+    }
+}
+
+impl fmt::LowerHex for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            f.write_str("0x")?;
+        }
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Checksum({self:#x})")
+    }
+}
+
+/// Hasher that keeps the whole digest rather than the 64 bits `Hasher::finish` has room for
+#[derive(Default)]
+pub struct ChecksumHasher(Sha3_512);
+
+impl ChecksumHasher {
+    pub fn digest(self) -> Checksum {
+        Checksum(self.0.finalize().into())
+    }
+}
+
+impl Hasher for ChecksumHasher {
+    fn finish(&self) -> u64 {
+        let digest: [u8; CHECKSUM_LEN] = self.0.clone().finalize().into();
+        u64::from_be_bytes(digest[..8].try_into().unwrap())
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes)
+    }
+}
+
+/// Checksum of anything fed to a hasher the way entries and blocks are
+pub fn checksum_of<T: Hash + ?Sized>(value: &T) -> Checksum {
+    let mut hasher = ChecksumHasher::default();
+    value.hash(&mut hasher);
+    hasher.digest()
+}
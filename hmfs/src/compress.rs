@@ -0,0 +1,177 @@
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core2::io::{Read, Write};
+use libflate::deflate::{Decoder, Encoder};
+use syscall::{Error, Result, EINVAL, EIO};
+
+use super::blocks::{BlockCodec, BLOCK_SIZE};
+use super::{Entry, EntryKind};
+
+// Transparent compression of file contents. A file marked with `COMPRESS_XATTR` has each block
+// compressed before whatever its directory stores blocks with (encryption included) sees it,
+// and decompressed after, so checksums still cover the bytes as stored and a scrub never has to
+// decompress anything. Each compressed block starts with a tag saying how the rest of it is
+// stored, so a block that doesn't get any smaller is kept as it is. A directory marked the same
+// way passes the mark on to whatever gets created in it.
+
+/// Extended attribute naming the compression a file's blocks are stored with, or that new
+/// entries in a directory get
+pub const COMPRESS_XATTR: &str = "hmfs.compress";
+
+/// Tag for a block kept as it is
+const TAG_RAW: u8 = 0;
+/// Tag for a block stored as a raw deflate stream
+const TAG_DEFLATE: u8 = 1;
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Compression {
+    #[default]
+    Off,
+    Deflate,
+}
+
+impl Compression {
+    /// Parses a value of `COMPRESS_XATTR`, failing with `EINVAL` for anything unknown
+    pub fn parse(value: &[u8]) -> Result<Self> {
+        match value {
+            b"deflate" => Ok(Self::Deflate),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+    /// The compression `entry` is marked with
+    pub fn of(entry: &Entry) -> Result<Self> {
+        entry
+            .xattr(COMPRESS_XATTR)
+            .map_or(Ok(Self::Off), Self::parse)
+    }
+    /// What goes in `COMPRESS_XATTR`, or `None` for no mark at all
+    pub fn value(self) -> Option<&'static [u8]> {
+        match self {
+            Self::Off => None,
+            Self::Deflate => Some(b"deflate"),
+        }
+    }
+    /// Blocks compressed this way before `inner` stores them
+    pub fn codec<'c>(self, inner: &'c dyn BlockCodec) -> Compressed<'c> {
+        Compressed {
+            compression: self,
+            inner,
+        }
+    }
+}
+
+/// A block codec with compression in front of it
+pub struct Compressed<'c> {
+    compression: Compression,
+    inner: &'c dyn BlockCodec,
+}
+
+impl BlockCodec for Compressed<'_> {
+    fn encode(&self, index: u64, plain: Vec<u8>) -> Vec<u8> {
+        let tagged = match self.compression {
+            Compression::Off => plain,
+            Compression::Deflate => deflate(plain),
+        };
+        self.inner.encode(index, tagged)
+    }
+    fn decode(&self, index: u64, stored: &[u8]) -> Result<Vec<u8>> {
+        let tagged = self.inner.decode(index, stored)?;
+        match self.compression {
+            Compression::Off => Ok(tagged),
+            Compression::Deflate => inflate(&tagged),
+        }
+    }
+}
+
+fn deflate(plain: Vec<u8>) -> Vec<u8> {
+    let mut encoder = Encoder::new(Vec::from([TAG_DEFLATE]));
+    encoder
+        .write_all(&plain)
+        .expect("writing to a Vec can't fail");
+    let compressed = encoder
+        .finish()
+        .into_result()
+        .expect("writing to a Vec can't fail");
+
+    if compressed.len() < plain.len() + 1 {
+        compressed
+    } else {
+        let mut raw = Vec::with_capacity(plain.len() + 1);
+        raw.push(TAG_RAW);
+        raw.extend_from_slice(&plain);
+        raw
+    }
+}
+
+fn inflate(tagged: &[u8]) -> Result<Vec<u8>> {
+    match tagged.split_first() {
+        Some((&TAG_RAW, raw)) => Ok(raw.to_vec()),
+        Some((&TAG_DEFLATE, compressed)) => {
+            // a block never holds more than `BLOCK_SIZE` bytes, so reading one past that is
+            // enough to tell a stream that claims more
+            let mut plain = Vec::new();
+            Decoder::new(compressed)
+                .take(BLOCK_SIZE + 1)
+                .read_to_end(&mut plain)
+                .map_err(|_| Error::new(EIO))?;
+
+            if plain.len() as u64 > BLOCK_SIZE {
+                return Err(Error::new(EIO));
+            }
+            Ok(plain)
+        }
+        _ => Err(Error::new(EIO)),
+    }
+}
+
+/// How much space a tree's file contents take up
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Usage {
+    /// Files in the tree
+    pub files: u64,
+    /// Total length of the files, holes included
+    pub logical: u64,
+    /// Bytes of blocks actually stored for them
+    pub stored: u64,
+}
+
+impl Usage {
+    /// Adds up the files at and below `entry`; hard links and blocks shared between files are
+    /// only counted once
+    pub fn of(entry: &Entry) -> Self {
+        let mut usage = Self::default();
+        let mut seen = BTreeSet::new();
+        usage.add(entry, &mut seen);
+        usage
+    }
+    /// How many times over the contents fit in the space they take, e.g. 2.0 for half
+    pub fn ratio(&self) -> f64 {
+        if self.stored == 0 {
+            return 1.0;
+        }
+        self.logical as f64 / self.stored as f64
+    }
+    fn add(&mut self, entry: &Entry, seen: &mut BTreeSet<usize>) {
+        match &entry.kind {
+            EntryKind::Directory(map) => {
+                for (props, child) in map.iter() {
+                    // the root's own "/" entry points back at the root
+                    if props.name != "/" && seen.insert(Arc::as_ptr(child) as usize) {
+                        self.add(child, seen);
+                    }
+                }
+            }
+            EntryKind::File(file) => {
+                self.files += 1;
+                self.logical += file.len();
+                for (_, block) in file.blocks() {
+                    if seen.insert(Arc::as_ptr(block) as usize) {
+                        self.stored += block.data().len() as u64;
+                    }
+                }
+            }
+            EntryKind::Root(_) | EntryKind::Symlink(_) => {}
+        }
+    }
+}
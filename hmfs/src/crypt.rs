@@ -0,0 +1,301 @@
+use aes_siv::aead::generic_array::GenericArray;
+use aes_siv::siv::Aes256Siv;
+use aes_siv::KeyInit;
+use alloc::string::String;
+use alloc::vec::Vec;
+use argon2::{Algorithm, Argon2, Params, Version};
+use core::fmt;
+use syscall::{Error, Result, EINVAL, EIO, EKEYREJECTED};
+
+use super::blocks::{BlockCodec, FileBlocks};
+use super::checksum::checksum_of;
+
+// Deterministic authenticated encryption with AES-SIV (RFC 5297), under keys stretched from a
+// passphrase with Argon2id. Equal plaintexts encrypt equally under the same key, which is what
+// lets an unlocked directory find a child by encrypting its name rather than decrypting every
+// sibling, and keeps checksums stable across re-encodes. Names are bound to a random nonce kept
+// with the directory they're in, so equal names only show as equal within one directory, and
+// can't be moved to another; file blocks are likewise bound to a nonce kept with each file, so
+// equal contents in two files don't show. Blocks are sealed one at a time, so a tag over the
+// file's length and which blocks it stores keeps it from being cut short or having blocks
+// dropped or swapped for older ones without it showing.
+
+/// Extended attribute that marks a directory as encrypted; holds its `CryptParams`
+pub const CRYPT_XATTR: &str = "hmfs.crypt";
+/// Extended attribute holding the nonce a file's blocks, or a directory's names, are sealed
+/// with, in an encrypted directory
+pub const NONCE_XATTR: &str = "hmfs.nonce";
+/// Extended attribute holding a file's tag over its length and stored blocks, in an encrypted
+/// directory
+pub const TAG_XATTR: &str = "hmfs.tag";
+
+pub const SALT_LEN: usize = 16;
+pub const NONCE_LEN: usize = 16;
+pub const TAG_LEN: usize = 16;
+
+/// Argon2id memory cost in KiB, passes and lanes for newly encrypted directories
+pub const DEFAULT_COST: Cost = Cost {
+    memory: Params::DEFAULT_M_COST,
+    passes: Params::DEFAULT_T_COST,
+    lanes: Params::DEFAULT_P_COST,
+};
+
+/// The most a directory can ask for, so a crafted image can't have unlocking it take all the
+/// memory or time there is
+pub const MAX_COST: Cost = Cost {
+    memory: 1 << 18,
+    passes: 16,
+    lanes: 16,
+};
+
+/// Random bytes from the operating system, or from RDRAND in the kernel
+pub fn random<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).map_err(|_| Error::new(EIO))?;
+    Ok(bytes)
+}
+
+/// What a ciphertext holds, so a name can never be passed off as file contents or vice versa
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Domain {
+    Name = 1,
+    Data = 2,
+    Check = 3,
+    Link = 4,
+    File = 5,
+}
+
+/// How hard a passphrase is to guess at, as Argon2id parameters
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Cost {
+    /// KiB of memory
+    pub memory: u32,
+    pub passes: u32,
+    pub lanes: u32,
+}
+
+/// A directory's AES-256-SIV key
+#[derive(Clone, Eq, PartialEq, Hash)]
+pub struct Key([u8; 64]);
+
+impl Key {
+    /// Stretches a passphrase with Argon2id, failing with `EINVAL` if `cost` is out of range
+    pub fn derive(passphrase: &[u8], salt: &[u8; SALT_LEN], cost: Cost) -> Result<Self> {
+        let params = Params::new(cost.memory, cost.passes, cost.lanes, Some(64))
+            .map_err(|_| Error::new(EINVAL))?;
+
+        let mut key = Self([0; 64]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase, salt, &mut key.0)
+            .map_err(|_| Error::new(EINVAL))?;
+        Ok(key)
+    }
+    fn siv(&self) -> Aes256Siv {
+        Aes256Siv::new(GenericArray::from_slice(&self.0))
+    }
+    /// Encrypts `plaintext` into a tag followed by the ciphertext
+    ///
+    /// `context` is authenticated without being stored, so the result only opens again given
+    /// the same context; blocks pass their file's nonce and their index, so they can't be
+    /// swapped around within a file or between files, and names their directory's nonce.
+    pub fn seal(&self, domain: Domain, context: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        self.siv()
+            .encrypt([&[domain as u8][..], context], plaintext)
+            .expect("AES-SIV takes any plaintext that fits in memory")
+    }
+    /// Decrypts the output of `seal`, failing with `EIO` if it was tampered with
+    pub fn open(&self, domain: Domain, context: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        self.siv()
+            .decrypt([&[domain as u8][..], context], sealed)
+            .map_err(|_| Error::new(EIO))
+    }
+    /// Encrypts a symbolic link's target into the lowercase hex form it's stored as
+    ///
+    /// Targets aren't bound to a directory, as a link can have names in several.
+    pub fn seal_link(&self, target: &str) -> String {
+        to_hex(&self.seal(Domain::Link, &[], target.as_bytes()))
+    }
+    pub fn open_link(&self, stored: &str) -> Result<String> {
+        let plaintext = self.open(Domain::Link, &[], &from_hex(stored)?)?;
+        String::from_utf8(plaintext).map_err(|_| Error::new(EIO))
+    }
+    /// This key bound to the nonce in a file's `NONCE_XATTR`, for sealing the file's blocks
+    pub fn for_file(&self, nonce: &[u8]) -> Result<FileKey> {
+        Ok(FileKey {
+            key: self.clone(),
+            nonce: nonce.try_into().map_err(|_| Error::new(EIO))?,
+        })
+    }
+    /// This key bound to the nonce in a directory's `NONCE_XATTR`, for sealing the names in it
+    pub fn for_dir(&self, nonce: &[u8]) -> Result<DirKey> {
+        Ok(DirKey {
+            key: self.clone(),
+            nonce: nonce.try_into().map_err(|_| Error::new(EIO))?,
+        })
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        out.push(char::from_digit((byte >> 4) as u32, 16).unwrap());
+        out.push(char::from_digit((byte & 0xf) as u32, 16).unwrap());
+    }
+    out
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(Error::new(EIO));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()
+        .ok_or(Error::new(EIO))
+}
+
+/// A key and the nonce of one directory under it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DirKey {
+    key: Key,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl DirKey {
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+    /// Encrypts a name into the lowercase hex form it's stored under in this directory
+    pub fn seal_name(&self, name: &str) -> String {
+        to_hex(&self.key.seal(Domain::Name, &self.nonce, name.as_bytes()))
+    }
+    pub fn open_name(&self, stored: &str) -> Result<String> {
+        let plaintext = self
+            .key
+            .open(Domain::Name, &self.nonce, &from_hex(stored)?)?;
+        String::from_utf8(plaintext).map_err(|_| Error::new(EIO))
+    }
+}
+
+/// A directory's key and one file's nonce
+pub struct FileKey {
+    key: Key,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl FileKey {
+    fn context(&self, index: u64) -> [u8; NONCE_LEN + 8] {
+        let mut context = [0u8; NONCE_LEN + 8];
+        context[..NONCE_LEN].copy_from_slice(&self.nonce);
+        context[NONCE_LEN..].copy_from_slice(&index.to_le_bytes());
+        context
+    }
+    // the nonce and the checksum of the file's length and the index and checksum of each
+    // stored block
+    fn file_context(&self, file: &FileBlocks) -> Vec<u8> {
+        let mut context = Vec::from(self.nonce);
+        context.extend_from_slice(checksum_of(file).as_bytes());
+        context
+    }
+    /// The tag kept in the file's `TAG_XATTR`, which changes with its length or any block
+    pub fn tag(&self, file: &FileBlocks) -> Vec<u8> {
+        self.key.seal(Domain::File, &self.file_context(file), &[])
+    }
+    /// Checks `file` against its tag, failing with `EIO` if either was tampered with
+    pub fn check(&self, file: &FileBlocks, tag: &[u8]) -> Result<()> {
+        self.key
+            .open(Domain::File, &self.file_context(file), tag)
+            .map(drop)
+    }
+}
+
+// each block is sealed on its own, so a write only re-encrypts the blocks it touches
+impl BlockCodec for FileKey {
+    fn encode(&self, index: u64, plain: Vec<u8>) -> Vec<u8> {
+        self.key.seal(Domain::Data, &self.context(index), &plain)
+    }
+    fn decode(&self, index: u64, stored: &[u8]) -> Result<Vec<u8>> {
+        self.key.open(Domain::Data, &self.context(index), stored)
+    }
+}
+
+// keep key material out of logs
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        for byte in self.0.iter_mut() {
+            // volatile so the wipe isn't optimized away as a dead store
+            unsafe { core::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+/// How an encrypted directory's key is derived, stored in its `CRYPT_XATTR`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CryptParams {
+    pub salt: [u8; SALT_LEN],
+    pub cost: Cost,
+    /// Seal of nothing, to tell a wrong passphrase apart from a right one
+    pub check: [u8; TAG_LEN],
+}
+
+impl CryptParams {
+    pub const LEN: usize = SALT_LEN + 12 + TAG_LEN;
+
+    /// Parameters for a new encrypted directory, and the key they describe
+    pub fn new(passphrase: &[u8], salt: [u8; SALT_LEN], cost: Cost) -> Result<(Self, Key)> {
+        let key = Key::derive(passphrase, &salt, cost)?;
+        let check = key.seal(Domain::Check, &[], &[]).try_into().unwrap();
+        Ok((Self { salt, cost, check }, key))
+    }
+    /// Derives the key for these parameters, or fails with `EKEYREJECTED`
+    pub fn unlock(&self, passphrase: &[u8]) -> Result<Key> {
+        let key = Key::derive(passphrase, &self.salt, self.cost)?;
+
+        key.open(Domain::Check, &[], &self.check)
+            .map_err(|_| Error::new(EKEYREJECTED))?;
+        Ok(key)
+    }
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::LEN);
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(&self.cost.memory.to_le_bytes());
+        out.extend_from_slice(&self.cost.passes.to_le_bytes());
+        out.extend_from_slice(&self.cost.lanes.to_le_bytes());
+        out.extend_from_slice(&self.check);
+        out
+    }
+    /// Reads parameters back, failing with `EIO` if they're malformed or cost more than
+    /// `MAX_COST`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != Self::LEN {
+            return Err(Error::new(EIO));
+        }
+
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let cost = Cost {
+            memory: word(SALT_LEN),
+            passes: word(SALT_LEN + 4),
+            lanes: word(SALT_LEN + 8),
+        };
+        if cost.memory > MAX_COST.memory
+            || cost.passes > MAX_COST.passes
+            || cost.lanes > MAX_COST.lanes
+        {
+            return Err(Error::new(EIO));
+        }
+
+        Ok(Self {
+            salt: bytes[..SALT_LEN].try_into().unwrap(),
+            cost,
+            check: bytes[SALT_LEN + 12..].try_into().unwrap(),
+        })
+    }
+}
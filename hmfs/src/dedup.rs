@@ -0,0 +1,236 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+use core::ops::RangeBounds;
+
+use super::blocks::{Block, FileBlocks};
+use super::checksum::Checksum;
+use super::{Entry, EntryKind, HashMap, Properties};
+
+// Deduplication of file blocks by content. Blocks are already shared by `Arc` between snapshots
+// and hard links, and an image stores each distinct `Arc` once, so making two files with the
+// same contents share storage only takes making them hold the same `Arc`. The dedup table finds
+// that `Arc` by the checksum of a block's stored bytes, which is SHA3-512 and so as good as the
+// bytes themselves; the bytes are compared anyway before anything is shared. The table holds
+// its blocks weakly, so a block's reference count is its own `Arc` count (one for each file
+// holding it, however many trees that file is in), and a block nothing holds any more drops
+// out on its own.
+//
+// Files are deduplicated as they're written when the root directory carries `DEDUP_XATTR`, and
+// `Dedup` does a whole volume at once. Blocks are compared as stored, so encrypted blocks only
+// ever match blocks encrypted the same way.
+
+/// Extended attribute of the root directory that turns on deduplication as files are written
+pub const DEDUP_XATTR: &str = "hmfs.dedup";
+
+/// Distinct blocks by the checksum of their stored bytes
+#[derive(Debug, Clone, Default)]
+pub struct DedupTable {
+    blocks: BTreeMap<Checksum, Weak<Block>>,
+    /// Size of the table when it was last cleared of blocks nothing holds
+    swept: usize,
+}
+
+// the table only caches what's in the trees, so it doesn't count towards what a volume is
+impl PartialEq for DedupTable {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for DedupTable {}
+
+impl Hash for DedupTable {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+impl DedupTable {
+    /// A table of every block in `trees`, which are left as they are; the first of several
+    /// identical blocks is the one the table hands out from then on
+    pub fn build<'e, 'a: 'e>(trees: impl IntoIterator<Item = &'e Entry<'a>>) -> Self {
+        let mut table = Self::default();
+        let mut seen = BTreeSet::new();
+        for tree in trees {
+            table.add(tree, &mut seen);
+        }
+        table
+    }
+    // adds the blocks at and below `entry`, skipping directories shared with a tree already added
+    fn add(&mut self, entry: &Entry, seen: &mut BTreeSet<usize>) {
+        match &entry.kind {
+            EntryKind::Directory(map) => {
+                if seen.insert(Arc::as_ptr(map) as usize) {
+                    for (_, child) in map.iter() {
+                        self.add(child, seen);
+                    }
+                }
+            }
+            EntryKind::File(file) => {
+                for (_, block) in file.blocks() {
+                    self.intern(block);
+                }
+            }
+            EntryKind::Root(_) | EntryKind::Symlink(_) => {}
+        }
+    }
+    /// The block in the table with the same stored bytes as `block`, or `block` itself after
+    /// adding it
+    pub fn intern(&mut self, block: &Arc<Block>) -> Arc<Block> {
+        match self.blocks.get(&block.checksum()).and_then(Weak::upgrade) {
+            Some(known) if known.data() == block.data() => known,
+            // a collision, or `block` is damaged; either way it's left to itself
+            Some(known) if known.is_intact() => Arc::clone(block),
+            // nothing to share with, or what's there is damaged and shouldn't be shared
+            _ => {
+                self.blocks.insert(block.checksum(), Arc::downgrade(block));
+                self.sweep();
+                Arc::clone(block)
+            }
+        }
+    }
+    /// Swaps the blocks of `file` with indexes in `range` for the table's copies, returning how
+    /// many were swapped
+    pub fn share(&mut self, file: &mut FileBlocks, range: impl RangeBounds<u64>) -> u64 {
+        file.share_blocks(range, &mut |block| {
+            let known = self.intern(block);
+            (!Arc::ptr_eq(&known, block)).then_some(known)
+        })
+    }
+    /// The block in the table stored with `checksum`, if anything still holds it
+    pub fn get(&self, checksum: &Checksum) -> Option<Arc<Block>> {
+        self.blocks.get(checksum).and_then(Weak::upgrade)
+    }
+    /// How many files hold the block stored with `checksum`, or 0 if none does
+    pub fn refs(&self, checksum: &Checksum) -> usize {
+        self.blocks.get(checksum).map_or(0, Weak::strong_count)
+    }
+    /// Distinct blocks still held by something
+    pub fn len(&self) -> usize {
+        self.blocks
+            .values()
+            .filter(|block| block.strong_count() > 0)
+            .count()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // forgets blocks nothing holds any more, once the table has doubled since it last did
+    fn sweep(&mut self) {
+        if self.blocks.len() > self.swept * 2 + 64 {
+            self.blocks.retain(|_, block| block.strong_count() > 0);
+            self.swept = self.blocks.len();
+        }
+    }
+}
+
+/// What a deduplication pass did
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct DedupStats {
+    /// Distinct blocks left
+    pub blocks: u64,
+    /// Duplicate blocks replaced by an identical one stored elsewhere
+    pub merged: u64,
+    /// Bytes the merged blocks took up
+    pub saved: u64,
+}
+
+type Map<'a> = HashMap<Properties<'a>, Arc<Entry<'a>>>;
+
+/// A pass over whole trees that makes every file share identical blocks
+///
+/// Only the entries that change, and the directories above them, are copied. Replacements are
+/// remembered by what they replace, so whatever the trees shared before is still shared after.
+/// Every tree passed to `entry` has to be kept alive until the pass is done, or an address it
+/// remembers could be reused.
+pub struct Dedup<'a> {
+    table: DedupTable,
+    blocks: BTreeMap<usize, Arc<Block>>,
+    /// `None` for a directory nothing below changed in, or one still being gone through
+    maps: BTreeMap<usize, Option<Arc<Map<'a>>>>,
+    entries: BTreeMap<usize, Option<Arc<Entry<'a>>>>,
+    stats: DedupStats,
+}
+
+impl<'a> Dedup<'a> {
+    /// A pass that shares blocks with those in `table` as well as between the trees it's given
+    pub fn new(table: DedupTable) -> Self {
+        Self {
+            table,
+            blocks: BTreeMap::new(),
+            maps: BTreeMap::new(),
+            entries: BTreeMap::new(),
+            stats: DedupStats::default(),
+        }
+    }
+    /// `entry` with everything at and below it sharing blocks, or `None` if nothing changed
+    pub fn entry(&mut self, entry: &Entry<'a>) -> Option<Entry<'a>> {
+        let kind = match &entry.kind {
+            EntryKind::Directory(map) => EntryKind::Directory(self.map(map)?),
+            EntryKind::File(file) => EntryKind::File(self.file(file)?),
+            EntryKind::Root(_) | EntryKind::Symlink(_) => return None,
+        };
+
+        // the contents are the same, and so is the checksum
+        Some(Entry {
+            kind,
+            ..entry.clone()
+        })
+    }
+    /// The table, now holding every block the pass went through, and what the pass did
+    pub fn finish(mut self) -> (DedupTable, DedupStats) {
+        self.stats.blocks = self.table.len() as u64;
+        (self.table, self.stats)
+    }
+    fn map(&mut self, map: &Arc<Map<'a>>) -> Option<Arc<Map<'a>>> {
+        let key = Arc::as_ptr(map) as usize;
+        if let Some(done) = self.maps.get(&key) {
+            return done.clone();
+        }
+        self.maps.insert(key, None);
+
+        let changed = map
+            .iter()
+            .filter_map(|(props, child)| Some((props.clone(), self.child(child)?)))
+            .collect::<Vec<_>>();
+
+        let out = (!changed.is_empty()).then(|| {
+            let mut out = (**map).clone();
+            out.extend(changed);
+            Arc::new(out)
+        });
+        self.maps.insert(key, out.clone());
+        out
+    }
+    fn child(&mut self, child: &Arc<Entry<'a>>) -> Option<Arc<Entry<'a>>> {
+        let key = Arc::as_ptr(child) as usize;
+        if let Some(done) = self.entries.get(&key) {
+            return done.clone();
+        }
+        self.entries.insert(key, None);
+
+        let out = self.entry(child).map(Arc::new);
+        self.entries.insert(key, out.clone());
+        out
+    }
+    fn file(&mut self, file: &FileBlocks) -> Option<FileBlocks> {
+        let mut out = file.clone();
+        let shared = out.share_blocks(.., &mut |block| {
+            let key = Arc::as_ptr(block) as usize;
+            if let Some(known) = self.blocks.get(&key) {
+                return Some(Arc::clone(known));
+            }
+
+            let known = self.table.intern(block);
+            if Arc::ptr_eq(&known, block) {
+                return None;
+            }
+            self.stats.merged += 1;
+            self.stats.saved += block.data().len() as u64;
+            self.blocks.insert(key, Arc::clone(&known));
+            Some(known)
+        });
+
+        (shared > 0).then_some(out)
+    }
+}
@@ -0,0 +1,1297 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+use mr_mime::Mime;
+use syscall::{Error, Result, EINVAL, EIO, ENOMEM, ENOSPC};
+
+use super::blocks::{self, Block, FileBlocks};
+use super::checksum::{checksum_of, Checksum, ChecksumHasher, CHECKSUM_LEN};
+use super::index::Index;
+use super::quota::Accounting;
+use super::watch::Watches;
+use super::{
+    detached, new_map_shorthand, sorted_children, time_t, Entry, EntryKind, Properties, RootEntry,
+    Snapshot,
+};
+
+// Volume layout, all integers little-endian:
+//
+// [superblock, SUPERBLOCK_SIZE bytes][record table, table_len bytes]
+// [snapshot table, snapshot_len bytes][data area, data_len bytes]
+//
+// That's how `encode` lays out a fresh image. On a device the superblock has two slots, one at
+// the very start and one in the last SUPERBLOCK_SIZE bytes, and the tables and data area sit
+// wherever the superblock says. A commit writes the new tables and data area somewhere the
+// current ones aren't, and only once they're safely on the device writes a superblock with the
+// next generation into the other slot. Mounting picks the newest intact slot, so a crash at any
+// point leaves either the old tree or the new one, never half of each.
+//
+// Records refer to each other by their index in the table, with the root directory always at
+// index 0, so nothing in the image depends on where the tree happened to live in memory.
+// Snapshots are further roots in the same table: a record that hasn't changed since a snapshot
+// was taken is listed by directories in both trees rather than written twice. File blocks work
+// the same way within the data area, so a block shared between versions of a file is stored once.
+//
+// A file or symbolic link with several hard links has one record holding its contents and a
+// hard link record for each further name, which only points at that first record.
+
+pub const MAGIC: u32 = 0x90a7cafe;
+pub const VERSION: u32 = 1;
+
+/// Space reserved for the superblock at the start of the volume; unused bytes are zeroed
+pub const SUPERBLOCK_SIZE: usize = 512;
+
+/// Longest volume label the superblock has room for, in bytes
+pub const LABEL_LEN: usize = 64;
+
+/// Parent index stored in the record of the root directory and of each snapshot's root
+pub const NO_PARENT: u64 = u64::MAX;
+
+pub const KIND_DIRECTORY: u8 = 0;
+pub const KIND_FILE: u8 = 1;
+pub const KIND_SYMLINK: u8 = 2;
+pub const KIND_HARD_LINK: u8 = 3;
+/// Set in a record's kind byte when extended attributes follow its payload
+pub const HAS_XATTRS: u8 = 0x80;
+/// Set in a record's kind byte when a link count follows its payload and extended attributes;
+/// without it the count is 1
+pub const HAS_LINKS: u8 = 0x40;
+
+pub fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+/// Bounds-checked cursor over an on-disk structure
+///
+/// Running off the end of the buffer means the volume is truncated or corrupted, so every read
+/// fails with `EIO` instead of panicking.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+    /// Bytes read so far
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).ok_or(Error::new(EIO))?;
+        let out = self.buf.get(self.pos..end).ok_or(Error::new(EIO))?;
+        self.pos = end;
+        Ok(out)
+    }
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub fn i128(&mut self) -> Result<i128> {
+        Ok(i128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+    pub fn checksum(&mut self) -> Result<Checksum> {
+        Ok(Checksum(self.take(CHECKSUM_LEN)?.try_into().unwrap()))
+    }
+    pub fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+    pub fn str(&mut self) -> Result<&'a str> {
+        core::str::from_utf8(self.bytes()?).map_err(|_| Error::new(EIO))
+    }
+}
+
+/// Fixed-size header at the start of every HMFS volume
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SuperBlock {
+    pub magic: u32,
+    pub version: u32,
+    pub system_clock: time_t,
+    pub entry_count: u64,
+    /// Checksum of the root directory
+    pub checksum: Checksum,
+    pub table_offset: u64,
+    pub table_len: u64,
+    pub data_offset: u64,
+    pub data_len: u64,
+    /// UTF-8, zero-padded
+    pub label: [u8; LABEL_LEN],
+    pub snapshot_offset: u64,
+    pub snapshot_len: u64,
+    /// Bumped by every commit; the slot with the highest intact generation is the live one
+    pub generation: u64,
+    /// Size of the device the superblock was committed to, so a copy of a volume stored as a
+    /// file inside another one is never mistaken for the outer volume's second slot
+    pub device_len: u64,
+}
+
+impl SuperBlock {
+    /// Creates a superblock for an empty layout; `write_image` fills in the table and data area
+    pub fn new(system_clock: time_t, label: &str, checksum: Checksum) -> Self {
+        // truncate on a character boundary so the label always reads back as UTF-8
+        let mut label_len = label.len().min(LABEL_LEN);
+        while !label.is_char_boundary(label_len) {
+            label_len -= 1;
+        }
+
+        let mut label_bytes = [0u8; LABEL_LEN];
+        label_bytes[..label_len].copy_from_slice(&label.as_bytes()[..label_len]);
+
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            system_clock,
+            entry_count: 0,
+            checksum,
+            table_offset: SUPERBLOCK_SIZE as u64,
+            table_len: 0,
+            data_offset: SUPERBLOCK_SIZE as u64,
+            data_len: 0,
+            label: label_bytes,
+            snapshot_offset: SUPERBLOCK_SIZE as u64,
+            snapshot_len: 0,
+            generation: 0,
+            device_len: 0,
+        }
+    }
+    pub fn label(&self) -> &str {
+        let len = self.label.iter().position(|&b| b == 0).unwrap_or(LABEL_LEN);
+        core::str::from_utf8(&self.label[..len]).unwrap_or_default()
+    }
+    pub fn to_bytes(self) -> [u8; SUPERBLOCK_SIZE] {
+        let mut fields = Vec::with_capacity(SUPERBLOCK_SIZE);
+
+        put_u32(&mut fields, self.magic);
+        put_u32(&mut fields, self.version);
+        fields.extend_from_slice(&self.system_clock.to_le_bytes());
+        put_u64(&mut fields, self.entry_count);
+        fields.extend_from_slice(self.checksum.as_bytes());
+        put_u64(&mut fields, self.table_offset);
+        put_u64(&mut fields, self.table_len);
+        put_u64(&mut fields, self.data_offset);
+        put_u64(&mut fields, self.data_len);
+        fields.extend_from_slice(&self.label);
+        put_u64(&mut fields, self.snapshot_offset);
+        put_u64(&mut fields, self.snapshot_len);
+        put_u64(&mut fields, self.generation);
+        put_u64(&mut fields, self.device_len);
+        let checksum = superblock_checksum(&fields);
+        fields.extend_from_slice(checksum.as_bytes());
+
+        let mut out = [0u8; SUPERBLOCK_SIZE];
+        out[..fields.len()].copy_from_slice(&fields);
+        out
+    }
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(buf.get(..SUPERBLOCK_SIZE).ok_or(Error::new(EINVAL))?);
+
+        let magic = reader.u32()?;
+        if magic != MAGIC {
+            return Err(Error::new(EINVAL)); // not an HMFS volume
+        }
+
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(Error::new(EINVAL));
+        }
+
+        let superblock = Self {
+            magic,
+            version,
+            system_clock: reader.i128()?,
+            entry_count: reader.u64()?,
+            checksum: reader.checksum()?,
+            table_offset: reader.u64()?,
+            table_len: reader.u64()?,
+            data_offset: reader.u64()?,
+            data_len: reader.u64()?,
+            label: reader.take(LABEL_LEN)?.try_into().unwrap(),
+            snapshot_offset: reader.u64()?,
+            snapshot_len: reader.u64()?,
+            generation: reader.u64()?,
+            device_len: reader.u64()?,
+        };
+
+        // a superblock torn by a crash halfway through writing it reads as no superblock at all
+        let fields = &buf[..reader.position()];
+        if reader.checksum()? != superblock_checksum(fields) {
+            return Err(Error::new(EIO));
+        }
+
+        Ok(superblock)
+    }
+    /// Where the tables and data area start and end, as one span
+    fn body(&self) -> (u64, u64) {
+        let regions = [
+            (self.table_offset, self.table_len),
+            (self.snapshot_offset, self.snapshot_len),
+            (self.data_offset, self.data_len),
+        ];
+        let start = regions.iter().map(|&(offset, _)| offset).min().unwrap();
+        let end = regions
+            .iter()
+            .map(|&(offset, len)| offset.saturating_add(len))
+            .max()
+            .unwrap();
+        (start, end)
+    }
+}
+
+fn superblock_checksum(fields: &[u8]) -> Checksum {
+    let mut hasher = ChecksumHasher::default();
+    hasher.write(fields);
+    hasher.digest()
+}
+
+/// Offset of each superblock slot on a device of `device_len` bytes
+pub fn slot_offsets(device_len: u64) -> [u64; 2] {
+    [0, device_len.saturating_sub(SUPERBLOCK_SIZE as u64)]
+}
+
+/// Picks the live superblock out of the contents of both slots, returning which slot it's in
+///
+/// An image straight from `write_image` only has the first slot, at generation 0, so the first
+/// commit to it lands in the second slot and wins from then on.
+pub fn newest_superblock(slots: [&[u8]; 2], device_len: u64) -> Result<(usize, SuperBlock)> {
+    let first = SuperBlock::from_bytes(slots[0]);
+    let second = SuperBlock::from_bytes(slots[1]).ok().filter(|superblock| {
+        superblock.device_len == device_len && device_len >= 2 * SUPERBLOCK_SIZE as u64
+    });
+
+    match (first, second) {
+        (Ok(first), Some(second)) if second.generation > first.generation => Ok((1, second)),
+        (Ok(first), _) => Ok((0, first)),
+        (Err(_), Some(second)) => Ok((1, second)),
+        (Err(err), None) => Err(err),
+    }
+}
+
+/// Parses whichever superblock of a volume is live
+pub fn read_superblock(buf: &[u8]) -> Result<SuperBlock> {
+    let [first, second] = slot_offsets(buf.len() as u64);
+    let slots = [&buf[first as usize..], &buf[second as usize..]];
+
+    newest_superblock(slots, buf.len() as u64).map(|(_, superblock)| superblock)
+}
+
+/// Storage a volume can be committed to
+pub trait Device {
+    /// Size in bytes, which stays fixed for the life of the volume
+    fn size(&mut self) -> Result<u64>;
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()>;
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()>;
+    /// Returns once everything written so far is durable
+    fn flush(&mut self) -> Result<()>;
+}
+
+/// Writes the on-disk form of `Properties`
+///
+/// `full_path` is derived from the name and `entry_kind` is a back-reference to the containing
+/// directory, so neither is stored. The same byte stream is fed to the hasher when checksumming
+/// a directory, which is what lets checksums be recomputed from the records alone.
+pub fn write_properties(props: &Properties, put: &mut dyn FnMut(&[u8])) {
+    let mime = props.mime_type.as_ref().map(|mime| mime.to_string());
+
+    PropertiesRecord {
+        name: &props.name,
+        mime_type: mime.as_deref(),
+        mode: props.mode,
+        created_by: &props.created_by,
+        owner: &props.owner,
+        date_created: props.date_created,
+        date_modified: props.date_modified,
+        uid: props.uid,
+        gid: props.gid,
+    }
+    .write(put)
+}
+
+pub fn encode_properties(props: &Properties) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_properties(props, &mut |bytes| out.extend_from_slice(bytes));
+    out
+}
+
+/// Borrowed view of an encoded `Properties`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PropertiesRecord<'a> {
+    pub name: &'a str,
+    pub mime_type: Option<&'a str>,
+    pub mode: u32,
+    pub created_by: &'a str,
+    pub owner: &'a str,
+    pub date_created: time_t,
+    pub date_modified: time_t,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl<'a> PropertiesRecord<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        let mut reader = Reader::new(bytes);
+
+        let name = reader.str()?;
+        let mime_type = Some(reader.str()?).filter(|mime| !mime.is_empty());
+        let mode = reader.u32()?;
+        let created_by = reader.str()?;
+        let owner = reader.str()?;
+        let date_created = reader.i128()?;
+        let date_modified = reader.i128()?;
+        // only written when either is non-zero
+        let (uid, gid) = if reader.is_empty() {
+            (0, 0)
+        } else {
+            (reader.u32()?, reader.u32()?)
+        };
+
+        Ok(Self {
+            name,
+            mime_type,
+            mode,
+            created_by,
+            owner,
+            date_created,
+            date_modified,
+            uid,
+            gid,
+        })
+    }
+    pub fn write(&self, put: &mut dyn FnMut(&[u8])) {
+        for field in [self.name, self.mime_type.unwrap_or_default()] {
+            put(&(field.len() as u32).to_le_bytes());
+            put(field.as_bytes());
+        }
+
+        put(&self.mode.to_le_bytes());
+
+        for field in [self.created_by, self.owner] {
+            put(&(field.len() as u32).to_le_bytes());
+            put(field.as_bytes());
+        }
+
+        put(&self.date_created.to_le_bytes());
+        put(&self.date_modified.to_le_bytes());
+
+        // left out for root, which owns most of a volume
+        if (self.uid, self.gid) != (0, 0) {
+            put(&self.uid.to_le_bytes());
+            put(&self.gid.to_le_bytes());
+        }
+    }
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write(&mut |bytes| out.extend_from_slice(bytes));
+        out
+    }
+    pub fn to_properties(self, entry_kind: EntryKind<'a>) -> Result<Properties<'a>> {
+        let mime_type = match self.mime_type {
+            Some(mime) => Some(Mime::parse(mime).map_err(|_| Error::new(EIO))?),
+            None => None,
+        };
+
+        Ok(Properties::new(
+            String::from(self.name),
+            entry_kind,
+            mime_type,
+            self.mode,
+            String::from(self.created_by),
+            self.date_created,
+            self.date_modified,
+            String::from(self.owner),
+            self.uid,
+            self.gid,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Payload {
+    /// Record indices of the children, in name order
+    Directory(Vec<u64>),
+    /// File length and stored blocks, in index order; anything missing is a hole
+    File { len: u64, blocks: Vec<BlockRecord> },
+    /// Target path, as stored
+    Symlink(String),
+    /// Index of the record holding the contents of the file or symbolic link this is another
+    /// name for
+    HardLink(u64),
+}
+
+/// Where one file block is stored
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BlockRecord {
+    pub index: u64,
+    /// Checksum of the stored bytes
+    pub checksum: Checksum,
+    /// Byte range within the data area
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// One entry in the record table
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Record<'a> {
+    pub parent: u64,
+    pub checksum: Checksum,
+    /// Encoded `Properties`; empty for the root directory
+    pub properties: &'a [u8],
+    pub payload: Payload,
+    /// Extended attributes, in strictly ascending key order
+    pub xattrs: Vec<(&'a str, &'a [u8])>,
+    /// Names the entry is linked under, counting hard link records; 1 for those records
+    /// themselves
+    pub links: u32,
+}
+
+impl<'a> Record<'a> {
+    pub fn kind(&self) -> u8 {
+        match self.payload {
+            Payload::Directory(_) => KIND_DIRECTORY,
+            Payload::File { .. } => KIND_FILE,
+            Payload::Symlink(_) => KIND_SYMLINK,
+            Payload::HardLink(_) => KIND_HARD_LINK,
+        }
+    }
+    pub fn read(reader: &mut Reader<'a>) -> Result<Self> {
+        let flags = reader.u8()?;
+        let kind = flags & !(HAS_XATTRS | HAS_LINKS);
+        let parent = reader.u64()?;
+        let checksum = reader.checksum()?;
+        let properties = reader.bytes()?;
+
+        let payload = match kind {
+            KIND_DIRECTORY => {
+                let count = reader.u64()?;
+                let mut children = Vec::new();
+                for _ in 0..count {
+                    children.push(reader.u64()?);
+                }
+                Payload::Directory(children)
+            }
+            KIND_FILE => {
+                let len = reader.u64()?;
+                let count = reader.u64()?;
+                let mut blocks = Vec::new();
+                for _ in 0..count {
+                    blocks.push(BlockRecord {
+                        index: reader.u64()?,
+                        checksum: reader.checksum()?,
+                        offset: reader.u64()?,
+                        len: reader.u64()?,
+                    });
+                }
+                Payload::File { len, blocks }
+            }
+            KIND_SYMLINK => Payload::Symlink(String::from(reader.str()?)),
+            KIND_HARD_LINK => Payload::HardLink(reader.u64()?),
+            _ => return Err(Error::new(EIO)),
+        };
+
+        let mut xattrs = Vec::new();
+        if flags & HAS_XATTRS != 0 {
+            for _ in 0..reader.u32()? {
+                let key = reader.str()?;
+
+                // checksums hash them in order, so anything else isn't a valid record
+                if xattrs.last().is_some_and(|&(last, _)| last >= key) {
+                    return Err(Error::new(EIO));
+                }
+
+                xattrs.push((key, reader.bytes()?));
+            }
+        }
+
+        let links = match flags & HAS_LINKS {
+            0 => 1,
+            _ => reader.u32()?,
+        };
+
+        Ok(Self {
+            parent,
+            checksum,
+            properties,
+            payload,
+            xattrs,
+            links,
+        })
+    }
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let mut flags = self.kind();
+        if !self.xattrs.is_empty() {
+            flags |= HAS_XATTRS;
+        }
+        if self.links != 1 {
+            flags |= HAS_LINKS;
+        }
+        out.push(flags);
+        put_u64(out, self.parent);
+        out.extend_from_slice(self.checksum.as_bytes());
+        put_bytes(out, self.properties);
+
+        match &self.payload {
+            Payload::Directory(children) => {
+                put_u64(out, children.len() as u64);
+                for child in children {
+                    put_u64(out, *child);
+                }
+            }
+            Payload::File { len, blocks } => {
+                put_u64(out, *len);
+                put_u64(out, blocks.len() as u64);
+                for block in blocks {
+                    put_u64(out, block.index);
+                    out.extend_from_slice(block.checksum.as_bytes());
+                    put_u64(out, block.offset);
+                    put_u64(out, block.len);
+                }
+            }
+            Payload::Symlink(target) => put_bytes(out, target.as_bytes()),
+            Payload::HardLink(target) => put_u64(out, *target),
+        }
+
+        if !self.xattrs.is_empty() {
+            put_u32(out, self.xattrs.len() as u32);
+            for (key, value) in self.xattrs.iter() {
+                put_bytes(out, key.as_bytes());
+                put_bytes(out, value);
+            }
+        }
+
+        if self.links != 1 {
+            put_u32(out, self.links);
+        }
+    }
+}
+
+/// One entry in the snapshot table
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SnapshotRecord<'a> {
+    pub name: &'a str,
+    pub created: time_t,
+    /// Record index of the snapshot's root directory
+    pub root: u64,
+    /// Checksum of that directory, as `SuperBlock::checksum` is for the live tree
+    pub checksum: Checksum,
+}
+
+impl<'a> SnapshotRecord<'a> {
+    pub fn read(reader: &mut Reader<'a>) -> Result<Self> {
+        Ok(Self {
+            name: reader.str()?,
+            created: reader.i128()?,
+            root: reader.u64()?,
+            checksum: reader.checksum()?,
+        })
+    }
+    pub fn write(&self, out: &mut Vec<u8>) {
+        put_bytes(out, self.name.as_bytes());
+        out.extend_from_slice(&self.created.to_le_bytes());
+        put_u64(out, self.root);
+        out.extend_from_slice(self.checksum.as_bytes());
+    }
+}
+
+// Feeds the hasher the same stream as `EntryKind`'s `Hash` impl, but from record fields
+enum Contents<'r> {
+    Directory(&'r [(&'r str, &'r [u8], Checksum)]),
+    File(u64, &'r [BlockRecord]),
+    Symlink(&'r str),
+}
+
+impl Hash for Contents<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Self::Directory(children) => {
+                state.write_u8(KIND_DIRECTORY);
+                for (_, properties, checksum) in children.iter() {
+                    state.write(properties);
+                    state.write(checksum.as_bytes());
+                }
+            }
+            Self::File(len, blocks) => {
+                state.write_u8(KIND_FILE);
+                blocks::hash_file(
+                    *len,
+                    blocks.iter().map(|block| (block.index, block.checksum)),
+                    state,
+                );
+            }
+            Self::Symlink(target) => hash_symlink(target, state),
+        }
+    }
+}
+
+/// Feeds a hasher an entry's extended attributes, which must be in key order
+///
+/// Nothing at all is written for an entry without any, which is most of them.
+pub fn hash_xattrs<'r, H: Hasher>(
+    xattrs: impl ExactSizeIterator<Item = (&'r str, &'r [u8])>,
+    state: &mut H,
+) {
+    if xattrs.len() == 0 {
+        return;
+    }
+
+    state.write(&(xattrs.len() as u64).to_le_bytes());
+    for (key, value) in xattrs {
+        state.write(&(key.len() as u32).to_le_bytes());
+        state.write(key.as_bytes());
+        state.write(&(value.len() as u32).to_le_bytes());
+        state.write(value);
+    }
+}
+
+/// Feeds a hasher a symbolic link's target as stored, after `KIND_SYMLINK`
+pub fn hash_symlink<H: Hasher>(target: &str, state: &mut H) {
+    state.write_u8(KIND_SYMLINK);
+    state.write(&(target.len() as u64).to_le_bytes());
+    state.write(target.as_bytes());
+}
+
+/// Feeds a hasher an entry's link count, after its extended attributes
+///
+/// Like `hash_xattrs` this writes nothing for the usual count of 1.
+pub fn hash_links<H: Hasher>(links: u32, state: &mut H) {
+    if links != 1 {
+        state.write(&links.to_le_bytes());
+    }
+}
+
+struct RecordXattrs<'r>(&'r [(&'r str, &'r [u8])]);
+
+impl Hash for RecordXattrs<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_xattrs(self.0.iter().copied(), state);
+    }
+}
+
+struct Links(u32);
+
+impl Hash for Links {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_links(self.0, state);
+    }
+}
+
+/// Checksum of a directory given each child's name, encoded properties and checksum, and the
+/// directory's own extended attributes
+///
+/// Matches the checksum `Entry` keeps for the equivalent `EntryKind::Directory`.
+pub fn directory_checksum(
+    children: &mut [(&str, &[u8], Checksum)],
+    xattrs: &[(&str, &[u8])],
+) -> Checksum {
+    children.sort_by(|a, b| a.0.cmp(b.0));
+    checksum_of(&(Contents::Directory(children), RecordXattrs(xattrs)))
+}
+
+/// Checksum of a file given its length, its blocks' stored checksums, its extended attributes
+/// and its link count; matches the checksum `Entry` keeps for the equivalent `EntryKind::File`
+pub fn file_checksum(
+    len: u64,
+    blocks: &[BlockRecord],
+    xattrs: &[(&str, &[u8])],
+    links: u32,
+) -> Checksum {
+    checksum_of(&(
+        Contents::File(len, blocks),
+        RecordXattrs(xattrs),
+        Links(links),
+    ))
+}
+
+/// Checksum of a symbolic link given its stored target, its extended attributes and its link
+/// count; matches the checksum `Entry` keeps for the equivalent `EntryKind::Symlink`
+pub fn symlink_checksum(target: &str, xattrs: &[(&str, &[u8])], links: u32) -> Checksum {
+    checksum_of(&(
+        Contents::Symlink(target),
+        RecordXattrs(xattrs),
+        Links(links),
+    ))
+}
+
+/// Parses the live superblock and the full record table of a volume
+pub fn read_records(buf: &[u8]) -> Result<(SuperBlock, Vec<Record<'_>>)> {
+    let superblock = read_superblock(buf)?;
+    let table = region(buf, superblock.table_offset, superblock.table_len)?;
+    let records = parse_records(&superblock, table)?;
+
+    Ok((superblock, records))
+}
+
+fn parse_records<'a>(superblock: &SuperBlock, table: &'a [u8]) -> Result<Vec<Record<'a>>> {
+    let mut reader = Reader::new(table);
+
+    let mut records = Vec::new();
+    for _ in 0..superblock.entry_count {
+        records.push(Record::read(&mut reader)?);
+    }
+
+    Ok(records)
+}
+
+/// Parses the snapshot table of a volume whose superblock has already been parsed
+pub fn read_snapshots<'a>(
+    buf: &'a [u8],
+    superblock: &SuperBlock,
+) -> Result<Vec<SnapshotRecord<'a>>> {
+    parse_snapshots(region(
+        buf,
+        superblock.snapshot_offset,
+        superblock.snapshot_len,
+    )?)
+}
+
+fn parse_snapshots(table: &[u8]) -> Result<Vec<SnapshotRecord<'_>>> {
+    let mut reader = Reader::new(table);
+
+    let mut snapshots = Vec::new();
+    while !reader.is_empty() {
+        snapshots.push(SnapshotRecord::read(&mut reader)?);
+    }
+
+    Ok(snapshots)
+}
+
+/// Returns the data area of a volume whose superblock has already been parsed
+pub fn data_area<'a>(buf: &'a [u8], superblock: &SuperBlock) -> Result<&'a [u8]> {
+    region(buf, superblock.data_offset, superblock.data_len)
+}
+
+fn region(buf: &[u8], offset: u64, len: u64) -> Result<&[u8]> {
+    let start = usize::try_from(offset).map_err(|_| Error::new(EIO))?;
+    let len = usize::try_from(len).map_err(|_| Error::new(EIO))?;
+    let end = start.checked_add(len).ok_or(Error::new(EIO))?;
+
+    buf.get(start..end).ok_or(Error::new(EIO))
+}
+
+/// The parts of the volume on `device` a tree is decoded from, as `read_device` reads them
+pub struct Regions {
+    pub superblock: SuperBlock,
+    pub table: Vec<u8>,
+    pub snapshots: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// Reads the live superblock of the volume on `device` and the record table, snapshot table and
+/// data area it points to, and nothing else of the device
+///
+/// Fails with `EINVAL` if there's no volume on the device, `EIO` if the superblock points past
+/// its end and `ENOMEM` if what it points to doesn't fit in memory.
+pub fn read_device(device: &mut dyn Device) -> Result<Regions> {
+    let device_len = device.size()?;
+    if device_len < SUPERBLOCK_SIZE as u64 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mut slots = [[0; SUPERBLOCK_SIZE]; 2];
+    for (slot, offset) in slots.iter_mut().zip(slot_offsets(device_len)) {
+        device.read_at(offset, slot)?;
+    }
+    let (_, superblock) = newest_superblock([&slots[0], &slots[1]], device_len)?;
+
+    let mut read = |offset: u64, len: u64| {
+        let end = offset.checked_add(len).ok_or(Error::new(EIO))?;
+        if end > device_len {
+            return Err(Error::new(EIO));
+        }
+        let len = usize::try_from(len).map_err(|_| Error::new(ENOMEM))?;
+
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(len).map_err(|_| Error::new(ENOMEM))?;
+        buf.resize(len, 0);
+        device.read_at(offset, &mut buf)?;
+        Ok(buf)
+    };
+
+    Ok(Regions {
+        table: read(superblock.table_offset, superblock.table_len)?,
+        snapshots: read(superblock.snapshot_offset, superblock.snapshot_len)?,
+        data: read(superblock.data_offset, superblock.data_len)?,
+        superblock,
+    })
+}
+
+/// Lays out a volume from a record table, the snapshots rooted in it and the data area its file
+/// extents point into
+///
+/// The record count and region offsets in `superblock` are overwritten to match.
+pub fn write_image(
+    mut superblock: SuperBlock,
+    records: &[Record],
+    snapshots: &[SnapshotRecord],
+    data: &[u8],
+) -> Vec<u8> {
+    let body = lay_out(
+        &mut superblock,
+        records,
+        snapshots,
+        data,
+        SUPERBLOCK_SIZE as u64,
+    );
+    superblock.device_len = (SUPERBLOCK_SIZE + body.len()) as u64;
+
+    let mut out = Vec::with_capacity(SUPERBLOCK_SIZE + body.len());
+    out.extend_from_slice(&superblock.to_bytes());
+    out.extend_from_slice(&body);
+    out
+}
+
+// The record table, snapshot table and data area back to back, to be written at `offset`, with
+// `superblock` updated to say so
+fn lay_out(
+    superblock: &mut SuperBlock,
+    records: &[Record],
+    snapshots: &[SnapshotRecord],
+    data: &[u8],
+    offset: u64,
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    for record in records {
+        record.write(&mut body);
+    }
+    let table_len = body.len() as u64;
+
+    for snapshot in snapshots {
+        snapshot.write(&mut body);
+    }
+    let snapshot_len = body.len() as u64 - table_len;
+
+    body.extend_from_slice(data);
+
+    superblock.version = VERSION;
+    superblock.entry_count = records.len() as u64;
+    superblock.table_offset = offset;
+    superblock.table_len = table_len;
+    superblock.snapshot_offset = superblock.table_offset + superblock.table_len;
+    superblock.snapshot_len = snapshot_len;
+    superblock.data_offset = superblock.snapshot_offset + superblock.snapshot_len;
+    superblock.data_len = data.len() as u64;
+    body
+}
+
+/// Commits a volume laid out the way `write_image` takes it to `device`, so that a crash at any
+/// point leaves the device holding either the volume as it was or the new one in full
+///
+/// The new tables and data area go where the current ones aren't, ahead of them if there's room
+/// and after them otherwise, and the superblock goes into the slot the current one isn't in
+/// with the next generation, after everything else is flushed. Fails with `ENOSPC` if the new
+/// volume doesn't fit on the device alongside the current one. Returns the superblock written.
+pub fn commit(
+    device: &mut dyn Device,
+    mut superblock: SuperBlock,
+    records: &[Record],
+    snapshots: &[SnapshotRecord],
+    data: &[u8],
+) -> Result<SuperBlock> {
+    let device_len = device.size()?;
+    if device_len < 2 * SUPERBLOCK_SIZE as u64 {
+        return Err(Error::new(ENOSPC));
+    }
+    let slots = slot_offsets(device_len);
+
+    let mut first = [0u8; SUPERBLOCK_SIZE];
+    let mut second = [0u8; SUPERBLOCK_SIZE];
+    device.read_at(slots[0], &mut first)?;
+    device.read_at(slots[1], &mut second)?;
+    // a device with no volume on it yet is all free space
+    let current = newest_superblock([&first, &second], device_len).ok();
+
+    let low = SUPERBLOCK_SIZE as u64;
+    let high = slots[1];
+    // nothing in the tables or data area depends on where they end up
+    let body = lay_out(&mut superblock, records, snapshots, data, 0);
+    let len = body.len() as u64;
+
+    let (offset, slot) = match current {
+        None => (Some(low), 0),
+        Some((slot, current)) => {
+            let (start, end) = current.body();
+            let after = end.max(low).next_multiple_of(SUPERBLOCK_SIZE as u64);
+            let offset = if low + len <= start {
+                Some(low)
+            } else if after.saturating_add(len) <= high {
+                Some(after)
+            } else {
+                None
+            };
+
+            // the other slot has to be clear of the current volume too
+            let clear = slot == 1 || end <= high;
+            (offset.filter(|_| clear), 1 - slot)
+        }
+    };
+    let offset = offset
+        .filter(|offset| offset + len <= high)
+        .ok_or(Error::new(ENOSPC))?;
+
+    superblock.table_offset += offset;
+    superblock.snapshot_offset += offset;
+    superblock.data_offset += offset;
+    superblock.generation = current.map_or(0, |(_, current)| current.generation) + 1;
+    superblock.device_len = device_len;
+
+    device.write_at(offset, &body)?;
+    device.flush()?;
+    device.write_at(slots[slot], &superblock.to_bytes())?;
+    device.flush()?;
+
+    Ok(superblock)
+}
+
+// `EntryKind::Root` only ever wraps the volume's own root, so treat it as that directory
+fn directory_of<'b, 'a>(kind: &'b EntryKind<'a>) -> Option<&'b EntryKind<'a>> {
+    match kind {
+        EntryKind::Root(root) => directory_of(&root.dir.kind),
+        other => Some(other),
+    }
+}
+
+/// Serializes a whole tree, snapshots included, into a volume image
+pub fn encode(root: &RootEntry) -> Vec<u8> {
+    serialize(root, write_image)
+}
+
+/// Commits a whole tree, snapshots included, to the volume on `device`; see `commit`
+pub fn commit_root(device: &mut dyn Device, root: &RootEntry) -> Result<SuperBlock> {
+    serialize(root, |superblock, records, snapshots, data| {
+        commit(device, superblock, records, snapshots, data)
+    })
+}
+
+// Turns a tree into the superblock, tables and data area `write_image` and `commit` take
+fn serialize<T>(
+    root: &RootEntry,
+    finish: impl FnOnce(SuperBlock, &[Record], &[SnapshotRecord], &[u8]) -> T,
+) -> T {
+    // the live tree at index 0, then each snapshot's root
+    let mut order = vec![(NO_PARENT, Vec::new(), &root.dir)];
+    order.extend(
+        root.snapshots
+            .iter()
+            .map(|snapshot| (NO_PARENT, Vec::new(), &snapshot.dir)),
+    );
+
+    // an entry that's linked under the same properties from more than one directory (because a
+    // snapshot still shares it with the live tree) is written once, and its parent is whichever
+    // directory got to it first
+    let mut written = BTreeMap::new();
+    let mut payloads = Vec::new();
+    let mut data = Vec::new();
+    // likewise a block shared between files, or between versions of one file
+    let mut stored_blocks = BTreeMap::new();
+    // an entry with hard links is written under the first name reached, and each other name
+    // gets a hard link record pointing there
+    let mut first_names = BTreeMap::new();
+    let mut hard_links = BTreeMap::new();
+
+    // breadth-first, with `order` doubling as the queue
+    while let Some(&(_, _, entry)) = order.get(payloads.len()) {
+        let index = payloads.len() as u64;
+
+        let payload = match directory_of(&entry.kind) {
+            _ if hard_links.contains_key(&index) => Payload::HardLink(hard_links[&index]),
+            Some(EntryKind::Directory(map)) => Payload::Directory(
+                sorted_children(map)
+                    .into_iter()
+                    .map(|(child_props, child)| {
+                        let properties = encode_properties(child_props);
+                        let key = (Arc::as_ptr(child) as usize, properties.clone());
+
+                        *written.entry(key).or_insert_with(|| {
+                            order.push((index, properties, child.as_ref()));
+                            let written = order.len() as u64 - 1;
+
+                            if child.links > 1 {
+                                let first = *first_names
+                                    .entry(Arc::as_ptr(child) as usize)
+                                    .or_insert(written);
+                                if first != written {
+                                    hard_links.insert(written, first);
+                                }
+                            }
+                            written
+                        })
+                    })
+                    .collect(),
+            ),
+            Some(EntryKind::File(file)) => Payload::File {
+                len: file.len(),
+                blocks: file
+                    .blocks()
+                    .map(|(index, block)| {
+                        let (offset, len) = *stored_blocks
+                            .entry(Arc::as_ptr(block) as usize)
+                            .or_insert_with(|| {
+                                let offset = data.len() as u64;
+                                data.extend_from_slice(block.data());
+                                (offset, block.data().len() as u64)
+                            });
+
+                        BlockRecord {
+                            index,
+                            checksum: block.checksum(),
+                            offset,
+                            len,
+                        }
+                    })
+                    .collect(),
+            },
+            Some(EntryKind::Symlink(target)) => Payload::Symlink(target.clone()),
+            _ => unreachable!("directory_of never returns a root"),
+        };
+
+        payloads.push(payload);
+    }
+
+    let records = order
+        .iter()
+        .zip(payloads)
+        .map(|((parent, properties, entry), payload)| {
+            // everything but the name lives in the record a hard link points at
+            let (xattrs, links) = match payload {
+                Payload::HardLink(_) => (Vec::new(), 1),
+                _ => (entry.xattrs().collect(), entry.links),
+            };
+
+            Record {
+                parent: *parent,
+                checksum: entry.checksum,
+                properties: properties.as_slice(),
+                payload,
+                xattrs,
+                links,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let snapshots = root
+        .snapshots
+        .iter()
+        .enumerate()
+        .map(|(i, snapshot)| SnapshotRecord {
+            name: &snapshot.name,
+            created: snapshot.created,
+            root: i as u64 + 1,
+            checksum: snapshot.dir.checksum,
+        })
+        .collect::<Vec<_>>();
+
+    let superblock = SuperBlock::new(root.system_clock, &root.label, root.checksum);
+    finish(superblock, &records, &snapshots, &data)
+}
+
+/// Rebuilds a tree from a volume image produced by `encode`
+///
+/// Back-references to containing directories (`Entry::parent` and `Properties::entry_kind`) are
+/// in-memory only: decoded entries have no parent and their properties point at an empty,
+/// detached directory until they're next rewritten. Records shared between snapshots come back
+/// as shared `Arc`s.
+pub fn decode(buf: &[u8]) -> Result<RootEntry<'_>> {
+    let superblock = read_superblock(buf)?;
+    decode_regions(
+        &superblock,
+        region(buf, superblock.table_offset, superblock.table_len)?,
+        region(buf, superblock.snapshot_offset, superblock.snapshot_len)?,
+        data_area(buf, &superblock)?,
+    )
+}
+
+/// Rebuilds a tree from the regions of a volume `superblock` points to, read separately; see
+/// `decode`
+///
+/// The tree borrows from the record table only, so the snapshot table and data area can go as
+/// soon as it's built.
+pub fn decode_regions<'a>(
+    superblock: &SuperBlock,
+    table: &'a [u8],
+    snapshots: &[u8],
+    data: &[u8],
+) -> Result<RootEntry<'a>> {
+    let records = parse_records(superblock, table)?;
+    let snapshot_records = parse_snapshots(snapshots)?;
+
+    let mut decoder = Decoder {
+        records: &records,
+        data,
+        blocks: BTreeMap::new(),
+        detached: detached(),
+        visiting: vec![false; records.len()],
+        built: vec![None; records.len()],
+    };
+
+    let dir = decoder.root(0)?;
+
+    let mut snapshots = Vec::new();
+    for snapshot in snapshot_records {
+        snapshots.push(Snapshot {
+            name: String::from(snapshot.name),
+            created: snapshot.created,
+            dir: decoder.root(snapshot.root)?,
+        });
+    }
+
+    let mut root = RootEntry {
+        magic: superblock.magic,
+        system_clock: superblock.system_clock,
+        entry_count: superblock.entry_count as usize,
+        checksum: superblock.checksum,
+        label: String::from(superblock.label()),
+        index: Arc::new(Index::build(&dir)),
+        usage: Arc::new(Accounting::build(&dir)),
+        dir,
+        snapshots,
+        read_only: false,
+        keys: BTreeMap::new(),
+        dedup: None,
+        watches: Watches::default(),
+    };
+    root.sync_dedup();
+    Ok(root)
+}
+
+struct Decoder<'r, 'a> {
+    records: &'r [Record<'a>],
+    data: &'r [u8],
+    /// Blocks already read, by where they're stored, so shared blocks stay shared
+    blocks: BTreeMap<(u64, u64), Arc<Block>>,
+    detached: EntryKind<'a>,
+    /// Directories the decoder is currently inside of, to catch cycles
+    visiting: Vec<bool>,
+    /// Entries already built, so records listed from several directories stay shared
+    built: Vec<Option<Arc<Entry<'a>>>>,
+}
+
+impl<'a> Decoder<'_, 'a> {
+    fn root(&mut self, index: u64) -> Result<Entry<'a>> {
+        let dir = Arc::unwrap_or_clone(self.entry(index)?);
+        if !matches!(dir.kind, EntryKind::Directory(_)) {
+            return Err(Error::new(EIO));
+        }
+        Ok(dir)
+    }
+
+    fn slot(&self, index: u64) -> Result<usize> {
+        usize::try_from(index)
+            .ok()
+            .filter(|&slot| slot < self.records.len())
+            .ok_or(Error::new(EIO))
+    }
+
+    fn entry(&mut self, index: u64) -> Result<Arc<Entry<'a>>> {
+        let slot = self.slot(index)?;
+        if let Some(entry) = &self.built[slot] {
+            return Ok(Arc::clone(entry));
+        }
+
+        // reaching a record from inside itself means the table contains a cycle
+        if core::mem::replace(&mut self.visiting[slot], true) {
+            return Err(Error::new(EIO));
+        }
+
+        let record = &self.records[slot];
+
+        // another name for an entry held by a file or symbolic link record
+        if let Payload::HardLink(target) = record.payload {
+            let target_record = &self.records[self.slot(target)?];
+            if !matches!(
+                target_record.payload,
+                Payload::File { .. } | Payload::Symlink(_)
+            ) {
+                return Err(Error::new(EIO));
+            }
+
+            let entry = self.entry(target)?;
+            self.visiting[slot] = false;
+            self.built[slot] = Some(Arc::clone(&entry));
+            return Ok(entry);
+        }
+
+        let kind = match &record.payload {
+            Payload::Directory(children) => {
+                let mut map = new_map_shorthand();
+
+                for &child in children {
+                    let child_record = &self.records[self.slot(child)?];
+
+                    // the parent link only names one of the directories listing a shared record
+                    if child_record.parent != index && !self.lists(child_record.parent, child) {
+                        return Err(Error::new(EIO));
+                    }
+
+                    let props = PropertiesRecord::parse(child_record.properties)?
+                        .to_properties(self.detached.clone())?;
+                    map.insert(props, self.entry(child)?);
+                }
+
+                EntryKind::Directory(Arc::new(map))
+            }
+            Payload::File { len, blocks } => {
+                let mut file = Vec::new();
+                for block in blocks {
+                    let key = (block.offset, block.len);
+                    let stored = match self.blocks.get(&key) {
+                        Some(stored) => Arc::clone(stored),
+                        None => {
+                            let data = region(self.data, block.offset, block.len)?;
+                            let stored =
+                                Arc::new(Block::with_checksum(data.to_vec(), block.checksum));
+                            self.blocks.insert(key, Arc::clone(&stored));
+                            stored
+                        }
+                    };
+                    file.push((block.index, stored));
+                }
+
+                EntryKind::File(FileBlocks::from_blocks(*len, file).ok_or(Error::new(EIO))?)
+            }
+            Payload::Symlink(target) => EntryKind::Symlink(target.clone()),
+            Payload::HardLink(_) => unreachable!("handled above"),
+        };
+
+        let entry = Arc::new(Entry {
+            kind,
+            checksum: record.checksum,
+            parent: None,
+            xattrs: record
+                .xattrs
+                .iter()
+                .map(|&(key, value)| (String::from(key), value.to_vec()))
+                .collect(),
+            links: record.links,
+        });
+
+        self.visiting[slot] = false;
+        self.built[slot] = Some(Arc::clone(&entry));
+        Ok(entry)
+    }
+
+    fn lists(&self, dir: u64, child: u64) -> bool {
+        self.slot(dir).is_ok_and(|slot| {
+            matches!(&self.records[slot].payload, Payload::Directory(children) if children.contains(&child))
+        })
+    }
+}
@@ -0,0 +1,295 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{time_t, Entry, EntryKind, Properties, Xattrs};
+
+// Secondary indexes over the live tree, so that finding entries by type, owner, time or
+// extended attribute doesn't mean walking every directory. Entries are indexed by the stored
+// names leading to them from the root, one record per name, so each hard link shows up under
+// every name it has. The index is brought up to date by comparing the tree before and after
+// each change: copying on write means anything a change didn't touch is still the same `Arc`,
+// so only the directories along the changed path get looked at.
+
+/// Stored names leading to an entry from the root
+pub type IndexPath = Arc<[String]>;
+
+/// What's indexed about each entry
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct Attributes {
+    mime: Option<String>,
+    uid: u32,
+    created: time_t,
+    modified: time_t,
+    xattrs: Xattrs,
+}
+
+impl Attributes {
+    fn of(props: &Properties, entry: &Entry) -> Self {
+        Self {
+            mime: props.mime_type.as_ref().map(|mime| mime.to_string()),
+            uid: props.uid,
+            created: props.date_created,
+            modified: props.date_modified,
+            xattrs: entry.xattrs.clone(),
+        }
+    }
+}
+
+/// Criteria entries have to meet all of; anything left `None` or empty matches everything
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Query {
+    pub mime: Option<String>,
+    pub uid: Option<u32>,
+    /// Created at or after
+    pub created_since: Option<time_t>,
+    /// Created strictly before
+    pub created_before: Option<time_t>,
+    /// Modified at or after
+    pub modified_since: Option<time_t>,
+    /// Modified strictly before
+    pub modified_before: Option<time_t>,
+    /// Extended attributes that have to be set, and to the given value where there is one
+    pub xattrs: Vec<(String, Option<Vec<u8>>)>,
+}
+
+impl Query {
+    fn matches(&self, attrs: &Attributes) -> bool {
+        self.mime
+            .as_ref()
+            .is_none_or(|mime| attrs.mime.as_ref() == Some(mime))
+            && self.uid.is_none_or(|uid| attrs.uid == uid)
+            && within(attrs.created, self.created_since, self.created_before)
+            && within(attrs.modified, self.modified_since, self.modified_before)
+            && self.xattrs.iter().all(|(key, value)| match value {
+                Some(value) => attrs.xattrs.get(key) == Some(value),
+                None => attrs.xattrs.contains_key(key),
+            })
+    }
+}
+
+fn within(time: time_t, since: Option<time_t>, before: Option<time_t>) -> bool {
+    since.is_none_or(|since| time >= since) && before.is_none_or(|before| time < before)
+}
+
+type Postings<K> = BTreeMap<K, BTreeSet<IndexPath>>;
+
+/// Indexes over the entries of one tree, kept in step with it by `RootEntry`
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Index {
+    records: BTreeMap<IndexPath, Attributes>,
+    by_mime: Postings<String>,
+    by_uid: Postings<u32>,
+    by_created: Postings<time_t>,
+    by_modified: Postings<time_t>,
+    by_xattr: Postings<String>,
+}
+
+impl Index {
+    /// Indexes everything in the tree under the root directory `root`
+    pub fn build(root: &Entry) -> Self {
+        let mut index = Self::default();
+        index.add_children(&[], root);
+        index
+    }
+    /// Brings the index up to date with a change from the root directory `before` to `after`
+    pub fn update(&mut self, before: &Entry, after: &Entry) {
+        self.diff_children(&[], before, after);
+    }
+    /// Stored paths of every entry matching `query`, in path order
+    ///
+    /// Candidates come from whichever index narrows things down most, and are checked against
+    /// the rest of the query from their records, so nothing outside the index is looked at.
+    pub fn query(&self, query: &Query) -> Vec<IndexPath> {
+        let mut exact = Vec::new();
+        exact.extend(query.mime.as_ref().map(|mime| self.by_mime.get(mime)));
+        exact.extend(query.uid.map(|uid| self.by_uid.get(&uid)));
+        exact.extend(query.xattrs.iter().map(|(key, _)| self.by_xattr.get(key)));
+
+        // a criterion nothing has at all shows up as an empty set, which wins
+        let smallest = exact
+            .into_iter()
+            .min_by_key(|set| set.map_or(0, BTreeSet::len));
+
+        let candidates: Vec<&IndexPath> = match smallest {
+            Some(None) => return Vec::new(),
+            Some(Some(set)) => set.iter().collect(),
+            None if query.modified_since.is_some() || query.modified_before.is_some() => in_range(
+                &self.by_modified,
+                query.modified_since,
+                query.modified_before,
+            ),
+            None if query.created_since.is_some() || query.created_before.is_some() => {
+                in_range(&self.by_created, query.created_since, query.created_before)
+            }
+            None => self.records.keys().collect(),
+        };
+
+        let mut found = candidates
+            .into_iter()
+            .filter(|path| query.matches(&self.records[*path]))
+            .cloned()
+            .collect::<Vec<_>>();
+        found.sort();
+        found
+    }
+    /// Entries indexed
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+    fn insert(&mut self, path: IndexPath, attrs: Attributes) {
+        if let Some(mime) = &attrs.mime {
+            post(&mut self.by_mime, mime.clone(), &path);
+        }
+        post(&mut self.by_uid, attrs.uid, &path);
+        post(&mut self.by_created, attrs.created, &path);
+        post(&mut self.by_modified, attrs.modified, &path);
+        for key in attrs.xattrs.keys() {
+            post(&mut self.by_xattr, key.clone(), &path);
+        }
+
+        self.records.insert(path, attrs);
+    }
+    fn remove(&mut self, path: &[String]) {
+        let Some((path, attrs)) = self.records.remove_entry(path) else {
+            return;
+        };
+
+        if let Some(mime) = &attrs.mime {
+            unpost(&mut self.by_mime, mime, &path);
+        }
+        unpost(&mut self.by_uid, &attrs.uid, &path);
+        unpost(&mut self.by_created, &attrs.created, &path);
+        unpost(&mut self.by_modified, &attrs.modified, &path);
+        for key in attrs.xattrs.keys() {
+            unpost(&mut self.by_xattr, key, &path);
+        }
+    }
+    fn add(&mut self, path: IndexPath, props: &Properties, entry: &Entry) {
+        self.insert(Arc::clone(&path), Attributes::of(props, entry));
+        self.add_children(&path, entry);
+    }
+    fn add_children(&mut self, path: &[String], dir: &Entry) {
+        for (props, child) in children(path, dir) {
+            self.add(child_path(path, &props.name), props, child);
+        }
+    }
+    fn remove_all(&mut self, path: &[String], entry: &Entry) {
+        self.remove(path);
+        for (props, child) in children(path, entry) {
+            self.remove_all(&child_path(path, &props.name), child);
+        }
+    }
+    // Compares the children of two versions of the directory at `path` by name. Children that
+    // are still the same `Arc` under the same properties haven't changed, and neither has
+    // anything beneath them.
+    fn diff_children(&mut self, path: &[String], before: &Entry, after: &Entry) {
+        if let (EntryKind::Directory(old), EntryKind::Directory(new)) = (&before.kind, &after.kind)
+        {
+            if Arc::ptr_eq(old, new) {
+                return;
+            }
+        }
+
+        let old = by_name(path, before);
+        let new = by_name(path, after);
+
+        for (name, (_, child)) in old.iter() {
+            if !new.contains_key(name) {
+                self.remove_all(&child_path(path, name), child);
+            }
+        }
+
+        for (name, (props, child)) in new.iter() {
+            let child_path = child_path(path, name);
+            match old.get(name) {
+                None => self.add(child_path, props, child),
+                Some((old_props, old_child)) => {
+                    let same_entry = Arc::ptr_eq(old_child, child);
+                    if same_entry && same_attributes(old_props, props) {
+                        continue;
+                    }
+
+                    let attrs = Attributes::of(props, child);
+                    if self.records.get(&child_path) != Some(&attrs) {
+                        self.remove(&child_path);
+                        self.insert(Arc::clone(&child_path), attrs);
+                    }
+                    if !same_entry {
+                        self.diff_children(&child_path, old_child, child);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// whether two sets of properties index the same; comparing the whole of each would also
+// compare the directories they point back to
+fn same_attributes(a: &Properties, b: &Properties) -> bool {
+    a.mime_type == b.mime_type
+        && a.uid == b.uid
+        && a.date_created == b.date_created
+        && a.date_modified == b.date_modified
+}
+
+// the children of `dir` worth indexing; the root's own "/" entry isn't a name for anything
+pub(crate) fn children<'e, 'a>(
+    path: &[String],
+    dir: &'e Entry<'a>,
+) -> impl Iterator<Item = (&'e Properties<'a>, &'e Arc<Entry<'a>>)> {
+    let map = match &dir.kind {
+        EntryKind::Directory(map) => Some(map),
+        _ => None,
+    };
+    let top = path.is_empty();
+
+    map.into_iter()
+        .flat_map(|map| map.iter())
+        .filter(move |(props, _)| !(top && props.name == "/"))
+}
+
+pub(crate) fn by_name<'e, 'a>(
+    path: &[String],
+    dir: &'e Entry<'a>,
+) -> BTreeMap<&'e str, (&'e Properties<'a>, &'e Arc<Entry<'a>>)> {
+    children(path, dir)
+        .map(|(props, child)| (props.name.as_str(), (props, child)))
+        .collect()
+}
+
+pub(crate) fn child_path(path: &[String], name: &str) -> IndexPath {
+    path.iter().cloned().chain([String::from(name)]).collect()
+}
+
+fn post<K: Ord>(postings: &mut Postings<K>, key: K, path: &IndexPath) {
+    postings.entry(key).or_default().insert(Arc::clone(path));
+}
+
+fn unpost<K: Ord>(postings: &mut Postings<K>, key: &K, path: &[String]) {
+    if let Some(paths) = postings.get_mut(key) {
+        paths.remove(path);
+        if paths.is_empty() {
+            postings.remove(key);
+        }
+    }
+}
+
+fn in_range(
+    postings: &Postings<time_t>,
+    since: Option<time_t>,
+    before: Option<time_t>,
+) -> Vec<&IndexPath> {
+    let since = since.unwrap_or(time_t::MIN);
+    let paths = match before {
+        Some(before) if before <= since => return Vec::new(),
+        Some(before) => postings.range(since..before),
+        None => postings.range(since..),
+    };
+
+    paths.flat_map(|(_, paths)| paths.iter()).collect()
+}
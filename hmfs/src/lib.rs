@@ -0,0 +1,2431 @@
+#![no_std]
+
+extern crate alloc;
+
+use alloc::borrow::ToOwned;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::hash::{BuildHasherDefault, Hash, Hasher};
+use core::ops::RangeBounds;
+use core::ptr;
+use mr_mime::Mime;
+use sha3::{Digest, Sha3_512};
+use syscall::{
+    Error, E2BIG, EBUSY, EDQUOT, EEXIST, EINVAL, EIO, EISDIR, ELOOP, EMLINK, ENAMETOOLONG, ENODATA,
+    ENOENT, ENOKEY, ENOTDIR, ENOTEMPTY, EPERM, EROFS, EXDEV,
+};
+use unix_path::{Component, Path, PathBuf};
+
+use self::blocks::{BlockCodec, FileBlocks, Plain, BLOCK_SIZE};
+use self::checksum::{checksum_of, Checksum, CHECKSUM_LEN};
+use self::compress::{Compression, COMPRESS_XATTR};
+use self::dedup::{Dedup, DedupStats, DedupTable, DEDUP_XATTR};
+use self::perm::{Credential, MODE_MASK, R_OK, S_ISGID, S_ISUID, W_OK, X_OK};
+use self::quota::{Account, Holder, Limits, Quota, QUOTA_XATTR};
+use self::send::{StreamHeader, StreamStats};
+use self::watch::{Event, EventKind, WatchId, Watches};
+
+pub mod blocks;
+pub mod checksum;
+pub mod compress;
+pub mod crypt;
+pub mod dedup;
+pub mod format;
+pub mod index;
+pub mod mem;
+pub mod perm;
+pub mod quota;
+pub mod scrub;
+pub mod send;
+pub mod sniff;
+pub mod volumes;
+pub mod watch;
+
+// The Hierarchical Metadata File System, shared by the kernel and hmfsprogs: the in-memory tree,
+// its on-disk format and everything built on the two. Nothing here touches hardware or the host;
+// a volume goes through `format::Device`, which the kernel implements over a disk, hmfsprogs over
+// an image file and `mem::MemDevice` over memory.
+
+/// Returns the first 64 bits of a 512-bit hash
+pub fn u64_from_slice(slice: &mut [u8]) -> u64 {
+    u64::from_be_bytes(slice.split_at_mut(8).0.try_into().unwrap())
+}
+
+/// `HashMap` hasher that uses 512-bit SHA-3 instead of AHash, as the kernel's does
+#[derive(Default)]
+pub struct HMFSHasher(Sha3_512);
+
+impl Hasher for HMFSHasher {
+    fn finish(&self) -> u64 {
+        u64_from_slice(self.0.clone().finalize().as_mut_slice())
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes)
+    }
+}
+
+pub type HMFSHashBuilder = BuildHasherDefault<HMFSHasher>;
+pub type HashMap<K, V> = hashbrown::HashMap<K, V, HMFSHashBuilder>;
+
+// going one-further than most other implementations to ensure this never overflows
+#[allow(non_camel_case_types)]
+pub type time_t = i128;
+
+pub type FileData = Vec<u8>;
+
+/// Extended attributes: arbitrary key/value pairs kept alongside an entry's contents
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+/// Longest extended attribute key, in bytes
+pub const XATTR_NAME_MAX: usize = 255;
+/// Largest extended attribute value, in bytes
+pub const XATTR_SIZE_MAX: usize = 65536;
+
+/// Most symbolic links one path lookup follows before failing with `ELOOP`
+pub const SYMLOOP_MAX: usize = 40;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EntryKind<'a> {
+    Directory(Arc<HashMap<Properties<'a>, Arc<Entry<'a>>>>),
+    File(FileBlocks),
+    Root(Arc<RootEntry<'a>>),
+    /// Target path as stored, i.e. encrypted if the link is in an encrypted directory
+    Symlink(String),
+}
+
+impl EntryKind<'_> {
+    /// Whether this can hold other entries
+    pub fn is_dir(&self) -> bool {
+        matches!(self, Self::Directory(_) | Self::Root(_))
+    }
+}
+
+// Merkle-style: a directory covers its children's properties and checksums rather than rehashing
+// their contents, so a checksum can be recomputed from one level of the on-disk records
+impl Hash for EntryKind<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Self::Directory(map) => {
+                state.write_u8(format::KIND_DIRECTORY);
+                for (properties, entry) in sorted_children(map) {
+                    properties.hash(state);
+                    state.write(entry.checksum.as_bytes());
+                }
+            }
+            Self::File(file) => {
+                state.write_u8(format::KIND_FILE);
+                file.hash(state);
+            }
+            Self::Root(root) => {
+                root.dir.kind.hash(state);
+            }
+            Self::Symlink(target) => format::hash_symlink(target, state),
+        }
+    }
+}
+
+pub fn new_map_shorthand<'a>() -> HashMap<Properties<'a>, Arc<Entry<'a>>> {
+    HashMap::<Properties<'a>, Arc<Entry<'a>>>::default()
+}
+
+/// Back-reference for entries built outside of a tree, e.g. while decoding or importing
+pub fn detached<'a>() -> EntryKind<'a> {
+    EntryKind::Directory(Arc::new(new_map_shorthand()))
+}
+
+/// Returns the children of a directory in name order
+///
+/// `HashMap` iteration order depends on insertion history, so anything that has to come out the
+/// same for two equal directories (checksums, the on-disk record table) goes through this instead
+pub fn sorted_children<'b, 'a>(
+    map: &'b HashMap<Properties<'a>, Arc<Entry<'a>>>,
+) -> Vec<(&'b Properties<'a>, &'b Arc<Entry<'a>>)> {
+    let mut children = map.iter().collect::<Vec<_>>();
+    children.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+    children
+}
+
+// the components of `path`, prefixed with those of `cwd` if it's relative
+fn components<'p>(cwd: &'p Path, path: &'p Path) -> impl Iterator<Item = Component<'p>> {
+    let start = if path.has_root() { None } else { Some(cwd) };
+    start
+        .into_iter()
+        .flat_map(|cwd| cwd.components())
+        .chain(path.components())
+}
+
+// Whether the extended attribute `key` is kept for the filesystem's own use. Only `encrypt` gets
+// to mark a directory as encrypted, and nothing gets to unmark one; nonces are only ever set by
+// `encrypt` and `link`, as names or contents are sealed, and a file's tag whenever its blocks
+// change; compression goes through `set_compression`, which recompresses what's already stored,
+// deduplication through `set_dedup` and quotas through `set_quota` and `set_owner_quota`.
+fn reserved_xattr(key: &str) -> bool {
+    [
+        crypt::CRYPT_XATTR,
+        crypt::NONCE_XATTR,
+        crypt::TAG_XATTR,
+        COMPRESS_XATTR,
+        DEDUP_XATTR,
+        QUOTA_XATTR,
+    ]
+    .contains(&key)
+        || key.starts_with(quota::OWNER_QUOTA_PREFIX)
+}
+
+// One component of a path being walked, owned so a symbolic link's target can join the walk
+enum Step {
+    Root,
+    Here,
+    Up,
+    Name(String),
+}
+
+impl Step {
+    fn of(component: Component) -> syscall::Result<Self> {
+        Ok(match component {
+            Component::RootDir => Self::Root,
+            Component::CurDir => Self::Here,
+            Component::ParentDir => Self::Up,
+            Component::Normal(name) => {
+                Self::Name(String::from(name.to_str().ok_or(Error::new(ENOENT))?))
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Entry<'a> {
+    kind: EntryKind<'a>,
+    checksum: Checksum,
+    parent: Option<EntryKind<'a>>,
+    xattrs: Xattrs,
+    /// Names the entry is linked under; only files and symbolic links ever have more than one
+    links: u32,
+}
+
+// Everything an entry's checksum covers
+struct Checksummed<'e, 'a> {
+    kind: &'e EntryKind<'a>,
+    xattrs: &'e Xattrs,
+    links: u32,
+}
+
+impl Hash for Checksummed<'_, '_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind.hash(state);
+        format::hash_xattrs(
+            self.xattrs
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_slice())),
+            state,
+        );
+        format::hash_links(self.links, state);
+    }
+}
+
+impl<'a> Entry<'a> {
+    pub fn new(kind: EntryKind<'a>, parent: Option<EntryKind<'a>>) -> Self {
+        if parent.as_ref().is_some_and(|parent| !parent.is_dir()) {
+            panic!("Parent must be a directory");
+        }
+
+        // entries without a parent (the root, and anything not linked in yet) get checksummed too
+        let mut entry = Self {
+            kind,
+            checksum: Checksum([0; CHECKSUM_LEN]),
+            parent,
+            xattrs: Xattrs::new(),
+            links: 1,
+        };
+        entry.update_checksum();
+        entry
+    }
+    fn update_checksum(&mut self) {
+        self.checksum = self.computed_checksum();
+    }
+    // what the checksum should be, going by the contents as they are
+    fn computed_checksum(&self) -> Checksum {
+        checksum_of(&Checksummed {
+            kind: &self.kind,
+            xattrs: &self.xattrs,
+            links: self.links,
+        })
+    }
+    pub fn parent(&self) -> Option<EntryKind<'_>> {
+        self.parent.clone()
+    }
+    pub fn kind(&self) -> &EntryKind<'a> {
+        &self.kind
+    }
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+    /// How many names the entry is linked under
+    pub fn links(&self) -> u32 {
+        self.links
+    }
+    /// Looks up a child of this directory by name
+    pub fn child(&self, name: &str) -> Option<(&Properties<'a>, &Arc<Entry<'a>>)> {
+        match &self.kind {
+            EntryKind::Directory(map) => map.iter().find(|(props, _)| props.name == name),
+            EntryKind::Root(root) => root.dir.child(name),
+            EntryKind::File(_) | EntryKind::Symlink(_) => None,
+        }
+    }
+    // copy-on-write access to the map backing this directory
+    fn map_mut(&mut self) -> syscall::Result<&mut HashMap<Properties<'a>, Arc<Entry<'a>>>> {
+        match &mut self.kind {
+            EntryKind::Directory(map) => Ok(Arc::make_mut(map)),
+            EntryKind::Root(root) => Arc::make_mut(root).dir.map_mut(),
+            EntryKind::File(_) | EntryKind::Symlink(_) => Err(Error::new(ENOTDIR)),
+        }
+    }
+    /// Links `entry` into this directory, replacing any child with the same name
+    pub(crate) fn link(&mut self, props: Properties<'a>, entry: Entry<'a>) -> syscall::Result<()> {
+        self.link_shared(props, Arc::new(entry))
+    }
+    // `link` for an entry that may also be linked elsewhere
+    fn link_shared(&mut self, props: Properties<'a>, entry: Arc<Entry<'a>>) -> syscall::Result<()> {
+        let map = self.map_mut()?;
+
+        if let Some(old) = map.keys().find(|old| old.name == props.name).cloned() {
+            map.remove(&old);
+        }
+        map.insert(props, entry);
+
+        self.update_checksum();
+        Ok(())
+    }
+    // points the child called `name` at `entry`, keeping its properties
+    fn replace_child(&mut self, name: &str, entry: Arc<Entry<'a>>) -> syscall::Result<()> {
+        let props = self
+            .child(name)
+            .map(|(props, _)| props.clone())
+            .ok_or(Error::new(ENOENT))?;
+
+        self.link_shared(props, entry)
+    }
+    // gives the child called `name` the metadata in `props`, keeping its own name
+    fn set_child_props(&mut self, name: &str, props: &Properties<'a>) -> syscall::Result<()> {
+        let map = self.map_mut()?;
+
+        let old = map
+            .keys()
+            .find(|old| old.name == name)
+            .cloned()
+            .ok_or(Error::new(ENOENT))?;
+        let (old, entry) = map.remove_entry(&old).unwrap();
+
+        let mut props = props.clone().renamed(old.name);
+        props.entry_kind = old.entry_kind;
+        map.insert(props, entry);
+
+        self.update_checksum();
+        Ok(())
+    }
+    /// Unlinks the child called `name` from this directory and hands it back
+    pub(crate) fn take_child(
+        &mut self,
+        name: &str,
+    ) -> syscall::Result<Option<(Properties<'a>, Entry<'a>)>> {
+        let map = self.map_mut()?;
+
+        let Some(props) = map.keys().find(|props| props.name == name).cloned() else {
+            return Ok(None);
+        };
+        let (props, entry) = map.remove_entry(&props).unwrap();
+
+        self.update_checksum();
+        Ok(Some((props, Arc::unwrap_or_clone(entry))))
+    }
+    /// Runs `f` on the directory `path` names below this one
+    ///
+    /// Every directory on the way down is copied if it's shared (`Arc::make_mut`), so other
+    /// holders of the old tree keep seeing it unchanged, and has its checksum refreshed on the
+    /// way back up.
+    pub(crate) fn with_dir_mut<R>(
+        &mut self,
+        path: &[&str],
+        f: impl FnOnce(&mut Entry<'a>) -> syscall::Result<R>,
+    ) -> syscall::Result<R> {
+        match path.split_first() {
+            None => {
+                if !self.kind.is_dir() {
+                    return Err(Error::new(ENOTDIR));
+                }
+
+                let out = f(self)?;
+                self.update_checksum();
+                Ok(out)
+            }
+            Some((name, rest)) => self.with_child_mut(name, |child| child.with_dir_mut(rest, f)),
+        }
+    }
+    /// Runs `f` on the child called `name`, which may be a file, copying it first if it's shared
+    pub(crate) fn with_child_mut<R>(
+        &mut self,
+        name: &str,
+        f: impl FnOnce(&mut Entry<'a>) -> syscall::Result<R>,
+    ) -> syscall::Result<R> {
+        let map = self.map_mut()?;
+        let props = map
+            .keys()
+            .find(|props| props.name == name)
+            .cloned()
+            .ok_or(Error::new(ENOENT))?;
+
+        let out = f(Arc::make_mut(map.get_mut(&props).unwrap()))?;
+        self.update_checksum();
+        Ok(out)
+    }
+    pub fn xattr(&self, key: &str) -> Option<&[u8]> {
+        self.xattrs.get(key).map(Vec::as_slice)
+    }
+    /// Extended attributes in key order
+    pub fn xattrs(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.xattrs
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_slice()))
+    }
+    /// Sets an extended attribute, replacing any value it already had
+    pub(crate) fn set_xattr(&mut self, key: String, value: Vec<u8>) -> syscall::Result<()> {
+        if key.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+        if key.len() > XATTR_NAME_MAX {
+            return Err(Error::new(ENAMETOOLONG));
+        }
+        if value.len() > XATTR_SIZE_MAX {
+            return Err(Error::new(E2BIG));
+        }
+
+        self.xattrs.insert(key, value);
+        self.update_checksum();
+        Ok(())
+    }
+    /// Removes an extended attribute and hands back its value, or fails with `ENODATA`
+    pub(crate) fn remove_xattr(&mut self, key: &str) -> syscall::Result<Vec<u8>> {
+        let value = self.xattrs.remove(key).ok_or(Error::new(ENODATA))?;
+        self.update_checksum();
+        Ok(value)
+    }
+    /// Creates a directory owned by `cred` in a mounted root and hands back a copy of it
+    ///
+    /// This is `RootEntry::link` at `/name`, so the root directory's permissions, the quotas of
+    /// `cred` and the volume and everything else `link` checks apply. A directory of its own has
+    /// no properties to check those against, so only a mounted root takes new entries this way
+    /// (`EPERM`).
+    pub fn mkdir(
+        &mut self,
+        cred: &Credential,
+        name: String,
+        mode: u32,
+        timestamp: time_t,
+    ) -> syscall::Result<Self> {
+        let kind = EntryKind::Directory(Arc::new(new_map_shorthand()));
+        self.create(cred, name, None, mode, timestamp, kind)
+    }
+    /// Creates a file owned by `cred` in a mounted root, as `mkdir` does
+    ///
+    /// Without a `mime` the type is sniffed from `data`, and left unset if it isn't recognized.
+    pub fn create_file(
+        &mut self,
+        cred: &Credential,
+        mime: Option<Mime<'a>>,
+        name: String,
+        mode: u32,
+        timestamp: time_t,
+        data: FileData,
+    ) -> syscall::Result<Self> {
+        let mime = mime.or_else(|| sniff::sniff(&data));
+        let kind = EntryKind::File(FileBlocks::from_bytes(&data));
+        self.create(cred, name, mime, mode, timestamp, kind)
+    }
+    // links a new entry of `kind` owned by `cred` into the mounted root this is, through
+    // `RootEntry::link`, and hands back a copy of it
+    fn create(
+        &mut self,
+        cred: &Credential,
+        name: String,
+        mime: Option<Mime<'a>>,
+        mode: u32,
+        timestamp: time_t,
+        kind: EntryKind<'a>,
+    ) -> syscall::Result<Self> {
+        let root = match &mut self.kind {
+            EntryKind::Root(root) => Arc::make_mut(root),
+            EntryKind::Directory(_) => return Err(Error::new(EPERM)),
+            EntryKind::File(_) | EntryKind::Symlink(_) => return Err(Error::new(ENOTDIR)),
+        };
+        // one name, not a path
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(Error::new(EINVAL));
+        }
+
+        let props = Properties::new(
+            name.clone(),
+            detached(),
+            mime,
+            mode & MODE_MASK,
+            cred.name(),
+            timestamp,
+            timestamp,
+            cred.name(),
+            cred.uid,
+            cred.gid,
+        );
+        let path = Path::new("/").join(Path::new(&name));
+        root.link(cred, Path::new("/"), &path, props, Entry::new(kind, None))?;
+        let (_, entry) = root.resolve_nofollow(&Credential::ROOT, Path::new("/"), &path)?;
+
+        self.update_checksum();
+        Ok(entry)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Properties<'a> {
+    name: String,
+    full_path: PathBuf,
+    entry_kind: EntryKind<'a>,
+    mime_type: Option<Mime<'a>>,
+    mode: u32,
+    created_by: String,
+    date_created: time_t,
+    date_modified: time_t,
+    /// Name of the owner, for people; permissions go by `uid` and `gid`
+    owner: String,
+    uid: u32,
+    gid: u32,
+}
+
+#[allow(clippy::too_many_arguments)]
+impl<'a> Properties<'a> {
+    pub fn new(
+        name: String,
+        entry_kind: EntryKind<'a>,
+        mime_type: Option<Mime<'a>>,
+        mode: u32,
+        created_by: String,
+        date_created: time_t,
+        date_modified: time_t,
+        owner: String,
+        uid: u32,
+        gid: u32,
+    ) -> Self {
+        // borrow checker
+        let cloned = name.clone();
+
+        Self {
+            name,
+            full_path: Path::new(cloned.as_str()).to_owned(),
+            entry_kind,
+            mime_type,
+            mode,
+            created_by,
+            date_created,
+            date_modified,
+            owner,
+            uid,
+            gid,
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn mime_type(&self) -> Option<&Mime<'a>> {
+        self.mime_type.as_ref()
+    }
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+    pub fn created_by(&self) -> &str {
+        &self.created_by
+    }
+    pub fn date_created(&self) -> time_t {
+        self.date_created
+    }
+    pub fn date_modified(&self) -> time_t {
+        self.date_modified
+    }
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+    // the same metadata under another name
+    fn renamed(mut self, name: String) -> Self {
+        self.full_path = Path::new(name.as_str()).to_owned();
+        self.name = name;
+        self
+    }
+}
+
+// Hashes the on-disk metadata only: `entry_kind` points back at the containing directory, and
+// hashing it would rehash that whole subtree on every lookup
+impl Hash for Properties<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        format::write_properties(self, &mut |bytes| state.write(bytes));
+    }
+}
+
+// (Partition) formatting; see `format` for the layout
+pub fn root_entry_bytes(entry: RootEntry) -> Vec<u8> {
+    format::encode(&entry)
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[allow(dead_code)]
+pub struct RootEntry<'a> {
+    magic: u32,
+    system_clock: time_t,
+    entry_count: usize,
+    checksum: Checksum,
+    label: String,
+    dir: Entry<'a>,
+    /// Oldest first
+    snapshots: Vec<Snapshot<'a>>,
+    read_only: bool,
+    /// Keys of unlocked encrypted directories by salt; never written out
+    keys: BTreeMap<[u8; crypt::SALT_LEN], crypt::Key>,
+    /// Attribute indexes over the live tree; rebuilt on mount rather than written out
+    index: Arc<index::Index>,
+    /// Blocks to share with as files are written, if the root directory carries `DEDUP_XATTR`;
+    /// rebuilt on mount rather than written out
+    dedup: Option<DedupTable>,
+    /// Usage charged to each owner and subtree of the live tree; rebuilt on mount rather than
+    /// written out
+    usage: Arc<quota::Accounting>,
+    /// Change notifications being queued for whoever is watching; never written out
+    watches: Watches,
+}
+
+// How names and file contents are stored directly inside a directory
+#[derive(Debug, Clone, PartialEq)]
+enum Sealing {
+    Plain,
+    /// Encrypted under a key that isn't loaded
+    Locked,
+    Unlocked(crypt::DirKey),
+}
+
+impl Sealing {
+    // whether entries can move between directories sealed like these as they are, which takes
+    // the same key; names are sealed anew for the directory they move to
+    fn compatible(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Unlocked(a), Self::Unlocked(b)) => a.key() == b.key(),
+            (a, b) => a == b,
+        }
+    }
+    fn stored_name(&self, name: &str) -> String {
+        match self {
+            Self::Unlocked(dir) => dir.seal_name(name),
+            _ => String::from(name),
+        }
+    }
+    fn shown_name(&self, stored: &str) -> syscall::Result<String> {
+        match self {
+            Self::Unlocked(dir) => dir.open_name(stored),
+            _ => Ok(String::from(stored)),
+        }
+    }
+    fn stored_target(&self, target: &str) -> String {
+        match self {
+            Self::Unlocked(dir) => dir.key().seal_link(target),
+            _ => String::from(target),
+        }
+    }
+    fn shown_target(&self, stored: &str) -> syscall::Result<String> {
+        match self {
+            Self::Plain => Ok(String::from(stored)),
+            Self::Locked => Err(Error::new(ENOKEY)),
+            Self::Unlocked(dir) => dir.key().open_link(stored),
+        }
+    }
+    // how the blocks of a file stored directly inside the directory are, given its `xattrs`
+    fn file_codec(&self, xattrs: &Xattrs) -> syscall::Result<FileCodec> {
+        match self {
+            Self::Plain => Ok(FileCodec::Plain),
+            Self::Locked => Err(Error::new(ENOKEY)),
+            Self::Unlocked(dir) => {
+                let nonce = xattrs.get(crypt::NONCE_XATTR).ok_or(Error::new(EIO))?;
+                Ok(FileCodec::Sealed(dir.key().for_file(nonce)?))
+            }
+        }
+    }
+}
+
+// How a file's blocks are stored, short of compression
+enum FileCodec {
+    Plain,
+    Sealed(crypt::FileKey),
+}
+
+impl FileCodec {
+    // brings the tag of `entry`, a file stored with this codec, up to date with its blocks
+    fn retag(&self, entry: &mut Entry<'_>) {
+        if let (Self::Sealed(key), EntryKind::File(file)) = (self, &entry.kind) {
+            let tag = key.tag(file);
+            entry.xattrs.insert(String::from(crypt::TAG_XATTR), tag);
+        }
+    }
+    // checks a sealed file's length and blocks against its tag, failing with `EIO`
+    fn check(&self, entry: &Entry<'_>) -> syscall::Result<()> {
+        match (self, &entry.kind) {
+            (Self::Sealed(key), EntryKind::File(file)) => {
+                let tag = entry.xattr(crypt::TAG_XATTR).ok_or(Error::new(EIO))?;
+                key.check(file, tag)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl BlockCodec for FileCodec {
+    fn encode(&self, index: u64, plain: Vec<u8>) -> Vec<u8> {
+        match self {
+            Self::Plain => Plain.encode(index, plain),
+            Self::Sealed(key) => key.encode(index, plain),
+        }
+    }
+    fn decode(&self, index: u64, stored: &[u8]) -> syscall::Result<Vec<u8>> {
+        match self {
+            Self::Plain => Plain.decode(index, stored),
+            Self::Sealed(key) => key.decode(index, stored),
+        }
+    }
+}
+
+// Where the last component of a path goes, whether or not anything is there yet
+struct Location<'a> {
+    /// Stored names of the directories leading to it from the root
+    parent: Vec<String>,
+    /// Properties of the directory it goes in
+    dir: Properties<'a>,
+    /// Its own name, as given
+    name: String,
+    sealing: Sealing,
+    /// The directory's `COMPRESS_XATTR`, which new entries in it get
+    compress: Option<Vec<u8>>,
+}
+
+impl Location<'_> {
+    fn parent(&self) -> Vec<&str> {
+        self.parent.iter().map(String::as_str).collect()
+    }
+    fn stored_name(&self) -> String {
+        self.sealing.stored_name(&self.name)
+    }
+    // stored names leading to it from the root, its own included
+    fn stored_path(&self) -> Vec<String> {
+        let mut path = self.parent.clone();
+        path.push(self.stored_name());
+        path
+    }
+    // checks that `cred` may add and remove names in the directory
+    fn check_writable(&self, cred: &Credential) -> syscall::Result<()> {
+        perm::access(cred, &self.dir, true, W_OK | X_OK)
+    }
+}
+
+/// A read-only copy of the root directory as it was at some point
+///
+/// Taking one only clones the root's `Arc`, and `Arc::make_mut` copies whatever the live tree
+/// changes afterwards, so a snapshot costs nothing up front and shares everything that hasn't
+/// been touched since.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Snapshot<'a> {
+    name: String,
+    created: time_t,
+    dir: Entry<'a>,
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn created(&self) -> time_t {
+        self.created
+    }
+    pub fn checksum(&self) -> Checksum {
+        self.dir.checksum
+    }
+}
+
+impl<'a> RootEntry<'a> {
+    pub fn new(timestamp: time_t) -> Self {
+        Self::new_owned_by(timestamp, String::from("root"), 0, 0)
+    }
+    /// An empty volume whose root directory belongs to `owner`, with mode 0755
+    pub fn new_owned_by(timestamp: time_t, owner: String, uid: u32, gid: u32) -> Self {
+        let mut root_map_inner = new_map_shorthand();
+        let root_map = Arc::new(root_map_inner.clone());
+
+        let root_props = Properties::new(
+            String::from("/"),
+            EntryKind::Directory(Arc::clone(&root_map)),
+            None,
+            0o755,
+            owner.clone(),
+            timestamp,
+            timestamp,
+            owner,
+            uid,
+            gid,
+        );
+
+        root_map_inner.insert(
+            root_props.clone(),
+            Arc::new(Entry::new(
+                EntryKind::Directory(Arc::clone(&root_map)),
+                None,
+            )),
+        );
+
+        drop(root_map);
+
+        let new_root_map = Arc::new(root_map_inner);
+        let old_entry = Entry::new(EntryKind::Directory(new_root_map.clone()), None);
+
+        let mut new_entry_parent = Self {
+            magic: 0x90a7cafe,
+            system_clock: timestamp,
+            entry_count: Arc::strong_count(&new_root_map),
+            checksum: checksum_of(&old_entry.kind),
+            label: String::new(),
+            dir: old_entry,
+            snapshots: Vec::new(),
+            read_only: false,
+            keys: BTreeMap::new(),
+            index: Arc::default(),
+            dedup: None,
+            usage: Arc::default(),
+            watches: Watches::default(),
+        };
+
+        let new_entry = Entry::new(
+            EntryKind::Directory(new_root_map.clone()),
+            Some(EntryKind::Root(Arc::new(new_entry_parent.clone()))),
+        );
+        new_entry_parent.dir = new_entry.clone();
+
+        // keep these values up-to-date
+        new_entry_parent.dir.parent = Some(EntryKind::Root(Arc::new(new_entry_parent.clone())));
+        new_entry_parent.dir.kind = EntryKind::Directory(new_root_map.clone());
+        new_entry_parent.checksum = checksum_of(&new_entry.kind);
+
+        // shadow this
+        let new_entry = new_entry_parent.dir.clone();
+
+        // keep HashMap up-to-date
+        if let EntryKind::Directory(ref mut dir) = &mut new_entry_parent.dir.kind {
+            Arc::make_mut(dir).remove_entry(&root_props);
+            Arc::make_mut(dir).insert(root_props, Arc::new(new_entry));
+        } else {
+            unreachable!()
+        }
+
+        // the map changed underneath both checksums
+        new_entry_parent.dir.update_checksum();
+        new_entry_parent.checksum = new_entry_parent.dir.checksum;
+
+        new_entry_parent
+    }
+    pub fn get_root_dir(&self) -> Entry<'_> {
+        assert_eq!(self.magic, 0x90a7cafe); // TODO: find a compiler-level way to do this
+        self.dir.clone()
+    }
+    // `Entry::with_dir_mut` from the root directory, keeping the root checksum, index and usage
+    // in step. What changes this way isn't reported to watches, so whatever calls it does that.
+    pub(crate) fn with_dir_mut<R>(
+        &mut self,
+        path: &[&str],
+        f: impl FnOnce(&mut Entry<'a>) -> syscall::Result<R>,
+    ) -> syscall::Result<R> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+
+        let before = self.dir.clone();
+        let out = self.dir.with_dir_mut(path, f);
+        // even a change that failed may have got partway
+        Arc::make_mut(&mut self.index).update(&before, &self.dir);
+        Arc::make_mut(&mut self.usage).update(&before, &self.dir);
+
+        let out = out?;
+        self.checksum = self.dir.checksum;
+        Ok(out)
+    }
+    // Runs `f`, and if that failed, or left any owner or subtree over a limit it wasn't over
+    // before or further over one, puts the tree back as it was, failing with `EDQUOT` in the
+    // latter case. Giving an entry another name or moving one charges accounts partway through
+    // that it's refunded by the end, so usage is only checked once `f` is done.
+    fn within_quota<R>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> syscall::Result<R>,
+    ) -> syscall::Result<R> {
+        let before = self.dir.clone();
+        Arc::make_mut(&mut self.usage).begin();
+
+        let out = match f(self) {
+            Ok(_) if self.usage.exceeded(&before, &self.dir) => Err(Error::new(EDQUOT)),
+            out => out,
+        };
+        if out.is_err() {
+            let after = core::mem::replace(&mut self.dir, before);
+            Arc::make_mut(&mut self.index).update(&after, &self.dir);
+            Arc::make_mut(&mut self.usage).update(&after, &self.dir);
+            // nothing was changed after all
+            Arc::make_mut(&mut self.usage).begin();
+            self.checksum = self.dir.checksum;
+        }
+        out
+    }
+    /// Looks up `path` and returns the entry it names along with its properties
+    ///
+    /// Relative paths start at `cwd`, which has to be absolute. `..` steps back out of whatever
+    /// directory the walk last entered, and stays put at the root. Symbolic links are followed,
+    /// relative ones from the directory holding them, and `..` after one steps out of wherever
+    /// it led. Inside an unlocked encrypted directory the name comes back decrypted, but the
+    /// entry is as stored; `read_file` and `read_dir` decrypt. `cred` needs search permission
+    /// on every directory looked in, here and in every other operation that takes a path.
+    pub fn resolve(
+        &self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+    ) -> syscall::Result<(Properties<'a>, Entry<'a>)> {
+        self.lookup(cred, cwd, path, true)
+    }
+    /// `resolve`, except that a symbolic link in the last component is returned rather than
+    /// followed, unless the path ends in a slash
+    pub fn resolve_nofollow(
+        &self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+    ) -> syscall::Result<(Properties<'a>, Entry<'a>)> {
+        self.lookup(cred, cwd, path, false)
+    }
+    fn lookup(
+        &self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        follow: bool,
+    ) -> syscall::Result<(Properties<'a>, Entry<'a>)> {
+        let trailing_slash = path.to_str().is_some_and(|path| path.ends_with('/'));
+        let walked = self.walk(cred, components(cwd, path), follow || trailing_slash)?;
+        let (props, entry, _) = &walked[walked.len() - 1];
+
+        // "file/" names a directory that isn't there
+        if !entry.kind.is_dir() && trailing_slash {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        let props = match walked.len() {
+            1 => (*props).clone(),
+            len => {
+                let shown = walked[len - 2].2.shown_name(&props.name)?;
+                (*props).clone().renamed(shown)
+            }
+        };
+
+        Ok((props, (*entry).clone()))
+    }
+    // how names and file contents are stored in `entry`, given how they are in its parent
+    fn sealing(&self, entry: &Entry<'a>, inherited: &Sealing) -> Sealing {
+        if !entry.kind.is_dir() {
+            return inherited.clone();
+        }
+
+        let key = match entry.xattr(crypt::CRYPT_XATTR) {
+            None => match inherited {
+                Sealing::Unlocked(dir) => dir.key(),
+                _ => return inherited.clone(),
+            },
+            Some(params) => match crypt::CryptParams::from_bytes(params) {
+                Ok(params) => match self.keys.get(&params.salt) {
+                    Some(key) => key,
+                    None => return Sealing::Locked,
+                },
+                Err(_) => return Sealing::Locked,
+            },
+        };
+
+        // names are bound to the directory they're in by its nonce
+        match entry
+            .xattr(crypt::NONCE_XATTR)
+            .map(|nonce| key.for_dir(nonce))
+        {
+            Some(Ok(dir)) => Sealing::Unlocked(dir),
+            _ => Sealing::Locked,
+        }
+    }
+    // every entry a path passes through on the way down, starting at the root; symbolic links
+    // are walked through rather than into, except one in the last component unless `follow`
+    fn walk<'p>(
+        &self,
+        cred: &Credential,
+        components: impl Iterator<Item = Component<'p>>,
+        follow: bool,
+    ) -> syscall::Result<Vec<(&Properties<'a>, &Entry<'a>, Sealing)>> {
+        // the root keeps its own properties under "/"
+        let (root_props, _) = self.dir.child("/").ok_or(Error::new(ENOENT))?;
+        let root_sealing = self.sealing(&self.dir, &Sealing::Plain);
+        let mut walked = Vec::from([(root_props, &self.dir, root_sealing)]);
+
+        // last step first, so a link's target can be put in front of whatever follows it
+        let mut pending = components
+            .map(Step::of)
+            .collect::<syscall::Result<Vec<_>>>()?;
+        pending.reverse();
+        let mut followed = 0;
+
+        while let Some(step) = pending.pop() {
+            let current = walked[walked.len() - 1].1;
+
+            if !current.kind.is_dir() {
+                return Err(Error::new(ENOTDIR));
+            }
+
+            match step {
+                Step::Root => walked.truncate(1),
+                Step::Here => {}
+                Step::Up => {
+                    if walked.len() > 1 {
+                        walked.pop();
+                    }
+                }
+                Step::Name(name) => {
+                    let (dir_props, _, sealing) = &walked[walked.len() - 1];
+                    perm::access(cred, dir_props, true, X_OK)?;
+
+                    let (props, entry) = current
+                        .child(&sealing.stored_name(&name))
+                        .ok_or(Error::new(ENOENT))?;
+
+                    if let EntryKind::Symlink(target) = &entry.kind {
+                        if follow || !pending.is_empty() {
+                            followed += 1;
+                            if followed > SYMLOOP_MAX {
+                                return Err(Error::new(ELOOP));
+                            }
+
+                            let target = sealing.shown_target(target)?;
+                            if target.is_empty() {
+                                return Err(Error::new(ENOENT));
+                            }
+
+                            let target = Path::new(&target)
+                                .components()
+                                .map(Step::of)
+                                .collect::<syscall::Result<Vec<_>>>()?;
+                            pending.extend(target.into_iter().rev());
+                            continue;
+                        }
+                    }
+
+                    let sealing = self.sealing(entry, sealing);
+                    walked.push((props, entry, sealing));
+                }
+            }
+        }
+
+        Ok(walked)
+    }
+    // where the last component of `path` goes, whether or not anything is there yet
+    fn locate(&self, cred: &Credential, cwd: &Path, path: &Path) -> syscall::Result<Location<'a>> {
+        let mut components = path.components().collect::<Vec<_>>();
+        let name = match components.pop() {
+            Some(Component::Normal(name)) => name.to_str().ok_or(Error::new(ENOENT))?,
+            Some(Component::RootDir) => return Err(Error::new(EBUSY)),
+            Some(Component::CurDir | Component::ParentDir) => return Err(Error::new(EINVAL)),
+            None => return Err(Error::new(ENOENT)),
+        };
+
+        let start = if path.has_root() { None } else { Some(cwd) };
+        let walked = self.walk(
+            cred,
+            start
+                .into_iter()
+                .flat_map(|cwd| cwd.components())
+                .chain(components),
+            true,
+        )?;
+
+        let (dir_props, dir, sealing) = &walked[walked.len() - 1];
+        if let EntryKind::File(_) = dir.kind {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        Ok(Location {
+            parent: walked[1..]
+                .iter()
+                .map(|(props, _, _)| props.name.clone())
+                .collect(),
+            dir: (*dir_props).clone(),
+            name: String::from(name),
+            sealing: sealing.clone(),
+            compress: dir.xattr(COMPRESS_XATTR).map(<[u8]>::to_vec),
+        })
+    }
+    fn entry_at(
+        &self,
+        parent: &[String],
+        name: &str,
+    ) -> Option<(&Properties<'a>, &Arc<Entry<'a>>)> {
+        parent
+            .iter()
+            .try_fold(&self.dir, |dir, name| {
+                dir.child(name).map(|(_, entry)| &**entry)
+            })?
+            .child(name)
+    }
+    // checks that the sticky bit doesn't stop `cred` removing or replacing whatever is called
+    // `name` (as stored) at `location`
+    fn check_delete(
+        &self,
+        cred: &Credential,
+        location: &Location<'a>,
+        name: &str,
+    ) -> syscall::Result<()> {
+        match self.entry_at(&location.parent, name) {
+            Some((props, _)) => perm::may_delete(cred, &location.dir, props),
+            None => Ok(()),
+        }
+    }
+    // stored paths of every name the live tree has for the entry at `entry`
+    fn names_of(&self, entry: *const Entry<'a>) -> Vec<Vec<String>> {
+        let mut names = Vec::new();
+        let mut stack = Vec::from([(Vec::new(), &self.dir)]);
+
+        while let Some((path, dir)) = stack.pop() {
+            let EntryKind::Directory(map) = &dir.kind else {
+                continue;
+            };
+
+            for (props, child) in map.iter() {
+                // the root's own "/" entry isn't a name for anything
+                if path.is_empty() && props.name == "/" {
+                    continue;
+                }
+
+                let mut child_path = path.clone();
+                child_path.push(props.name.clone());
+
+                if ptr::eq(Arc::as_ptr(child), entry) {
+                    names.push(child_path);
+                } else if child.kind.is_dir() {
+                    stack.push((child_path, child));
+                }
+            }
+        }
+
+        names
+    }
+    // Points every name the live tree has for the entry at `old` at `entry` instead. Hard links
+    // share one `Arc`, and copying it on write only updates the name it was reached through.
+    fn relink(&mut self, old: *const Entry<'a>, entry: &Arc<Entry<'a>>) -> syscall::Result<()> {
+        if ptr::eq(old, Arc::as_ptr(entry)) {
+            return Ok(());
+        }
+
+        for path in self.names_of(old) {
+            let (name, parent) = path.split_last().unwrap();
+            let parent = parent.iter().map(String::as_str).collect::<Vec<_>>();
+            self.with_dir_mut(&parent, |dir| dir.replace_child(name, Arc::clone(entry)))?;
+        }
+        Ok(())
+    }
+    // one name fewer for `entry`, which was just unlinked from the live tree
+    fn drop_link(&mut self, entry: Arc<Entry<'a>>) -> syscall::Result<()> {
+        if entry.links <= 1 {
+            return Ok(());
+        }
+
+        let mut remaining = (*entry).clone();
+        remaining.links -= 1;
+        remaining.update_checksum();
+        self.relink(Arc::as_ptr(&entry), &Arc::new(remaining))
+    }
+    /// Removes the file or symbolic link at `path`
+    ///
+    /// A file with other hard links stays in place under those, with one link fewer. As with
+    /// every operation that adds or removes names, `cred` needs write and search permission on
+    /// the directory, and in a sticky one has to own the directory or the entry (`EPERM`).
+    pub fn unlink(&mut self, cred: &Credential, cwd: &Path, path: &Path) -> syscall::Result<()> {
+        let location = self.locate(cred, cwd, path)?;
+        let name = location.stored_name();
+
+        location.check_writable(cred)?;
+        self.check_delete(cred, &location, &name)?;
+        let stored = location.stored_path();
+        let watched = self.watched(&stored);
+
+        let removed = self.with_dir_mut(&location.parent(), |dir| match dir.child(&name) {
+            None => Err(Error::new(ENOENT)),
+            Some((_, entry)) if entry.kind.is_dir() => Err(Error::new(EISDIR)),
+            Some((_, entry)) => {
+                let entry = Arc::clone(entry);
+                dir.take_child(&name)?;
+                Ok(entry)
+            }
+        })?;
+
+        self.drop_link(removed)?;
+        self.notify(EventKind::Delete, &stored, watched);
+        Ok(())
+    }
+    /// Removes the empty directory at `path`
+    pub fn rmdir(&mut self, cred: &Credential, cwd: &Path, path: &Path) -> syscall::Result<()> {
+        let location = self.locate(cred, cwd, path)?;
+        let name = location.stored_name();
+
+        location.check_writable(cred)?;
+        self.check_delete(cred, &location, &name)?;
+        let stored = location.stored_path();
+        let watched = self.watched(&stored);
+
+        self.with_dir_mut(&location.parent(), |dir| match dir.child(&name) {
+            None => Err(Error::new(ENOENT)),
+            Some((_, entry)) => match &entry.kind {
+                EntryKind::Directory(map) if !map.is_empty() => Err(Error::new(ENOTEMPTY)),
+                EntryKind::Directory(_) => dir.take_child(&name).map(drop),
+                _ => Err(Error::new(ENOTDIR)),
+            },
+        })?;
+
+        self.notify(EventKind::Delete, &stored, watched);
+        self.watches.removed(&stored);
+        Ok(())
+    }
+    /// Moves the entry at `from` to `to`, which may be in another directory
+    ///
+    /// As with POSIX `rename`, an existing `to` is replaced as long as it's the same kind of
+    /// entry as `from` (and empty, if it's a directory), and a directory can't be moved into
+    /// itself. Entries can't move between directories encrypted under different keys (`EXDEV`),
+    /// or in or out of a locked one (`ENOKEY`).
+    pub fn rename(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        from: &Path,
+        to: &Path,
+    ) -> syscall::Result<()> {
+        let from = self.locate(cred, cwd, from)?;
+        let to = self.locate(cred, cwd, to)?;
+
+        from.check_writable(cred)?;
+        to.check_writable(cred)?;
+
+        match (&from.sealing, &to.sealing) {
+            (Sealing::Locked, _) | (_, Sealing::Locked) => return Err(Error::new(ENOKEY)),
+            (from, to) if !from.compatible(to) => return Err(Error::new(EXDEV)),
+            _ => {}
+        }
+
+        let (from_name, to_name) = (from.stored_name(), to.stored_name());
+        self.check_delete(cred, &from, &from_name)?;
+        self.check_delete(cred, &to, &to_name)?;
+
+        // held on to so its address can't be reused while its other names are looked up
+        let source = self
+            .entry_at(&from.parent, &from_name)
+            .map(|(_, entry)| Arc::clone(entry))
+            .ok_or(Error::new(ENOENT))?;
+        let moving_dir = source.kind.is_dir();
+
+        if from.parent == to.parent && from_name == to_name {
+            return Ok(());
+        }
+
+        let mut source_path = from.parent.clone();
+        source_path.push(from_name.clone());
+        if moving_dir && to.parent.starts_with(&source_path) {
+            return Err(Error::new(EINVAL));
+        }
+
+        let replaced = self
+            .entry_at(&to.parent, &to_name)
+            .map(|(_, entry)| Arc::clone(entry));
+        if let Some(target) = &replaced {
+            // both names already lead to the same entry, which POSIX leaves alone
+            if Arc::ptr_eq(target, &source) {
+                return Ok(());
+            }
+
+            match (&target.kind, moving_dir) {
+                (EntryKind::Directory(map), true) if !map.is_empty() => {
+                    return Err(Error::new(ENOTEMPTY))
+                }
+                (kind, true) if !kind.is_dir() => return Err(Error::new(ENOTDIR)),
+                (kind, false) if kind.is_dir() => return Err(Error::new(EISDIR)),
+                _ => {}
+            }
+        }
+
+        let mut target_path = to.parent.clone();
+        target_path.push(to_name.clone());
+        // the path it's reported as moving from has to be looked up while it's still there
+        let source_shown = (!self.watches.is_empty()).then(|| self.event_path(&source_path));
+
+        // a failure anywhere below, the quota included, puts everything back, so the tree is
+        // never left with the entry in neither place
+        self.within_quota(|root| {
+            let (props, mut entry) = root
+                .with_dir_mut(&from.parent(), |dir| dir.take_child(&from_name))?
+                .ok_or(Error::new(ENOENT))?;
+
+            root.with_dir_mut(&to.parent(), |dir| {
+                entry.parent = Some(dir.kind.clone());
+                dir.link(props.renamed(to_name.clone()), entry)
+            })?;
+
+            // the move copied the entry, so its other names have to follow
+            if source.links > 1 {
+                let moved = Arc::clone(root.entry_at(&to.parent, &to_name).unwrap().1);
+                root.relink(Arc::as_ptr(&source), &moved)?;
+            }
+            match replaced {
+                Some(replaced) => root.drop_link(replaced),
+                None => Ok(()),
+            }
+        })?;
+
+        if let Some(source_shown) = source_shown {
+            let target_shown = self.event_path(&target_path);
+            self.watches
+                .renamed((&source_path, &source_shown), (&target_path, &target_shown));
+        }
+        Ok(())
+    }
+    /// Links `entry` at `path`, replacing whatever is there
+    ///
+    /// The name comes from `path` rather than `props`, and a symbolic link in its last
+    /// component is replaced rather than followed. In an encrypted directory the name, a file's
+    /// contents and a symbolic link's target are encrypted on the way in; directories are linked
+    /// as they are, so anything already in one has to have been stored for that directory's key
+    /// and nonce.
+    /// New entries in a directory marked for compression are marked the same way, and a marked
+    /// file's contents are compressed on the way in. With deduplication on, a file's blocks are
+    /// then shared with any identical ones already stored.
+    ///
+    /// Root links entries with whatever owner `props` gives them, anyone else only ever as
+    /// their own. A set-group-ID directory gives new entries its group, and subdirectories the
+    /// bit as well.
+    pub fn link(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        props: Properties<'a>,
+        mut entry: Entry<'a>,
+    ) -> syscall::Result<()> {
+        let location = self.locate(cred, cwd, path)?;
+        location.check_writable(cred)?;
+
+        let inherits =
+            !matches!(entry.kind, EntryKind::Symlink(_)) && entry.xattr(COMPRESS_XATTR).is_none();
+        if let Some(compress) = location.compress.clone().filter(|_| inherits) {
+            entry.set_xattr(String::from(COMPRESS_XATTR), compress)?;
+        }
+
+        let compression = Compression::of(&entry)?;
+        // contents are sealed anew, so they get a nonce of their own, as does a directory that
+        // doesn't have one for the names that go in it
+        if let Sealing::Unlocked(_) = &location.sealing {
+            let sealed = match &entry.kind {
+                EntryKind::File(_) => true,
+                EntryKind::Directory(_) => entry.xattr(crypt::NONCE_XATTR).is_none(),
+                _ => false,
+            };
+            if sealed {
+                entry.xattrs.insert(
+                    String::from(crypt::NONCE_XATTR),
+                    crypt::random::<{ crypt::NONCE_LEN }>()?.to_vec(),
+                );
+            }
+        }
+        match (&location.sealing, &mut entry.kind) {
+            (Sealing::Locked, _) => return Err(Error::new(ENOKEY)),
+            (Sealing::Plain, EntryKind::File(_)) if compression == Compression::Off => {}
+            (sealing, EntryKind::File(file)) => {
+                let codec = sealing.file_codec(&entry.xattrs)?;
+                *file = file.recode(&Plain, &compression.codec(&codec))?;
+                codec.retag(&mut entry);
+                entry.update_checksum();
+            }
+            (sealing, EntryKind::Symlink(target)) => {
+                *target = sealing.stored_target(target);
+                entry.update_checksum();
+            }
+            _ => {}
+        }
+        if let (Some(table), EntryKind::File(file)) = (&mut self.dedup, &mut entry.kind) {
+            table.share(file, ..);
+        }
+
+        let name = location.stored_name();
+        self.check_delete(cred, &location, &name)?;
+        let replaced = self
+            .entry_at(&location.parent, &name)
+            .map(|(_, entry)| Arc::clone(entry));
+
+        let mut props = props.renamed(name);
+        if !cred.is_root() {
+            props.owner = cred.name();
+            props.created_by = cred.name();
+            props.uid = cred.uid;
+            props.gid = cred.gid;
+        }
+        if location.dir.mode & S_ISGID != 0 {
+            props.gid = location.dir.gid;
+            if entry.kind.is_dir() {
+                props.mode |= S_ISGID;
+            }
+        }
+        // nor can anyone but root make a file set-group-ID for a group they aren't in
+        if !cred.is_root() && props.gid != cred.gid && !entry.kind.is_dir() {
+            props.mode &= !S_ISGID;
+        }
+
+        self.within_quota(|root| {
+            root.with_dir_mut(&location.parent(), |dir| dir.link(props, entry))?;
+
+            match replaced {
+                Some(replaced) => root.drop_link(replaced),
+                None => Ok(()),
+            }
+        })?;
+
+        let stored = location.stored_path();
+        self.notify(EventKind::Create, &stored, self.watched(&stored));
+        Ok(())
+    }
+    /// Gives the file or symbolic link at `from` another name at `to`, with the same properties
+    ///
+    /// As with POSIX `link`, a symbolic link at `from` is linked rather than followed, nothing
+    /// may be at `to` yet (`EEXIST`) and directories can't be linked (`EPERM`). Both names have to
+    /// be in directories encrypted the same way (`EXDEV`). The names share one entry, so a change
+    /// through either shows through both, at the cost of a walk over the tree to find the others.
+    pub fn hard_link(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        from: &Path,
+        to: &Path,
+    ) -> syscall::Result<()> {
+        let from = self.locate(cred, cwd, from)?;
+        let to = self.locate(cred, cwd, to)?;
+        to.check_writable(cred)?;
+
+        match (&from.sealing, &to.sealing) {
+            (Sealing::Locked, _) | (_, Sealing::Locked) => return Err(Error::new(ENOKEY)),
+            (from, to) if !from.compatible(to) => return Err(Error::new(EXDEV)),
+            _ => {}
+        }
+
+        let (props, source) = self
+            .entry_at(&from.parent, &from.stored_name())
+            .map(|(props, entry)| (props.clone(), Arc::clone(entry)))
+            .ok_or(Error::new(ENOENT))?;
+
+        if source.kind.is_dir() {
+            return Err(Error::new(EPERM));
+        }
+
+        let to_name = to.stored_name();
+        if self.entry_at(&to.parent, &to_name).is_some() {
+            return Err(Error::new(EEXIST));
+        }
+
+        let mut linked = (*source).clone();
+        linked.links = linked.links.checked_add(1).ok_or(Error::new(EMLINK))?;
+        linked.update_checksum();
+        let linked = Arc::new(linked);
+
+        self.within_quota(|root| {
+            root.relink(Arc::as_ptr(&source), &linked)?;
+            root.with_dir_mut(&to.parent(), |dir| {
+                dir.link_shared(props.renamed(to_name), linked)
+            })
+        })?;
+
+        let stored = to.stored_path();
+        self.notify(EventKind::Create, &stored, self.watched(&stored));
+        Ok(())
+    }
+    /// Target of the symbolic link at `path`, decrypted if it's in an encrypted directory
+    pub fn read_link(&self, cred: &Credential, cwd: &Path, path: &Path) -> syscall::Result<String> {
+        let walked = self.walk(cred, components(cwd, path), false)?;
+        let EntryKind::Symlink(target) = &walked[walked.len() - 1].1.kind else {
+            return Err(Error::new(EINVAL));
+        };
+
+        // the root is a directory, so a link always has a parent in the walk
+        walked[walked.len() - 2].2.shown_target(target)
+    }
+    // the file at `path`, and how its blocks are stored, if `cred` has `wanted` on it
+    fn file_at(
+        &self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        wanted: u32,
+    ) -> syscall::Result<(&FileBlocks, FileCodec, Compression)> {
+        let walked = self.walk(cred, components(cwd, path), true)?;
+        let (props, entry, _) = &walked[walked.len() - 1];
+        let EntryKind::File(file) = &entry.kind else {
+            return Err(Error::new(EISDIR));
+        };
+        perm::access(cred, props, false, wanted)?;
+        let compression = Compression::of(entry)?;
+
+        // the root is a directory, so a file always has a parent in the walk
+        let codec = walked[walked.len() - 2].2.file_codec(&entry.xattrs)?;
+        codec.check(entry)?;
+        Ok((file, codec, compression))
+    }
+    /// Contents of the file at `path`, decrypted if it's in an encrypted directory and
+    /// decompressed if it's compressed
+    pub fn read_file(
+        &self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+    ) -> syscall::Result<Vec<u8>> {
+        let (file, codec, compression) = self.file_at(cred, cwd, path, R_OK)?;
+        file.to_vec(&compression.codec(&codec))
+    }
+    /// Reads from the file at `path` starting at `offset`, returning how many bytes there were
+    pub fn read_at(
+        &self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> syscall::Result<usize> {
+        let (file, codec, compression) = self.file_at(cred, cwd, path, R_OK)?;
+        file.read_at(offset, buf, &compression.codec(&codec))
+    }
+    // runs `f` on the file at `path` with the codec its blocks are stored with, within the
+    // quotas of its owner and the subtrees it's in; with deduplication on, the blocks in
+    // `touched` are shared afterwards
+    fn with_file_mut<R>(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        touched: impl RangeBounds<u64>,
+        f: impl FnOnce(&mut FileBlocks, &dyn BlockCodec) -> syscall::Result<R>,
+    ) -> syscall::Result<R> {
+        let (_, codec, compression) = self.file_at(cred, cwd, path, W_OK)?;
+        let mut dedup = self.dedup.take();
+
+        let out = self.within_quota(|root| {
+            root.entry_mut(cred, cwd, path, |entry| {
+                let EntryKind::File(file) = &mut entry.kind else {
+                    return Err(Error::new(EISDIR));
+                };
+
+                let out = f(file, &compression.codec(&codec))?;
+                if let Some(table) = &mut dedup {
+                    table.share(file, touched);
+                }
+                codec.retag(entry);
+                entry.update_checksum();
+                Ok(out)
+            })
+        });
+
+        self.dedup = dedup;
+        let out = out?;
+        self.notify_path(cred, cwd, path, EventKind::Modify);
+        Ok(out)
+    }
+    /// Writes `buf` into the file at `path` at `offset`, which may be past its end
+    ///
+    /// Only the blocks the write touches are copied and rehashed; the rest stay shared with
+    /// snapshots.
+    pub fn write_at(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        offset: u64,
+        buf: &[u8],
+    ) -> syscall::Result<usize> {
+        // writing nothing changes nothing, not even the length, but is checked all the same
+        if buf.is_empty() {
+            if self.read_only {
+                return Err(Error::new(EROFS));
+            }
+            return self.file_at(cred, cwd, path, W_OK).map(|_| 0);
+        }
+
+        let touched =
+            offset / BLOCK_SIZE..offset.saturating_add(buf.len() as u64).div_ceil(BLOCK_SIZE);
+        self.with_file_mut(cred, cwd, path, touched, |file, codec| {
+            file.write_at(offset, buf, codec)
+        })
+    }
+    /// Cuts the file at `path` down to `len` bytes, or extends it with a hole
+    pub fn truncate(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        len: u64,
+    ) -> syscall::Result<()> {
+        // only the block the file now ends in can have been rewritten
+        let end = len / BLOCK_SIZE;
+        self.with_file_mut(cred, cwd, path, end..=end, |file, codec| {
+            file.truncate(len, codec)
+        })
+    }
+    /// Lists the directory at `path` in name order
+    ///
+    /// Names are decrypted if the directory is encrypted and unlocked, and listed as stored if
+    /// it's locked. Entries are as stored; `read_file` gives a file's decrypted contents.
+    pub fn read_dir(
+        &self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+    ) -> syscall::Result<Vec<(Properties<'a>, Arc<Entry<'a>>)>> {
+        let walked = self.walk(cred, components(cwd, path), true)?;
+        let (props, dir, sealing) = &walked[walked.len() - 1];
+
+        let EntryKind::Directory(map) = &dir.kind else {
+            return Err(Error::new(ENOTDIR));
+        };
+        perm::access(cred, props, true, R_OK)?;
+
+        let mut listing = map
+            .iter()
+            .map(|(props, entry)| {
+                let shown = sealing.shown_name(&props.name)?;
+                Ok((props.clone().renamed(shown), Arc::clone(entry)))
+            })
+            .collect::<syscall::Result<Vec<_>>>()?;
+
+        listing.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        Ok(listing)
+    }
+    /// Encrypts the empty directory at `path` under a key derived from `passphrase`, and leaves
+    /// it unlocked
+    ///
+    /// Everything stored beneath it afterwards has its name and contents encrypted; metadata and
+    /// extended attributes stay readable, so `fsck` can still check the tree without the key.
+    /// Only the directory's owner or root may encrypt it (`EPERM`).
+    pub fn encrypt(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        passphrase: &[u8],
+    ) -> syscall::Result<()> {
+        let walked = self.walk(cred, components(cwd, path), true)?;
+        let (props, dir, _) = &walked[walked.len() - 1];
+
+        // the root also holds its own "/" entry, which has to stay findable by name
+        if walked.len() == 1 {
+            return Err(Error::new(EINVAL));
+        }
+
+        match &dir.kind {
+            EntryKind::Directory(map) if map.is_empty() => {}
+            EntryKind::Directory(_) => return Err(Error::new(ENOTEMPTY)),
+            _ => return Err(Error::new(ENOTDIR)),
+        }
+
+        if dir.xattr(crypt::CRYPT_XATTR).is_some() {
+            return Err(Error::new(EEXIST));
+        }
+        if !cred.owns(props) {
+            return Err(Error::new(EPERM));
+        }
+
+        let salt = crypt::random()?;
+        let (params, key) = crypt::CryptParams::new(passphrase, salt, crypt::DEFAULT_COST)?;
+        let nonce = crypt::random::<{ crypt::NONCE_LEN }>()?;
+
+        self.with_entry_mut(cred, cwd, path, |dir| {
+            dir.set_xattr(String::from(crypt::NONCE_XATTR), nonce.to_vec())?;
+            dir.set_xattr(String::from(crypt::CRYPT_XATTR), params.to_bytes())
+        })?;
+        self.keys.insert(salt, key);
+        Ok(())
+    }
+    // the key parameters of the encrypted directory at `path`
+    fn crypt_params(
+        &self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+    ) -> syscall::Result<crypt::CryptParams> {
+        let walked = self.walk(cred, components(cwd, path), true)?;
+        let params = walked[walked.len() - 1]
+            .1
+            .xattr(crypt::CRYPT_XATTR)
+            .ok_or(Error::new(EINVAL))?;
+
+        crypt::CryptParams::from_bytes(params)
+    }
+    /// Loads the key for the encrypted directory at `path`, failing with `EKEYREJECTED` if
+    /// `passphrase` is wrong
+    pub fn unlock(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        passphrase: &[u8],
+    ) -> syscall::Result<()> {
+        let params = self.crypt_params(cred, cwd, path)?;
+        let key = params.unlock(passphrase)?;
+
+        self.keys.insert(params.salt, key);
+        Ok(())
+    }
+    /// Drops the key for the encrypted directory at `path`
+    pub fn lock(&mut self, cred: &Credential, cwd: &Path, path: &Path) -> syscall::Result<()> {
+        let params = self.crypt_params(cred, cwd, path)?;
+
+        self.keys
+            .remove(&params.salt)
+            .map(drop)
+            .ok_or(Error::new(ENOKEY))
+    }
+    /// Runs `f` on the entry `path` names, which unlike `with_dir_mut` may also be a file
+    ///
+    /// The change shows through every hard link to the entry, and is reported to watches
+    /// under `path`. Only search permission is checked; anything else `f` needs is up to the
+    /// caller.
+    pub fn with_entry_mut<R>(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        f: impl FnOnce(&mut Entry<'a>) -> syscall::Result<R>,
+    ) -> syscall::Result<R> {
+        let out = self.entry_mut(cred, cwd, path, f)?;
+        self.notify_path(cred, cwd, path, EventKind::Modify);
+        Ok(out)
+    }
+    // `with_entry_mut` without reporting the change, for changes that may yet be undone
+    fn entry_mut<R>(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        f: impl FnOnce(&mut Entry<'a>) -> syscall::Result<R>,
+    ) -> syscall::Result<R> {
+        let walked = self.walk(cred, components(cwd, path), true)?;
+        let mut names = walked
+            .iter()
+            .skip(1)
+            .map(|(props, _, _)| props.name.clone())
+            .collect::<Vec<_>>();
+
+        let linked = walked[walked.len() - 1].1.links > 1;
+
+        let Some(name) = names.pop() else {
+            return self.with_dir_mut(&[], f);
+        };
+
+        // held on to so its address can't be reused while its other names are looked up
+        let shared = linked.then(|| Arc::clone(self.entry_at(&names, &name).unwrap().1));
+
+        let parent = names.iter().map(String::as_str).collect::<Vec<_>>();
+        let out = self.with_dir_mut(&parent, |dir| dir.with_child_mut(&name, f));
+
+        // copying on write left the other names on the old entry, even if `f` then failed
+        if let Some(old) = shared {
+            let (_, changed) = self.entry_at(&names, &name).unwrap();
+            let changed = Arc::clone(changed);
+            self.relink(Arc::as_ptr(&old), &changed)?;
+        }
+        out
+    }
+    /// Sets an extended attribute of the entry at `path`, which `cred` needs write permission on
+    pub fn set_xattr(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        key: String,
+        value: Vec<u8>,
+    ) -> syscall::Result<()> {
+        if reserved_xattr(&key) {
+            return Err(Error::new(EPERM));
+        }
+
+        self.access(cred, cwd, path, W_OK)?;
+        self.with_entry_mut(cred, cwd, path, |entry| entry.set_xattr(key, value))
+    }
+    pub fn remove_xattr(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        key: &str,
+    ) -> syscall::Result<Vec<u8>> {
+        if reserved_xattr(key) {
+            return Err(Error::new(EPERM));
+        }
+
+        self.access(cred, cwd, path, W_OK)?;
+        self.with_entry_mut(cred, cwd, path, |entry| entry.remove_xattr(key))
+    }
+    /// Marks the file or directory at `path`, which `cred` needs write permission on, to have
+    /// its contents stored with `compression`
+    ///
+    /// A file's blocks are recompressed straight away, so it reads back the same either way. A
+    /// directory passes the mark on to entries created in it from then on, and leaves what's
+    /// already in it as it is.
+    pub fn set_compression(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        compression: Compression,
+    ) -> syscall::Result<()> {
+        self.access(cred, cwd, path, W_OK)?;
+
+        let (mut recoded, codec) = match self.file_at(cred, cwd, path, W_OK) {
+            Ok((file, codec, old)) => {
+                let recoded = file.recode(&old.codec(&codec), &compression.codec(&codec))?;
+                (Some(recoded), Some(codec))
+            }
+            Err(e) if e.errno == EISDIR => (None, None),
+            Err(e) => return Err(e),
+        };
+        if let (Some(table), Some(file)) = (&mut self.dedup, &mut recoded) {
+            table.share(file, ..);
+        }
+
+        self.with_entry_mut(cred, cwd, path, |entry| {
+            if let (Some(recoded), EntryKind::File(file)) = (recoded, &mut entry.kind) {
+                *file = recoded;
+            }
+            if let Some(codec) = &codec {
+                codec.retag(entry);
+            }
+            match compression.value() {
+                Some(value) => entry
+                    .xattrs
+                    .insert(String::from(COMPRESS_XATTR), value.to_vec()),
+                None => entry.xattrs.remove(COMPRESS_XATTR),
+            };
+
+            entry.update_checksum();
+            Ok(())
+        })
+    }
+    /// Checks that `cred` has every permission in `wanted` (see `perm::access`) on the entry at
+    /// `path`, failing with `EACCES`
+    pub fn access(
+        &self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        wanted: u32,
+    ) -> syscall::Result<()> {
+        let walked = self.walk(cred, components(cwd, path), true)?;
+        let (props, entry, _) = &walked[walked.len() - 1];
+        perm::access(cred, props, entry.kind.is_dir(), wanted)
+    }
+    // Runs `f` on the properties of the entry at `path`, and stores what it leaves under every
+    // name the entry has. Hard links each keep their own properties, which are kept equal.
+    fn with_props_mut<R>(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        f: impl FnOnce(&mut Properties<'a>, &EntryKind<'a>) -> syscall::Result<R>,
+    ) -> syscall::Result<R> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+
+        let walked = self.walk(cred, components(cwd, path), true)?;
+        let (props, entry, _) = &walked[walked.len() - 1];
+
+        let mut changed = (*props).clone();
+        let out = f(&mut changed, &entry.kind)?;
+
+        // the root keeps its own properties under "/"
+        let names = match walked.len() {
+            1 => Vec::from([Vec::from([String::from("/")])]),
+            _ if entry.links > 1 => self.names_of(ptr::from_ref(*entry)),
+            _ => Vec::from([walked[1..]
+                .iter()
+                .map(|(props, _, _)| props.name.clone())
+                .collect()]),
+        };
+
+        for path in names {
+            let (name, parent) = path.split_last().unwrap();
+            let parent = parent.iter().map(String::as_str).collect::<Vec<_>>();
+            self.with_dir_mut(&parent, |dir| dir.set_child_props(name, &changed))?;
+        }
+        Ok(out)
+    }
+    /// Sets the permission bits of the entry at `path` (those in `perm::MODE_MASK`)
+    ///
+    /// Only the owner or root may (`EPERM`), and anyone but root setting the set-group-ID bit on
+    /// an entry outside their group has it quietly left off, as POSIX allows.
+    pub fn chmod(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        mode: u32,
+    ) -> syscall::Result<()> {
+        self.with_props_mut(cred, cwd, path, |props, _| {
+            if !cred.owns(props) {
+                return Err(Error::new(EPERM));
+            }
+
+            props.mode = mode & MODE_MASK;
+            if !cred.is_root() && cred.gid != props.gid {
+                props.mode &= !S_ISGID;
+            }
+            Ok(())
+        })?;
+
+        self.notify_path(cred, cwd, path, EventKind::Modify);
+        Ok(())
+    }
+    /// Gives the entry at `path` to another user and/or group; `None` leaves either as it is
+    ///
+    /// Only root may change the owning user, and an owner may only move the entry into their
+    /// own group (`EPERM`). When anyone but root does so, a file loses its set-user-ID and
+    /// set-group-ID bits. A new owner is recorded by uid, as `Credential::name` gives it, and
+    /// has to have room for the entry within their quota (`EDQUOT`).
+    pub fn chown(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> syscall::Result<()> {
+        self.within_quota(|root| {
+            root.with_props_mut(cred, cwd, path, |props, kind| {
+                let uid = uid.unwrap_or(props.uid);
+                let gid = gid.unwrap_or(props.gid);
+
+                if !cred.is_root() {
+                    if !cred.owns(props)
+                        || uid != props.uid
+                        || (gid != props.gid && gid != cred.gid)
+                    {
+                        return Err(Error::new(EPERM));
+                    }
+                    if !kind.is_dir() {
+                        props.mode &= !(S_ISUID | S_ISGID);
+                    }
+                }
+
+                if uid != props.uid {
+                    props.owner = Credential::new(uid, gid).name();
+                }
+                props.uid = uid;
+                props.gid = gid;
+                Ok(())
+            })
+        })?;
+
+        self.notify_path(cred, cwd, path, EventKind::Modify);
+        Ok(())
+    }
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+    /// Renames the volume; only root may, as with everything else that affects the whole volume
+    pub fn set_label(&mut self, cred: &Credential, label: String) -> syscall::Result<()> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+        if !cred.is_root() {
+            return Err(Error::new(EPERM));
+        }
+        if label.len() > format::LABEL_LEN {
+            return Err(Error::new(ENAMETOOLONG));
+        }
+
+        self.label = label;
+        Ok(())
+    }
+    /// Keeps the tree as it is now under `name`
+    pub fn snapshot(
+        &mut self,
+        cred: &Credential,
+        name: String,
+        timestamp: time_t,
+    ) -> syscall::Result<()> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+        if !cred.is_root() {
+            return Err(Error::new(EPERM));
+        }
+        if name.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+        if self.snapshots.iter().any(|snapshot| snapshot.name == name) {
+            return Err(Error::new(EEXIST));
+        }
+
+        self.snapshots.push(Snapshot {
+            name,
+            created: timestamp,
+            dir: self.dir.clone(),
+        });
+        Ok(())
+    }
+    pub fn snapshots(&self) -> &[Snapshot<'a>] {
+        &self.snapshots
+    }
+    fn find_snapshot(&self, name: &str) -> syscall::Result<&Snapshot<'a>> {
+        self.snapshots
+            .iter()
+            .find(|snapshot| snapshot.name == name)
+            .ok_or(Error::new(ENOENT))
+    }
+    /// Opens a snapshot as a volume of its own, which refuses every change with `EROFS`
+    pub fn mount_snapshot(&self, name: &str) -> syscall::Result<Self> {
+        let snapshot = self.find_snapshot(name)?;
+
+        Ok(Self {
+            magic: self.magic,
+            system_clock: snapshot.created,
+            entry_count: self.entry_count,
+            checksum: snapshot.dir.checksum,
+            label: self.label.clone(),
+            dir: snapshot.dir.clone(),
+            snapshots: Vec::new(),
+            read_only: true,
+            keys: self.keys.clone(),
+            index: Arc::new(index::Index::build(&snapshot.dir)),
+            dedup: None,
+            usage: Arc::new(quota::Accounting::build(&snapshot.dir)),
+            watches: Watches::default(),
+        })
+    }
+    pub fn delete_snapshot(&mut self, cred: &Credential, name: &str) -> syscall::Result<()> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+        if !cred.is_root() {
+            return Err(Error::new(EPERM));
+        }
+
+        let before = self.snapshots.len();
+        self.snapshots.retain(|snapshot| snapshot.name != name);
+
+        if self.snapshots.len() == before {
+            return Err(Error::new(ENOENT));
+        }
+        Ok(())
+    }
+    /// Puts the tree back the way it was when `name` was taken, keeping the snapshot itself
+    pub fn rollback(&mut self, cred: &Credential, name: &str) -> syscall::Result<()> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+        if !cred.is_root() {
+            return Err(Error::new(EPERM));
+        }
+
+        let restored = self.find_snapshot(name)?.dir.clone();
+        let before = core::mem::replace(&mut self.dir, restored);
+        Arc::make_mut(&mut self.index).update(&before, &self.dir);
+        Arc::make_mut(&mut self.usage).update(&before, &self.dir);
+        self.checksum = self.dir.checksum;
+        // the snapshot may not have had deduplication on, or off
+        self.sync_dedup();
+
+        let dir = &self.dir;
+        self.watches.overflow(|stored| {
+            stored
+                .iter()
+                .try_fold(dir, |dir, name| dir.child(name).map(|(_, entry)| &**entry))
+                .is_some_and(|entry| entry.kind.is_dir())
+        });
+        Ok(())
+    }
+    /// The stream that turns snapshot `from` into snapshot `to`, or that holds the whole of `to`
+    /// if there's no `from`, along with what went into it; for root only
+    pub fn send(
+        &self,
+        cred: &Credential,
+        from: Option<&str>,
+        to: &str,
+    ) -> syscall::Result<(Vec<u8>, StreamStats)> {
+        if !cred.is_root() {
+            return Err(Error::new(EPERM));
+        }
+
+        let base = from.map(|name| self.find_snapshot(name)).transpose()?;
+        let target = self.find_snapshot(to)?;
+        let header = StreamHeader {
+            base: base.map(Snapshot::checksum),
+            name: &target.name,
+            created: target.created,
+            checksum: target.checksum(),
+        };
+
+        Ok(send::send(
+            &header,
+            base.map(|snapshot| &snapshot.dir),
+            &target.dir,
+        ))
+    }
+    /// Takes the snapshot a stream from `send` makes, starting from the snapshot it was made
+    /// against; for root only
+    ///
+    /// Fails with `ENOENT` if no snapshot has the checksum of the stream's base, `EEXIST` if one
+    /// already has the name of the snapshot it makes, and `EIO` if the stream is damaged or the
+    /// tree doesn't come out to the checksum it was sent with. Nothing changes unless it works.
+    pub fn receive(&mut self, cred: &Credential, stream: &'a [u8]) -> syscall::Result<StreamStats> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+        if !cred.is_root() {
+            return Err(Error::new(EPERM));
+        }
+
+        let mut reader = format::Reader::new(stream);
+        let header = StreamHeader::read(&mut reader)?;
+        if header.name.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+        if self.find_snapshot(header.name).is_ok() {
+            return Err(Error::new(EEXIST));
+        }
+
+        let base = match header.base {
+            Some(checksum) => Some(
+                &self
+                    .snapshots
+                    .iter()
+                    .find(|snapshot| snapshot.checksum() == checksum)
+                    .ok_or(Error::new(ENOENT))?
+                    .dir,
+            ),
+            None => None,
+        };
+        let (dir, stats) = send::receive(&mut reader, base)?;
+        if dir.checksum != header.checksum {
+            return Err(Error::new(EIO));
+        }
+
+        self.snapshots.push(Snapshot {
+            name: String::from(header.name),
+            created: header.created,
+            dir,
+        });
+        Ok(stats)
+    }
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    /// Turns deduplication of blocks as files are written on or off, for root only
+    ///
+    /// Turning it on looks through every block in the volume so new ones can be shared with
+    /// them, but leaves what's already stored as it is; `dedup` does that.
+    pub fn set_dedup(&mut self, cred: &Credential, on: bool) -> syscall::Result<()> {
+        if !cred.is_root() {
+            return Err(Error::new(EPERM));
+        }
+
+        self.with_dir_mut(&[], |root| {
+            match on {
+                true => root.xattrs.insert(String::from(DEDUP_XATTR), Vec::new()),
+                false => root.xattrs.remove(DEDUP_XATTR),
+            };
+            Ok(())
+        })?;
+        self.sync_dedup();
+        Ok(())
+    }
+    /// Whether blocks are deduplicated as files are written
+    pub fn dedups(&self) -> bool {
+        self.dedup.is_some()
+    }
+    // builds the table for deduplicating as files are written if the root directory carries
+    // `DEDUP_XATTR`, or drops it if not
+    fn sync_dedup(&mut self) {
+        let on = !self.read_only && self.dir.xattr(DEDUP_XATTR).is_some();
+        self.dedup = match self.dedup.take() {
+            Some(table) if on => Some(table),
+            None if on => Some(DedupTable::build(self.trees())),
+            _ => None,
+        };
+    }
+    // the root directory of the live tree and of each snapshot
+    fn trees(&self) -> impl Iterator<Item = &Entry<'a>> {
+        core::iter::once(&self.dir).chain(self.snapshots.iter().map(|snapshot| &snapshot.dir))
+    }
+    /// Makes every file in the live tree and in each snapshot share blocks with identical
+    /// contents, so each is stored once; for root only
+    ///
+    /// Contents and checksums stay as they are, so the snapshots are no different afterwards,
+    /// and whatever the trees already shared stays shared.
+    pub fn dedup(&mut self, cred: &Credential) -> syscall::Result<DedupStats> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+        if !cred.is_root() {
+            return Err(Error::new(EPERM));
+        }
+
+        // every tree is gone through before any is replaced, so none of the old ones are freed
+        // while the pass still remembers them by address
+        let mut pass = Dedup::new(DedupTable::default());
+        let changed = self.trees().map(|dir| pass.entry(dir)).collect::<Vec<_>>();
+        let (table, stats) = pass.finish();
+
+        let mut changed = changed.into_iter();
+        if let Some(dir) = changed.next().flatten() {
+            // hard links are now other `Arc`s, which usage is kept by
+            let before = core::mem::replace(&mut self.dir, dir);
+            Arc::make_mut(&mut self.usage).update(&before, &self.dir);
+        }
+        for (snapshot, dir) in self.snapshots.iter_mut().zip(changed) {
+            if let Some(dir) = dir {
+                snapshot.dir = dir;
+            }
+        }
+
+        if self.dedup.is_some() {
+            self.dedup = Some(table);
+        }
+        Ok(stats)
+    }
+    /// Limits what `uid` can store in the live tree, for root only; `Limits::default()` lifts
+    /// them
+    ///
+    /// Limits hold from the next change on. An owner already over one can still shrink or
+    /// remove what they have, but anything that would leave them with more of it fails with
+    /// `EDQUOT`, root included.
+    pub fn set_owner_quota(
+        &mut self,
+        cred: &Credential,
+        uid: u32,
+        limits: Limits,
+    ) -> syscall::Result<()> {
+        if !cred.is_root() {
+            return Err(Error::new(EPERM));
+        }
+
+        let key = quota::owner_key(uid);
+        self.with_dir_mut(&[], |root| {
+            match limits.value() {
+                Some(value) => root.xattrs.insert(key, value),
+                None => root.xattrs.remove(&key),
+            };
+            Ok(())
+        })
+    }
+    /// Limits what can be stored below the directory at `path`, by anyone, as `set_owner_quota`
+    /// does for an owner; for root only
+    ///
+    /// The limits go with the directory if it's renamed, and a directory moved in whole isn't
+    /// held to its own limits until something more is stored in it.
+    pub fn set_quota(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+        limits: Limits,
+    ) -> syscall::Result<()> {
+        if !cred.is_root() {
+            return Err(Error::new(EPERM));
+        }
+        if !self.resolve(cred, cwd, path)?.1.kind.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        self.with_entry_mut(cred, cwd, path, |dir| {
+            match limits.value() {
+                Some(value) => dir.xattrs.insert(String::from(QUOTA_XATTR), value),
+                None => dir.xattrs.remove(QUOTA_XATTR),
+            };
+            dir.update_checksum();
+            Ok(())
+        })
+    }
+    /// Every owner with anything stored or any limits, by uid, then every directory with limits
+    /// that `cred` could find by listing the directories on the way, by path
+    pub fn quotas(&self, cred: &Credential) -> Vec<Quota> {
+        let mut uids = self
+            .usage
+            .accounts()
+            .filter_map(|(account, _)| match account {
+                Account::Owner(uid) => Some(*uid),
+                Account::Subtree(_) => None,
+            })
+            .collect::<BTreeSet<_>>();
+        uids.extend(self.dir.xattrs().filter_map(|(key, _)| {
+            key.strip_prefix(quota::OWNER_QUOTA_PREFIX)?
+                .parse::<u32>()
+                .ok()
+        }));
+
+        let owners = uids
+            .into_iter()
+            .map(|uid| (Holder::Owner(uid), Account::Owner(uid)));
+
+        // the root directory isn't indexed, not being a name for anything
+        let root = self.dir.xattr(QUOTA_XATTR).map(|_| {
+            let path = index::IndexPath::from([]);
+            (Holder::Directory(String::from("/")), Account::Subtree(path))
+        });
+        let limited = index::Query {
+            xattrs: Vec::from([(String::from(QUOTA_XATTR), None)]),
+            ..Default::default()
+        };
+        let dirs = self.index.query(&limited).into_iter().filter_map(|stored| {
+            let shown = self.shown_path(cred, &stored).ok()?;
+            Some((Holder::Directory(shown), Account::Subtree(stored)))
+        });
+
+        owners
+            .chain(root)
+            .chain(dirs)
+            .filter_map(|(holder, account)| {
+                Some(Quota {
+                    holder,
+                    limits: quota::limits(&self.dir, &account)?,
+                    usage: self.usage.usage(&account),
+                })
+            })
+            .collect()
+    }
+    /// Paths of the entries in the live tree that match `query`, in order, found through the
+    /// attribute indexes rather than by walking the tree
+    ///
+    /// Paths are shown as `read_dir` would show their names, and only entries `cred` could find
+    /// by listing the directories on the way are included.
+    pub fn query(&self, cred: &Credential, query: &index::Query) -> Vec<String> {
+        self.index
+            .query(query)
+            .iter()
+            .filter_map(|stored| self.shown_path(cred, stored).ok())
+            .collect()
+    }
+    /// Starts watching the directory at `path`, which `cred` needs read permission on, for
+    /// changes to anything below it; see the `watch` module
+    ///
+    /// Events are queued until `take_events` takes them, and stop once `unwatch` ends the
+    /// watch. Changes to a hard-linked file through a name outside the watched directory aren't
+    /// seen.
+    pub fn watch(
+        &mut self,
+        cred: &Credential,
+        cwd: &Path,
+        path: &Path,
+    ) -> syscall::Result<WatchId> {
+        let walked = self.walk(cred, components(cwd, path), true)?;
+        let (props, entry, _) = &walked[walked.len() - 1];
+        if !entry.kind.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+        perm::access(cred, props, true, R_OK)?;
+
+        let stored = walked[1..]
+            .iter()
+            .map(|(props, _, _)| props.name.clone())
+            .collect::<Vec<_>>();
+        let shown = self.event_path(&stored);
+        Ok(self.watches.add(stored, shown))
+    }
+    /// Ends a watch, failing with `EBADF` if there's no such watch
+    pub fn unwatch(&mut self, id: WatchId) -> syscall::Result<()> {
+        self.watches.remove(id)
+    }
+    /// Takes the oldest events queued on a watch, as many as fit in `max` bytes written out
+    pub fn take_events(&mut self, id: WatchId, max: usize) -> syscall::Result<Vec<Event>> {
+        self.watches.take(id, max)
+    }
+    /// How many events are queued on a watch
+    pub fn pending_events(&self, id: WatchId) -> syscall::Result<usize> {
+        self.watches.pending(id)
+    }
+    // the path a change to the entry at the stored names `stored` is reported under, if
+    // anything is watching it
+    fn watched(&self, stored: &[String]) -> Option<String> {
+        self.watches.covers(stored).then(|| self.event_path(stored))
+    }
+    // Events give paths as root would see them, so watchers all agree on what's where. A
+    // locked directory's names are given as stored.
+    fn event_path(&self, stored: &[String]) -> String {
+        match stored {
+            [] => String::from("/"),
+            _ => self
+                .shown_path(&Credential::ROOT, stored)
+                .unwrap_or_default(),
+        }
+    }
+    // reports `kind` at the stored names `stored`, if `watched` found anything watching them
+    fn notify(&mut self, kind: EventKind, stored: &[String], watched: Option<String>) {
+        if let Some(path) = watched {
+            self.watches.notify(kind, stored, &path);
+        }
+    }
+    // reports `kind` at the entry `path` names, which a change has just been made to
+    fn notify_path(&mut self, cred: &Credential, cwd: &Path, path: &Path, kind: EventKind) {
+        if self.watches.is_empty() {
+            return;
+        }
+
+        let Ok(walked) = self.walk(cred, components(cwd, path), true) else {
+            return;
+        };
+        let stored = walked[1..]
+            .iter()
+            .map(|(props, _, _)| props.name.clone())
+            .collect::<Vec<_>>();
+        self.notify(kind, &stored, self.watched(&stored));
+    }
+    // The path to the entry at the stored names `stored` as `cred` would see it, checking the
+    // search permission that walking there takes and read permission on the directory it's in
+    fn shown_path(&self, cred: &Credential, stored: &[String]) -> syscall::Result<String> {
+        let (mut props, _) = self.dir.child("/").ok_or(Error::new(ENOENT))?;
+        let mut dir = &self.dir;
+        let mut sealing = self.sealing(dir, &Sealing::Plain);
+        let mut shown = String::new();
+
+        for (i, name) in stored.iter().enumerate() {
+            let wanted = if i + 1 == stored.len() {
+                R_OK | X_OK
+            } else {
+                X_OK
+            };
+            perm::access(cred, props, true, wanted)?;
+
+            let (child_props, child) = dir.child(name).ok_or(Error::new(ENOENT))?;
+            shown.push('/');
+            shown.push_str(&sealing.shown_name(name)?);
+
+            sealing = self.sealing(child, &sealing);
+            (props, dir) = (child_props, child);
+        }
+
+        Ok(shown)
+    }
+}
@@ -0,0 +1,105 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use syscall::{Error, Result, EIO};
+
+use super::format::Device;
+
+// A device that's just memory, for RAM disks and for exercising the on-disk format on the host.
+// It can be told to stop taking writes partway through a commit, which is what a crash or a
+// pulled cable looks like from the volume's side, so the atomicity `format::commit` promises can
+// be checked at every point it could be cut off at.
+
+/// A `Device` over a buffer in memory
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MemDevice {
+    bytes: Vec<u8>,
+    /// Writes still to go through before every write fails, if the device is set to fail
+    writes_left: Option<usize>,
+    /// Whether the write that fails lands its first half anyway, as a torn sector would
+    tear: bool,
+    /// Writes and flushes that went through
+    writes: usize,
+    flushes: usize,
+}
+
+impl MemDevice {
+    /// A zeroed device of `len` bytes
+    pub fn new(len: usize) -> Self {
+        Self::from_bytes(vec![0; len])
+    }
+    /// A device holding `bytes`, e.g. an image from `format::encode`
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            ..Self::default()
+        }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+    /// Lets `writes` more writes through, then fails every write and flush with `EIO` until
+    /// `recover` is called; the write that fails leaves the device as it was
+    pub fn fail_after(&mut self, writes: usize) {
+        self.writes_left = Some(writes);
+        self.tear = false;
+    }
+    /// Like `fail_after`, except that the write that fails gets its first half onto the device
+    pub fn tear_after(&mut self, writes: usize) {
+        self.writes_left = Some(writes);
+        self.tear = true;
+    }
+    /// Takes writes again after `fail_after` or `tear_after`
+    pub fn recover(&mut self) {
+        self.writes_left = None;
+    }
+    /// Writes that went through so far
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+    /// Flushes that went through so far
+    pub fn flushes(&self) -> usize {
+        self.flushes
+    }
+}
+
+impl Device for MemDevice {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.bytes.len() as u64)
+    }
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let start = usize::try_from(offset).map_err(|_| Error::new(EIO))?;
+        let end = start.checked_add(buf.len()).ok_or(Error::new(EIO))?;
+
+        buf.copy_from_slice(self.bytes.get(start..end).ok_or(Error::new(EIO))?);
+        Ok(())
+    }
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let start = usize::try_from(offset).map_err(|_| Error::new(EIO))?;
+        let end = start.checked_add(buf.len()).ok_or(Error::new(EIO))?;
+        let dest = self.bytes.get_mut(start..end).ok_or(Error::new(EIO))?;
+
+        if self.writes_left == Some(0) {
+            if self.tear {
+                let half = buf.len() / 2;
+                dest[..half].copy_from_slice(&buf[..half]);
+            }
+            return Err(Error::new(EIO));
+        }
+        dest.copy_from_slice(buf);
+        self.writes += 1;
+        if let Some(left) = &mut self.writes_left {
+            *left -= 1;
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<()> {
+        if self.writes_left == Some(0) {
+            return Err(Error::new(EIO));
+        }
+        self.flushes += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use alloc::string::{String, ToString};
+use syscall::{Error, Result, EACCES, EPERM};
+
+use super::Properties;
+
+// POSIX-style ownership: every entry has an owning user and group and nine permission bits, plus
+// the set-user-ID, set-group-ID and sticky bits. The superuser (uid 0) may read and write
+// anything and search any directory, but only executes files that someone could.
+
+/// Set-user-ID bit of `Properties::mode`
+pub const S_ISUID: u32 = 0o4000;
+/// Set-group-ID bit; on a directory, new entries take its group and new subdirectories the bit
+pub const S_ISGID: u32 = 0o2000;
+/// Sticky bit; in a directory, only an entry's owner (or the directory's) may remove or rename it
+pub const S_ISVTX: u32 = 0o1000;
+/// Every bit `chmod` can set
+pub const MODE_MASK: u32 = 0o7777;
+
+/// Read permission, as passed to `access`
+pub const R_OK: u32 = 4;
+/// Write permission
+pub const W_OK: u32 = 2;
+/// Execute permission for a file, search permission for a directory
+pub const X_OK: u32 = 1;
+
+/// Who an operation is carried out for
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Credential {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credential {
+    pub const ROOT: Self = Self { uid: 0, gid: 0 };
+
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self { uid, gid }
+    }
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+    /// Name recorded as the owner of entries this credential creates
+    ///
+    /// The kernel has no user database, so anyone but root goes by their uid, the same way
+    /// hmfsprogs records host users without a passwd entry.
+    pub fn name(&self) -> String {
+        match self.uid {
+            0 => String::from("root"),
+            uid => uid.to_string(),
+        }
+    }
+    /// Whether this is the owner of `props`, or root, who may act as any owner
+    pub fn owns(&self, props: &Properties) -> bool {
+        self.is_root() || self.uid == props.uid
+    }
+}
+
+/// Checks that `cred` has every permission in `wanted` (some of `R_OK`, `W_OK` and `X_OK`) on
+/// the entry with `props`, failing with `EACCES`
+pub fn access(cred: &Credential, props: &Properties, is_dir: bool, wanted: u32) -> Result<()> {
+    let granted = if cred.is_root() {
+        // searching a directory always works, executing a file needs an execute bit somewhere
+        if is_dir || props.mode & 0o111 != 0 {
+            R_OK | W_OK | X_OK
+        } else {
+            R_OK | W_OK
+        }
+    } else if cred.uid == props.uid {
+        (props.mode >> 6) & 7
+    } else if cred.gid == props.gid {
+        (props.mode >> 3) & 7
+    } else {
+        props.mode & 7
+    };
+
+    if granted & wanted != wanted {
+        return Err(Error::new(EACCES));
+    }
+    Ok(())
+}
+
+/// Checks that `cred` may remove or rename the entry with `props` from the directory with
+/// `dir_props`, given that it can already write to the directory; only the sticky bit stands in
+/// the way, failing with `EPERM`
+pub fn may_delete(cred: &Credential, dir_props: &Properties, props: &Properties) -> Result<()> {
+    if dir_props.mode & S_ISVTX == 0 || cred.owns(dir_props) || cred.owns(props) {
+        return Ok(());
+    }
+    Err(Error::new(EPERM))
+}
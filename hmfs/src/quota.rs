@@ -0,0 +1,352 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+use syscall::{Error, Result, EINVAL};
+
+use super::index::{by_name, child_path, children, IndexPath};
+use super::{Entry, EntryKind};
+
+// Byte and inode quotas, per owner and per directory subtree. What's charged is the live tree
+// only: a file costs its length, holes included and however its blocks happen to be stored, and
+// every entry costs an inode. Snapshots share the live tree's blocks until it changes, so they
+// aren't charged to anyone, and taking or rolling back to one just changes what the live tree
+// is. A file with several hard links is charged once to each owner its names have, and once to
+// each subtree holding any of them.
+//
+// Limits are kept as extended attributes, on the root directory for owners and on the directory
+// itself for subtrees, so they're covered by checksums and go into snapshots and send streams
+// like anything else. Usage is worked out as the index is: built on mount, and brought up to
+// date by comparing the tree before and after each change.
+
+/// Extended attribute of a directory limiting what can be stored beneath it
+pub const QUOTA_XATTR: &str = "hmfs.quota";
+/// Start of the extended attributes of the root directory limiting what each owner can store;
+/// the uid follows in decimal
+pub const OWNER_QUOTA_PREFIX: &str = "hmfs.quota.uid.";
+
+/// The extended attribute of the root directory holding the limits for `uid`
+pub fn owner_key(uid: u32) -> String {
+    format!("{OWNER_QUOTA_PREFIX}{uid}")
+}
+
+/// What an owner or subtree has stored
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct Usage {
+    /// Total length of files, holes included
+    pub bytes: u64,
+    /// Entries of any kind
+    pub inodes: u64,
+}
+
+impl Usage {
+    /// Adds up everything below the directory `dir`, not counting `dir` itself; hard links are
+    /// only counted once
+    pub fn of(dir: &Entry) -> Self {
+        let mut usage = Self::default();
+        let mut seen = BTreeSet::new();
+        usage.add_below(&[], dir, &mut seen);
+        usage
+    }
+    /// What `entry` costs by itself
+    pub fn entry(entry: &Entry) -> Self {
+        Self {
+            bytes: match &entry.kind {
+                EntryKind::File(file) => file.len(),
+                _ => 0,
+            },
+            inodes: 1,
+        }
+    }
+    fn add_below(&mut self, path: &[String], dir: &Entry, seen: &mut BTreeSet<usize>) {
+        for (props, child) in children(path, dir) {
+            if seen.insert(Arc::as_ptr(child) as usize) {
+                *self = *self + Self::entry(child);
+                self.add_below(&child_path(path, &props.name), child, seen);
+            }
+        }
+    }
+}
+
+impl core::ops::Add for Usage {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            bytes: self.bytes.saturating_add(other.bytes),
+            inodes: self.inodes.saturating_add(other.inodes),
+        }
+    }
+}
+
+impl core::ops::Sub for Usage {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            bytes: self.bytes.saturating_sub(other.bytes),
+            inodes: self.inodes.saturating_sub(other.inodes),
+        }
+    }
+}
+
+/// Most an owner or subtree may store; 0 leaves either without a limit
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct Limits {
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+impl Limits {
+    /// Parses a value of `QUOTA_XATTR` or an owner's attribute, failing with `EINVAL` for
+    /// anything but two little-endian `u64`s
+    pub fn parse(value: &[u8]) -> Result<Self> {
+        if value.len() != 16 {
+            return Err(Error::new(EINVAL));
+        }
+        let (bytes, inodes) = value.split_at(8);
+        Ok(Self {
+            bytes: u64::from_le_bytes(bytes.try_into().unwrap()),
+            inodes: u64::from_le_bytes(inodes.try_into().unwrap()),
+        })
+    }
+    /// The limits kept in the attribute `key` of `entry`, or none if it isn't set; a damaged
+    /// value limits nothing either, rather than locking everyone out
+    pub fn of(entry: &Entry, key: &str) -> Self {
+        entry
+            .xattr(key)
+            .and_then(|value| Self::parse(value).ok())
+            .unwrap_or_default()
+    }
+    /// What goes in the attribute, or `None` for no limits at all
+    pub fn value(self) -> Option<Vec<u8>> {
+        if self.is_unlimited() {
+            return None;
+        }
+        let mut value = Vec::with_capacity(16);
+        value.extend_from_slice(&self.bytes.to_le_bytes());
+        value.extend_from_slice(&self.inodes.to_le_bytes());
+        Some(value)
+    }
+    pub fn is_unlimited(self) -> bool {
+        self.bytes == 0 && self.inodes == 0
+    }
+    /// Whether going from `was` to `now` broke a limit; usage that only shrank or stayed put
+    /// never does, so lowering a limit doesn't stop anyone from cleaning up
+    pub fn exceeded(self, was: Usage, now: Usage) -> bool {
+        let over = |limit: u64, was: u64, now: u64| limit != 0 && now > was && now > limit;
+        over(self.bytes, was.bytes, now.bytes) || over(self.inodes, was.inodes, now.inodes)
+    }
+}
+
+/// Something usage is charged to
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Account {
+    Owner(u32),
+    /// Everything below the directory at these stored names; the root directory is `[]`
+    Subtree(IndexPath),
+}
+
+impl Account {
+    // the accounts an entry at `path` owned by `uid` is charged to
+    fn of(path: &[String], uid: u32) -> impl Iterator<Item = Self> + '_ {
+        (0..path.len())
+            .map(|len| Self::Subtree(IndexPath::from(&path[..len])))
+            .chain([Self::Owner(uid)])
+    }
+}
+
+/// Who a quota applies to, as `RootEntry::quotas` shows it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Holder {
+    Owner(u32),
+    /// The directory at this path, decrypted as `RootEntry::query` shows paths
+    Directory(String),
+}
+
+/// The limits on an owner or subtree, and what it has stored
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Quota {
+    pub holder: Holder,
+    pub limits: Limits,
+    pub usage: Usage,
+}
+
+/// Usage of every owner and subtree in one tree, kept in step with it by `RootEntry`
+#[derive(Debug, Clone, Default)]
+pub struct Accounting {
+    accounts: BTreeMap<Account, Usage>,
+    /// Owners of each name of entries with more than one, by address
+    shared: BTreeMap<usize, BTreeMap<IndexPath, u32>>,
+    /// Usage of every account changed since `begin`, as it was then
+    touched: BTreeMap<Account, Usage>,
+}
+
+// accounting only follows the tree, so it doesn't count towards what a volume is
+impl PartialEq for Accounting {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Accounting {}
+
+impl Hash for Accounting {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+impl Accounting {
+    /// Charges everything in the tree under the root directory `root`
+    pub fn build(root: &Entry) -> Self {
+        let mut accounting = Self::default();
+        accounting.add_children(&[], root);
+        accounting.touched.clear();
+        accounting
+    }
+    /// Brings usage up to date with a change from the root directory `before` to `after`
+    pub fn update(&mut self, before: &Entry, after: &Entry) {
+        self.diff_children(&[], before, after);
+    }
+    pub fn usage(&self, account: &Account) -> Usage {
+        self.accounts.get(account).copied().unwrap_or_default()
+    }
+    /// Every account with anything charged to it, in order
+    pub fn accounts(&self) -> impl Iterator<Item = (&Account, &Usage)> {
+        self.accounts.iter()
+    }
+    /// Starts keeping track of which accounts change, forgetting any changed before
+    pub fn begin(&mut self) {
+        self.touched.clear();
+    }
+    /// Whether any account changed since `begin` has gone over a limit, by the limits in the
+    /// root directory `after`; a subtree that wasn't there in `before` was moved in whole,
+    /// which stores nothing new below it
+    pub fn exceeded(&self, before: &Entry, after: &Entry) -> bool {
+        self.touched.iter().any(|(account, was)| match account {
+            Account::Subtree(path) if dir_at(before, path).is_none() => false,
+            _ => limits(after, account)
+                .is_some_and(|limits| limits.exceeded(*was, self.usage(account))),
+        })
+    }
+    fn charge(&mut self, account: Account, cost: Usage, add: bool) {
+        let was = self.usage(&account);
+        self.touched.entry(account.clone()).or_insert(was);
+
+        let now = match add {
+            true => was + cost,
+            false => was - cost,
+        };
+        match now == Usage::default() {
+            true => self.accounts.remove(&account),
+            false => self.accounts.insert(account, now),
+        };
+    }
+    // Charges or refunds the entry at `path` by itself. An entry with other names only costs
+    // each account once, so only the accounts it gains or loses by this name change.
+    fn charge_entry(&mut self, path: &[String], uid: u32, entry: &Arc<Entry>, add: bool) {
+        let cost = Usage::entry(entry);
+        if entry.links <= 1 {
+            for account in Account::of(path, uid).collect::<Vec<_>>() {
+                self.charge(account, cost, add);
+            }
+            return;
+        }
+
+        let key = Arc::as_ptr(entry) as usize;
+        let names = self.shared.entry(key).or_default();
+        let before = accounts_of(names);
+        match add {
+            true => names.insert(IndexPath::from(path), uid),
+            false => names.remove(path),
+        };
+        let after = accounts_of(names);
+        if names.is_empty() {
+            self.shared.remove(&key);
+        }
+
+        let (gained, lost) = (&after - &before, &before - &after);
+        for account in gained {
+            self.charge(account, cost, true);
+        }
+        for account in lost {
+            self.charge(account, cost, false);
+        }
+    }
+    fn add(&mut self, path: &[String], uid: u32, entry: &Arc<Entry>) {
+        self.charge_entry(path, uid, entry, true);
+        self.add_children(path, entry);
+    }
+    fn add_children(&mut self, path: &[String], dir: &Entry) {
+        for (props, child) in children(path, dir) {
+            self.add(&child_path(path, &props.name), props.uid, child);
+        }
+    }
+    fn remove_all(&mut self, path: &[String], uid: u32, entry: &Arc<Entry>) {
+        self.charge_entry(path, uid, entry, false);
+        for (props, child) in children(path, entry) {
+            self.remove_all(&child_path(path, &props.name), props.uid, child);
+        }
+    }
+    // Compares the children of two versions of the directory at `path` by name, as
+    // `Index::diff_children` does. A child costs the same as long as it's the same `Arc` under
+    // the same owner.
+    fn diff_children(&mut self, path: &[String], before: &Entry, after: &Entry) {
+        if let (EntryKind::Directory(old), EntryKind::Directory(new)) = (&before.kind, &after.kind)
+        {
+            if Arc::ptr_eq(old, new) {
+                return;
+            }
+        }
+
+        let old = by_name(path, before);
+        let new = by_name(path, after);
+
+        for (name, (props, child)) in old.iter() {
+            if !new.contains_key(name) {
+                self.remove_all(&child_path(path, name), props.uid, child);
+            }
+        }
+
+        for (name, (props, child)) in new.iter() {
+            let child_path = child_path(path, name);
+            match old.get(name) {
+                None => self.add(&child_path, props.uid, child),
+                Some((old_props, old_child)) => {
+                    let same_entry = Arc::ptr_eq(old_child, child);
+                    if !same_entry || old_props.uid != props.uid {
+                        self.charge_entry(&child_path, old_props.uid, old_child, false);
+                        self.charge_entry(&child_path, props.uid, child, true);
+                    }
+                    if !same_entry {
+                        self.diff_children(&child_path, old_child, child);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The limits on `account`, kept in the tree under the root directory `root`, or `None` if it's
+/// a subtree that isn't there
+pub fn limits(root: &Entry, account: &Account) -> Option<Limits> {
+    match account {
+        Account::Owner(uid) => Some(Limits::of(root, &owner_key(*uid))),
+        Account::Subtree(path) => dir_at(root, path).map(|dir| Limits::of(dir, QUOTA_XATTR)),
+    }
+}
+
+// the entry at the stored names `path` below the root directory `root`
+fn dir_at<'e, 'a>(root: &'e Entry<'a>, path: &[String]) -> Option<&'e Entry<'a>> {
+    path.iter()
+        .try_fold(root, |dir, name| dir.child(name).map(|(_, entry)| &**entry))
+}
+
+// every account any of the names of one entry is charged to
+fn accounts_of(names: &BTreeMap<IndexPath, u32>) -> BTreeSet<Account> {
+    names
+        .iter()
+        .flat_map(|(path, uid)| Account::of(path, *uid))
+        .collect()
+}
@@ -0,0 +1,190 @@
+use alloc::collections::{BTreeSet, VecDeque};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt;
+
+use super::blocks::block_checksum;
+use super::checksum::Checksum;
+use super::{sorted_children, Entry, EntryKind, RootEntry};
+
+// A scrub re-verifies a whole volume against its checksums: every entry against its contents
+// (for a directory, its children's properties and stored checksums, the same as on disk), every
+// file block against its data, and the root against the checksum the volume keeps for it. It
+// holds its own references to the trees it started on, so the volume can carry on changing while
+// it works through them one entry at a time. Entries and blocks shared between snapshots, or
+// between hard links, are only verified once.
+
+/// Something a scrub found that doesn't match its checksum
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Damage {
+    /// Path the damaged entry was reached by, prefixed with `@name:` inside a snapshot
+    pub path: String,
+    pub kind: DamageKind,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DamageKind {
+    /// The entry's contents don't match its checksum
+    Entry {
+        stored: Checksum,
+        computed: Checksum,
+    },
+    /// One of a file's blocks doesn't match its checksum
+    Block {
+        index: u64,
+        stored: Checksum,
+        computed: Checksum,
+    },
+    /// The checksum the volume keeps for its root doesn't match the root directory's
+    Root {
+        stored: Checksum,
+        computed: Checksum,
+    },
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = &self.path;
+        match self.kind {
+            DamageKind::Entry { stored, computed } => write!(
+                f,
+                "{path}: checksum mismatch (stored {stored:#x}, computed {computed:#x})"
+            ),
+            DamageKind::Block {
+                index,
+                stored,
+                computed,
+            } => write!(
+                f,
+                "{path}: block {index} checksum mismatch (stored {stored:#x}, computed \
+                 {computed:#x})"
+            ),
+            DamageKind::Root { stored, computed } => write!(
+                f,
+                "{path}: root checksum mismatch (stored {stored:#x}, computed {computed:#x})"
+            ),
+        }
+    }
+}
+
+/// A scrub of one volume, worked through a step at a time
+pub struct Scrub<'a> {
+    /// Entries still to verify, with the path each was reached by and, for the root, the
+    /// checksum the volume keeps for it
+    queue: VecDeque<(String, Arc<Entry<'a>>, Option<Checksum>)>,
+    /// Entries and blocks already queued or verified, by address; the queue keeps every tree
+    /// alive until the scrub is done, so no address gets reused in the meantime
+    seen: BTreeSet<usize>,
+    entries: u64,
+    blocks: u64,
+}
+
+impl<'a> Scrub<'a> {
+    /// Starts a scrub of the live tree and every snapshot of `root` as they are now
+    pub fn new(root: &RootEntry<'a>) -> Self {
+        let mut queue = VecDeque::from([(
+            String::from("/"),
+            Arc::new(root.dir.clone()),
+            Some(root.checksum),
+        )]);
+        queue.extend(root.snapshots.iter().map(|snapshot| {
+            (
+                format!("@{}:/", snapshot.name),
+                Arc::new(snapshot.dir.clone()),
+                None,
+            )
+        }));
+
+        Self {
+            queue,
+            seen: BTreeSet::new(),
+            entries: 0,
+            blocks: 0,
+        }
+    }
+    /// Verifies the next entry, and the blocks of a file, passing anything wrong with it to
+    /// `report`; returns whether there's anything left to verify
+    pub fn step(&mut self, report: &mut dyn FnMut(Damage)) -> bool {
+        let Some((path, entry, kept)) = self.queue.pop_front() else {
+            return false;
+        };
+        self.entries += 1;
+
+        let computed = entry.computed_checksum();
+        if computed != entry.checksum {
+            report(Damage {
+                path: path.clone(),
+                kind: DamageKind::Entry {
+                    stored: entry.checksum,
+                    computed,
+                },
+            });
+        }
+
+        if let Some(stored) = kept.filter(|&kept| kept != entry.checksum) {
+            report(Damage {
+                path: path.clone(),
+                kind: DamageKind::Root {
+                    stored,
+                    computed: entry.checksum,
+                },
+            });
+        }
+
+        match &entry.kind {
+            EntryKind::Directory(map) => {
+                for (props, child) in sorted_children(map) {
+                    if self.seen.insert(Arc::as_ptr(child) as usize) {
+                        self.queue
+                            .push_back((join(&path, &props.name), Arc::clone(child), None));
+                    }
+                }
+            }
+            EntryKind::File(file) => {
+                for (index, block) in file.blocks() {
+                    if !self.seen.insert(Arc::as_ptr(block) as usize) {
+                        continue;
+                    }
+                    self.blocks += 1;
+
+                    if !block.is_intact() {
+                        report(Damage {
+                            path: path.clone(),
+                            kind: DamageKind::Block {
+                                index,
+                                stored: block.checksum(),
+                                computed: block_checksum(block.data()),
+                            },
+                        });
+                    }
+                }
+            }
+            // a link's target is covered by its own checksum, and a root only ever turns up as a
+            // back-reference to the volume being scrubbed
+            EntryKind::Root(_) | EntryKind::Symlink(_) => {}
+        }
+
+        !self.queue.is_empty()
+    }
+    /// Works through everything left, passing what's wrong to `report`
+    pub fn run(&mut self, report: &mut dyn FnMut(Damage)) {
+        while self.step(report) {}
+    }
+    /// Entries verified so far
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+    /// Distinct file blocks verified so far
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{dir}{name}")
+    } else {
+        format!("{dir}/{name}")
+    }
+}
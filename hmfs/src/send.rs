@@ -0,0 +1,465 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use syscall::{Error, Result, EINVAL, EIO, ENOENT};
+
+use super::blocks::{Block, FileBlocks};
+use super::checksum::{Checksum, CHECKSUM_LEN};
+use super::dedup::DedupTable;
+use super::format::{self, put_bytes, put_u32, put_u64, PropertiesRecord, Reader};
+use super::{detached, new_map_shorthand, sorted_children, time_t, Entry, EntryKind, Xattrs};
+
+// Send streams: the difference between two snapshots, as a list of operations that turn the
+// older tree into the newer one. Every subtree is addressed by its checksum, so whatever has the
+// same checksum (and is linked under the same properties) in both trees is skipped without
+// looking inside, and a file that changed only carries the blocks the receiving end doesn't
+// already have; the rest are named by checksum. Names, link targets and blocks are sent as
+// stored, so encrypted and compressed entries go across as they are, without any keys.
+//
+// The stream names the snapshot it was made against by checksum, which is what the receiving
+// end looks for, and the checksum of the snapshot it makes, which the rebuilt tree has to come
+// out to before it's kept. A stream without a base starts from an empty directory and so holds
+// the whole tree.
+//
+// Layout: `STREAM_MAGIC`, the version as a u32, a byte saying whether a base checksum follows,
+// the name and creation time of the snapshot being sent and its checksum, then operations, each
+// a tag byte followed by its fields, ending with `OP_END`. A path is a u32 count of stored names.
+
+pub const STREAM_MAGIC: [u8; 8] = *b"HMFSSEND";
+pub const STREAM_VERSION: u32 = 1;
+
+const OP_END: u8 = 0;
+/// Unlinks whatever is at a path
+const OP_REMOVE: u8 = 1;
+/// Gives what's at a path new properties
+const OP_PROPS: u8 = 2;
+/// Replaces a directory's extended attributes, the root's included
+const OP_XATTRS: u8 = 3;
+/// Links an empty directory at a path, in place of whatever was there
+const OP_DIR: u8 = 4;
+const OP_FILE: u8 = 5;
+const OP_SYMLINK: u8 = 6;
+/// Links the file or symbolic link already at one path under another
+const OP_LINK: u8 = 7;
+
+/// A block sent in full, after its checksum
+const BLOCK_DATA: u8 = 0;
+/// A block the receiving end already has, by checksum
+const BLOCK_REF: u8 = 1;
+
+/// The snapshots a stream goes between
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct StreamHeader<'s> {
+    /// Checksum of the snapshot the stream was made against, or `None` for a whole tree
+    pub base: Option<Checksum>,
+    /// Name of the snapshot the stream makes
+    pub name: &'s str,
+    pub created: time_t,
+    /// Checksum the tree the stream makes has to have
+    pub checksum: Checksum,
+}
+
+impl<'s> StreamHeader<'s> {
+    /// Reads the header at the start of a stream, leaving `reader` at the first operation
+    pub fn read(reader: &mut Reader<'s>) -> Result<Self> {
+        if reader.take(STREAM_MAGIC.len())? != STREAM_MAGIC {
+            return Err(Error::new(EINVAL));
+        }
+        if reader.u32()? != STREAM_VERSION {
+            return Err(Error::new(EINVAL));
+        }
+
+        let base = match reader.u8()? {
+            0 => None,
+            1 => Some(reader.checksum()?),
+            _ => return Err(Error::new(EIO)),
+        };
+        Ok(Self {
+            base,
+            name: reader.str()?,
+            created: reader.i128()?,
+            checksum: reader.checksum()?,
+        })
+    }
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&STREAM_MAGIC);
+        put_u32(out, STREAM_VERSION);
+
+        match self.base {
+            Some(base) => {
+                out.push(1);
+                out.extend_from_slice(base.as_bytes());
+            }
+            None => out.push(0),
+        }
+        put_bytes(out, self.name.as_bytes());
+        out.extend_from_slice(&self.created.to_le_bytes());
+        out.extend_from_slice(self.checksum.as_bytes());
+    }
+}
+
+/// What went into a stream, or came out of one
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct StreamStats {
+    /// Operations in the stream
+    pub ops: u64,
+    /// Blocks sent in full
+    pub blocks: u64,
+    /// Bytes of the blocks sent in full
+    pub bytes: u64,
+    /// Blocks named by checksum instead
+    pub shared: u64,
+}
+
+/// The stream that turns `base` (or an empty directory) into `to`, which `header` describes
+pub fn send(header: &StreamHeader, base: Option<&Entry>, to: &Entry) -> (Vec<u8>, StreamStats) {
+    let empty = Entry::new(EntryKind::Directory(Arc::new(new_map_shorthand())), None);
+    let base = base.unwrap_or(&empty);
+
+    let mut first_names = BTreeMap::new();
+    first_links(to, &mut Vec::new(), &mut first_names);
+
+    let mut sender = Sender {
+        out: Vec::new(),
+        known: DedupTable::build([base]),
+        sent: BTreeSet::new(),
+        first_names,
+        stats: StreamStats::default(),
+    };
+    header.write(&mut sender.out);
+
+    if base.xattrs != to.xattrs {
+        sender.op(OP_XATTRS, &[]);
+        put_xattrs(&mut sender.out, &to.xattrs);
+    }
+    sender.dir(&mut Vec::new(), base, to);
+
+    sender.out.push(OP_END);
+    (sender.out, sender.stats)
+}
+
+// records the first path, in the order a stream goes through them, of every entry that's
+// linked under more than one name
+fn first_links(dir: &Entry, path: &mut Vec<String>, first: &mut BTreeMap<usize, Vec<String>>) {
+    let EntryKind::Directory(map) = &dir.kind else {
+        return;
+    };
+    for (props, child) in sorted_children(map) {
+        path.push(props.name.clone());
+        if child.links > 1 {
+            first
+                .entry(Arc::as_ptr(child) as usize)
+                .or_insert_with(|| path.clone());
+        }
+        first_links(child, path, first);
+        path.pop();
+    }
+}
+
+struct Sender {
+    out: Vec<u8>,
+    /// Blocks the receiving end has, being in the base
+    known: DedupTable,
+    /// Blocks already sent in full
+    sent: BTreeSet<Checksum>,
+    /// First path of each entry linked under more than one name, by address
+    first_names: BTreeMap<usize, Vec<String>>,
+    stats: StreamStats,
+}
+
+impl Sender {
+    fn op(&mut self, tag: u8, path: &[String]) {
+        self.stats.ops += 1;
+        self.out.push(tag);
+        put_path(&mut self.out, path);
+    }
+    // sends what changed between two versions of the directory at `path`
+    fn dir(&mut self, path: &mut Vec<String>, base: &Entry, to: &Entry) {
+        if base.checksum == to.checksum {
+            return;
+        }
+        let (EntryKind::Directory(old), EntryKind::Directory(new)) = (&base.kind, &to.kind) else {
+            unreachable!("only called on directories");
+        };
+
+        let mut old = sorted_children(old)
+            .into_iter()
+            .map(|(props, child)| (props.name.as_str(), (props, child)))
+            .collect::<BTreeMap<_, _>>();
+        let new = sorted_children(new);
+
+        let kept = new
+            .iter()
+            .map(|(props, _)| props.name.as_str())
+            .collect::<BTreeSet<_>>();
+        for name in old.keys().filter(|name| !kept.contains(*name)) {
+            path.push(String::from(*name));
+            self.op(OP_REMOVE, path);
+            path.pop();
+        }
+
+        for (props, child) in new {
+            path.push(props.name.clone());
+            let properties = format::encode_properties(props);
+
+            match old.remove(props.name.as_str()) {
+                Some((old_props, old)) if old.checksum == child.checksum => {
+                    if format::encode_properties(old_props) != properties {
+                        self.op(OP_PROPS, path);
+                        put_bytes(&mut self.out, &properties);
+                    }
+                }
+                Some((old_props, old)) if old.kind.is_dir() && child.kind.is_dir() => {
+                    if format::encode_properties(old_props) != properties {
+                        self.op(OP_PROPS, path);
+                        put_bytes(&mut self.out, &properties);
+                    }
+                    if old.xattrs != child.xattrs {
+                        self.op(OP_XATTRS, path);
+                        put_xattrs(&mut self.out, &child.xattrs);
+                    }
+                    self.dir(path, old, child);
+                }
+                _ => self.put(path, &properties, child),
+            }
+            path.pop();
+        }
+    }
+    // sends the whole of `entry`, to be linked at `path` under `properties`
+    fn put(&mut self, path: &mut Vec<String>, properties: &[u8], entry: &Arc<Entry>) {
+        if let Some(first) = self.first_names.get(&(Arc::as_ptr(entry) as usize)) {
+            if first != path {
+                let first = first.clone();
+                self.op(OP_LINK, path);
+                put_bytes(&mut self.out, properties);
+                put_path(&mut self.out, &first);
+                return;
+            }
+        }
+
+        match &entry.kind {
+            EntryKind::Directory(_) => {
+                self.op(OP_DIR, path);
+                put_bytes(&mut self.out, properties);
+                put_xattrs(&mut self.out, &entry.xattrs);
+
+                let empty = Entry::new(EntryKind::Directory(Arc::new(new_map_shorthand())), None);
+                self.dir(path, &empty, entry);
+            }
+            EntryKind::File(file) => {
+                self.op(OP_FILE, path);
+                put_bytes(&mut self.out, properties);
+                put_xattrs(&mut self.out, &entry.xattrs);
+                put_u32(&mut self.out, entry.links);
+                put_u64(&mut self.out, file.len());
+                put_u64(&mut self.out, file.blocks().len() as u64);
+
+                for (index, block) in file.blocks() {
+                    put_u64(&mut self.out, index);
+                    let checksum = block.checksum();
+
+                    if self.known.get(&checksum).is_some() || self.sent.contains(&checksum) {
+                        self.out.push(BLOCK_REF);
+                        self.out.extend_from_slice(checksum.as_bytes());
+                        self.stats.shared += 1;
+                    } else {
+                        self.out.push(BLOCK_DATA);
+                        self.out.extend_from_slice(checksum.as_bytes());
+                        put_bytes(&mut self.out, block.data());
+                        self.sent.insert(checksum);
+                        self.stats.blocks += 1;
+                        self.stats.bytes += block.data().len() as u64;
+                    }
+                }
+            }
+            EntryKind::Symlink(target) => {
+                self.op(OP_SYMLINK, path);
+                put_bytes(&mut self.out, properties);
+                put_xattrs(&mut self.out, &entry.xattrs);
+                put_u32(&mut self.out, entry.links);
+                put_bytes(&mut self.out, target.as_bytes());
+            }
+            EntryKind::Root(_) => unreachable!("the root is never anyone's child"),
+        }
+    }
+}
+
+fn put_path(out: &mut Vec<u8>, path: &[String]) {
+    put_u32(out, path.len() as u32);
+    for name in path {
+        put_bytes(out, name.as_bytes());
+    }
+}
+
+fn put_xattrs(out: &mut Vec<u8>, xattrs: &Xattrs) {
+    put_u32(out, xattrs.len() as u32);
+    for (key, value) in xattrs {
+        put_bytes(out, key.as_bytes());
+        put_bytes(out, value);
+    }
+}
+
+fn read_path<'s>(reader: &mut Reader<'s>) -> Result<Vec<&'s str>> {
+    (0..reader.u32()?).map(|_| reader.str()).collect()
+}
+
+fn read_xattrs(reader: &mut Reader) -> Result<Xattrs> {
+    (0..reader.u32()?)
+        .map(|_| Ok((String::from(reader.str()?), reader.bytes()?.to_vec())))
+        .collect()
+}
+
+// a path with at least one name, split into the directory and the name in it
+fn split<'p, 's>(path: &'p [&'s str]) -> Result<(&'p [&'s str], &'s str)> {
+    let (name, parent) = path.split_last().ok_or(Error::new(EINVAL))?;
+    Ok((parent, name))
+}
+
+/// Plays the operations in a stream, `reader` being just past its header, over `base` (or an
+/// empty directory), and hands back the tree they make and what was in them
+///
+/// The tree is only checked against the checksum in the header by the caller; anything that
+/// doesn't parse, or refers to something that isn't there, fails with `EIO`.
+pub fn receive<'a>(
+    reader: &mut Reader<'a>,
+    base: Option<&Entry<'a>>,
+) -> Result<(Entry<'a>, StreamStats)> {
+    let mut dir = match base {
+        Some(base) => base.clone(),
+        None => Entry::new(EntryKind::Directory(Arc::new(new_map_shorthand())), None),
+    };
+    let mut known = DedupTable::build([&dir]);
+    let mut stats = StreamStats::default();
+
+    // the base is the one the stream was made against, so an operation that doesn't fit it
+    // means the stream is damaged
+    let corrupt = |_: Error| Error::new(EIO);
+
+    loop {
+        let tag = reader.u8()?;
+        if tag == OP_END {
+            break;
+        }
+        stats.ops += 1;
+        let path = read_path(reader)?;
+
+        match tag {
+            OP_REMOVE => {
+                let (parent, name) = split(&path)?;
+                dir.with_dir_mut(parent, |parent| {
+                    parent.take_child(name)?.ok_or(Error::new(ENOENT))
+                })
+                .map_err(corrupt)?;
+            }
+            OP_PROPS => {
+                let (parent, name) = split(&path)?;
+                let props = PropertiesRecord::parse(reader.bytes()?)?.to_properties(detached())?;
+                dir.with_dir_mut(parent, |parent| parent.set_child_props(name, &props))
+                    .map_err(corrupt)?;
+            }
+            OP_XATTRS => {
+                let xattrs = read_xattrs(reader)?;
+                dir.with_dir_mut(&path, |dir| {
+                    dir.xattrs = xattrs;
+                    Ok(())
+                })
+                .map_err(corrupt)?;
+            }
+            OP_DIR | OP_FILE | OP_SYMLINK => {
+                let (parent, name) = split(&path)?;
+                let props = PropertiesRecord::parse(reader.bytes()?)?.to_properties(detached())?;
+                if props.name != name {
+                    return Err(Error::new(EIO));
+                }
+                let xattrs = read_xattrs(reader)?;
+
+                let (kind, links) = match tag {
+                    OP_DIR => (EntryKind::Directory(Arc::new(new_map_shorthand())), 1),
+                    OP_FILE => {
+                        let links = reader.u32()?;
+                        let file = read_file(reader, &mut known, &mut stats)?;
+                        (EntryKind::File(file), links)
+                    }
+                    _ => {
+                        let links = reader.u32()?;
+                        (EntryKind::Symlink(String::from(reader.str()?)), links)
+                    }
+                };
+                let mut entry = Entry {
+                    kind,
+                    checksum: Checksum([0; CHECKSUM_LEN]),
+                    parent: None,
+                    xattrs,
+                    links,
+                };
+                entry.update_checksum();
+
+                dir.with_dir_mut(parent, |parent| parent.link(props, entry))
+                    .map_err(corrupt)?;
+            }
+            OP_LINK => {
+                let (parent, name) = split(&path)?;
+                let props = PropertiesRecord::parse(reader.bytes()?)?.to_properties(detached())?;
+                if props.name != name {
+                    return Err(Error::new(EIO));
+                }
+                let target = entry_at(&dir, &read_path(reader)?)?;
+                if target.kind.is_dir() {
+                    return Err(Error::new(EIO));
+                }
+
+                dir.with_dir_mut(parent, |parent| parent.link_shared(props, target))
+                    .map_err(corrupt)?;
+            }
+            _ => return Err(Error::new(EIO)),
+        }
+    }
+
+    if !reader.is_empty() {
+        return Err(Error::new(EIO));
+    }
+    Ok((dir, stats))
+}
+
+fn read_file(
+    reader: &mut Reader,
+    known: &mut DedupTable,
+    stats: &mut StreamStats,
+) -> Result<FileBlocks> {
+    let len = reader.u64()?;
+    let mut blocks = Vec::new();
+
+    for _ in 0..reader.u64()? {
+        let index = reader.u64()?;
+        let kind = reader.u8()?;
+        let checksum = reader.checksum()?;
+
+        let block = match kind {
+            BLOCK_DATA => {
+                let data = reader.bytes()?.to_vec();
+                stats.blocks += 1;
+                stats.bytes += data.len() as u64;
+                known.intern(&Arc::new(Block::with_checksum(data, checksum)))
+            }
+            BLOCK_REF => {
+                stats.shared += 1;
+                known.get(&checksum).ok_or(Error::new(EIO))?
+            }
+            _ => return Err(Error::new(EIO)),
+        };
+        blocks.push((index, block));
+    }
+
+    FileBlocks::from_blocks(len, blocks).ok_or(Error::new(EIO))
+}
+
+// the entry at `path` below `dir`, as shared
+fn entry_at<'a>(dir: &Entry<'a>, path: &[&str]) -> Result<Arc<Entry<'a>>> {
+    let (first, rest) = path.split_first().ok_or(Error::new(EIO))?;
+    let mut entry = dir.child(first).ok_or(Error::new(EIO))?.1;
+    for name in rest {
+        entry = entry.child(name).ok_or(Error::new(EIO))?.1;
+    }
+    Ok(Arc::clone(entry))
+}
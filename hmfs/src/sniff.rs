@@ -0,0 +1,82 @@
+use mr_mime::Mime;
+
+// Content sniffing for the formats the kernel itself handles, going by magic bytes rather than
+// names: executables it loads, images it draws, compressed archives and initramfs images it
+// unpacks, and text. Anything else is left without a type rather than guessed at.
+
+/// How much of a file `sniff` looks at
+pub const SNIFF_LEN: usize = 1024;
+
+/// Sizes of the BMP info headers in use, one of which follows the 14-byte file header
+const BMP_INFO_SIZES: [u32; 7] = [12, 40, 52, 56, 64, 108, 124];
+
+/// Detects the type of `data` from its first `SNIFF_LEN` bytes
+pub fn sniff(data: &[u8]) -> Option<Mime<'static>> {
+    let head = &data[..data.len().min(SNIFF_LEN)];
+
+    let name = if head.starts_with(b"\x7fELF") {
+        "application/x-elf"
+    } else if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if is_bmp(head) {
+        "image/bmp"
+    } else if head.starts_with(&[0x1f, 0x8b, 0x08]) {
+        "application/gzip"
+    } else if is_zlib(head) {
+        "application/zlib"
+    } else if is_cpio(head) {
+        "application/x-cpio"
+    } else if is_text(head, head.len() < data.len()) {
+        "text/plain"
+    } else {
+        return None;
+    };
+
+    Mime::parse(name).ok()
+}
+
+fn is_bmp(head: &[u8]) -> bool {
+    let Some(info_size) = head.get(14..18) else {
+        return false;
+    };
+    let info_size = u32::from_le_bytes(info_size.try_into().unwrap());
+
+    head.starts_with(b"BM") && BMP_INFO_SIZES.contains(&info_size)
+}
+
+// A deflate stream in a zlib wrapper: method 8, a window of at most 32 KiB and a header that's a
+// multiple of 31, which is what keeps the first two bytes of text from passing for one
+fn is_zlib(head: &[u8]) -> bool {
+    let [cmf, flg, ..] = *head else {
+        return false;
+    };
+
+    cmf & 0x0f == 8 && cmf >> 4 <= 7 && (u16::from(cmf) << 8 | u16::from(flg)) % 31 == 0
+}
+
+// The portable ASCII headers ("newc" with and without checksums, and "odc"), and the old binary
+// header in either byte order
+fn is_cpio(head: &[u8]) -> bool {
+    [b"070701", b"070702", b"070707"]
+        .iter()
+        .any(|magic| head.starts_with(*magic))
+        || head.starts_with(&[0xc7, 0x71])
+        || head.starts_with(&[0x71, 0xc7])
+}
+
+// UTF-8 without control characters other than the whitespace ones and escape; when `truncated`,
+// a character cut off by the end of `head` doesn't count against it
+fn is_text(head: &[u8], truncated: bool) -> bool {
+    let valid = match core::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if truncated && e.error_len().is_none() => {
+            core::str::from_utf8(&head[..e.valid_up_to()]).unwrap()
+        }
+        Err(_) => return false,
+    };
+
+    !valid.is_empty()
+        && valid
+            .chars()
+            .all(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b'))
+}
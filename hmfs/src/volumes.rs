@@ -0,0 +1,189 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::relax::{RelaxStrategy, Spin};
+use spin::rwlock::RwLock;
+use syscall::scheme::Scheme;
+use syscall::{
+    Error, EventFlags, Result, EAGAIN, EBADF, EEXIST, EINVAL, ENOENT, EROFS, MODE_CHR, O_ACCMODE,
+    O_CREAT, O_RDONLY,
+};
+use unix_path::Path;
+
+use super::perm::Credential;
+use super::watch::{Event, WatchId};
+use super::RootEntry;
+
+// The table of mounted volumes, and the `watch:` scheme processes see them through. Both are
+// generic over how their locks wait, so the kernel can have them give up the CPU rather than
+// spin; everything else gets the plain spinning kind.
+
+/// A mounted volume, shared by everything that reads, changes or watches it
+pub type Volume<R = Spin> = Arc<RwLock<RootEntry<'static>, R>>;
+
+/// Mounted volumes by name
+pub struct Volumes<R = Spin> {
+    volumes: RwLock<BTreeMap<String, Volume<R>>, R>,
+}
+
+impl<R: RelaxStrategy> Volumes<R> {
+    pub const fn new() -> Self {
+        Self {
+            volumes: RwLock::new(BTreeMap::new()),
+        }
+    }
+    /// Mounts `root` under `name`, failing with `EEXIST` if something already is
+    pub fn mount(&self, name: String, root: RootEntry<'static>) -> Result<Volume<R>> {
+        let mut volumes = self.volumes.write();
+        if volumes.contains_key(&name) {
+            return Err(Error::new(EEXIST));
+        }
+
+        let volume = Arc::new(RwLock::new(root));
+        volumes.insert(name, Arc::clone(&volume));
+        Ok(volume)
+    }
+    /// Unmounts the volume called `name`; anything still holding it, watches included, keeps it
+    /// as it was
+    pub fn unmount(&self, name: &str) -> Result<Volume<R>> {
+        self.volumes.write().remove(name).ok_or(Error::new(ENOENT))
+    }
+    /// The volume mounted under `name`
+    pub fn volume(&self, name: &str) -> Option<Volume<R>> {
+        self.volumes.read().get(name).cloned()
+    }
+}
+
+impl<R: RelaxStrategy> Default for Volumes<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A watch on a directory of a mounted volume, which is ended when this is dropped
+///
+/// Kernel subsystems hold one of these directly; processes get one by opening `watch:`.
+pub struct Watch<R: RelaxStrategy = Spin> {
+    volume: Volume<R>,
+    id: WatchId,
+}
+
+impl<R: RelaxStrategy> Watch<R> {
+    /// Watches the directory at `path` in the volume called `name`, as `cred`; see
+    /// `RootEntry::watch`
+    pub fn new(volumes: &Volumes<R>, name: &str, cred: &Credential, path: &Path) -> Result<Self> {
+        let volume = volumes.volume(name).ok_or(Error::new(ENOENT))?;
+        let id = volume.write().watch(cred, Path::new("/"), path)?;
+        Ok(Self { volume, id })
+    }
+    /// Takes the oldest events, as many as fit in `max` bytes written out
+    pub fn events(&self, max: usize) -> Result<Vec<Event>> {
+        self.volume.write().take_events(self.id, max)
+    }
+    /// How many events are waiting to be taken
+    pub fn pending(&self) -> usize {
+        self.volume.read().pending_events(self.id).unwrap_or(0)
+    }
+}
+
+impl<R: RelaxStrategy> Drop for Watch<R> {
+    fn drop(&mut self) {
+        let _ = self.volume.write().unwatch(self.id);
+    }
+}
+
+/// Change notifications on mounted volumes, for processes
+///
+/// Opening `watch:<volume>/<path>` watches that directory, as the opening user, and reading the
+/// handle takes the events queued since as `watch::Event::write` writes them, whole events
+/// only. Reads never wait: with nothing queued they fail with `EAGAIN`, and `fevent` reports
+/// the handle readable once something is. A buffer too small for the next event fails with
+/// `EINVAL`.
+pub struct WatchScheme<'v, R: RelaxStrategy = Spin> {
+    volumes: &'v Volumes<R>,
+    handles: RwLock<BTreeMap<u64, Watch<R>>, R>,
+    next: AtomicU64,
+}
+
+impl<'v, R: RelaxStrategy> WatchScheme<'v, R> {
+    pub fn new(volumes: &'v Volumes<R>) -> Self {
+        Self {
+            volumes,
+            handles: RwLock::new(BTreeMap::new()),
+            next: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<R: RelaxStrategy> Scheme for WatchScheme<'_, R> {
+    fn open(&self, path: &str, flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        if flags & O_CREAT == O_CREAT || flags & O_ACCMODE != O_RDONLY {
+            return Err(Error::new(EROFS));
+        }
+
+        let path = path.trim_start_matches('/');
+        let (volume, dir) = path.split_once('/').unwrap_or((path, ""));
+        if volume.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let dir = Path::new("/").join(dir);
+        let watch = Watch::new(self.volumes, volume, &Credential::new(uid, gid), &dir)?;
+
+        let fd = self.next.fetch_add(1, Ordering::Relaxed);
+        self.handles.write().insert(fd, watch);
+        Ok(fd as usize)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut syscall::Stat) -> Result<usize> {
+        let handles = self.handles.read();
+        let watch = handles.get(&(id as u64)).ok_or(Error::new(EBADF))?;
+
+        stat.st_mode = MODE_CHR | 0o444;
+        stat.st_size = watch.pending().try_into().unwrap_or(u64::MAX);
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        if self.handles.write().remove(&(id as u64)).is_none() {
+            return Err(Error::new(EBADF));
+        }
+        Ok(0)
+    }
+
+    fn write(&self, _id: usize, _buf: &[u8]) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+        let handles = self.handles.read();
+        let watch = handles.get(&(id as u64)).ok_or(Error::new(EBADF))?;
+
+        match watch.pending() {
+            0 => Ok(EventFlags::empty()),
+            _ => Ok(EventFlags::EVENT_READ),
+        }
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handles = self.handles.read();
+        let watch = handles.get(&(id as u64)).ok_or(Error::new(EBADF))?;
+
+        let events = watch.events(buf.len())?;
+        if events.is_empty() {
+            return match watch.pending() {
+                0 => Err(Error::new(EAGAIN)),
+                _ => Err(Error::new(EINVAL)),
+            };
+        }
+
+        let mut out = Vec::with_capacity(buf.len());
+        for event in &events {
+            event.write(&mut out);
+        }
+        buf[..out.len()].copy_from_slice(&out);
+        Ok(out.len())
+    }
+}
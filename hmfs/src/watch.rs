@@ -0,0 +1,275 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+use syscall::{Error, Result, EBADF, EIO};
+
+use super::format::{put_bytes, Reader};
+
+// Change notifications, in the manner of inotify. A watch on a directory sees every entry
+// created, deleted, modified or renamed anywhere below it, reported by the path `read_dir` would
+// show it under from the root, and follows the directory if it's renamed. Each watch queues its
+// own events until they're taken; a queue that fills up is replaced by a single `Overflow`,
+// after which the watcher has to look at the tree again to find out what it missed. Rolling
+// the tree back to a snapshot changes too much at once to report entry by entry, so it's an
+// `Overflow` as well.
+//
+// Watches belong to whoever is watching rather than to the volume, so they're never written
+// out, and what's in a volume doesn't depend on them.
+//
+// An event is written out as its kind as a byte, then its path, then for a rename the path it
+// was renamed from, each path a u32 length and UTF-8.
+
+/// Events a watch queues before it overflows
+pub const QUEUE_LEN: usize = 1024;
+
+/// Identifies a watch on a volume
+pub type WatchId = u64;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[repr(u8)]
+pub enum EventKind {
+    /// An entry was linked, made or given another name
+    Create = 1,
+    /// An entry was unlinked or removed
+    Delete = 2,
+    /// An entry's contents, properties or extended attributes changed
+    Modify = 3,
+    /// An entry moved from one path to another, both below the watched directory, or the
+    /// watched directory itself moved; one moved in or out shows up as `Create` or `Delete`
+    Rename = 4,
+    /// Events were lost, and the tree has to be looked at again; the path is the watched
+    /// directory
+    Overflow = 5,
+    /// The watched directory itself was removed, and the watch won't see anything again
+    Gone = 6,
+}
+
+impl EventKind {
+    fn from_u8(kind: u8) -> Result<Self> {
+        Ok(match kind {
+            1 => Self::Create,
+            2 => Self::Delete,
+            3 => Self::Modify,
+            4 => Self::Rename,
+            5 => Self::Overflow,
+            6 => Self::Gone,
+            _ => return Err(Error::new(EIO)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Event {
+    pub kind: EventKind,
+    pub path: String,
+    /// Where a renamed entry was before
+    pub from: Option<String>,
+}
+
+impl Event {
+    fn new(kind: EventKind, path: String) -> Self {
+        Self {
+            kind,
+            path,
+            from: None,
+        }
+    }
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.kind as u8);
+        put_bytes(out, self.path.as_bytes());
+        if let Some(from) = &self.from {
+            put_bytes(out, from.as_bytes());
+        }
+    }
+    /// Reads one event as `write` wrote it, failing with `EIO` if it's cut short or damaged
+    pub fn read(reader: &mut Reader) -> Result<Self> {
+        let kind = EventKind::from_u8(reader.u8()?)?;
+        let path = String::from(reader.str()?);
+        let from = match kind {
+            EventKind::Rename => Some(String::from(reader.str()?)),
+            _ => None,
+        };
+
+        Ok(Self { kind, path, from })
+    }
+    /// Bytes `write` writes
+    pub fn size(&self) -> usize {
+        1 + 4 + self.path.len() + self.from.as_ref().map_or(0, |from| 4 + from.len())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Watch {
+    /// Stored names of the watched directory, or `None` once it's gone
+    dir: Option<Vec<String>>,
+    /// Shown path of the watched directory
+    shown: String,
+    queue: VecDeque<Event>,
+}
+
+impl Watch {
+    fn covers(&self, stored: &[String]) -> bool {
+        self.dir.as_ref().is_some_and(|dir| stored.starts_with(dir))
+    }
+    // queues `event`, unless the queue has already overflowed; the last event a watch sees,
+    // `Gone`, always makes it in
+    fn push(&mut self, event: Event) {
+        if event.kind != EventKind::Gone {
+            if matches!(self.queue.back(), Some(last) if last.kind == EventKind::Overflow) {
+                return;
+            }
+            if self.queue.len() + 1 >= QUEUE_LEN {
+                self.queue.clear();
+                self.queue
+                    .push_back(Event::new(EventKind::Overflow, self.shown.clone()));
+                return;
+            }
+        }
+
+        self.queue.push_back(event);
+    }
+}
+
+/// The watches on one volume, kept by `RootEntry`
+#[derive(Debug, Clone, Default)]
+pub struct Watches {
+    watches: BTreeMap<WatchId, Watch>,
+    next: WatchId,
+}
+
+// watches aren't part of the volume
+impl PartialEq for Watches {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Watches {}
+
+impl Hash for Watches {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+impl Watches {
+    /// Starts watching the directory at the stored names `dir`, shown as `shown`
+    pub fn add(&mut self, dir: Vec<String>, shown: String) -> WatchId {
+        let id = self.next;
+        self.next += 1;
+        self.watches.insert(
+            id,
+            Watch {
+                dir: Some(dir),
+                shown,
+                queue: VecDeque::new(),
+            },
+        );
+        id
+    }
+    /// Stops a watch and drops whatever it still had queued, failing with `EBADF` if there's
+    /// no such watch
+    pub fn remove(&mut self, id: WatchId) -> Result<()> {
+        self.watches.remove(&id).map(drop).ok_or(Error::new(EBADF))
+    }
+    /// Takes the events queued on a watch, oldest first, as many as fit in `max` bytes written
+    /// out
+    pub fn take(&mut self, id: WatchId, max: usize) -> Result<Vec<Event>> {
+        let watch = self.watches.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let mut taken = Vec::new();
+        let mut len = 0;
+        while let Some(event) = watch.queue.front() {
+            len += event.size();
+            if len > max {
+                break;
+            }
+            taken.extend(watch.queue.pop_front());
+        }
+        Ok(taken)
+    }
+    /// Events queued on a watch
+    pub fn pending(&self, id: WatchId) -> Result<usize> {
+        self.watches
+            .get(&id)
+            .map(|watch| watch.queue.len())
+            .ok_or(Error::new(EBADF))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+    /// Whether any watch sees changes to the entry at the stored names `stored`
+    pub fn covers(&self, stored: &[String]) -> bool {
+        self.watches.values().any(|watch| watch.covers(stored))
+    }
+    /// Reports `kind` at the entry at the stored names `stored`, shown as `path`, to every
+    /// watch that sees it
+    pub fn notify(&mut self, kind: EventKind, stored: &[String], path: &str) {
+        for watch in self.watches.values_mut() {
+            if watch.covers(stored) {
+                watch.push(Event::new(kind, String::from(path)));
+            }
+        }
+    }
+    /// Reports the entry at `from` moving to `to`, each as stored names and a shown path, and
+    /// has watches on it or anything below it follow it there. A watched directory it replaced
+    /// is gone.
+    pub fn renamed(&mut self, from: (&[String], &str), to: (&[String], &str)) {
+        for watch in self.watches.values_mut() {
+            // one replaced is gone, below, and one moved sees itself move wherever it goes
+            if watch.dir.as_deref() == Some(to.0) {
+                continue;
+            }
+            let own = watch.dir.as_deref() == Some(from.0);
+            match (watch.covers(from.0), own || watch.covers(to.0)) {
+                (true, true) => watch.push(Event {
+                    kind: EventKind::Rename,
+                    path: String::from(to.1),
+                    from: Some(String::from(from.1)),
+                }),
+                (true, false) => watch.push(Event::new(EventKind::Delete, String::from(from.1))),
+                (false, true) => watch.push(Event::new(EventKind::Create, String::from(to.1))),
+                (false, false) => {}
+            }
+        }
+
+        self.removed(to.0);
+        for watch in self.watches.values_mut() {
+            let Some(dir) = &mut watch.dir else {
+                continue;
+            };
+            if dir.starts_with(from.0) {
+                let below = dir.split_off(from.0.len());
+                *dir = to.0.iter().cloned().chain(below).collect();
+                let shown_below = watch.shown.strip_prefix(from.1).unwrap_or_default();
+                watch.shown = [to.1, shown_below].concat();
+            }
+        }
+    }
+    /// Ends the watches on the directory at the stored names `stored`, which was removed
+    pub fn removed(&mut self, stored: &[String]) {
+        for watch in self.watches.values_mut() {
+            if watch.dir.as_deref() == Some(stored) {
+                watch.dir = None;
+                watch.push(Event::new(EventKind::Gone, watch.shown.clone()));
+            }
+        }
+    }
+    /// Tells every watch to look at the tree again, after a change too big to report; watches
+    /// on directories that `exists` says aren't there any more are gone
+    pub fn overflow(&mut self, exists: impl Fn(&[String]) -> bool) {
+        for watch in self.watches.values_mut() {
+            let Some(dir) = &watch.dir else {
+                continue;
+            };
+
+            let kind = match exists(dir) {
+                true => EventKind::Overflow,
+                false => EventKind::Gone,
+            };
+            if kind == EventKind::Gone {
+                watch.dir = None;
+            }
+            watch.push(Event::new(kind, watch.shown.clone()));
+        }
+    }
+}
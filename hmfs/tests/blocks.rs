@@ -0,0 +1,198 @@
+mod common;
+
+use common::*;
+use hmfs::blocks::{FileBlocks, Plain, BLOCK_SIZE};
+use hmfs::perm::Credential;
+use hmfs::{format, RootEntry};
+use syscall::{EACCES, EISDIR, EROFS};
+
+#[test]
+fn matches_a_vec() {
+    let mut model: Vec<u8> = Vec::new();
+    let mut blocks = FileBlocks::default();
+    let mut seed = 12345u64;
+    let mut random = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+
+    for _ in 0..400 {
+        match random() % 3 {
+            0 => {
+                let offset = (random() % 20000) as usize;
+                let len = (random() % 9000) as usize;
+                let zeroes = random() % 4 == 0;
+                let buf: Vec<u8> = (0..len)
+                    .map(|i| if zeroes { 0 } else { (i + offset) as u8 | 1 })
+                    .collect();
+                blocks.write_at(offset as u64, &buf, &Plain).unwrap();
+                if len > 0 && model.len() < offset + len {
+                    model.resize(offset + len, 0);
+                }
+                model[offset..offset + len].copy_from_slice(&buf);
+            }
+            1 => {
+                let len = random() % 20000;
+                blocks.truncate(len, &Plain).unwrap();
+                model.resize(len as usize, 0);
+            }
+            _ => {
+                let offset = (random() % 22000) as usize;
+                let len = (random() % 9000) as usize;
+                let mut buf = vec![0xaa; len];
+                let read = blocks.read_at(offset as u64, &mut buf, &Plain).unwrap();
+                let want = model.get(offset..).unwrap_or(&[]);
+                assert_eq!(&buf[..read], &want[..want.len().min(len)]);
+            }
+        }
+        assert_eq!(blocks.len(), model.len() as u64);
+        assert_eq!(blocks.to_vec(&Plain).unwrap(), model);
+        assert!(blocks
+            .blocks()
+            .all(|(_, b)| !b.data().is_empty() && b.data().len() as u64 <= BLOCK_SIZE));
+    }
+
+    // a hole costs nothing
+    let mut sparse = FileBlocks::default();
+    sparse.write_at(1 << 30, b"x", &Plain).unwrap();
+    assert_eq!(sparse.blocks().len(), 1);
+    assert_eq!(sparse.len(), (1 << 30) + 1);
+
+    // and writing nothing, wherever, leaves the file be
+    assert_eq!(sparse.write_at(1 << 40, &[], &Plain).unwrap(), 0);
+    assert_eq!(sparse.blocks().len(), 1);
+    assert_eq!(sparse.len(), (1 << 30) + 1);
+}
+
+#[test]
+fn writes_share_untouched_blocks() {
+    let c = cwd();
+    let big: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8 + 1).collect();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/big"), props("x"), file(&big))
+        .unwrap();
+    root.snapshot(&ROOT, "s".into(), 1).unwrap();
+
+    root.write_at(&ROOT, c, path("/big"), 5000, b"HELLO")
+        .unwrap();
+    root.truncate(&ROOT, c, path("/big"), 60000).unwrap();
+    let mut want = big.clone();
+    want[5000..5005].copy_from_slice(b"HELLO");
+    want.resize(60000, 0);
+    assert_eq!(root.read_file(&ROOT, c, path("/big")).unwrap(), want);
+    let mut buf = [0; 10];
+    assert_eq!(
+        root.read_at(&ROOT, c, path("/big"), 4998, &mut buf)
+            .unwrap(),
+        10
+    );
+    assert_eq!(buf, want[4998..5008]);
+    assert_eq!(
+        errno(root.write_at(&ROOT, c, path("/"), 0, b"x")),
+        Err(EISDIR)
+    );
+
+    // the ten blocks of the snapshot and the one that changed, the zeroes past the end as a hole
+    let bytes = round_trip(&root);
+    let (superblock, _) = format::read_records(&bytes).unwrap();
+    assert_eq!(superblock.data_len, 40000 + BLOCK_SIZE);
+    let back = format::decode(&bytes).unwrap();
+    assert_eq!(back.read_file(&ROOT, c, path("/big")).unwrap(), want);
+    let snapshot = back.mount_snapshot("s").unwrap();
+    assert_eq!(snapshot.read_file(&ROOT, c, path("/big")).unwrap(), big);
+}
+
+#[test]
+fn checksummed_per_block() {
+    let c = cwd();
+    let block = BLOCK_SIZE as usize;
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/f"), props("x"), file(&noise(3 * block, 1)))
+        .unwrap();
+
+    // each block stored, by index and checksum
+    let stored = |root: &RootEntry| {
+        let bytes = round_trip(root);
+        let (_, records) = format::read_records(&bytes).unwrap();
+        records
+            .iter()
+            .find_map(|record| match &record.payload {
+                format::Payload::File { blocks, .. } => Some(
+                    blocks
+                        .iter()
+                        .map(|block| (block.index, block.checksum))
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            })
+            .unwrap()
+    };
+
+    // a write only changes the checksum of the block it lands in
+    let before = stored(&root);
+    root.write_at(&ROOT, c, path("/f"), BLOCK_SIZE + 10, b"changed")
+        .unwrap();
+    let after = stored(&root);
+    assert_eq!(
+        before.iter().map(|(index, _)| *index).collect::<Vec<_>>(),
+        [0, 1, 2]
+    );
+    assert_eq!(before[0], after[0]);
+    assert_ne!(before[1], after[1]);
+    assert_eq!(before[2], after[2]);
+
+    // growing a file leaves a hole that reads as zeroes, and past the end reads nothing
+    root.truncate(&ROOT, c, path("/f"), 10 * BLOCK_SIZE)
+        .unwrap();
+    assert_eq!(stored(&root), after);
+    let mut buf = [0xaa; 8];
+    assert_eq!(
+        root.read_at(&ROOT, c, path("/f"), 5 * BLOCK_SIZE, &mut buf)
+            .unwrap(),
+        8
+    );
+    assert_eq!(buf, [0; 8]);
+    assert_eq!(
+        root.read_at(&ROOT, c, path("/f"), 10 * BLOCK_SIZE, &mut buf)
+            .unwrap(),
+        0
+    );
+
+    // and shrinking one drops the blocks past the new end
+    root.truncate(&ROOT, c, path("/f"), BLOCK_SIZE).unwrap();
+    assert_eq!(stored(&root), after[..1]);
+}
+
+#[test]
+fn empty_writes_change_nothing() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/f"), props("x"), file(b"hello"))
+        .unwrap();
+    root.snapshot(&ROOT, "s".into(), 1).unwrap();
+    let before = checksum(&root);
+
+    assert_eq!(
+        root.write_at(&ROOT, c, path("/f"), 1 << 20, &[]).unwrap(),
+        0
+    );
+    assert_eq!(root.read_file(&ROOT, c, path("/f")).unwrap(), b"hello");
+    assert_eq!(checksum(&root), before);
+
+    // but are still checked like any other
+    assert_eq!(
+        errno(root.write_at(&ROOT, c, path("/"), 0, &[])),
+        Err(EISDIR)
+    );
+    assert_eq!(
+        errno(root.write_at(&Credential::new(7, 7), c, path("/f"), 0, &[])),
+        Err(EACCES)
+    );
+    let mut snapshot = root.mount_snapshot("s").unwrap();
+    assert_eq!(
+        errno(snapshot.write_at(&ROOT, c, path("/f"), 0, &[])),
+        Err(EROFS)
+    );
+}
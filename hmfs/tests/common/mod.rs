@@ -0,0 +1,98 @@
+// Helpers shared by the test files; not every file uses all of them
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use hmfs::blocks::FileBlocks;
+use hmfs::checksum::Checksum;
+use hmfs::perm::Credential;
+use hmfs::scrub::{Damage, Scrub};
+use hmfs::{detached, format, new_map_shorthand, Entry, EntryKind, Properties, RootEntry};
+use unix_path::Path;
+
+pub const ROOT: Credential = Credential::ROOT;
+
+/// The working directory every test resolves against
+pub fn cwd() -> &'static Path {
+    Path::new("/")
+}
+
+pub fn path(path: &str) -> &Path {
+    Path::new(path)
+}
+
+/// Properties for a new entry; the name is replaced by the last component of the path it's
+/// linked at
+pub fn props(name: &str) -> Properties<'static> {
+    props_with(name, 0o755, 0, 0)
+}
+
+pub fn props_with(name: &str, mode: u32, uid: u32, gid: u32) -> Properties<'static> {
+    Properties::new(
+        name.into(),
+        detached(),
+        None,
+        mode,
+        "root".into(),
+        0,
+        0,
+        "root".into(),
+        uid,
+        gid,
+    )
+}
+
+pub fn dir() -> Entry<'static> {
+    Entry::new(EntryKind::Directory(Arc::new(new_map_shorthand())), None)
+}
+
+pub fn file(data: &[u8]) -> Entry<'static> {
+    Entry::new(EntryKind::File(FileBlocks::from_bytes(data)), None)
+}
+
+pub fn symlink(target: &str) -> Entry<'static> {
+    Entry::new(EntryKind::Symlink(target.into()), None)
+}
+
+/// The errno of a failed call, so expected failures compare with `assert_eq!`
+pub fn errno<T>(result: syscall::Result<T>) -> Result<T, i32> {
+    result.map_err(|e| e.errno)
+}
+
+/// Bytes that don't compress or repeat, the same ones for the same seed
+pub fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            (x >> 32) as u8
+        })
+        .collect()
+}
+
+pub fn checksum(root: &RootEntry) -> Checksum {
+    root.get_root_dir().checksum()
+}
+
+pub fn scrub(root: &RootEntry) -> Vec<Damage> {
+    let mut damage = Vec::new();
+    Scrub::new(root).run(&mut |d| damage.push(d));
+    damage
+}
+
+/// Encodes `root` and checks that the image decodes to a tree that encodes to the same bytes,
+/// with the checksum it was encoded with
+pub fn round_trip(root: &RootEntry) -> Vec<u8> {
+    let bytes = format::encode(root);
+    {
+        let back = format::decode(&bytes).unwrap();
+        assert_eq!(format::encode(&back), bytes);
+        assert_eq!(checksum(&back), checksum(root));
+
+        let (superblock, records) = format::read_records(&bytes).unwrap();
+        assert_eq!(superblock.checksum, records[0].checksum);
+    }
+    bytes
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use common::*;
+use hmfs::blocks::{BlockCodec, Plain};
+use hmfs::compress::{Compression, Usage};
+use hmfs::{format, EntryKind, RootEntry};
+use syscall::{EIO, EPERM};
+
+/// Bytes of `path` as stored, after compression and encryption
+fn stored(root: &RootEntry, at: &str) -> usize {
+    let (_, entry) = root.resolve(&ROOT, cwd(), path(at)).unwrap();
+    let EntryKind::File(blocks) = entry.kind() else {
+        panic!("{at} isn't a file")
+    };
+    blocks.blocks().map(|(_, b)| b.data().len()).sum()
+}
+
+fn text() -> Vec<u8> {
+    b"the quick brown fox jumps over the lazy dog\n"
+        .iter()
+        .cycle()
+        .take(20000)
+        .copied()
+        .collect()
+}
+
+#[test]
+fn compressed_files() {
+    let c = cwd();
+    let text = text();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/t"), props("x"), file(&text))
+        .unwrap();
+    let plain = stored(&root, "/t");
+    root.set_compression(&ROOT, c, path("/t"), Compression::Deflate)
+        .unwrap();
+    assert!(stored(&root, "/t") * 10 < plain);
+    assert_eq!(root.read_file(&ROOT, c, path("/t")).unwrap(), text);
+    assert_eq!(
+        errno(root.set_xattr(
+            &ROOT,
+            c,
+            path("/t"),
+            "hmfs.compress".into(),
+            b"deflate".to_vec()
+        )),
+        Err(EPERM)
+    );
+    assert_eq!(
+        errno(root.remove_xattr(&ROOT, c, path("/t"), "hmfs.compress")),
+        Err(EPERM)
+    );
+
+    // writes across a block boundary and truncation go through the codec
+    root.write_at(&ROOT, c, path("/t"), 4090, b"HELLO-ACROSS-A-BLOCK")
+        .unwrap();
+    let mut want = text.clone();
+    want[4090..4110].copy_from_slice(b"HELLO-ACROSS-A-BLOCK");
+    assert_eq!(root.read_file(&ROOT, c, path("/t")).unwrap(), want);
+    root.truncate(&ROOT, c, path("/t"), 5000).unwrap();
+    want.truncate(5000);
+    let mut buf = vec![0; 100];
+    assert_eq!(
+        root.read_at(&ROOT, c, path("/t"), 4080, &mut buf).unwrap(),
+        100
+    );
+    assert_eq!(buf, want[4080..4180]);
+
+    let bytes = round_trip(&root);
+    let mut back = format::decode(&bytes).unwrap();
+    assert_eq!(back.read_file(&ROOT, c, path("/t")).unwrap(), want);
+    assert!(scrub(&back).is_empty());
+
+    // turning it off stores the blocks as they are again
+    back.set_compression(&ROOT, c, path("/t"), Compression::Off)
+        .unwrap();
+    assert_eq!(stored(&back, "/t"), want.len());
+    assert_eq!(back.read_file(&ROOT, c, path("/t")).unwrap(), want);
+}
+
+#[test]
+fn inherited_from_directories() {
+    let c = cwd();
+    let text = text();
+    let random = noise(9000, 0x2545f4914f6cdd1d);
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/z"), props("x"), dir()).unwrap();
+    root.set_compression(&ROOT, c, path("/z"), Compression::Deflate)
+        .unwrap();
+    root.link(&ROOT, c, path("/z/n"), props("x"), file(&random))
+        .unwrap();
+    root.link(&ROOT, c, path("/z/t"), props("x"), file(&text))
+        .unwrap();
+    root.link(&ROOT, c, path("/z/s"), props("x"), dir())
+        .unwrap();
+    root.link(&ROOT, c, path("/z/s/t"), props("x"), file(&text))
+        .unwrap();
+    assert!(stored(&root, "/z/t") * 10 < text.len());
+    assert!(stored(&root, "/z/s/t") * 10 < text.len());
+
+    // a block that doesn't compress costs a byte
+    assert_eq!(stored(&root, "/z/n"), random.len() + 3);
+    assert_eq!(root.read_file(&ROOT, c, path("/z/n")).unwrap(), random);
+    let usage = Usage::of(&root.get_root_dir());
+    assert_eq!(usage.files, 3);
+    assert!(usage.ratio() > 2.0);
+
+    // encrypted as well as compressed
+    root.link(&ROOT, c, path("/z/e"), props("x"), dir())
+        .unwrap();
+    root.encrypt(&ROOT, c, path("/z/e"), b"pw").unwrap();
+    root.link(&ROOT, c, path("/z/e/t"), props("x"), file(&text))
+        .unwrap();
+    assert!(stored(&root, "/z/e/t") * 5 < text.len());
+    let bytes = format::encode(&root);
+    let mut back = format::decode(&bytes).unwrap();
+    back.unlock(&ROOT, c, path("/z/e"), b"pw").unwrap();
+    assert_eq!(back.read_file(&ROOT, c, path("/z/e/t")).unwrap(), text);
+}
+
+#[test]
+fn corrupt_streams() {
+    let codec = Compression::Deflate.codec(&Plain);
+    assert_eq!(errno(codec.decode(0, &[1, 0xff, 0xff, 0xff])), Err(EIO));
+    assert_eq!(errno(codec.decode(0, &[7])), Err(EIO));
+    assert_eq!(errno(codec.decode(0, &[])), Err(EIO));
+    assert_eq!(
+        codec.decode(0, &codec.encode(0, vec![0; 4096])).unwrap(),
+        vec![0; 4096]
+    );
+
+    // a stream that inflates past a block is refused, not allocated
+    let bomb = codec.encode(0, vec![0; 100000]);
+    assert!(bomb.len() < 1000);
+    assert_eq!(errno(codec.decode(0, &bomb)), Err(EIO));
+}
@@ -0,0 +1,369 @@
+mod common;
+
+use common::*;
+use hmfs::blocks::BLOCK_SIZE;
+use hmfs::crypt::{CryptParams, MAX_COST, SALT_LEN};
+use hmfs::{format, EntryKind, RootEntry};
+use syscall::{EEXIST, EINVAL, EIO, EKEYREJECTED, ENOENT, ENOKEY, EPERM, EXDEV};
+
+#[test]
+fn encrypted_directory() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/s"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/plain"), props("x"), dir())
+        .unwrap();
+    assert_eq!(errno(root.encrypt(&ROOT, c, path("/"), b"pw")), Err(EINVAL));
+    root.encrypt(&ROOT, c, path("/s"), b"pw").unwrap();
+    assert_eq!(
+        errno(root.encrypt(&ROOT, c, path("/s"), b"pw")),
+        Err(EEXIST)
+    );
+    assert_eq!(
+        errno(root.set_xattr(&ROOT, c, path("/plain"), "hmfs.crypt".into(), vec![])),
+        Err(EPERM)
+    );
+
+    root.link(
+        &ROOT,
+        c,
+        path("/s/secret.txt"),
+        props("x"),
+        file(b"hello world"),
+    )
+    .unwrap();
+    root.link(&ROOT, c, path("/s/sub"), props("x"), dir())
+        .unwrap();
+    root.link(
+        &ROOT,
+        path("/s/sub"),
+        path("deep"),
+        props("x"),
+        file(b"deep"),
+    )
+    .unwrap();
+    assert_eq!(
+        root.read_file(&ROOT, c, path("/s/secret.txt")).unwrap(),
+        b"hello world"
+    );
+    assert_eq!(
+        root.read_file(&ROOT, c, path("/s/sub/deep")).unwrap(),
+        b"deep"
+    );
+    let names: Vec<_> = root
+        .read_dir(&ROOT, c, path("/s"))
+        .unwrap()
+        .into_iter()
+        .map(|(props, _)| props.name().to_string())
+        .collect();
+    assert_eq!(names, ["secret.txt", "sub"]);
+
+    // what's stored is a tag and the ciphertext, sealed with a nonce of the file's own
+    let (_, secret) = root.resolve(&ROOT, c, path("/s/secret.txt")).unwrap();
+    let EntryKind::File(blocks) = secret.kind() else {
+        panic!("not a file")
+    };
+    let stored = blocks.blocks().next().unwrap().1.data();
+    assert_eq!(stored.len(), 16 + 11);
+    assert_ne!(&stored[16..], b"hello world");
+    assert_eq!(secret.xattr("hmfs.nonce").map(<[u8]>::len), Some(16));
+    assert_eq!(
+        errno(root.set_xattr(
+            &ROOT,
+            c,
+            path("/s/secret.txt"),
+            "hmfs.nonce".into(),
+            vec![0; 16]
+        )),
+        Err(EPERM)
+    );
+
+    // so the same contents in another file are stored differently
+    root.link(&ROOT, c, path("/s/copy"), props("x"), file(b"hello world"))
+        .unwrap();
+    let (_, copy) = root.resolve(&ROOT, c, path("/s/copy")).unwrap();
+    let EntryKind::File(copied) = copy.kind() else {
+        panic!("not a file")
+    };
+    assert_ne!(copied.blocks().next().unwrap().1.data(), stored);
+    assert_eq!(
+        root.read_file(&ROOT, c, path("/s/copy")).unwrap(),
+        b"hello world"
+    );
+    root.unlink(&ROOT, c, path("/s/copy")).unwrap();
+
+    // entries can move around inside it, but not out
+    assert_eq!(
+        errno(root.rename(&ROOT, c, path("/s/sub"), path("/plain/sub"))),
+        Err(EXDEV)
+    );
+    root.rename(&ROOT, c, path("/s/sub/deep"), path("/s/moved"))
+        .unwrap();
+    assert_eq!(root.read_file(&ROOT, c, path("/s/moved")).unwrap(), b"deep");
+
+    // neither names nor contents make it to the image
+    let bytes = format::encode(&root);
+    assert!(!bytes.windows(10).any(|w| w == b"secret.txt"));
+    assert!(!bytes.windows(11).any(|w| w == b"hello world"));
+
+    // and it's locked once read back
+    let mut back = format::decode(&bytes).unwrap();
+    let listing = back.read_dir(&ROOT, c, path("/s")).unwrap();
+    assert!(listing.iter().all(|(props, _)| props.name().len() > 32));
+    assert_eq!(
+        errno(back.resolve(&ROOT, c, path("/s/secret.txt"))).err(),
+        Some(ENOENT)
+    );
+    let (hidden, _) = listing
+        .iter()
+        .find(|(_, entry)| !entry.kind().is_dir())
+        .unwrap();
+    let hidden = format!("/s/{}", hidden.name());
+    assert_eq!(
+        errno(back.read_file(&ROOT, c, path(&hidden))).err(),
+        Some(ENOKEY)
+    );
+    assert_eq!(
+        errno(back.link(&ROOT, c, path("/s/new"), props("x"), file(b""))),
+        Err(ENOKEY)
+    );
+    assert_eq!(
+        errno(back.unlock(&ROOT, c, path("/s"), b"wrong")),
+        Err(EKEYREJECTED)
+    );
+    assert_eq!(
+        errno(back.unlock(&ROOT, c, path("/plain"), b"pw")),
+        Err(EINVAL)
+    );
+    back.unlock(&ROOT, c, path("/s"), b"pw").unwrap();
+    assert_eq!(
+        back.read_file(&ROOT, c, path("/s/secret.txt")).unwrap(),
+        b"hello world"
+    );
+    back.lock(&ROOT, c, path("/s")).unwrap();
+    assert_eq!(errno(back.lock(&ROOT, c, path("/s"))), Err(ENOKEY));
+}
+
+#[test]
+fn tampered_ciphertext() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/s"), props("x"), dir()).unwrap();
+    root.encrypt(&ROOT, c, path("/s"), b"pw").unwrap();
+    root.link(&ROOT, c, path("/s/f"), props("x"), file(b"hello world"))
+        .unwrap();
+
+    let (_, secret) = root.resolve(&ROOT, c, path("/s/f")).unwrap();
+    let EntryKind::File(blocks) = secret.kind() else {
+        panic!("not a file")
+    };
+    let ciphertext = blocks.blocks().next().unwrap().1.data().to_vec();
+    let mut bytes = format::encode(&root);
+    let at = bytes
+        .windows(ciphertext.len())
+        .position(|w| w == ciphertext)
+        .unwrap();
+    bytes[at + 20] ^= 1;
+
+    // the block no longer matches its checksum, or else it doesn't decrypt
+    let back = format::decode(&bytes);
+    if let Ok(mut back) = back {
+        back.unlock(&ROOT, c, path("/s"), b"pw").unwrap();
+        assert!(back.read_file(&ROOT, c, path("/s/f")).is_err());
+    }
+}
+
+#[test]
+fn bounded_cost() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/s"), props("x"), dir()).unwrap();
+    root.encrypt(&ROOT, c, path("/s"), b"pw").unwrap();
+    let (_, s) = root.resolve(&ROOT, c, path("/s")).unwrap();
+    let stored = s.xattr("hmfs.crypt").unwrap().to_vec();
+    let params = CryptParams::from_bytes(&stored).unwrap();
+    assert_eq!(params.to_bytes(), stored);
+
+    // an image can ask for up to the most there is, and not a bit more
+    let most = [MAX_COST.memory, MAX_COST.passes, MAX_COST.lanes];
+    for (field, max) in most.into_iter().enumerate() {
+        let at = SALT_LEN + 4 * field;
+        let mut bytes = stored.clone();
+        bytes[at..at + 4].copy_from_slice(&max.to_le_bytes());
+        assert!(CryptParams::from_bytes(&bytes).is_ok());
+        for over in [max + 1, u32::MAX] {
+            bytes[at..at + 4].copy_from_slice(&over.to_le_bytes());
+            assert_eq!(errno(CryptParams::from_bytes(&bytes)), Err(EIO));
+        }
+    }
+}
+
+// the image `bytes` with its records rewritten by `f`, as anyone could without the key;
+// checksums are left as they were, which nothing holds them up against short of a scrub
+fn tamper(bytes: &[u8], f: impl FnOnce(&mut Vec<format::Record>)) -> Vec<u8> {
+    let (superblock, mut records) = format::read_records(bytes).unwrap();
+    f(&mut records);
+
+    let mut table = Vec::new();
+    for record in &records {
+        record.write(&mut table);
+    }
+    assert_eq!(table.len() as u64, superblock.table_len);
+    let mut out = bytes.to_vec();
+    let at = superblock.table_offset as usize;
+    out[at..at + table.len()].copy_from_slice(&table);
+    out
+}
+
+fn unlocked(bytes: &[u8]) -> RootEntry<'_> {
+    let mut root = format::decode(bytes).unwrap();
+    root.unlock(&ROOT, cwd(), path("/s"), b"pw").unwrap();
+    root
+}
+
+#[test]
+fn names_bound_to_their_directory() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/s"), props("x"), dir()).unwrap();
+    root.encrypt(&ROOT, c, path("/s"), b"pw").unwrap();
+    for name in ["/s/a", "/s/b"] {
+        root.link(&ROOT, c, path(name), props("x"), dir()).unwrap();
+    }
+    root.link(&ROOT, c, path("/s/a/one"), props("x"), file(b"1"))
+        .unwrap();
+    root.link(&ROOT, c, path("/s/b/two"), props("x"), file(b"2"))
+        .unwrap();
+    root.link(&ROOT, c, path("/s/b/one"), props("x"), file(b"3"))
+        .unwrap();
+
+    // the same name is stored differently in each directory
+    let stored = |root: &RootEntry, dir: &str| {
+        let (_, dir) = root.resolve(&ROOT, c, path(dir)).unwrap();
+        let EntryKind::Directory(map) = dir.kind() else {
+            panic!("not a directory")
+        };
+        map.iter()
+            .map(|(props, _)| props.name().to_string())
+            .collect::<Vec<_>>()
+    };
+    let (a, b) = (stored(&root, "/s/a"), stored(&root, "/s/b"));
+    assert!(a.iter().all(|name| !b.contains(name)));
+
+    // and renaming one seals it anew for where it goes
+    root.rename(&ROOT, c, path("/s/a/one"), path("/s/b/moved"))
+        .unwrap();
+    root.rename(&ROOT, c, path("/s/b/two"), path("/s/a/two"))
+        .unwrap();
+    assert_eq!(root.read_file(&ROOT, c, path("/s/b/moved")).unwrap(), b"1");
+    let bytes = format::encode(&root);
+    let back = unlocked(&bytes);
+    assert_eq!(back.read_file(&ROOT, c, path("/s/a/two")).unwrap(), b"2");
+
+    // so a name moved to another directory behind the key's back doesn't open there
+    let moved = tamper(&bytes, |records| {
+        let one_byte: Vec<_> = (0..records.len())
+            .filter(|&i| matches!(records[i].payload, format::Payload::File { len: 1, .. }))
+            .collect();
+        let [first, .., last] = one_byte[..] else {
+            panic!("no files")
+        };
+        let properties = records[first].properties;
+        records[first].properties = records[last].properties;
+        records[last].properties = properties;
+    });
+    let back = unlocked(&moved);
+    assert_eq!(
+        errno(back.read_dir(&ROOT, c, path("/s/a"))).err(),
+        Some(EIO)
+    );
+    assert_eq!(
+        errno(back.read_dir(&ROOT, c, path("/s/b"))).err(),
+        Some(EIO)
+    );
+}
+
+#[test]
+fn files_bound_to_their_length_and_blocks() {
+    let c = cwd();
+    let block = BLOCK_SIZE as usize;
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/s"), props("x"), dir()).unwrap();
+    root.encrypt(&ROOT, c, path("/s"), b"pw").unwrap();
+    root.link(
+        &ROOT,
+        c,
+        path("/s/f"),
+        props("x"),
+        file(&noise(3 * block, 1)),
+    )
+    .unwrap();
+    root.snapshot(&ROOT, "old".into(), 1).unwrap();
+    root.write_at(&ROOT, c, path("/s/f"), BLOCK_SIZE, b"new")
+        .unwrap();
+    root.truncate(&ROOT, c, path("/s/f"), 3 * BLOCK_SIZE - 10)
+        .unwrap();
+    let want = root.read_file(&ROOT, c, path("/s/f")).unwrap();
+    let (_, live) = root.resolve(&ROOT, c, path("/s/f")).unwrap();
+    assert_eq!(live.xattr("hmfs.tag").map(<[u8]>::len), Some(16));
+    let bytes = format::encode(&root);
+    assert_eq!(
+        unlocked(&bytes).read_file(&ROOT, c, path("/s/f")).unwrap(),
+        want
+    );
+
+    let live = |records: &mut Vec<format::Record>| {
+        let index = records
+            .iter()
+            .position(|record| {
+                matches!(record.payload, format::Payload::File { len, .. } if len == 3 * BLOCK_SIZE - 10)
+            })
+            .unwrap();
+        let format::Payload::File { blocks, .. } = &records[index].payload else {
+            unreachable!()
+        };
+        (index, blocks.clone())
+    };
+
+    // cutting it short
+    let shortened = tamper(&bytes, |records| {
+        let (index, blocks) = live(records);
+        records[index].payload = format::Payload::File {
+            len: 2 * BLOCK_SIZE + 1,
+            blocks,
+        };
+    });
+    // or swapping a block back for the one it replaced, which the snapshot still holds
+    let rolled_back = tamper(&bytes, |records| {
+        let (index, mut blocks) = live(records);
+        let old = records
+            .iter()
+            .find_map(|record| match &record.payload {
+                format::Payload::File { len, blocks } if *len == 3 * BLOCK_SIZE => Some(blocks[1]),
+                _ => None,
+            })
+            .unwrap();
+        assert_ne!(old.checksum, blocks[1].checksum);
+        blocks[1] = old;
+        records[index].payload = format::Payload::File {
+            len: 3 * BLOCK_SIZE - 10,
+            blocks,
+        };
+    });
+    for tampered in [shortened, rolled_back] {
+        let mut back = unlocked(&tampered);
+        assert_eq!(
+            errno(back.read_file(&ROOT, c, path("/s/f"))).err(),
+            Some(EIO)
+        );
+        assert_eq!(
+            errno(back.write_at(&ROOT, c, path("/s/f"), 0, b"x")),
+            Err(EIO)
+        );
+        let snapshot = back.mount_snapshot("old").unwrap();
+        assert_eq!(
+            snapshot.read_file(&ROOT, c, path("/s/f")).unwrap(),
+            noise(3 * block, 1)
+        );
+    }
+}
@@ -0,0 +1,120 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::*;
+use hmfs::perm::Credential;
+use hmfs::{format, EntryKind, RootEntry};
+use syscall::EPERM;
+
+/// Where each block of `path` lives, to tell which files share blocks
+fn blocks(root: &RootEntry, at: &str) -> Vec<usize> {
+    let (_, entry) = root.resolve(&ROOT, cwd(), path(at)).unwrap();
+    let EntryKind::File(blocks) = entry.kind() else {
+        panic!("{at} isn't a file")
+    };
+    blocks
+        .blocks()
+        .map(|(_, b)| Arc::as_ptr(b) as usize)
+        .collect()
+}
+
+#[test]
+fn dedup_volume() {
+    let c = cwd();
+    let a = noise(3 * 4096, 7);
+    let mut b = a.clone();
+    b.extend(noise(4096, 9));
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/d"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/d/a"), props("x"), file(&a))
+        .unwrap();
+    root.link(&ROOT, c, path("/d/b"), props("x"), file(&b))
+        .unwrap();
+    root.link(&ROOT, c, path("/other"), props("x"), file(&a))
+        .unwrap();
+    root.snapshot(&ROOT, "s".into(), 1).unwrap();
+    root.link(&ROOT, c, path("/later"), props("x"), file(&a[..4096]))
+        .unwrap();
+    let before = format::encode(&root);
+    let sum = checksum(&root);
+
+    assert_eq!(errno(root.dedup(&Credential::new(1, 1))).err(), Some(EPERM));
+    let stats = root.dedup(&ROOT).unwrap();
+    // b's first three blocks, other's three and later's one are all a's
+    assert_eq!(stats.merged, 3 + 3 + 1);
+    assert_eq!(stats.saved, 7 * 4096);
+    assert_eq!(stats.blocks, 4);
+    assert_eq!(checksum(&root), sum);
+    assert_eq!(blocks(&root, "/d/a"), blocks(&root, "/other"));
+    assert_eq!(blocks(&root, "/d/a"), blocks(&root, "/d/b")[..3]);
+    assert_eq!(root.dedup(&ROOT).unwrap().merged, 0);
+
+    // the image is smaller by the same, and reads back shared
+    let after = round_trip(&root);
+    assert_eq!(before.len() - after.len(), 7 * 4096);
+    let back = format::decode(&after).unwrap();
+    assert!(!back.dedups());
+    assert_eq!(blocks(&back, "/d/a"), blocks(&back, "/other"));
+    assert_eq!(back.read_file(&ROOT, c, path("/d/b")).unwrap(), b);
+    let snapshot = back.mount_snapshot("s").unwrap();
+    assert_eq!(snapshot.read_file(&ROOT, c, path("/other")).unwrap(), a);
+    assert!(scrub(&back).is_empty());
+}
+
+#[test]
+fn dedup_on_write() {
+    let c = cwd();
+    let a = noise(3 * 4096, 7);
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/a"), props("x"), file(&a))
+        .unwrap();
+    root.snapshot(&ROOT, "s".into(), 1).unwrap();
+
+    assert_eq!(
+        errno(root.set_dedup(&Credential::new(1, 1), true)),
+        Err(EPERM)
+    );
+    assert_eq!(
+        errno(root.set_xattr(&ROOT, c, path("/"), "hmfs.dedup".into(), vec![])),
+        Err(EPERM)
+    );
+    root.set_dedup(&ROOT, true).unwrap();
+    assert!(root.dedups());
+    root.link(&ROOT, c, path("/copy"), props("x"), file(&a))
+        .unwrap();
+    assert_eq!(blocks(&root, "/copy"), blocks(&root, "/a"));
+
+    // blocks are shared as they're filled in, however they're written
+    root.link(&ROOT, c, path("/w"), props("x"), file(&[]))
+        .unwrap();
+    root.write_at(&ROOT, c, path("/w"), 4096, &a[4096..8192])
+        .unwrap();
+    assert_eq!(blocks(&root, "/w"), [blocks(&root, "/a")[1]]);
+    root.write_at(&ROOT, c, path("/w"), 0, &a[..10]).unwrap();
+    root.write_at(&ROOT, c, path("/w"), 10, &a[10..4096])
+        .unwrap();
+    assert_eq!(blocks(&root, "/w"), blocks(&root, "/a")[..2]);
+    root.truncate(&ROOT, c, path("/w"), 4096 + 100).unwrap();
+    root.link(&ROOT, c, path("/x"), props("x"), file(&a[..4096 + 100]))
+        .unwrap();
+    assert_eq!(blocks(&root, "/w"), blocks(&root, "/x"));
+
+    // it stays on across a remount, and a rollback to before it turns it off
+    let bytes = round_trip(&root);
+    let mut again = format::decode(&bytes).unwrap();
+    assert!(again.dedups());
+    again
+        .link(&ROOT, c, path("/y"), props("x"), file(&a))
+        .unwrap();
+    assert_eq!(blocks(&again, "/y"), blocks(&again, "/a"));
+    again.rollback(&ROOT, "s").unwrap();
+    assert!(!again.dedups());
+
+    root.set_dedup(&ROOT, false).unwrap();
+    root.link(&ROOT, c, path("/z"), props("x"), file(&a))
+        .unwrap();
+    assert_ne!(blocks(&root, "/z"), blocks(&root, "/a"));
+    assert_eq!(root.dedup(&ROOT).unwrap().merged, 3);
+    assert_eq!(blocks(&root, "/z"), blocks(&root, "/a"));
+}
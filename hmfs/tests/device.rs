@@ -0,0 +1,220 @@
+mod common;
+
+use common::*;
+use hmfs::checksum::Checksum;
+use hmfs::format::{self, Device, SUPERBLOCK_SIZE};
+use hmfs::mem::MemDevice;
+use hmfs::RootEntry;
+use syscall::{EINVAL, EIO, ENOSPC};
+
+/// The checksum and top-level names of the tree on a device, which has to be undamaged
+fn state(device: &MemDevice) -> (Checksum, Vec<String>) {
+    let root = format::decode(device.as_bytes()).unwrap();
+    let damage = scrub(&root);
+    assert!(damage.is_empty(), "{damage:?}");
+
+    let mut names: Vec<String> = root
+        .read_dir(&ROOT, cwd(), path("/"))
+        .unwrap()
+        .into_iter()
+        .map(|(props, _)| props.name().to_string())
+        .filter(|name| name != "/")
+        .collect();
+    names.sort();
+    (checksum(&root), names)
+}
+
+#[test]
+fn device_bounds() {
+    let mut device = MemDevice::new(16);
+    let mut buf = [0; 8];
+    assert_eq!(device.size(), Ok(16));
+    device.write_at(8, b"abcdefgh").unwrap();
+    device.read_at(8, &mut buf).unwrap();
+    assert_eq!(&buf, b"abcdefgh");
+    assert_eq!(errno(device.read_at(9, &mut buf)), Err(EIO));
+    assert_eq!(errno(device.write_at(9, &buf)), Err(EIO));
+    assert_eq!(errno(device.write_at(u64::MAX, &buf)), Err(EIO));
+
+    device.fail_after(1);
+    device.write_at(0, b"12345678").unwrap();
+    assert_eq!(errno(device.write_at(8, b"ABCDEFGH")), Err(EIO));
+    assert_eq!(errno(device.flush()), Err(EIO));
+    assert_eq!(&device.as_bytes()[8..], b"abcdefgh");
+    device.tear_after(0);
+    assert_eq!(errno(device.write_at(8, b"ABCDEFGH")), Err(EIO));
+    assert_eq!(&device.as_bytes()[8..], b"ABCDefgh");
+    device.recover();
+    device.flush().unwrap();
+    assert_eq!((device.writes(), device.flushes()), (2, 1));
+    assert_eq!(device.into_bytes(), b"12345678ABCDefgh");
+}
+
+#[test]
+fn commits_alternate_slots() {
+    let c = cwd();
+    let mut device = MemDevice::new(256 * 1024);
+    let mut root = RootEntry::new(0);
+    let superblock = format::commit_root(&mut device, &root).unwrap();
+    assert_eq!(superblock.generation, 1);
+    assert_eq!(device.flushes(), 2);
+    assert_eq!(state(&device).0, checksum(&root));
+
+    let mut offsets = Vec::new();
+    for i in 0..6 {
+        let name = format!("/d{i}");
+        root.link(&ROOT, c, path(&name), props("x"), dir()).unwrap();
+        root.link(
+            &ROOT,
+            c,
+            path(&format!("{name}/f")),
+            props("x"),
+            file(&[i as u8 + 1; 9000]),
+        )
+        .unwrap();
+        let superblock = format::commit_root(&mut device, &root).unwrap();
+        assert_eq!(superblock.generation, i + 2);
+        assert_eq!(state(&device).0, checksum(&root));
+        assert_eq!(
+            format::read_superblock(device.as_bytes()).unwrap(),
+            superblock
+        );
+        offsets.push(superblock.table_offset);
+    }
+    // each tree goes where the one before last was, so the last is never written over
+    for pair in offsets.windows(2) {
+        assert_ne!(pair[0], pair[1]);
+    }
+}
+
+#[test]
+fn crash_keeps_old_tree() {
+    let c = cwd();
+    let mut device = MemDevice::new(256 * 1024);
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/old"), props("x"), file(b"old"))
+        .unwrap();
+    format::commit_root(&mut device, &root).unwrap();
+    format::commit_root(&mut device, &root).unwrap();
+    let good = device.clone();
+    let good_state = state(&good);
+
+    // whatever write the commit is cut off at, even halfway through, the old tree is what's there
+    root.link(&ROOT, c, path("/new"), props("x"), file(b"new"))
+        .unwrap();
+    let needed = {
+        let mut device = good.clone();
+        format::commit_root(&mut device, &root).unwrap();
+        device.writes() - good.writes()
+    };
+    for writes in 0..needed {
+        for tear in [false, true] {
+            let mut device = good.clone();
+            if tear {
+                device.tear_after(writes);
+            } else {
+                device.fail_after(writes);
+            }
+            assert!(format::commit_root(&mut device, &root).is_err());
+            assert_eq!(
+                state(&device),
+                good_state,
+                "cut off after {writes} writes, torn {tear}"
+            );
+
+            // and the next commit after it works
+            device.recover();
+            format::commit_root(&mut device, &root).unwrap();
+            assert_eq!(state(&device).1, ["new", "old"]);
+        }
+    }
+}
+
+#[test]
+fn too_small() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/f"), props("x"), file(&[1; 9000]))
+        .unwrap();
+    let mut device = MemDevice::new(2 * SUPERBLOCK_SIZE + 2000);
+    assert_eq!(
+        errno(format::commit_root(&mut device, &root)).err(),
+        Some(ENOSPC)
+    );
+    assert_eq!(device.writes(), 0);
+}
+
+#[test]
+fn stored_image_isnt_a_superblock() {
+    // an image kept as a file's data can end up where the second superblock would be
+    let c = cwd();
+    let mut inner = RootEntry::new(0);
+    inner
+        .link(&ROOT, c, path("/f"), props("x"), file(b"inner"))
+        .unwrap();
+    let image = format::encode(&inner);
+
+    let empty = RootEntry::new(0);
+    let mut device = MemDevice::new(64 * 1024);
+    format::commit_root(&mut device, &empty).unwrap();
+    let mut bytes = device.into_bytes();
+    let len = bytes.len();
+    bytes[len - SUPERBLOCK_SIZE..].copy_from_slice(&image[..SUPERBLOCK_SIZE]);
+
+    let root = format::decode(&bytes).unwrap();
+    assert_eq!(checksum(&root), checksum(&empty));
+}
+
+#[test]
+fn read_only_what_the_superblock_points_to() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/f"), props("x"), file(&[1; 9000]))
+        .unwrap();
+    let mut device = MemDevice::new(1 << 20);
+    format::commit_root(&mut device, &root).unwrap();
+    root.link(&ROOT, c, path("/g"), props("x"), file(b"g"))
+        .unwrap();
+    let superblock = format::commit_root(&mut device, &root).unwrap();
+
+    let regions = format::read_device(&mut device).unwrap();
+    assert_eq!(regions.superblock, superblock);
+    assert_eq!(
+        [
+            regions.table.len(),
+            regions.snapshots.len(),
+            regions.data.len()
+        ],
+        [
+            superblock.table_len as usize,
+            superblock.snapshot_len as usize,
+            superblock.data_len as usize
+        ]
+    );
+    let back = format::decode_regions(
+        &regions.superblock,
+        &regions.table,
+        &regions.snapshots,
+        &regions.data,
+    )
+    .unwrap();
+    assert_eq!(checksum(&back), checksum(&root));
+
+    // a superblock pointing past the end of the device is refused before anything's allocated
+    let len = device.as_bytes().len() as u64;
+    let slot = format::slot_offsets(len)
+        .into_iter()
+        .find(|&offset| {
+            format::SuperBlock::from_bytes(&device.as_bytes()[offset as usize..]) == Ok(superblock)
+        })
+        .unwrap();
+    let mut bad = superblock;
+    bad.data_len = u64::MAX - bad.data_offset;
+    device.write_at(slot, &bad.to_bytes()).unwrap();
+    assert_eq!(errno(format::read_device(&mut device)).err(), Some(EIO));
+
+    assert_eq!(
+        errno(format::read_device(&mut MemDevice::new(16))).err(),
+        Some(EINVAL)
+    );
+}
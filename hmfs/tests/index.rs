@@ -0,0 +1,202 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::*;
+use hmfs::index::Query;
+use hmfs::perm::Credential;
+use hmfs::{detached, format, Entry, EntryKind, Properties, RootEntry};
+use mr_mime::Mime;
+
+fn props_at(mime: Option<&'static str>, uid: u32, time: i128) -> Properties<'static> {
+    Properties::new(
+        "x".into(),
+        detached(),
+        mime.map(|m| Mime::parse(m).unwrap()),
+        0o755,
+        "root".into(),
+        time,
+        time,
+        "root".into(),
+        uid,
+        0,
+    )
+}
+
+fn queries() -> Vec<Query> {
+    vec![
+        Query::default(),
+        Query {
+            mime: Some("image/png".into()),
+            uid: Some(1000),
+            modified_since: Some(5),
+            ..Default::default()
+        },
+        Query {
+            modified_since: Some(3),
+            modified_before: Some(8),
+            ..Default::default()
+        },
+        Query {
+            created_before: Some(4),
+            ..Default::default()
+        },
+        Query {
+            xattrs: vec![("user.tag".into(), None)],
+            ..Default::default()
+        },
+        Query {
+            xattrs: vec![("user.tag".into(), Some(b"red".to_vec()))],
+            ..Default::default()
+        },
+        Query {
+            mime: Some("nope/nope".into()),
+            ..Default::default()
+        },
+    ]
+}
+
+/// Runs every query, checking the index kept up to date gives what one built afresh does
+fn check(root: &RootEntry) -> Vec<Vec<String>> {
+    let bytes = format::encode(root);
+    let fresh = format::decode(&bytes).unwrap();
+    let got: Vec<_> = queries().iter().map(|q| root.query(&ROOT, q)).collect();
+    let want: Vec<_> = queries().iter().map(|q| fresh.query(&ROOT, q)).collect();
+    assert_eq!(got, want);
+    got
+}
+
+#[test]
+fn kept_up_to_date() {
+    let c = cwd();
+    let png = Some("image/png");
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/pics"), props_at(None, 1000, 1), dir())
+        .unwrap();
+    root.link(
+        &ROOT,
+        c,
+        path("/pics/a.png"),
+        props_at(png, 1000, 2),
+        file(b"a"),
+    )
+    .unwrap();
+    root.link(
+        &ROOT,
+        c,
+        path("/pics/b.png"),
+        props_at(png, 1000, 6),
+        file(b"b"),
+    )
+    .unwrap();
+    root.link(
+        &ROOT,
+        c,
+        path("/pics/c.png"),
+        props_at(png, 1001, 7),
+        file(b"c"),
+    )
+    .unwrap();
+    root.link(&ROOT, c, path("/pics/sub"), props_at(None, 1000, 3), dir())
+        .unwrap();
+    root.link(
+        &ROOT,
+        c,
+        path("/pics/sub/d.png"),
+        props_at(png, 1000, 9),
+        file(b"d"),
+    )
+    .unwrap();
+    root.link(
+        &ROOT,
+        c,
+        path("/doc"),
+        props_at(Some("text/plain"), 0, 4),
+        file(b"t"),
+    )
+    .unwrap();
+    let results = check(&root);
+    assert_eq!(results[0].len(), 7);
+    assert_eq!(results[1], ["/pics/b.png", "/pics/sub/d.png"]);
+    assert!(results[6].is_empty());
+    root.snapshot(&ROOT, "s".into(), 1).unwrap();
+
+    root.set_xattr(
+        &ROOT,
+        c,
+        path("/pics/a.png"),
+        "user.tag".into(),
+        b"red".to_vec(),
+    )
+    .unwrap();
+    root.set_xattr(
+        &ROOT,
+        c,
+        path("/pics/sub"),
+        "user.tag".into(),
+        b"blue".to_vec(),
+    )
+    .unwrap();
+    let results = check(&root);
+    assert_eq!(results[4], ["/pics/a.png", "/pics/sub"]);
+    assert_eq!(results[5], ["/pics/a.png"]);
+
+    root.hard_link(&ROOT, c, path("/pics/a.png"), path("/a2"))
+        .unwrap();
+    assert_eq!(check(&root)[5], ["/a2", "/pics/a.png"]);
+    root.rename(&ROOT, c, path("/pics"), path("/photos"))
+        .unwrap();
+    assert_eq!(check(&root)[1], ["/photos/b.png", "/photos/sub/d.png"]);
+
+    // so are changes of owner and mode
+    root.chown(&ROOT, c, path("/photos/b.png"), Some(5), None)
+        .unwrap();
+    root.chmod(&ROOT, c, path("/photos/sub"), 0o700).unwrap();
+    assert_eq!(check(&root)[1], ["/photos/sub/d.png"]);
+
+    root.write_at(&ROOT, c, path("/a2"), 0, b"zzz").unwrap();
+    root.remove_xattr(&ROOT, c, path("/a2"), "user.tag")
+        .unwrap();
+    assert_eq!(check(&root)[4], ["/photos/sub"]);
+    root.unlink(&ROOT, c, path("/photos/sub/d.png")).unwrap();
+    root.rmdir(&ROOT, c, path("/photos/sub")).unwrap();
+    root.unlink(&ROOT, c, path("/a2")).unwrap();
+    check(&root);
+    assert!(root.rmdir(&ROOT, c, path("/photos")).is_err());
+    check(&root);
+
+    root.rollback(&ROOT, "s").unwrap();
+    let results = check(&root);
+    assert_eq!(results[1], ["/pics/b.png", "/pics/sub/d.png"]);
+    assert!(results[4].is_empty());
+    let snapshot = root.mount_snapshot("s").unwrap();
+    assert_eq!(snapshot.query(&ROOT, &queries()[1]), results[1]);
+
+    // somebody who can't search /pics doesn't see what's in it
+    root.chmod(&ROOT, c, path("/pics"), 0o700).unwrap();
+    assert_eq!(
+        root.query(&Credential::new(7, 7), &Query::default()),
+        ["/doc", "/pics"]
+    );
+}
+
+#[test]
+fn created_through_a_mounted_root() {
+    let mut mounted = Entry::new(EntryKind::Root(Arc::new(RootEntry::new(0))), None);
+    let png = Mime::parse("image/png").ok();
+    mounted
+        .create_file(&ROOT, png, "a.png".into(), 0o644, 6, b"a".to_vec())
+        .unwrap();
+    mounted.mkdir(&ROOT, "sub".into(), 0o755, 7).unwrap();
+
+    let EntryKind::Root(root) = mounted.kind() else {
+        unreachable!()
+    };
+    let results = check(root);
+    assert_eq!(results[0], ["/a.png", "/sub"]);
+    let pngs = Query {
+        mime: Some("image/png".into()),
+        ..Default::default()
+    };
+    assert_eq!(root.query(&ROOT, &pngs), ["/a.png"]);
+}
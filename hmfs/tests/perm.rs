@@ -0,0 +1,252 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::*;
+use hmfs::perm::{Credential, X_OK};
+use hmfs::{format, Entry, EntryKind, RootEntry};
+use mr_mime::Mime;
+use syscall::{EACCES, EINVAL, EPERM};
+
+fn alice() -> Credential {
+    Credential::new(1000, 1000)
+}
+
+fn bob() -> Credential {
+    Credential::new(1001, 1001)
+}
+
+#[test]
+fn owners_and_modes() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    assert_eq!(
+        errno(root.link(
+            &alice(),
+            c,
+            path("/x"),
+            props_with("x", 0o644, 0, 0),
+            file(b"x")
+        )),
+        Err(EACCES)
+    );
+    root.link(
+        &ROOT,
+        c,
+        path("/home"),
+        props_with("home", 0o1777, 0, 0),
+        dir(),
+    )
+    .unwrap();
+
+    // new entries belong to whoever made them, whatever the properties said
+    root.link(
+        &alice(),
+        c,
+        path("/home/a"),
+        props_with("a", 0o4644, 0, 0),
+        file(b"hello"),
+    )
+    .unwrap();
+    let (a, _) = root.resolve(&ROOT, c, path("/home/a")).unwrap();
+    assert_eq!(
+        (a.uid(), a.gid(), a.owner(), a.mode()),
+        (1000, 1000, "1000", 0o4644)
+    );
+
+    // the sticky bit keeps others from removing it
+    assert_eq!(errno(root.unlink(&bob(), c, path("/home/a"))), Err(EPERM));
+    assert_eq!(
+        errno(root.rename(&bob(), c, path("/home/a"), path("/home/b"))),
+        Err(EPERM)
+    );
+
+    root.chmod(&alice(), c, path("/home/a"), 0o600).unwrap();
+    assert_eq!(
+        errno(root.read_file(&bob(), c, path("/home/a"))),
+        Err(EACCES)
+    );
+    assert_eq!(
+        root.read_file(&alice(), c, path("/home/a")).unwrap(),
+        b"hello"
+    );
+    assert_eq!(root.read_file(&ROOT, c, path("/home/a")).unwrap(), b"hello");
+    assert_eq!(
+        errno(root.chmod(&bob(), c, path("/home/a"), 0o777)),
+        Err(EPERM)
+    );
+
+    // an owner can only give a file to a group they're in, and only root can give it away
+    assert_eq!(
+        errno(root.chown(&alice(), c, path("/home/a"), Some(1001), None)),
+        Err(EPERM)
+    );
+    assert_eq!(
+        errno(root.chown(&alice(), c, path("/home/a"), None, Some(5))),
+        Err(EPERM)
+    );
+    root.chown(&alice(), c, path("/home/a"), None, Some(1000))
+        .unwrap();
+    root.chown(&ROOT, c, path("/home/a"), Some(1001), Some(1001))
+        .unwrap();
+    let (a, _) = root.resolve(&ROOT, c, path("/home/a")).unwrap();
+    assert_eq!((a.uid(), a.gid(), a.owner()), (1001, 1001, "1001"));
+    root.write_at(&bob(), c, path("/home/a"), 0, b"J").unwrap();
+    assert_eq!(
+        errno(root.write_at(&alice(), c, path("/home/a"), 0, b"J")),
+        Err(EACCES)
+    );
+
+    // a chown by the owner clears setuid and setgid
+    root.chmod(&bob(), c, path("/home/a"), 0o6755).unwrap();
+    assert_eq!(
+        root.resolve(&ROOT, c, path("/home/a")).unwrap().0.mode(),
+        0o6755
+    );
+    root.chown(&bob(), c, path("/home/a"), None, None).unwrap();
+    assert_eq!(
+        root.resolve(&ROOT, c, path("/home/a")).unwrap().0.mode(),
+        0o755
+    );
+
+    // and it all survives a round trip
+    let bytes = round_trip(&root);
+    let back = format::decode(&bytes).unwrap();
+    let (a, _) = back.resolve(&ROOT, c, path("/home/a")).unwrap();
+    assert_eq!((a.uid(), a.gid(), a.mode()), (1001, 1001, 0o755));
+}
+
+#[test]
+fn directories() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+
+    // searching a directory takes execute permission, listing it read permission
+    root.link(
+        &ROOT,
+        c,
+        path("/priv"),
+        props_with("priv", 0o700, 0, 0),
+        dir(),
+    )
+    .unwrap();
+    root.link(
+        &ROOT,
+        c,
+        path("/priv/f"),
+        props_with("f", 0o644, 0, 0),
+        file(b"s"),
+    )
+    .unwrap();
+    assert_eq!(
+        errno(root.resolve(&alice(), c, path("/priv/f"))).err(),
+        Some(EACCES)
+    );
+    assert_eq!(
+        errno(root.read_dir(&alice(), c, path("/priv"))).err(),
+        Some(EACCES)
+    );
+    assert!(root.resolve(&alice(), c, path("/priv")).is_ok());
+    root.chmod(&ROOT, c, path("/priv"), 0o711).unwrap();
+    assert!(root.read_file(&alice(), c, path("/priv/f")).is_ok());
+    assert_eq!(
+        errno(root.read_dir(&alice(), c, path("/priv"))).err(),
+        Some(EACCES)
+    );
+
+    // a setgid directory hands its group down, and its setgid bit to subdirectories
+    root.link(&ROOT, c, path("/g"), props_with("g", 0o2777, 0, 50), dir())
+        .unwrap();
+    root.link(
+        &alice(),
+        c,
+        path("/g/sub"),
+        props_with("sub", 0o755, 0, 0),
+        dir(),
+    )
+    .unwrap();
+    root.link(
+        &alice(),
+        c,
+        path("/g/file"),
+        props_with("file", 0o2644, 0, 0),
+        file(b""),
+    )
+    .unwrap();
+    let (sub, _) = root.resolve(&ROOT, c, path("/g/sub")).unwrap();
+    assert_eq!((sub.gid(), sub.mode()), (50, 0o2755));
+    let (inside, _) = root.resolve(&ROOT, c, path("/g/file")).unwrap();
+    assert_eq!((inside.gid(), inside.mode()), (50, 0o644));
+
+    // hard links share their mode
+    root.link(
+        &ROOT,
+        c,
+        path("/h"),
+        props_with("h", 0o644, 0, 0),
+        file(b"h"),
+    )
+    .unwrap();
+    root.hard_link(&ROOT, c, path("/h"), path("/g/h2")).unwrap();
+    root.chmod(&ROOT, c, path("/h"), 0o640).unwrap();
+    assert_eq!(
+        root.resolve(&ROOT, c, path("/g/h2")).unwrap().0.mode(),
+        0o640
+    );
+
+    // the root directory has a mode of its own
+    root.chmod(&ROOT, c, path("/"), 0o777).unwrap();
+    assert_eq!(root.resolve(&ROOT, c, path("/")).unwrap().0.mode(), 0o777);
+    root.link(
+        &alice(),
+        c,
+        path("/x"),
+        props_with("x", 0o644, 0, 0),
+        file(b"x"),
+    )
+    .unwrap();
+    root.chmod(&ROOT, c, path("/"), 0o755).unwrap();
+
+    // root can search anything, but only run what somebody can
+    assert_eq!(errno(root.access(&ROOT, c, path("/h"), X_OK)), Err(EACCES));
+    assert!(root.access(&ROOT, c, path("/g"), X_OK).is_ok());
+    assert_eq!(errno(root.snapshot(&alice(), "s".into(), 0)), Err(EPERM));
+    round_trip(&root);
+}
+
+#[test]
+fn create_in_mounted_root() {
+    let root = RootEntry::new(0);
+    let mut mounted = Entry::new(EntryKind::Root(Arc::new(root)), None);
+    let text = Mime::guess("txt").next();
+
+    assert_eq!(
+        errno(mounted.create_file(&alice(), text, "n".into(), 0o644, 0, vec![])).err(),
+        Some(EACCES)
+    );
+    let made = mounted
+        .create_file(&ROOT, text, "n".into(), 0o644, 0, vec![1])
+        .unwrap();
+    assert!(matches!(made.kind(), EntryKind::File(_)));
+    assert_eq!(
+        errno(mounted.mkdir(&alice(), "m".into(), 0o755, 0)).err(),
+        Some(EACCES)
+    );
+    assert!(mounted.mkdir(&ROOT, "m".into(), 0o755, 0).is_ok());
+
+    // both land in the root directory itself
+    let (props, child) = mounted.child("n").unwrap();
+    assert_eq!(props.mime_type(), text.as_ref());
+    assert_eq!(child.as_ref(), &made);
+    assert!(mounted.child("m").unwrap().1.kind().is_dir());
+
+    // a name, not a path, and only a mounted root has the permissions to check against
+    assert_eq!(
+        errno(mounted.mkdir(&ROOT, "m/n".into(), 0o755, 0)).err(),
+        Some(EINVAL)
+    );
+    assert_eq!(
+        errno(dir().mkdir(&ROOT, "m".into(), 0o755, 0)).err(),
+        Some(EPERM)
+    );
+}
@@ -0,0 +1,268 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::*;
+use hmfs::perm::Credential;
+use hmfs::quota::{Holder, Limits, Quota, Usage};
+use hmfs::{format, Entry, EntryKind, RootEntry};
+use syscall::{EDQUOT, EPERM};
+
+fn alice() -> Credential {
+    Credential::new(1000, 1000)
+}
+
+fn usage(bytes: u64, inodes: u64) -> Usage {
+    Usage { bytes, inodes }
+}
+
+fn limits(bytes: u64, inodes: u64) -> Limits {
+    Limits { bytes, inodes }
+}
+
+/// What `quotas` says about `holder`
+fn quota(root: &RootEntry, holder: Holder) -> Option<Quota> {
+    root.quotas(&ROOT).into_iter().find(|q| q.holder == holder)
+}
+
+fn used(root: &RootEntry, holder: Holder) -> Usage {
+    quota(root, holder).map_or(Usage::default(), |q| q.usage)
+}
+
+/// A volume with a /home anyone can write to
+fn volume() -> RootEntry<'static> {
+    let mut root = RootEntry::new(0);
+    root.link(
+        &ROOT,
+        cwd(),
+        path("/home"),
+        props_with("home", 0o1777, 0, 0),
+        dir(),
+    )
+    .unwrap();
+    root
+}
+
+#[test]
+fn owner_quotas() {
+    let (a, c) = (alice(), cwd());
+    let mut root = volume();
+    assert_eq!(
+        errno(root.set_owner_quota(&a, 1000, limits(100, 0))),
+        Err(EPERM)
+    );
+    assert_eq!(
+        errno(root.set_xattr(
+            &ROOT,
+            c,
+            path("/"),
+            "hmfs.quota.uid.1000".into(),
+            vec![0; 16]
+        )),
+        Err(EPERM)
+    );
+    root.set_owner_quota(&ROOT, 1000, limits(100, 3)).unwrap();
+
+    root.link(&a, c, path("/home/d"), props("x"), dir())
+        .unwrap();
+    root.link(&a, c, path("/home/d/f"), props("x"), file(&[1; 60]))
+        .unwrap();
+    assert_eq!(
+        errno(root.link(&a, c, path("/home/g"), props("x"), file(&[1; 60]))),
+        Err(EDQUOT)
+    );
+    assert!(root.resolve(&a, c, path("/home/g")).is_err());
+
+    // a write that grows a file past the limit changes nothing, one within it goes through
+    let before = checksum(&root);
+    assert_eq!(
+        errno(root.write_at(&a, c, path("/home/d/f"), 90, &[2; 20])),
+        Err(EDQUOT)
+    );
+    assert_eq!(checksum(&root), before);
+    root.write_at(&a, c, path("/home/d/f"), 50, &[2; 50])
+        .unwrap();
+    assert_eq!(
+        errno(root.truncate(&a, c, path("/home/d/f"), 101)),
+        Err(EDQUOT)
+    );
+    assert_eq!(used(&root, Holder::Owner(1000)), usage(100, 2));
+
+    // root isn't exempt, and neither is giving something to a user over their quota
+    root.link(&ROOT, c, path("/big"), props("x"), file(&[1; 10]))
+        .unwrap();
+    root.set_owner_quota(&ROOT, 0, limits(0, 2)).unwrap();
+    assert_eq!(
+        errno(root.link(&ROOT, c, path("/more"), props("x"), dir())),
+        Err(EDQUOT)
+    );
+    assert_eq!(
+        errno(root.chown(&ROOT, c, path("/big"), Some(1000), None)),
+        Err(EDQUOT)
+    );
+    assert_eq!(root.resolve(&ROOT, c, path("/big")).unwrap().0.uid(), 0);
+
+    // lowering a limit below what's stored leaves it there, and it can still shrink
+    root.set_owner_quota(&ROOT, 1000, limits(10, 0)).unwrap();
+    root.truncate(&a, c, path("/home/d/f"), 50).unwrap();
+    assert_eq!(
+        errno(root.write_at(&a, c, path("/home/d/f"), 50, &[1])),
+        Err(EDQUOT)
+    );
+    root.unlink(&a, c, path("/home/d/f")).unwrap();
+    assert_eq!(
+        quota(&root, Holder::Owner(1000)),
+        Some(Quota {
+            holder: Holder::Owner(1000),
+            limits: limits(10, 0),
+            usage: usage(0, 1),
+        })
+    );
+
+    // limits are kept in the image
+    let bytes = round_trip(&root);
+    let back = format::decode(&bytes).unwrap();
+    assert_eq!(back.quotas(&ROOT), root.quotas(&ROOT));
+}
+
+#[test]
+fn subtree_quotas() {
+    let c = cwd();
+    let mut root = volume();
+    root.link(&ROOT, c, path("/p"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/p/s"), props("x"), dir())
+        .unwrap();
+    root.link(&ROOT, c, path("/out"), props("x"), file(&[1; 30]))
+        .unwrap();
+    assert_eq!(
+        errno(root.set_quota(&alice(), c, path("/p"), limits(50, 0))),
+        Err(EPERM)
+    );
+    root.set_quota(&ROOT, c, path("/p"), limits(50, 0)).unwrap();
+
+    root.link(&ROOT, c, path("/p/s/f"), props("x"), file(&[1; 30]))
+        .unwrap();
+    assert_eq!(used(&root, Holder::Directory("/p".into())), usage(30, 2));
+
+    // moving something in counts as storing it there, and a failed move leaves it where it was
+    assert_eq!(
+        errno(root.rename(&ROOT, c, path("/out"), path("/p/out"))),
+        Err(EDQUOT)
+    );
+    assert!(root.resolve(&ROOT, c, path("/out")).is_ok());
+    assert!(root.resolve(&ROOT, c, path("/p/out")).is_err());
+    assert_eq!(
+        errno(root.hard_link(&ROOT, c, path("/out"), path("/p/s/out"))),
+        Err(EDQUOT)
+    );
+    assert_eq!(root.resolve(&ROOT, c, path("/out")).unwrap().1.links(), 1);
+
+    // moving within it or out of it is fine, and so is moving the whole directory
+    root.rename(&ROOT, c, path("/p/s/f"), path("/p/f")).unwrap();
+    root.rename(&ROOT, c, path("/p/s"), path("/s")).unwrap();
+    assert_eq!(used(&root, Holder::Directory("/p".into())), usage(30, 1));
+    root.set_quota(&ROOT, c, path("/s"), limits(0, 1)).unwrap();
+    root.rename(&ROOT, c, path("/s"), path("/p/s")).unwrap();
+    assert_eq!(used(&root, Holder::Directory("/p/s".into())), usage(0, 0));
+    assert_eq!(used(&root, Holder::Directory("/p".into())), usage(30, 2));
+
+    // the whole volume's quota is the root directory's
+    root.set_quota(&ROOT, c, path("/"), limits(0, 6)).unwrap();
+    assert_eq!(used(&root, Holder::Directory("/".into())), usage(60, 5));
+    root.link(&ROOT, c, path("/p/s/x"), props("x"), dir())
+        .unwrap();
+    assert_eq!(
+        errno(root.link(&ROOT, c, path("/p/s/y"), props("x"), dir())),
+        Err(EDQUOT)
+    );
+    root.set_quota(&ROOT, c, path("/"), Limits::default())
+        .unwrap();
+    assert!(quota(&root, Holder::Directory("/".into())).is_none());
+}
+
+#[test]
+fn hard_links_and_snapshots() {
+    let c = cwd();
+    let mut root = volume();
+    root.link(&ROOT, c, path("/a"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/b"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/a/f"), props("x"), file(&[1; 100]))
+        .unwrap();
+    root.hard_link(&ROOT, c, path("/a/f"), path("/a/g"))
+        .unwrap();
+    root.hard_link(&ROOT, c, path("/a/f"), path("/b/f"))
+        .unwrap();
+
+    // one file, however many names it has, as long as they all belong to the same owner
+    let (vol, a, b) = (
+        Holder::Directory("/".into()),
+        Holder::Directory("/a".into()),
+        Holder::Directory("/b".into()),
+    );
+    for at in ["/", "/a", "/b"] {
+        root.set_quota(&ROOT, c, path(at), limits(1000, 0)).unwrap();
+    }
+    assert_eq!(used(&root, Holder::Owner(0)), usage(100, 4));
+    assert_eq!(used(&root, vol.clone()), usage(100, 4));
+    assert_eq!(used(&root, a.clone()), usage(100, 1));
+    assert_eq!(used(&root, b.clone()), usage(100, 1));
+    root.write_at(&ROOT, c, path("/b/f"), 100, &[2; 50])
+        .unwrap();
+    assert_eq!(used(&root, vol.clone()), usage(150, 4));
+    root.unlink(&ROOT, c, path("/a/f")).unwrap();
+    root.unlink(&ROOT, c, path("/a/g")).unwrap();
+    assert_eq!(used(&root, a.clone()), usage(0, 0));
+    assert_eq!(used(&root, b.clone()), usage(150, 1));
+
+    // snapshots aren't charged, and rolling back to one charges what it has again
+    root.snapshot(&ROOT, "s".into(), 1).unwrap();
+    root.unlink(&ROOT, c, path("/b/f")).unwrap();
+    assert_eq!(used(&root, vol.clone()), usage(0, 3));
+    root.rollback(&ROOT, "s").unwrap();
+    assert_eq!(used(&root, vol.clone()), usage(150, 4));
+    let snapshot = root.mount_snapshot("s").unwrap();
+    assert_eq!(snapshot.quotas(&ROOT), root.quotas(&ROOT));
+
+    // deduplication doesn't change what anything costs
+    root.link(&ROOT, c, path("/copy"), props("x"), file(&[2; 150]))
+        .unwrap();
+    root.dedup(&ROOT).unwrap();
+    root.unlink(&ROOT, c, path("/copy")).unwrap();
+    assert_eq!(used(&root, vol), usage(150, 4));
+}
+
+#[test]
+fn create_in_mounted_root() {
+    let mut root = volume();
+    // root owns everything there is so far
+    let before = used(&root, Holder::Owner(0));
+    root.set_owner_quota(&ROOT, 0, limits(before.bytes + 10, 0))
+        .unwrap();
+    root.set_quota(&ROOT, cwd(), path("/"), limits(0, before.inodes + 2))
+        .unwrap();
+    let mut mounted = Entry::new(EntryKind::Root(Arc::new(root)), None);
+
+    // the owner's quota and the volume's both count
+    assert_eq!(
+        errno(mounted.create_file(&ROOT, None, "n".into(), 0o644, 0, vec![0; 11])).err(),
+        Some(EDQUOT)
+    );
+    assert!(mounted
+        .create_file(&ROOT, None, "n".into(), 0o644, 0, vec![0; 10])
+        .is_ok());
+    assert!(mounted.mkdir(&ROOT, "m".into(), 0o755, 0).is_ok());
+    assert_eq!(
+        errno(mounted.mkdir(&ROOT, "o".into(), 0o755, 0)).err(),
+        Some(EDQUOT)
+    );
+
+    // and both are charged for what got made
+    let EntryKind::Root(root) = mounted.kind() else {
+        unreachable!()
+    };
+    assert_eq!(used(root, Holder::Owner(0)), before + usage(10, 2));
+    assert_eq!(
+        used(root, Holder::Directory("/".into())),
+        before + usage(10, 2)
+    );
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use common::*;
+use hmfs::scrub::{DamageKind, Scrub};
+use hmfs::{format, RootEntry};
+
+fn volume() -> RootEntry<'static> {
+    let c = cwd();
+    let big: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8 + 1).collect();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/a"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/a/big"), props("x"), file(&big))
+        .unwrap();
+    root.link(&ROOT, c, path("/small"), props("x"), file(b"hello"))
+        .unwrap();
+    root.hard_link(&ROOT, c, path("/small"), path("/a/again"))
+        .unwrap();
+    root.snapshot(&ROOT, "one".into(), 1).unwrap();
+    root.write_at(&ROOT, c, path("/a/big"), 5000, b"changed")
+        .unwrap();
+    root
+}
+
+#[test]
+fn clean() {
+    let root = volume();
+    let mut scrub = Scrub::new(&root);
+    let mut damage = Vec::new();
+    scrub.run(&mut |d| damage.push(d));
+    assert!(damage.is_empty(), "{damage:?}");
+    // big's five blocks, the one that changed since the snapshot, and small's, each once
+    assert_eq!(scrub.blocks(), 7);
+
+    let bytes = round_trip(&root);
+    let back = format::decode(&bytes).unwrap();
+    assert!(common::scrub(&back).is_empty());
+}
+
+#[test]
+fn damaged_block() {
+    let bytes = format::encode(&volume());
+    let (superblock, _) = format::read_records(&bytes).unwrap();
+    let data = superblock.data_offset as usize;
+    let at = data
+        + bytes[data..]
+            .windows(5)
+            .position(|w| w == b"hello")
+            .unwrap();
+    let mut bad = bytes.clone();
+    bad[at] ^= 1;
+
+    // found once, under whichever name it's reached by first
+    let back = format::decode(&bad).unwrap();
+    let damage = scrub(&back);
+    assert_eq!(damage.len(), 1, "{damage:?}");
+    assert!(matches!(damage[0].kind, DamageKind::Block { index: 0, .. }));
+    assert!(["/small", "/a/again"].contains(&damage[0].path.as_str()));
+}
+
+#[test]
+fn damaged_entries() {
+    let bytes = format::encode(&volume());
+
+    // a changed name no longer matches the checksum of the directory listing it
+    let at = bytes.windows(3).position(|w| w == b"big").unwrap();
+    let mut bad = bytes.clone();
+    bad[at] = b'B';
+    let back = format::decode(&bad).unwrap();
+    let damage = scrub(&back);
+    assert!(damage
+        .iter()
+        .any(|d| d.path == "/a" && matches!(d.kind, DamageKind::Entry { .. })));
+
+    // nor does a changed checksum of the root
+    let (_, records) = format::read_records(&bytes).unwrap();
+    let checksum = records[0].checksum;
+    let at = format::SUPERBLOCK_SIZE
+        + bytes[format::SUPERBLOCK_SIZE..]
+            .windows(64)
+            .position(|w| w == checksum.as_bytes())
+            .unwrap();
+    let mut bad = bytes.clone();
+    bad[at] ^= 0x80;
+    let back = format::decode(&bad).unwrap();
+    let damage = scrub(&back);
+    assert!(damage
+        .iter()
+        .any(|d| d.path == "/" && matches!(d.kind, DamageKind::Root { .. })));
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use common::*;
+use hmfs::perm::Credential;
+use hmfs::{format, RootEntry};
+use syscall::{EEXIST, EINVAL, EIO, ENOENT, EPERM, EROFS};
+
+fn hard_links(image: &[u8]) -> usize {
+    let (_, records) = format::read_records(image).unwrap();
+    records
+        .iter()
+        .filter(|r| r.kind() == format::KIND_HARD_LINK)
+        .count()
+}
+
+/// A volume with snapshots "a" and "b" of a bit of everything, and what /d/e/big holds in "b"
+fn source(big: &[u8]) -> (RootEntry<'static>, Vec<u8>) {
+    let c = cwd();
+    let mut src = RootEntry::new(0);
+    src.link(&ROOT, c, path("/d"), props("x"), dir()).unwrap();
+    src.link(&ROOT, c, path("/d/e"), props("x"), dir()).unwrap();
+    src.link(&ROOT, c, path("/d/e/big"), props("x"), file(big))
+        .unwrap();
+    src.link(&ROOT, c, path("/d/small"), props("x"), file(b"small"))
+        .unwrap();
+    src.link(&ROOT, c, path("/gone"), props("x"), file(b"bye"))
+        .unwrap();
+    src.link(&ROOT, c, path("/ln"), props("x"), symlink("d/small"))
+        .unwrap();
+    src.hard_link(&ROOT, c, path("/d/small"), path("/hl"))
+        .unwrap();
+    src.set_xattr(&ROOT, c, path("/d"), "user.a".into(), b"1".to_vec())
+        .unwrap();
+    src.link(&ROOT, c, path("/sec"), props("x"), dir()).unwrap();
+    src.encrypt(&ROOT, c, path("/sec"), b"pw").unwrap();
+    src.link(&ROOT, c, path("/sec/s"), props("x"), file(b"secret"))
+        .unwrap();
+    src.snapshot(&ROOT, "a".into(), 5).unwrap();
+
+    // one block of big changes, a file goes, a copy of big and another name for small come,
+    // modes and xattrs change
+    src.write_at(&ROOT, c, path("/d/e/big"), 4096 * 4 + 10, b"changed")
+        .unwrap();
+    src.unlink(&ROOT, c, path("/gone")).unwrap();
+    src.link(&ROOT, c, path("/new"), props("x"), file(big))
+        .unwrap();
+    src.hard_link(&ROOT, c, path("/hl"), path("/hl2")).unwrap();
+    src.chmod(&ROOT, c, path("/ln"), 0o700).unwrap();
+    src.chmod(&ROOT, c, path("/"), 0o711).unwrap();
+    src.set_xattr(&ROOT, c, path("/d"), "user.a".into(), b"2".to_vec())
+        .unwrap();
+    src.set_dedup(&ROOT, true).unwrap();
+    src.link(&ROOT, c, path("/sec/t"), props("x"), file(b"more"))
+        .unwrap();
+    src.snapshot(&ROOT, "b".into(), 6).unwrap();
+
+    let mut changed = big.to_vec();
+    changed[4096 * 4 + 10..4096 * 4 + 17].copy_from_slice(b"changed");
+    (src, changed)
+}
+
+#[test]
+fn send_and_receive() {
+    let c = cwd();
+    let big = noise(10 * 4096, 3);
+    let (src, changed) = source(&big);
+
+    assert_eq!(
+        errno(src.send(&Credential::new(1, 1), None, "a")).err(),
+        Some(EPERM)
+    );
+    assert_eq!(errno(src.send(&ROOT, None, "nope")).err(), Some(ENOENT));
+    let (full, full_stats) = src.send(&ROOT, None, "a").unwrap();
+    let (incremental, stats) = src.send(&ROOT, Some("a"), "b").unwrap();
+    assert_eq!(full_stats.blocks, 10 + 2 + 1);
+    // only the block of big that changed and /sec/t go in full; /new is big's blocks, old and
+    // new, and small goes again by reference since its link count changed
+    assert_eq!(stats.blocks, 1 + 1, "{stats:?}");
+    assert_eq!(stats.shared, 9 + 10 + 1, "{stats:?}");
+    assert!(incremental.len() < 4 * 4096, "{}", incremental.len());
+
+    let mut dst = RootEntry::new(1);
+    assert_eq!(errno(dst.receive(&ROOT, &incremental)).err(), Some(ENOENT));
+    assert_eq!(
+        errno(dst.receive(&Credential::new(1, 1), &full)).err(),
+        Some(EPERM)
+    );
+    dst.receive(&ROOT, &full).unwrap();
+    assert_eq!(errno(dst.receive(&ROOT, &full)).err(), Some(EEXIST));
+    assert_eq!(dst.receive(&ROOT, &incremental).unwrap(), stats);
+    for (sent, received) in src.snapshots().iter().zip(dst.snapshots()) {
+        assert_eq!(
+            (sent.name(), sent.created(), sent.checksum()),
+            (received.name(), received.created(), received.checksum())
+        );
+    }
+
+    // hard links and shared blocks come across shared, and /new is stored as big's blocks even
+    // though it was written before dedup was turned on
+    let mut b = dst.clone();
+    b.rollback(&ROOT, "b").unwrap();
+    let sent = format::encode(&src);
+    let received = round_trip(&b);
+    assert_eq!(hard_links(&sent), hard_links(&received));
+    let (sent, _) = format::read_records(&sent).unwrap();
+    let (received, _) = format::read_records(&received).unwrap();
+    assert_eq!(sent.data_len - received.data_len, 10 * 4096);
+    assert!(b.dedups());
+    assert!(scrub(&b).is_empty());
+
+    assert_eq!(b.read_file(&ROOT, c, path("/d/e/big")).unwrap(), changed);
+    assert_eq!(b.read_file(&ROOT, c, path("/new")).unwrap(), big);
+    assert_eq!(b.read_file(&ROOT, c, path("/hl2")).unwrap(), b"small");
+    assert_eq!(b.read_link(&ROOT, c, path("/ln")).unwrap(), "d/small");
+    assert_eq!(b.resolve(&ROOT, c, path("/ln")).unwrap().0.mode(), 0o700);
+    assert!(b.read_file(&ROOT, c, path("/gone")).is_err());
+    b.unlock(&ROOT, c, path("/sec"), b"pw").unwrap();
+    assert_eq!(b.read_file(&ROOT, c, path("/sec/t")).unwrap(), b"more");
+    let a = dst.mount_snapshot("a").unwrap();
+    assert_eq!(a.read_file(&ROOT, c, path("/gone")).unwrap(), b"bye");
+}
+
+#[test]
+fn bad_streams() {
+    let big = noise(10 * 4096, 3);
+    let (src, _) = source(&big);
+    let (full, _) = src.send(&ROOT, None, "a").unwrap();
+    let (incremental, _) = src.send(&ROOT, Some("a"), "b").unwrap();
+    let mut flipped = incremental.clone();
+    let at = flipped.len() - 100;
+    flipped[at] ^= 1;
+
+    let mut dst = RootEntry::new(1);
+    dst.receive(&ROOT, &full).unwrap();
+    assert_eq!(errno(dst.receive(&ROOT, &flipped)).err(), Some(EIO));
+    assert_eq!(
+        errno(dst.receive(&ROOT, &incremental[..incremental.len() - 1])).err(),
+        Some(EIO)
+    );
+    assert_eq!(errno(dst.receive(&ROOT, b"HMFSSENX")).err(), Some(EINVAL));
+    assert_eq!(dst.snapshots().len(), 1);
+
+    // nothing can be received into a snapshot
+    let mut a = dst.mount_snapshot("a").unwrap();
+    assert_eq!(errno(a.receive(&ROOT, &incremental)).err(), Some(EROFS));
+}
+
+#[test]
+fn chained_streams() {
+    let c = cwd();
+    let big = noise(10 * 4096, 3);
+    let (mut src, _) = source(&big);
+    src.unlink(&ROOT, c, path("/new")).unwrap();
+    src.snapshot(&ROOT, "c".into(), 7).unwrap();
+    let (full, _) = src.send(&ROOT, None, "a").unwrap();
+    let (incremental, _) = src.send(&ROOT, Some("a"), "b").unwrap();
+    let (next, stats) = src.send(&ROOT, Some("b"), "c").unwrap();
+    assert_eq!((stats.ops, stats.blocks), (1, 0));
+
+    // a stream against a snapshot that was itself received
+    let mut dst = RootEntry::new(1);
+    dst.receive(&ROOT, &full).unwrap();
+    dst.receive(&ROOT, &incremental).unwrap();
+    dst.receive(&ROOT, &next).unwrap();
+    assert_eq!(dst.snapshots()[2].checksum(), src.snapshots()[2].checksum());
+}
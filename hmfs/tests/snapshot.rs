@@ -0,0 +1,151 @@
+mod common;
+
+use common::*;
+use hmfs::format::{self, Payload};
+use hmfs::perm::Credential;
+use hmfs::RootEntry;
+use syscall::{EEXIST, EINVAL, ENOENT, EPERM, EROFS};
+
+fn volume() -> RootEntry<'static> {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/d"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/d/kept"), props("x"), file(b"kept"))
+        .unwrap();
+    root.link(&ROOT, c, path("/d/changed"), props("x"), file(b"before"))
+        .unwrap();
+    root
+}
+
+fn names(root: &RootEntry) -> Vec<(String, i128)> {
+    root.snapshots()
+        .iter()
+        .map(|snapshot| (snapshot.name().to_string(), snapshot.created()))
+        .collect()
+}
+
+#[test]
+fn take_mount_and_delete() {
+    let c = cwd();
+    let mut root = volume();
+    root.snapshot(&ROOT, "first".into(), 10).unwrap();
+    let at_first = checksum(&root);
+
+    root.write_at(&ROOT, c, path("/d/changed"), 0, b"AFTER!")
+        .unwrap();
+    root.link(&ROOT, c, path("/new"), props("x"), file(b"new"))
+        .unwrap();
+    root.snapshot(&ROOT, "second".into(), 20).unwrap();
+    assert_eq!(names(&root), [("first".into(), 10), ("second".into(), 20)]);
+    assert_eq!(root.snapshots()[0].checksum(), at_first);
+
+    // a snapshot is the tree as it was, and stays that way
+    let first = root.mount_snapshot("first").unwrap();
+    assert_eq!(
+        first.read_file(&ROOT, c, path("/d/changed")).unwrap(),
+        b"before"
+    );
+    assert_eq!(
+        errno(first.resolve(&ROOT, c, path("/new"))).err(),
+        Some(ENOENT)
+    );
+    root.unlink(&ROOT, c, path("/d/kept")).unwrap();
+    let mut first = root.mount_snapshot("first").unwrap();
+    assert_eq!(first.read_file(&ROOT, c, path("/d/kept")).unwrap(), b"kept");
+
+    // mounted, it refuses every change
+    assert!(first.is_read_only());
+    assert_eq!(
+        errno(first.link(&ROOT, c, path("/x"), props("x"), file(b""))),
+        Err(EROFS)
+    );
+    assert_eq!(
+        errno(first.write_at(&ROOT, c, path("/d/kept"), 0, b"x")),
+        Err(EROFS)
+    );
+    assert_eq!(errno(first.snapshot(&ROOT, "third".into(), 30)), Err(EROFS));
+
+    assert_eq!(errno(root.snapshot(&ROOT, "first".into(), 30)), Err(EEXIST));
+    assert_eq!(errno(root.snapshot(&ROOT, "".into(), 30)), Err(EINVAL));
+    let alice = Credential::new(1000, 1000);
+    assert_eq!(errno(root.snapshot(&alice, "mine".into(), 30)), Err(EPERM));
+    assert_eq!(errno(root.delete_snapshot(&alice, "first")), Err(EPERM));
+    assert_eq!(errno(root.mount_snapshot("nope")).err(), Some(ENOENT));
+
+    root.delete_snapshot(&ROOT, "first").unwrap();
+    assert_eq!(names(&root), [("second".into(), 20)]);
+    assert_eq!(errno(root.delete_snapshot(&ROOT, "first")), Err(ENOENT));
+}
+
+#[test]
+fn rollback() {
+    let c = cwd();
+    let mut root = volume();
+    root.snapshot(&ROOT, "clean".into(), 10).unwrap();
+    let at_snapshot = checksum(&root);
+
+    root.write_at(&ROOT, c, path("/d/changed"), 0, b"AFTER!")
+        .unwrap();
+    root.unlink(&ROOT, c, path("/d/kept")).unwrap();
+    root.link(&ROOT, c, path("/new"), props("x"), file(b"new"))
+        .unwrap();
+
+    assert_eq!(
+        errno(root.rollback(&Credential::new(1000, 1000), "clean")),
+        Err(EPERM)
+    );
+    assert_eq!(errno(root.rollback(&ROOT, "nope")), Err(ENOENT));
+
+    root.rollback(&ROOT, "clean").unwrap();
+    assert_eq!(checksum(&root), at_snapshot);
+    assert_eq!(
+        root.read_file(&ROOT, c, path("/d/changed")).unwrap(),
+        b"before"
+    );
+    assert_eq!(root.read_file(&ROOT, c, path("/d/kept")).unwrap(), b"kept");
+    assert_eq!(
+        errno(root.resolve(&ROOT, c, path("/new"))).err(),
+        Some(ENOENT)
+    );
+
+    // the snapshot is kept, and the tree can move on from it again
+    assert_eq!(names(&root), [("clean".into(), 10)]);
+    root.unlink(&ROOT, c, path("/d/kept")).unwrap();
+    let clean = root.mount_snapshot("clean").unwrap();
+    assert_eq!(clean.read_file(&ROOT, c, path("/d/kept")).unwrap(), b"kept");
+}
+
+#[test]
+fn stored_shared() {
+    let c = cwd();
+    let mut root = volume();
+    root.snapshot(&ROOT, "first".into(), 10).unwrap();
+    root.write_at(&ROOT, c, path("/d/changed"), 0, b"AFTER!")
+        .unwrap();
+
+    // the file nothing changed is stored once for both trees, the other once for each
+    let bytes = round_trip(&root);
+    let (superblock, records) = format::read_records(&bytes).unwrap();
+    let files = records
+        .iter()
+        .filter(|record| matches!(record.payload, Payload::File { .. }))
+        .count();
+    assert_eq!(files, 3);
+
+    let snapshots = format::read_snapshots(&bytes, &superblock).unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!((snapshots[0].name, snapshots[0].created), ("first", 10));
+    assert_eq!(snapshots[0].checksum, root.snapshots()[0].checksum());
+
+    let back = format::decode(&bytes).unwrap();
+    assert_eq!(names(&back), [("first".into(), 10)]);
+    let first = back.mount_snapshot("first").unwrap();
+    assert_eq!(
+        first.read_file(&ROOT, c, path("/d/changed")).unwrap(),
+        b"before"
+    );
+    assert_eq!(
+        back.read_file(&ROOT, c, path("/d/changed")).unwrap(),
+        b"AFTER!"
+    );
+}
@@ -0,0 +1,28 @@
+use hmfs::sniff::sniff;
+
+fn sniffed(data: &[u8]) -> Option<String> {
+    sniff(data).map(|mime| mime.to_string())
+}
+
+#[test]
+fn by_contents() {
+    assert_eq!(
+        sniffed(b"\x7fELF\x02\x01"),
+        Some("application/x-elf".into())
+    );
+    assert_eq!(sniffed(b"\x89PNG\r\n\x1a\nxxxx"), Some("image/png".into()));
+    assert_eq!(sniffed(b"x\x9c"), Some("application/zlib".into()));
+    assert_eq!(sniffed(b"plain"), Some("text/plain".into()));
+    assert_eq!(sniffed(b"xy"), Some("text/plain".into()));
+    assert_eq!(sniffed(b""), None);
+    assert_eq!(sniffed(b"a\0b"), None);
+    assert_eq!(sniffed(b"BM\0\0"), None);
+
+    // a character cut in two by the end of what's looked at is still text, bad UTF-8 isn't
+    let mut text = vec![b'a'; 1023];
+    text.extend("é".as_bytes());
+    assert_eq!(sniffed(&text), Some("text/plain".into()));
+    let mut text = vec![b'a'; 1023];
+    text.push(0xc3);
+    assert_eq!(sniffed(&text), None);
+}
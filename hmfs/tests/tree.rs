@@ -0,0 +1,398 @@
+mod common;
+
+use common::*;
+use hmfs::{format, EntryKind, RootEntry, SYMLOOP_MAX};
+use syscall::{EBUSY, EEXIST, EINVAL, EISDIR, ELOOP, ENOENT, ENOTDIR, ENOTEMPTY, EPERM, EXDEV};
+
+#[test]
+fn resolve() {
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, cwd(), path("/a"), props("a"), dir())
+        .unwrap();
+    root.link(&ROOT, cwd(), path("/a/f"), props("f"), file(&[1]))
+        .unwrap();
+
+    let name = |cwd: &str, to: &str| {
+        errno(root.resolve(&ROOT, path(cwd), path(to))).map(|(p, _)| p.name().to_string())
+    };
+    assert_eq!(name("/", "/a/f"), Ok("f".into()));
+    assert_eq!(name("/a", "f"), Ok("f".into()));
+    assert_eq!(name("/a", "./f"), Ok("f".into()));
+    assert_eq!(name("/a", "../a/../a/f"), Ok("f".into()));
+    assert_eq!(name("/a", ".."), Ok("/".into()));
+    assert_eq!(name("/", "../../.."), Ok("/".into()));
+    assert_eq!(name("/", "/a/f/"), Err(ENOTDIR));
+    assert_eq!(name("/", "/a/f/x"), Err(ENOTDIR));
+    assert_eq!(name("/", "/a/f/.."), Err(ENOTDIR));
+    assert_eq!(name("/", "/a/g"), Err(ENOENT));
+    assert_eq!(name("/nope", "x"), Err(ENOENT));
+}
+
+#[test]
+fn resolve_properties() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/a"), props_with("x", 0o750, 7, 8), dir())
+        .unwrap();
+    root.link(
+        &ROOT,
+        c,
+        path("/a/f"),
+        props_with("x", 0o640, 7, 8),
+        file(b"data"),
+    )
+    .unwrap();
+
+    // the entry comes with the properties its directory has for it, under the name it's linked as
+    let (props, entry) = root.resolve(&ROOT, path("/a"), path("f")).unwrap();
+    assert_eq!(
+        (props.name(), props.mode(), props.uid(), props.gid()),
+        ("f", 0o640, 7, 8)
+    );
+    assert!(matches!(entry.kind(), EntryKind::File(_)));
+    let (props, entry) = root.resolve(&ROOT, c, path("/a/.")).unwrap();
+    assert_eq!((props.name(), props.mode()), ("a", 0o750));
+    assert!(entry.kind().is_dir());
+
+    // a working directory that isn't one only matters to relative paths
+    assert_eq!(
+        errno(root.resolve(&ROOT, path("/a/f"), path("g"))).err(),
+        Some(ENOTDIR)
+    );
+    assert_eq!(
+        errno(root.resolve(&ROOT, path("/a/f"), path("/a"))).map(|(p, _)| p.mode()),
+        Ok(0o750)
+    );
+}
+
+/// Checks that every record's parent index is the directory that lists it
+fn check_parents(root: &RootEntry) {
+    let bytes = round_trip(root);
+    let (_, records) = format::read_records(&bytes).unwrap();
+    for (index, record) in records.iter().enumerate() {
+        if let format::Payload::Directory(children) = &record.payload {
+            for &child in children {
+                assert_eq!(records[child as usize].parent, index as u64, "#{child}");
+            }
+        }
+    }
+}
+
+#[test]
+fn rename_across_directories() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/a"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/a/d"), props("x"), dir())
+        .unwrap();
+    root.link(&ROOT, c, path("/a/d/f"), props("x"), file(b"data"))
+        .unwrap();
+    root.link(&ROOT, c, path("/b"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/b/e"), props("x"), dir())
+        .unwrap();
+    root.link(&ROOT, c, path("/b/old"), props("x"), file(b"old"))
+        .unwrap();
+
+    let dir_checksum = |root: &RootEntry, at: &str| {
+        let (_, entry) = root.resolve(&ROOT, c, path(at)).unwrap();
+        entry.checksum()
+    };
+    let before = [
+        checksum(&root),
+        dir_checksum(&root, "/a"),
+        dir_checksum(&root, "/b"),
+    ];
+
+    // a directory replaces an empty one, and a file another file
+    root.rename(&ROOT, c, path("/a/d"), path("/b/e")).unwrap();
+    root.rename(&ROOT, c, path("/b/e/f"), path("/b/old"))
+        .unwrap();
+    assert_eq!(root.read_file(&ROOT, c, path("/b/old")).unwrap(), b"data");
+    assert_eq!(
+        errno(root.resolve(&ROOT, c, path("/a/d"))).err(),
+        Some(ENOENT)
+    );
+    assert_eq!(
+        errno(root.resolve(&ROOT, c, path("/b/e/f"))).err(),
+        Some(ENOENT)
+    );
+
+    // every directory on both paths changed, and says so
+    let after = [
+        checksum(&root),
+        dir_checksum(&root, "/a"),
+        dir_checksum(&root, "/b"),
+    ];
+    for (before, after) in before.iter().zip(after.iter()) {
+        assert_ne!(before, after);
+    }
+    check_parents(&root);
+
+    // a directory doesn't replace one with something in it
+    root.link(&ROOT, c, path("/a/full"), props("x"), dir())
+        .unwrap();
+    root.link(&ROOT, c, path("/a/full/f"), props("x"), file(b""))
+        .unwrap();
+    assert_eq!(
+        errno(root.rename(&ROOT, c, path("/b/e"), path("/a/full"))),
+        Err(ENOTEMPTY)
+    );
+    assert_eq!(
+        errno(root.rename(&ROOT, c, path("/b/nope"), path("/a/x"))),
+        Err(ENOENT)
+    );
+}
+
+#[test]
+fn unlink_rmdir_rename() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/a"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/b"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/a/f"), props("x"), file(&[1]))
+        .unwrap();
+    root.link(&ROOT, c, path("/a/s"), props("x"), dir())
+        .unwrap();
+    let before = root.clone();
+
+    assert_eq!(errno(root.rmdir(&ROOT, c, path("/a"))), Err(ENOTEMPTY));
+    assert_eq!(errno(root.unlink(&ROOT, c, path("/a"))), Err(EISDIR));
+    assert_eq!(errno(root.rmdir(&ROOT, c, path("/a/f"))), Err(ENOTDIR));
+    assert_eq!(errno(root.rmdir(&ROOT, c, path("/"))), Err(EBUSY));
+    assert_eq!(errno(root.rmdir(&ROOT, c, path("a/.."))), Err(EINVAL));
+    let rename =
+        |root: &mut RootEntry, from, to| errno(root.rename(&ROOT, c, path(from), path(to)));
+    assert_eq!(rename(&mut root, "/a", "/a/s/x"), Err(EINVAL));
+    assert_eq!(rename(&mut root, "/a/f", "/b"), Err(EISDIR));
+    assert_eq!(rename(&mut root, "/a", "/a/f"), Err(EINVAL));
+    assert_eq!(rename(&mut root, "/a/s", "/a/f"), Err(ENOTDIR));
+
+    root.rename(&ROOT, path("/a"), path("f"), path("../b/g"))
+        .unwrap();
+    assert!(root.resolve(&ROOT, c, path("/b/g")).is_ok());
+    assert!(root.resolve(&ROOT, c, path("/a/f")).is_err());
+    rename(&mut root, "/a/s", "/b/t").unwrap();
+    root.rmdir(&ROOT, c, path("/a")).unwrap();
+    root.unlink(&ROOT, c, path("/b/g")).unwrap();
+    rename(&mut root, "/b", "/b").unwrap();
+
+    // the copy taken before shares nothing it can see change
+    assert!(before.resolve(&ROOT, c, path("/a/f")).is_ok());
+    let bytes = round_trip(&root);
+    let back = format::decode(&bytes).unwrap();
+    assert!(back.resolve(&ROOT, c, path("/b/t")).is_ok());
+}
+
+#[test]
+fn symlinks() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/a"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/a/b"), props("x"), dir())
+        .unwrap();
+    root.link(&ROOT, c, path("/a/b/file"), props("x"), file(b"hello"))
+        .unwrap();
+    root.link(&ROOT, c, path("/rel"), props("x"), symlink("a/b"))
+        .unwrap();
+    root.link(&ROOT, c, path("/abs"), props("x"), symlink("/a/b/file"))
+        .unwrap();
+    root.link(&ROOT, c, path("/a/up"), props("x"), symlink("../a/b"))
+        .unwrap();
+    root.link(&ROOT, c, path("/loop1"), props("x"), symlink("loop2"))
+        .unwrap();
+    root.link(&ROOT, c, path("/loop2"), props("x"), symlink("loop1"))
+        .unwrap();
+    root.link(&ROOT, c, path("/dangling"), props("x"), symlink("nope"))
+        .unwrap();
+
+    for through in ["/rel/file", "/abs", "/a/up/file", "/rel/../b/file"] {
+        assert_eq!(root.read_file(&ROOT, c, path(through)).unwrap(), b"hello");
+    }
+    assert_eq!(
+        errno(root.resolve(&ROOT, c, path("/loop1"))).err(),
+        Some(ELOOP)
+    );
+    assert!(root.resolve_nofollow(&ROOT, c, path("/loop1")).is_ok());
+    assert_eq!(
+        errno(root.resolve(&ROOT, c, path("/dangling"))).err(),
+        Some(ENOENT)
+    );
+    assert_eq!(root.read_link(&ROOT, c, path("/rel")).unwrap(), "a/b");
+    let (_, rel) = root.resolve_nofollow(&ROOT, c, path("/rel")).unwrap();
+    assert!(matches!(rel.kind(), EntryKind::Symlink(_)));
+    // a trailing slash follows the last one too
+    let (_, rel) = root.resolve_nofollow(&ROOT, c, path("/rel/")).unwrap();
+    assert!(rel.kind().is_dir());
+    assert_eq!(
+        errno(root.resolve(&ROOT, c, path("/abs/"))).err(),
+        Some(ENOTDIR)
+    );
+
+    root.write_at(&ROOT, c, path("/abs"), 5, b" world").unwrap();
+    assert_eq!(
+        root.read_file(&ROOT, c, path("/a/b/file")).unwrap(),
+        b"hello world"
+    );
+    // unlinking a symlink leaves what it points to
+    root.unlink(&ROOT, c, path("/abs")).unwrap();
+    assert!(root.resolve(&ROOT, c, path("/a/b/file")).is_ok());
+    assert_eq!(errno(root.rmdir(&ROOT, c, path("/rel"))), Err(ENOTDIR));
+    round_trip(&root);
+}
+
+#[test]
+fn symlink_chains() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/file"), props("x"), file(b"end"))
+        .unwrap();
+    root.link(&ROOT, c, path("/l0"), props("x"), symlink("file"))
+        .unwrap();
+    for i in 1..=SYMLOOP_MAX {
+        let target = format!("l{}", i - 1);
+        root.link(
+            &ROOT,
+            c,
+            path(&format!("/l{i}")),
+            props("x"),
+            symlink(&target),
+        )
+        .unwrap();
+    }
+
+    // as many as a lookup may follow still gets there, one more is a loop
+    let last = format!("/l{}", SYMLOOP_MAX - 1);
+    assert_eq!(root.read_file(&ROOT, c, path(&last)).unwrap(), b"end");
+    let over = format!("/l{SYMLOOP_MAX}");
+    assert_eq!(
+        errno(root.resolve(&ROOT, c, path(&over))).err(),
+        Some(ELOOP)
+    );
+    assert!(root.resolve_nofollow(&ROOT, c, path(&over)).is_ok());
+    round_trip(&root);
+}
+
+#[test]
+fn hard_links() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/a"), props("x"), dir()).unwrap();
+    root.link(&ROOT, c, path("/a/file"), props("x"), file(b"hello world"))
+        .unwrap();
+    root.link(&ROOT, c, path("/sym"), props("x"), symlink("/a/file"))
+        .unwrap();
+
+    root.hard_link(&ROOT, c, path("/a/file"), path("/hl"))
+        .unwrap();
+    assert_eq!(
+        errno(root.hard_link(&ROOT, c, path("/a/file"), path("/hl"))),
+        Err(EEXIST)
+    );
+    assert_eq!(
+        errno(root.hard_link(&ROOT, c, path("/a"), path("/hd"))),
+        Err(EPERM)
+    );
+    assert_eq!(root.resolve(&ROOT, c, path("/hl")).unwrap().1.links(), 2);
+    assert_eq!(
+        root.resolve(&ROOT, c, path("/a/file")).unwrap().1.links(),
+        2
+    );
+
+    let before = root.clone();
+    root.write_at(&ROOT, c, path("/hl"), 0, b"HELLO").unwrap();
+    assert_eq!(
+        root.read_file(&ROOT, c, path("/a/file")).unwrap(),
+        b"HELLO world"
+    );
+    assert_eq!(
+        before.read_file(&ROOT, c, path("/a/file")).unwrap(),
+        b"hello world"
+    );
+    root.set_xattr(&ROOT, c, path("/a/file"), "user.k".into(), b"v".to_vec())
+        .unwrap();
+    let (_, hl) = root.resolve(&ROOT, c, path("/hl")).unwrap();
+    assert_eq!(hl.xattr("user.k"), Some(&b"v"[..]));
+
+    // stored once, and still shared once read back
+    let bytes = round_trip(&root);
+    let (_, records) = format::read_records(&bytes).unwrap();
+    let shared = records
+        .iter()
+        .filter(|r| matches!(r.payload, format::Payload::HardLink(_)))
+        .count();
+    assert_eq!(shared, 1);
+    let stored = records
+        .iter()
+        .find(|r| matches!(r.payload, format::Payload::File { .. }))
+        .unwrap();
+    assert_eq!(stored.links, 2);
+    let mut back = format::decode(&bytes).unwrap();
+    back.write_at(&ROOT, c, path("/a/file"), 0, b"J").unwrap();
+    assert_eq!(
+        back.read_file(&ROOT, c, path("/hl")).unwrap(),
+        b"JELLO world"
+    );
+
+    // renaming one name keeps them shared, and renaming it onto the other does nothing
+    back.rename(&ROOT, c, path("/hl"), path("/a/hl2")).unwrap();
+    back.write_at(&ROOT, c, path("/a/hl2"), 1, b"U").unwrap();
+    assert_eq!(
+        back.read_file(&ROOT, c, path("/a/file")).unwrap(),
+        b"JULLO world"
+    );
+    back.rename(&ROOT, c, path("/a/hl2"), path("/a/file"))
+        .unwrap();
+    assert!(back.resolve(&ROOT, c, path("/a/hl2")).is_ok());
+
+    // unlinking or replacing a name drops the count
+    back.unlink(&ROOT, c, path("/a/hl2")).unwrap();
+    assert_eq!(
+        back.resolve(&ROOT, c, path("/a/file")).unwrap().1.links(),
+        1
+    );
+    back.hard_link(&ROOT, c, path("/a/file"), path("/h3"))
+        .unwrap();
+    back.link(&ROOT, c, path("/h3"), props("x"), file(b"other"))
+        .unwrap();
+    assert_eq!(
+        back.resolve(&ROOT, c, path("/a/file")).unwrap().1.links(),
+        1
+    );
+
+    back.hard_link(&ROOT, c, path("/sym"), path("/sym2"))
+        .unwrap();
+    assert_eq!(back.read_link(&ROOT, c, path("/sym2")).unwrap(), "/a/file");
+    round_trip(&back);
+}
+
+#[test]
+fn links_into_encrypted_directories() {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/s"), props("x"), dir()).unwrap();
+    root.encrypt(&ROOT, c, path("/s"), b"pw").unwrap();
+    root.link(&ROOT, c, path("/s/f"), props("x"), file(b"data"))
+        .unwrap();
+    root.link(&ROOT, c, path("/s/l"), props("x"), symlink("f"))
+        .unwrap();
+    root.link(&ROOT, c, path("/out"), props("x"), symlink("/s/l"))
+        .unwrap();
+    assert_eq!(root.read_file(&ROOT, c, path("/out")).unwrap(), b"data");
+    assert_eq!(root.read_link(&ROOT, c, path("/s/l")).unwrap(), "f");
+    assert_eq!(
+        errno(root.hard_link(&ROOT, c, path("/s/f"), path("/plain"))),
+        Err(EXDEV)
+    );
+    root.hard_link(&ROOT, c, path("/s/f"), path("/s/g"))
+        .unwrap();
+
+    let bytes = format::encode(&root);
+    let mut back = format::decode(&bytes).unwrap();
+    assert_eq!(
+        errno(back.read_file(&ROOT, c, path("/out"))).err(),
+        Some(ENOENT)
+    );
+    back.unlock(&ROOT, c, path("/s"), b"pw").unwrap();
+    assert_eq!(back.read_file(&ROOT, c, path("/out")).unwrap(), b"data");
+    assert_eq!(back.read_file(&ROOT, c, path("/s/g")).unwrap(), b"data");
+}
@@ -0,0 +1,300 @@
+mod common;
+
+use common::*;
+use hmfs::format::Reader;
+use hmfs::perm::Credential;
+use hmfs::quota::Limits;
+use hmfs::volumes::{Volumes, WatchScheme};
+use hmfs::watch::{Event, EventKind, WatchId, QUEUE_LEN};
+use hmfs::RootEntry;
+use syscall::scheme::Scheme;
+use syscall::{
+    EventFlags, EACCES, EAGAIN, EBADF, EEXIST, EINVAL, ENOENT, ENOTDIR, EROFS, O_RDONLY, O_RDWR,
+};
+
+use EventKind::*;
+
+/// Everything queued on `id`, as kinds and paths
+fn events(root: &mut RootEntry, id: WatchId) -> Vec<(EventKind, String)> {
+    root.take_events(id, usize::MAX)
+        .unwrap()
+        .into_iter()
+        .map(|event| match event.from {
+            Some(from) => (event.kind, format!("{from} -> {}", event.path)),
+            None => (event.kind, event.path),
+        })
+        .collect()
+}
+
+fn event(kind: EventKind, path: &str) -> (EventKind, String) {
+    (kind, String::from(path))
+}
+
+/// A volume with /a/b and /c
+fn volume() -> RootEntry<'static> {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    for at in ["/a", "/a/b", "/c"] {
+        root.link(&ROOT, c, path(at), props("x"), dir()).unwrap();
+    }
+    root
+}
+
+#[test]
+fn create_modify_delete() {
+    let c = cwd();
+    let mut root = volume();
+    assert_eq!(
+        errno(root.watch(&Credential::new(1000, 1000), c, path("/a"))).map(drop),
+        Ok(())
+    );
+    root.chmod(&ROOT, c, path("/a"), 0o700).unwrap();
+    assert_eq!(
+        errno(root.watch(&Credential::new(1000, 1000), c, path("/a"))),
+        Err(EACCES)
+    );
+    root.link(&ROOT, c, path("/c/f"), props("x"), file(b"f"))
+        .unwrap();
+    assert_eq!(errno(root.watch(&ROOT, c, path("/c/f"))), Err(ENOTDIR));
+
+    let a = root.watch(&ROOT, c, path("/a")).unwrap();
+    let all = root.watch(&ROOT, c, path("/")).unwrap();
+    root.link(&ROOT, c, path("/a/b/f"), props("x"), file(b"hello"))
+        .unwrap();
+    root.write_at(&ROOT, c, path("/a/b/f"), 5, b" world")
+        .unwrap();
+    root.set_xattr(&ROOT, c, path("/a/b"), "user.k".into(), b"v".to_vec())
+        .unwrap();
+    root.chmod(&ROOT, c, path("/a/b/f"), 0o600).unwrap();
+    root.hard_link(&ROOT, c, path("/a/b/f"), path("/c/g"))
+        .unwrap();
+    root.unlink(&ROOT, c, path("/a/b/f")).unwrap();
+    root.truncate(&ROOT, c, path("/c/g"), 0).unwrap();
+
+    assert_eq!(root.pending_events(a).unwrap(), 5);
+    assert_eq!(
+        events(&mut root, a),
+        [
+            event(Create, "/a/b/f"),
+            event(Modify, "/a/b/f"),
+            event(Modify, "/a/b"),
+            event(Modify, "/a/b/f"),
+            event(Delete, "/a/b/f"),
+        ]
+    );
+    assert_eq!(events(&mut root, a), []);
+    assert_eq!(
+        events(&mut root, all)[4..],
+        [
+            event(Create, "/c/g"),
+            event(Delete, "/a/b/f"),
+            event(Modify, "/c/g"),
+        ]
+    );
+
+    // a change that fails, or is undone for going over a quota, isn't reported
+    assert!(root.rmdir(&ROOT, c, path("/a")).is_err());
+    let limits = Limits {
+        bytes: 1,
+        inodes: 0,
+    };
+    root.set_owner_quota(&ROOT, 0, limits).unwrap();
+    assert!(root.write_at(&ROOT, c, path("/c/g"), 0, b"xx").is_err());
+    assert!(root
+        .link(&ROOT, c, path("/a/big"), props("x"), file(b"xx"))
+        .is_err());
+    assert_eq!(events(&mut root, a), []);
+
+    root.unwatch(a).unwrap();
+    assert_eq!(errno(root.unwatch(a)), Err(EBADF));
+    assert_eq!(errno(root.pending_events(a)), Err(EBADF));
+}
+
+#[test]
+fn renames() {
+    let c = cwd();
+    let mut root = volume();
+    root.link(&ROOT, c, path("/a/f"), props("x"), file(b"f"))
+        .unwrap();
+    let a = root.watch(&ROOT, c, path("/a")).unwrap();
+    let b = root.watch(&ROOT, c, path("/a/b")).unwrap();
+
+    root.rename(&ROOT, c, path("/a/f"), path("/a/b/g")).unwrap();
+    root.rename(&ROOT, c, path("/a/b/g"), path("/c/g")).unwrap();
+    root.rename(&ROOT, c, path("/c/g"), path("/a/h")).unwrap();
+    assert_eq!(
+        events(&mut root, a),
+        [
+            event(Rename, "/a/f -> /a/b/g"),
+            event(Delete, "/a/b/g"),
+            event(Create, "/a/h"),
+        ]
+    );
+    assert_eq!(
+        events(&mut root, b),
+        [event(Create, "/a/b/g"), event(Delete, "/a/b/g")]
+    );
+
+    // watches follow a directory wherever it's moved
+    root.rename(&ROOT, c, path("/a"), path("/c/a")).unwrap();
+    root.link(&ROOT, c, path("/c/a/b/n"), props("x"), dir())
+        .unwrap();
+    assert_eq!(
+        events(&mut root, a),
+        [event(Rename, "/a -> /c/a"), event(Create, "/c/a/b/n")]
+    );
+    assert_eq!(events(&mut root, b), [event(Create, "/c/a/b/n")]);
+
+    // and are gone with it, whether it's removed or replaced
+    root.rmdir(&ROOT, c, path("/c/a/b/n")).unwrap();
+    root.rmdir(&ROOT, c, path("/c/a/b")).unwrap();
+    root.unlink(&ROOT, c, path("/c/a/h")).unwrap();
+    root.link(&ROOT, c, path("/e"), props("x"), dir()).unwrap();
+    root.rename(&ROOT, c, path("/e"), path("/c/a")).unwrap();
+    root.link(&ROOT, c, path("/c/a/f"), props("x"), dir())
+        .unwrap();
+    assert_eq!(
+        events(&mut root, b),
+        [
+            event(Delete, "/c/a/b/n"),
+            event(Delete, "/c/a/b"),
+            event(Gone, "/c/a/b"),
+        ]
+    );
+    assert_eq!(
+        events(&mut root, a),
+        [
+            event(Delete, "/c/a/b/n"),
+            event(Delete, "/c/a/b"),
+            event(Delete, "/c/a/h"),
+            event(Gone, "/c/a"),
+        ]
+    );
+}
+
+#[test]
+fn encrypted_directories() {
+    let c = cwd();
+    let mut root = volume();
+    root.encrypt(&ROOT, c, path("/c"), b"pw").unwrap();
+    let id = root.watch(&ROOT, c, path("/c")).unwrap();
+
+    // paths are given as they're shown, not as they're stored
+    root.link(&ROOT, c, path("/c/secret"), props("x"), file(b"s"))
+        .unwrap();
+    assert_eq!(events(&mut root, id), [event(Create, "/c/secret")]);
+}
+
+#[test]
+fn overflow_and_rollback() {
+    let c = cwd();
+    let mut root = volume();
+    root.link(&ROOT, c, path("/c/f"), props("x"), file(b"f"))
+        .unwrap();
+    let id = root.watch(&ROOT, c, path("/c")).unwrap();
+    for _ in 0..QUEUE_LEN {
+        root.write_at(&ROOT, c, path("/c/f"), 0, b"g").unwrap();
+    }
+    assert_eq!(events(&mut root, id), [event(Overflow, "/c")]);
+
+    root.snapshot(&ROOT, "s".into(), 1).unwrap();
+    let b = root.watch(&ROOT, c, path("/a/b")).unwrap();
+    root.rename(&ROOT, c, path("/c"), path("/d")).unwrap();
+    root.rollback(&ROOT, "s").unwrap();
+    assert_eq!(
+        events(&mut root, id),
+        [event(Rename, "/c -> /d"), event(Gone, "/d")]
+    );
+    assert_eq!(events(&mut root, b), [event(Overflow, "/a/b")]);
+
+    // events are only taken as far as they fit
+    root.link(&ROOT, c, path("/a/b/x"), props("x"), dir())
+        .unwrap();
+    root.link(&ROOT, c, path("/a/b/y"), props("x"), dir())
+        .unwrap();
+    assert_eq!(root.take_events(b, 21).unwrap().len(), 1);
+    assert_eq!(root.take_events(b, 10).unwrap().len(), 0);
+    assert_eq!(root.pending_events(b).unwrap(), 1);
+}
+
+#[test]
+fn encoding() {
+    let events = [
+        Event {
+            kind: Create,
+            path: "/a/ß".into(),
+            from: None,
+        },
+        Event {
+            kind: Rename,
+            path: "/b".into(),
+            from: Some("/a".into()),
+        },
+    ];
+    let mut out = Vec::new();
+    for event in &events {
+        event.write(&mut out);
+    }
+    assert_eq!(out.len(), events.iter().map(Event::size).sum::<usize>());
+
+    let mut reader = Reader::new(&out);
+    for event in &events {
+        assert_eq!(&Event::read(&mut reader).unwrap(), event);
+    }
+    assert!(reader.is_empty());
+
+    // a cut-short or unknown event is damage
+    assert!(Event::read(&mut Reader::new(&out[..3])).is_err());
+    out[0] = 9;
+    assert!(Event::read(&mut Reader::new(&out)).is_err());
+}
+
+#[test]
+fn through_the_scheme() {
+    let c = cwd();
+    let volumes: Volumes = Volumes::new();
+    let volume = volumes.mount("v".into(), volume()).unwrap();
+    assert_eq!(
+        errno(volumes.mount("v".into(), RootEntry::new(0))).map(drop),
+        Err(EEXIST)
+    );
+    let scheme = WatchScheme::new(&volumes);
+
+    assert_eq!(errno(scheme.open("v/a", O_RDWR, 0, 0)), Err(EROFS));
+    assert_eq!(errno(scheme.open("w/a", O_RDONLY, 0, 0)), Err(ENOENT));
+    assert_eq!(errno(scheme.open("", O_RDONLY, 0, 0)), Err(ENOENT));
+    assert_eq!(errno(scheme.open("v/c/f", O_RDONLY, 0, 0)), Err(ENOENT));
+    let fd = scheme.open("v/a", O_RDONLY, 0, 0).unwrap();
+    let mut buf = [0; 64];
+    assert_eq!(errno(scheme.read(fd, &mut buf)), Err(EAGAIN));
+    assert_eq!(
+        scheme.fevent(fd, EventFlags::EVENT_READ),
+        Ok(EventFlags::empty())
+    );
+
+    // a change to the mounted volume reaches the handle
+    volume
+        .write()
+        .link(&ROOT, c, path("/a/f"), props("x"), file(b"f"))
+        .unwrap();
+    assert_eq!(
+        scheme.fevent(fd, EventFlags::EVENT_READ),
+        Ok(EventFlags::EVENT_READ)
+    );
+    let mut stat = syscall::Stat::default();
+    scheme.fstat(fd, &mut stat).unwrap();
+    assert_eq!(stat.st_size, 1);
+    assert_eq!(errno(scheme.read(fd, &mut buf[..3])), Err(EINVAL));
+    let len = scheme.read(fd, &mut buf).unwrap();
+    let mut reader = Reader::new(&buf[..len]);
+    assert_eq!(Event::read(&mut reader).unwrap().path, "/a/f");
+    assert!(reader.is_empty());
+
+    // an unmounted volume can't be watched any more, but open handles keep working until closed
+    volumes.unmount("v").unwrap();
+    assert_eq!(errno(scheme.open("v/a", O_RDONLY, 0, 0)), Err(ENOENT));
+    assert_eq!(errno(scheme.read(fd, &mut buf)), Err(EAGAIN));
+    scheme.close(fd).unwrap();
+    assert_eq!(errno(scheme.close(fd)), Err(EBADF));
+    assert_eq!(errno(scheme.read(fd, &mut buf)), Err(EBADF));
+}
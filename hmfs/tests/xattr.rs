@@ -0,0 +1,131 @@
+mod common;
+
+use common::*;
+use hmfs::crypt::CRYPT_XATTR;
+use hmfs::perm::Credential;
+use hmfs::{format, RootEntry, XATTR_NAME_MAX, XATTR_SIZE_MAX};
+use syscall::{E2BIG, EACCES, EINVAL, ENAMETOOLONG, ENODATA, EPERM};
+
+fn volume() -> RootEntry<'static> {
+    let c = cwd();
+    let mut root = RootEntry::new(0);
+    root.link(&ROOT, c, path("/d"), props("x"), dir()).unwrap();
+    root.link(
+        &ROOT,
+        c,
+        path("/d/f"),
+        props_with("x", 0o644, 1000, 1000),
+        file(b"data"),
+    )
+    .unwrap();
+    root
+}
+
+fn xattrs(root: &RootEntry, at: &str) -> Vec<(String, Vec<u8>)> {
+    let (_, entry) = root.resolve(&ROOT, cwd(), path(at)).unwrap();
+    entry
+        .xattrs()
+        .map(|(key, value)| (key.to_string(), value.to_vec()))
+        .collect()
+}
+
+#[test]
+fn get_set_list_remove() {
+    let c = cwd();
+    let mut root = volume();
+    let set = |root: &mut RootEntry, key: &str, value: &[u8]| {
+        errno(root.set_xattr(&ROOT, c, path("/d/f"), key.into(), value.to_vec()))
+    };
+
+    set(&mut root, "user.b", b"2").unwrap();
+    set(&mut root, "user.a", b"1").unwrap();
+    set(&mut root, "user.b", b"two").unwrap();
+    root.set_xattr(&ROOT, c, path("/d"), "user.dir".into(), Vec::new())
+        .unwrap();
+
+    // listed in key order, and a value replaces the one before
+    assert_eq!(
+        xattrs(&root, "/d/f"),
+        [
+            ("user.a".into(), b"1".to_vec()),
+            ("user.b".into(), b"two".to_vec())
+        ]
+    );
+    assert_eq!(xattrs(&root, "/d"), [("user.dir".into(), Vec::new())]);
+
+    assert_eq!(
+        errno(root.remove_xattr(&ROOT, c, path("/d/f"), "user.a")),
+        Ok(b"1".to_vec())
+    );
+    assert_eq!(
+        errno(root.remove_xattr(&ROOT, c, path("/d/f"), "user.a")),
+        Err(ENODATA)
+    );
+    assert_eq!(xattrs(&root, "/d/f"), [("user.b".into(), b"two".to_vec())]);
+
+    assert_eq!(set(&mut root, "", b""), Err(EINVAL));
+    assert_eq!(
+        set(&mut root, &"k".repeat(XATTR_NAME_MAX + 1), b""),
+        Err(ENAMETOOLONG)
+    );
+    assert_eq!(
+        set(&mut root, "user.big", &vec![0; XATTR_SIZE_MAX + 1]),
+        Err(E2BIG)
+    );
+    set(
+        &mut root,
+        &"k".repeat(XATTR_NAME_MAX),
+        &vec![0; XATTR_SIZE_MAX],
+    )
+    .unwrap();
+
+    // the filesystem's own are off limits
+    assert_eq!(set(&mut root, CRYPT_XATTR, b""), Err(EPERM));
+
+    // and changing any takes write permission on the entry
+    let bob = Credential::new(1001, 1001);
+    assert_eq!(
+        errno(root.set_xattr(&bob, c, path("/d/f"), "user.c".into(), Vec::new())),
+        Err(EACCES)
+    );
+    let alice = Credential::new(1000, 1000);
+    root.set_xattr(&alice, c, path("/d/f"), "user.c".into(), Vec::new())
+        .unwrap();
+}
+
+#[test]
+fn checksummed_and_stored() {
+    let c = cwd();
+    let mut root = volume();
+    let before = checksum(&root);
+    let (_, f) = root.resolve(&ROOT, c, path("/d/f")).unwrap();
+    let file_before = f.checksum();
+
+    root.set_xattr(&ROOT, c, path("/d/f"), "user.k".into(), b"v".to_vec())
+        .unwrap();
+    let (_, f) = root.resolve(&ROOT, c, path("/d/f")).unwrap();
+    assert_ne!(f.checksum(), file_before);
+    assert_ne!(checksum(&root), before);
+    let with_v = checksum(&root);
+
+    root.set_xattr(&ROOT, c, path("/d/f"), "user.k".into(), b"w".to_vec())
+        .unwrap();
+    assert_ne!(checksum(&root), with_v);
+    root.remove_xattr(&ROOT, c, path("/d/f"), "user.k").unwrap();
+    assert_eq!(checksum(&root), before);
+
+    root.set_xattr(&ROOT, c, path("/d/f"), "user.k".into(), b"v".to_vec())
+        .unwrap();
+    let bytes = round_trip(&root);
+    let back = format::decode(&bytes).unwrap();
+    assert_eq!(xattrs(&back, "/d/f"), [("user.k".into(), b"v".to_vec())]);
+
+    // a record whose attributes aren't in key order isn't valid
+    let (_, mut records) = format::read_records(&bytes).unwrap();
+    let record = records.iter_mut().find(|r| !r.xattrs.is_empty()).unwrap();
+    record.xattrs = vec![("user.b", b"1"), ("user.a", b"2")];
+    let mut out = Vec::new();
+    record.write(&mut out);
+    let mut reader = format::Reader::new(&out);
+    assert!(format::Record::read(&mut reader).is_err());
+}
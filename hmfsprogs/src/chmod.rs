@@ -0,0 +1,36 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use hmfs::format;
+use hmfs::perm::{Credential, MODE_MASK};
+use unix_path::Path;
+
+use crate::cli::{commit, image_path, passphrase, unlock_along, Args};
+
+/// `hmfsprogs chmod <image>:/path MODE [--passphrase-file FILE]`
+///
+/// Sets the permission bits of one entry to the octal MODE, which may include the set-user-ID,
+/// set-group-ID and sticky bits. Inside an encrypted directory the entry is found with the
+/// passphrase in FILE.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &["--passphrase-file"], &[])?;
+    let spec = args.positional(0, "image:/path")?;
+    let mode = args.positional(1, "mode")?;
+    let (image, components) = image_path(spec)?;
+
+    let mode = u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| mode & !MODE_MASK == 0)
+        .ok_or_else(|| format!("invalid mode {mode:?}"))?;
+
+    let buf = std::fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    let passphrase = passphrase(&args)?;
+    unlock_along(&mut root, &components, passphrase.as_deref())
+        .map_err(|e| format!("{spec}: {e}"))?;
+
+    let path = format!("/{}", components.join("/"));
+    root.chmod(&Credential::ROOT, Path::new("/"), Path::new(&path), mode)
+        .map_err(|e| format!("{spec}: {e}"))?;
+
+    commit(image, &root)
+}
@@ -0,0 +1,62 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use hmfs::format;
+use hmfs::perm::Credential;
+use unix_path::Path;
+
+use crate::cli::{commit, image_path, passphrase, unlock_along, Args, Users};
+
+/// `hmfsprogs chown <image>:/path OWNER[:GROUP] [--passphrase-file FILE]`
+///
+/// Hands one entry to another owner and optionally group, each given as a host name or a number;
+/// `:GROUP` alone changes only the group. Inside an encrypted directory the entry is found with
+/// the passphrase in FILE.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &["--passphrase-file"], &[])?;
+    let spec = args.positional(0, "image:/path")?;
+    let owner = args.positional(1, "owner[:group]")?;
+    let (image, components) = image_path(spec)?;
+
+    let users = Users::load();
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (owner, None),
+    };
+    let uid = match user {
+        "" => None,
+        user => Some(
+            users
+                .id(user)
+                .ok_or_else(|| format!("unknown user {user:?}"))?,
+        ),
+    };
+    let gid = match group {
+        None | Some("") => None,
+        Some(group) => Some(
+            users
+                .group_id(group)
+                .ok_or_else(|| format!("unknown group {group:?}"))?,
+        ),
+    };
+    if uid.is_none() && gid.is_none() {
+        return Err(format!("invalid owner {owner:?}"));
+    }
+
+    let buf = std::fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    let passphrase = passphrase(&args)?;
+    unlock_along(&mut root, &components, passphrase.as_deref())
+        .map_err(|e| format!("{spec}: {e}"))?;
+
+    let path = format!("/{}", components.join("/"));
+    root.chown(
+        &Credential::ROOT,
+        Path::new("/"),
+        Path::new(&path),
+        uid,
+        gid,
+    )
+    .map_err(|e| format!("{spec}: {e}"))?;
+
+    commit(image, &root)
+}
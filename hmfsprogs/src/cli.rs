@@ -0,0 +1,313 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmfs::format::{self, Device};
+use hmfs::{crypt::CRYPT_XATTR, perm::Credential, time_t, Entry, Properties, RootEntry};
+use syscall::{Error, EIO, ENOENT, ENOKEY, ENOSPC};
+use unix_path::Path;
+
+/// Positional arguments and `--flag` options of one subcommand
+pub struct Args {
+    positional: Vec<String>,
+    values: Vec<(String, String)>,
+    switches: Vec<String>,
+}
+
+impl Args {
+    /// Splits `args` into positionals, flags that take a value and flags that don't
+    pub fn parse(
+        args: impl Iterator<Item = String>,
+        value_flags: &[&str],
+        switch_flags: &[&str],
+    ) -> Result<Self, String> {
+        let mut out = Self {
+            positional: Vec::new(),
+            values: Vec::new(),
+            switches: Vec::new(),
+        };
+
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            if value_flags.contains(&arg.as_str()) {
+                let value = args.next().ok_or_else(|| format!("{arg} needs a value"))?;
+                out.values.push((arg, value));
+            } else if switch_flags.contains(&arg.as_str()) {
+                out.switches.push(arg);
+            } else if arg.starts_with("--") {
+                return Err(format!("unknown option {arg}"));
+            } else {
+                out.positional.push(arg);
+            }
+        }
+
+        Ok(out)
+    }
+    pub fn positional(&self, index: usize, name: &str) -> Result<&str, String> {
+        self.positional
+            .get(index)
+            .map(String::as_str)
+            .ok_or_else(|| format!("missing <{name}>"))
+    }
+    pub fn value(&self, flag: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(name, _)| name == flag)
+            .map(|(_, value)| value.as_str())
+    }
+    /// Every value given for a flag that may be repeated, in order
+    pub fn values<'s>(&'s self, flag: &'s str) -> impl Iterator<Item = &'s str> {
+        self.values
+            .iter()
+            .filter(move |(name, _)| name == flag)
+            .map(|(_, value)| value.as_str())
+    }
+    pub fn switch(&self, flag: &str) -> bool {
+        self.switches.iter().any(|name| name == flag)
+    }
+}
+
+/// Parses a byte count with an optional binary `K`/`M`/`G`/`T` suffix
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let (digits, shift) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 10),
+        Some('M') => (&size[..size.len() - 1], 20),
+        Some('G') => (&size[..size.len() - 1], 30),
+        Some('T') => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size {size:?}"))
+}
+
+/// Current time in the units HMFS stores in `time_t` fields
+pub fn now() -> time_t {
+    to_time_t(SystemTime::now())
+}
+
+pub fn to_time_t(time: SystemTime) -> time_t {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_secs() as time_t,
+        Err(before) => -(before.duration().as_secs() as time_t),
+    }
+}
+
+pub fn from_time_t(time: time_t) -> SystemTime {
+    let secs = Duration::from_secs(time.unsigned_abs().min(u64::MAX as u128) as u64);
+    if time < 0 {
+        UNIX_EPOCH - secs
+    } else {
+        UNIX_EPOCH + secs
+    }
+}
+
+/// Splits `<image>:/path` into the image file and the path's components
+///
+/// A bare `<image>` refers to the root directory.
+pub fn image_path(spec: &str) -> Result<(&str, Vec<&str>), String> {
+    let (image, path) = match spec.rsplit_once(":/") {
+        Some((image, path)) => (image, path),
+        None => (spec, ""),
+    };
+
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return Err(format!("{spec}: `..` isn't supported in image paths")),
+            name => components.push(name),
+        }
+    }
+
+    Ok((image, components))
+}
+
+/// An image file as the device its volume lives on
+pub struct ImageDevice(pub File);
+
+impl ImageDevice {
+    /// Opens an existing image for committing to
+    pub fn open(image: &str) -> Result<Self, String> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(image)
+            .map(Self)
+            .map_err(|e| format!("{image}: {e}"))
+    }
+}
+
+fn device_error(e: io::Error) -> Error {
+    Error::new(e.raw_os_error().unwrap_or(EIO))
+}
+
+impl Device for ImageDevice {
+    fn size(&mut self) -> syscall::Result<u64> {
+        self.0
+            .metadata()
+            .map(|meta| meta.len())
+            .map_err(device_error)
+    }
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> syscall::Result<()> {
+        self.0.read_exact_at(buf, offset).map_err(device_error)
+    }
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> syscall::Result<()> {
+        self.0.write_all_at(buf, offset).map_err(device_error)
+    }
+    fn flush(&mut self) -> syscall::Result<()> {
+        self.0.sync_data().map_err(device_error)
+    }
+}
+
+/// Commits `root` to an existing image without changing its size; if that's interrupted, the
+/// image still holds the volume as it was
+pub fn commit(image: &str, root: &RootEntry) -> Result<(), String> {
+    format::commit_root(&mut ImageDevice::open(image)?, root)
+        .map(drop)
+        .map_err(|e| commit_error(image, e))
+}
+
+/// Describes why a commit to `image` failed
+pub fn commit_error(image: &str, e: Error) -> String {
+    if e.errno == ENOSPC {
+        format!("{image}: not enough free space to write the new tree alongside the current one")
+    } else {
+        format!("{image}: {e}")
+    }
+}
+
+/// Reads the passphrase in `--passphrase-file`, up to its first newline
+pub fn passphrase(args: &Args) -> Result<Option<Vec<u8>>, String> {
+    let Some(file) = args.value("--passphrase-file") else {
+        return Ok(None);
+    };
+
+    let mut passphrase = fs::read(file).map_err(|e| format!("{file}: {e}"))?;
+    if let Some(end) = passphrase.iter().position(|&byte| byte == b'\n') {
+        passphrase.truncate(end);
+    }
+    Ok(Some(passphrase))
+}
+
+/// Loads the key of the directory at `path` if it's encrypted, failing with `ENOKEY` when
+/// there's no passphrase to try
+pub fn unlock(
+    root: &mut RootEntry,
+    path: &str,
+    entry: &Entry,
+    passphrase: Option<&[u8]>,
+) -> syscall::Result<()> {
+    if entry.xattr(CRYPT_XATTR).is_none() {
+        return Ok(());
+    }
+
+    match passphrase {
+        Some(passphrase) => root.unlock(
+            &Credential::ROOT,
+            Path::new("/"),
+            Path::new(path),
+            passphrase,
+        ),
+        None => Err(Error::new(ENOKEY)),
+    }
+}
+
+/// Unlocks every encrypted directory along an image path, as far as it exists
+pub fn unlock_along(
+    root: &mut RootEntry,
+    components: &[&str],
+    passphrase: Option<&[u8]>,
+) -> syscall::Result<()> {
+    let mut path = String::new();
+    for name in components {
+        path = format!("{path}/{name}");
+
+        let entry = match root.resolve(&Credential::ROOT, Path::new("/"), Path::new(&path)) {
+            Ok((_, entry)) => entry,
+            Err(e) if e.errno == ENOENT => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        unlock(root, &path, &entry, passphrase)?;
+    }
+
+    Ok(())
+}
+
+/// User and group names from the host's /etc/passwd and /etc/group
+pub struct Users {
+    /// Name, uid and primary gid of each user
+    users: Vec<(String, u32, u32)>,
+    groups: Vec<(String, u32)>,
+}
+
+impl Users {
+    pub fn load() -> Self {
+        let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+        let group = std::fs::read_to_string("/etc/group").unwrap_or_default();
+
+        Self {
+            users: passwd
+                .lines()
+                .filter_map(|line| {
+                    let mut fields = line.split(':');
+                    let name = fields.next()?;
+                    let uid = fields.nth(1)?.parse().ok()?;
+                    let gid = fields.next()?.parse().ok()?;
+                    Some((String::from(name), uid, gid))
+                })
+                .collect(),
+            groups: group
+                .lines()
+                .filter_map(|line| {
+                    let mut fields = line.split(':');
+                    let name = fields.next()?;
+                    let gid = fields.nth(1)?.parse().ok()?;
+                    Some((String::from(name), gid))
+                })
+                .collect(),
+        }
+    }
+    /// Host user name for `uid`, or the number itself if it has no passwd entry
+    pub fn name(&self, uid: u32) -> String {
+        self.users
+            .iter()
+            .find(|(_, id, _)| *id == uid)
+            .map(|(name, _, _)| name.clone())
+            .unwrap_or_else(|| uid.to_string())
+    }
+    /// Host uid for an HMFS owner, which may also be a bare number
+    pub fn id(&self, name: &str) -> Option<u32> {
+        self.users
+            .iter()
+            .find(|(user, _, _)| user == name)
+            .map(|(_, id, _)| *id)
+            .or_else(|| name.parse().ok())
+    }
+    /// Primary gid of the host user with `uid`
+    pub fn primary_group(&self, uid: u32) -> Option<u32> {
+        self.users
+            .iter()
+            .find(|(_, id, _)| *id == uid)
+            .map(|(_, _, gid)| *gid)
+    }
+    /// Host gid for a group name, which may also be a bare number
+    pub fn group_id(&self, name: &str) -> Option<u32> {
+        self.groups
+            .iter()
+            .find(|(group, _)| group == name)
+            .map(|(_, id)| *id)
+            .or_else(|| name.parse().ok())
+    }
+    /// Host uid and gid for an HMFS entry: its owner by name where the host knows the name,
+    /// otherwise the ids it's stored with
+    pub fn ids(&self, props: &Properties) -> (u32, u32) {
+        (self.id(props.owner()).unwrap_or(props.uid()), props.gid())
+    }
+}
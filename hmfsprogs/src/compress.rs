@@ -0,0 +1,62 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use hmfs::compress::{Compression, Usage};
+use hmfs::format;
+use hmfs::perm::Credential;
+use unix_path::Path;
+
+use crate::cli::{commit, image_path, passphrase, unlock_along, Args};
+
+/// `hmfsprogs compress <image>:/path [deflate|off] [--passphrase-file FILE]`
+///
+/// Marks a file or directory for deflate compression, or clears the mark, and reports how well
+/// the files at and below the path compress: their total length against the bytes stored for
+/// them. A marked file is recompressed straight away; a marked directory compresses what gets
+/// created in it from then on. With no mode given, only the report is printed. Inside an
+/// encrypted directory the entry is found with the passphrase in FILE.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &["--passphrase-file"], &[])?;
+    let spec = args.positional(0, "image:/path")?;
+    let (image, components) = image_path(spec)?;
+
+    let compression = match args.positional(1, "mode").ok() {
+        None => None,
+        Some("deflate") => Some(Compression::Deflate),
+        Some("off") => Some(Compression::Off),
+        Some(mode) => return Err(format!("unknown compression {mode:?}")),
+    };
+
+    let buf = std::fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    let passphrase = passphrase(&args)?;
+    unlock_along(&mut root, &components, passphrase.as_deref())
+        .map_err(|e| format!("{spec}: {e}"))?;
+
+    let path = format!("/{}", components.join("/"));
+    let (cwd, path) = (Path::new("/"), Path::new(&path));
+
+    if let Some(compression) = compression {
+        root.set_compression(&Credential::ROOT, cwd, path, compression)
+            .map_err(|e| format!("{spec}: {e}"))?;
+        commit(image, &root)?;
+    }
+
+    let (_, entry) = root
+        .resolve(&Credential::ROOT, cwd, path)
+        .map_err(|e| format!("{spec}: {e}"))?;
+    let mark = match Compression::of(&entry) {
+        Ok(Compression::Off) => "off",
+        Ok(Compression::Deflate) => "deflate",
+        Err(_) => "unknown",
+    };
+
+    let usage = Usage::of(&entry);
+    println!(
+        "{spec}: {mark}, {} files, {} bytes stored in {} ({:.2}x)",
+        usage.files,
+        usage.logical,
+        usage.stored,
+        usage.ratio()
+    );
+    Ok(())
+}
@@ -0,0 +1,43 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::fs;
+
+use hmfs::format;
+use hmfs::perm::Credential;
+
+use crate::cli::{commit, Args};
+
+/// `hmfsprogs dedup <image> [--on|--off]`
+///
+/// Makes every file in the volume, snapshots included, share blocks with identical contents, so
+/// each is stored once, and reports how much that saved. `--on` also has the kernel share the
+/// blocks of files as they're written from then on, and `--off` stops it doing so.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &[], &["--on", "--off"])?;
+    let image = args.positional(0, "image")?;
+
+    let buf = fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    match (args.switch("--on"), args.switch("--off")) {
+        (true, true) => return Err(String::from("--on and --off don't go together")),
+        (true, false) => root.set_dedup(&Credential::ROOT, true),
+        (false, true) => root.set_dedup(&Credential::ROOT, false),
+        (false, false) => Ok(()),
+    }
+    .map_err(|e| format!("{image}: {e}"))?;
+
+    let stats = root
+        .dedup(&Credential::ROOT)
+        .map_err(|e| format!("{image}: {e}"))?;
+    commit(image, &root)?;
+
+    println!(
+        "{image}: merged {} duplicate blocks, {} bytes freed, {} distinct blocks; deduplication \
+         on write {}",
+        stats.merged,
+        stats.saved,
+        stats.blocks,
+        if root.dedups() { "on" } else { "off" }
+    );
+    Ok(())
+}
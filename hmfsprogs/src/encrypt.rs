@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::fs;
+use std::sync::Arc;
+
+use hmfs::perm::Credential;
+use hmfs::{detached, format, new_map_shorthand, Entry, EntryKind, Properties};
+use syscall::ENOENT;
+use unix_path::Path;
+
+use crate::cli::{commit, image_path, now, passphrase, unlock_along, Args};
+
+/// `hmfsprogs encrypt <image>:/path --passphrase-file FILE`
+///
+/// Encrypts an empty directory, creating it if needed, under a key derived from the passphrase
+/// in FILE (up to its first newline). Names and contents that `import` puts beneath it from then
+/// on are stored encrypted, and `import` and `export` need the same passphrase to get at them.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &["--passphrase-file"], &[])?;
+    let spec = args.positional(0, "image:/path")?;
+    let (image, components) = image_path(spec)?;
+    let passphrase =
+        passphrase(&args)?.ok_or_else(|| String::from("encrypt needs --passphrase-file"))?;
+
+    let buf = fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    // an encrypted directory can live inside another, whose key it takes to find
+    let parents = &components[..components.len().saturating_sub(1)];
+    unlock_along(&mut root, parents, Some(&passphrase)).map_err(|e| format!("{spec}: {e}"))?;
+
+    let path = format!("/{}", components.join("/"));
+    let (cwd, path) = (Path::new("/"), Path::new(&path));
+
+    if let Err(e) = root.resolve(&Credential::ROOT, cwd, path) {
+        if e.errno != ENOENT {
+            return Err(format!("{spec}: {e}"));
+        }
+
+        let timestamp = now();
+        let props = Properties::new(
+            String::new(),
+            detached(),
+            None,
+            0o700,
+            String::from("root"),
+            timestamp,
+            timestamp,
+            String::from("root"),
+            0,
+            0,
+        );
+        let dir = Entry::new(EntryKind::Directory(Arc::new(new_map_shorthand())), None);
+        root.link(&Credential::ROOT, cwd, path, props, dir)
+            .map_err(|e| format!("{spec}: {e}"))?;
+    }
+
+    root.encrypt(&Credential::ROOT, cwd, path, &passphrase)
+        .map_err(|e| format!("{spec}: {e}"))?;
+
+    commit(image, &root)
+}
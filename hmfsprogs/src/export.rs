@@ -0,0 +1,227 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::collections::HashMap;
+use std::fs::{self, File, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use hmfs::perm::Credential;
+use hmfs::{format, Entry, EntryKind, Properties, RootEntry};
+use unix_path::Path as ImagePath;
+
+use crate::cli::{from_time_t, image_path, passphrase, unlock, unlock_along, Args, Users};
+
+#[derive(Default)]
+struct Stats {
+    files: usize,
+    dirs: usize,
+    symlinks: usize,
+    hard_links: usize,
+    bytes: usize,
+    skipped: usize,
+}
+
+struct Export<'r, 'a> {
+    root: &'r mut RootEntry<'a>,
+    passphrase: Option<Vec<u8>>,
+    users: Users,
+    stats: Stats,
+    /// Where each entry with more than one link was exported to first
+    exported: HashMap<*const Entry<'a>, PathBuf>,
+}
+
+/// `hmfsprogs export <image>:/path <host-dir> [--snapshot NAME] [--passphrase-file FILE]`
+///
+/// Copies `/path` on an HMFS image, or in one of its snapshots, out into a host directory,
+/// which is created if needed. Mode bits and times are restored, as are owners when running
+/// with enough privilege to `chown`. Symbolic links are recreated as they are, and the names of
+/// an entry with hard links become hard links to one host file. Encrypted directories are
+/// decrypted with the passphrase in FILE, and skipped if it doesn't unlock them.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &["--snapshot", "--passphrase-file"], &[])?;
+    let spec = args.positional(0, "image:/path")?;
+    let (image, components) = image_path(spec)?;
+    let host = Path::new(args.positional(1, "host-dir")?);
+
+    let buf = fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+    if let Some(name) = args.value("--snapshot") {
+        root = root
+            .mount_snapshot(name)
+            .map_err(|e| format!("{image}: snapshot {name:?}: {e}"))?;
+    }
+
+    let passphrase = passphrase(&args)?;
+    unlock_along(&mut root, &components, passphrase.as_deref())
+        .map_err(|e| format!("{spec}: {e}"))?;
+
+    let path = format!("/{}", components.join("/"));
+    let (props, entry) = root
+        .resolve_nofollow(
+            &Credential::ROOT,
+            ImagePath::new("/"),
+            ImagePath::new(&path),
+        )
+        .map_err(|e| format!("{spec}: {e}"))?;
+
+    let mut export = Export {
+        root: &mut root,
+        passphrase,
+        users: Users::load(),
+        stats: Stats::default(),
+        exported: HashMap::new(),
+    };
+
+    fs::create_dir_all(host).map_err(|e| format!("{}: {e}", host.display()))?;
+
+    match entry.kind() {
+        EntryKind::File(_) | EntryKind::Symlink(_) => export.entry(&path, &props, &entry, host)?,
+        _ => {
+            export.children(&path, host)?;
+
+            // the root's own metadata stays with the image, not whatever directory it lands in
+            if !components.is_empty() {
+                apply_metadata(host, &props, &export.users)?;
+            }
+        }
+    }
+
+    let stats = export.stats;
+    println!(
+        "{spec}: exported {} files, {} directories, {} symbolic links and {} hard links ({} \
+         bytes) to {}{}",
+        stats.files,
+        stats.dirs,
+        stats.symlinks,
+        stats.hard_links,
+        stats.bytes,
+        host.display(),
+        match stats.skipped {
+            0 => String::new(),
+            n => format!(", skipped {n}"),
+        }
+    );
+    Ok(())
+}
+
+impl<'a> Export<'_, 'a> {
+    fn children(&mut self, path: &str, host: &Path) -> Result<(), String> {
+        let children = self
+            .root
+            .read_dir(&Credential::ROOT, ImagePath::new("/"), ImagePath::new(path))
+            .map_err(|e| format!("{path}: {e}"))?;
+
+        for (props, child) in children {
+            // the root keeps its own metadata under "/", which isn't something a host can hold
+            if props.name() == "/" {
+                continue;
+            }
+
+            if matches!(props.name(), "" | "." | "..") || props.name().contains('/') {
+                eprintln!("skipping {:?}: not a valid host file name", props.name());
+                continue;
+            }
+
+            let child_path = format!("{}/{}", path.trim_end_matches('/'), props.name());
+            self.entry(&child_path, &props, &child, host)?;
+        }
+
+        Ok(())
+    }
+    fn entry(
+        &mut self,
+        path: &str,
+        props: &Properties,
+        entry: &Entry<'a>,
+        host: &Path,
+    ) -> Result<(), String> {
+        let target = host.join(props.name());
+
+        if entry.links() > 1 {
+            match self.exported.get(&(entry as *const _)) {
+                Some(first) => {
+                    // the host file already has its metadata, which belongs to it rather than
+                    // to any one name
+                    replace(&target)?;
+                    fs::hard_link(first, &target)
+                        .map_err(|e| format!("{}: {e}", target.display()))?;
+                    self.stats.hard_links += 1;
+                    return Ok(());
+                }
+                None => {
+                    self.exported.insert(entry, target.clone());
+                }
+            }
+        }
+
+        match entry.kind() {
+            EntryKind::Symlink(_) => {
+                let link = self
+                    .root
+                    .read_link(&Credential::ROOT, ImagePath::new("/"), ImagePath::new(path))
+                    .map_err(|e| format!("{path}: {e}"))?;
+
+                replace(&target)?;
+                std::os::unix::fs::symlink(&link, &target)
+                    .map_err(|e| format!("{}: {e}", target.display()))?;
+                self.stats.symlinks += 1;
+
+                // a link's own mode and times aren't worth anything, and setting them would
+                // follow it; only the owner is kept
+                let (uid, gid) = self.users.ids(props);
+                let _ = std::os::unix::fs::lchown(&target, Some(uid), Some(gid));
+                return Ok(());
+            }
+            EntryKind::File(_) => {
+                let data = self
+                    .root
+                    .read_file(&Credential::ROOT, ImagePath::new("/"), ImagePath::new(path))
+                    .map_err(|e| format!("{path}: {e}"))?;
+
+                fs::write(&target, &data).map_err(|e| format!("{}: {e}", target.display()))?;
+                self.stats.files += 1;
+                self.stats.bytes += data.len();
+            }
+            _ => {
+                if let Err(e) = unlock(self.root, path, entry, self.passphrase.as_deref()) {
+                    eprintln!("skipping {path}: {e}");
+                    self.stats.skipped += 1;
+                    return Ok(());
+                }
+
+                if !target.is_dir() {
+                    fs::create_dir(&target).map_err(|e| format!("{}: {e}", target.display()))?;
+                }
+                self.children(path, &target)?;
+                self.stats.dirs += 1;
+            }
+        }
+
+        // after the contents, since writing children bumps a directory's mtime
+        apply_metadata(&target, props, &self.users)
+    }
+}
+
+// clears the way for a link at `target`, which unlike a file write won't replace what's there
+fn replace(target: &Path) -> Result<(), String> {
+    match fs::symlink_metadata(target) {
+        Ok(meta) if !meta.is_dir() => {
+            fs::remove_file(target).map_err(|e| format!("{}: {e}", target.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn apply_metadata(target: &Path, props: &Properties, users: &Users) -> Result<(), String> {
+    let error = |e: std::io::Error| format!("{}: {e}", target.display());
+
+    File::open(target)
+        .and_then(|file| file.set_modified(from_time_t(props.date_modified())))
+        .map_err(error)?;
+
+    // only succeeds with enough privilege; otherwise the exporting user keeps ownership
+    let (uid, gid) = users.ids(props);
+    let _ = std::os::unix::fs::chown(target, Some(uid), Some(gid));
+
+    // last, since chown clears setuid bits and a read-only mode would block the steps above
+    fs::set_permissions(target, Permissions::from_mode(props.mode() & 0o7777)).map_err(error)
+}
@@ -0,0 +1,65 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::fs;
+
+use hmfs::format;
+use hmfs::perm::Credential;
+use hmfs::sniff::sniff;
+use unix_path::Path;
+
+use crate::cli::{image_path, passphrase, unlock_along, Args};
+
+/// `hmfsprogs file <path>|<image>:/path [--passphrase-file FILE]`
+///
+/// Prints the type HMFS detects from a file's contents, the same way it fills in the type of a
+/// file created without one, for a host file or a file inside an image. Inside an encrypted
+/// directory the file is read with the passphrase in FILE. Contents of no recognized type print
+/// as `data`.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &["--passphrase-file"], &[])?;
+    let spec = args.positional(0, "path")?;
+
+    let data = if spec.contains(":/") {
+        let Some(data) = image_file(&args, spec)? else {
+            println!("{spec}: directory");
+            return Ok(());
+        };
+        data
+    } else if fs::metadata(spec).is_ok_and(|meta| meta.is_dir()) {
+        println!("{spec}: directory");
+        return Ok(());
+    } else {
+        fs::read(spec).map_err(|e| format!("{spec}: {e}"))?
+    };
+
+    match sniff(&data) {
+        Some(mime) => println!("{spec}: {mime}"),
+        None => println!("{spec}: data"),
+    }
+    Ok(())
+}
+
+// contents of the file at an image path, or `None` for a directory
+fn image_file(args: &Args, spec: &str) -> Result<Option<Vec<u8>>, String> {
+    let (image, components) = image_path(spec)?;
+
+    let buf = fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    let passphrase = passphrase(args)?;
+    unlock_along(&mut root, &components, passphrase.as_deref())
+        .map_err(|e| format!("{spec}: {e}"))?;
+
+    let path = format!("/{}", components.join("/"));
+    let (cwd, path) = (Path::new("/"), Path::new(&path));
+
+    let (_, entry) = root
+        .resolve(&Credential::ROOT, cwd, path)
+        .map_err(|e| format!("{spec}: {e}"))?;
+    if entry.kind().is_dir() {
+        return Ok(None);
+    }
+
+    root.read_file(&Credential::ROOT, cwd, path)
+        .map(Some)
+        .map_err(|e| format!("{spec}: {e}"))
+}
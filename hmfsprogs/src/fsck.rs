@@ -0,0 +1,774 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::collections::{HashMap, VecDeque};
+
+use hmfs::blocks::{self, BLOCK_SIZE};
+use hmfs::checksum::Checksum;
+use hmfs::format::{
+    self, BlockRecord, Device, Payload, PropertiesRecord, Record, SnapshotRecord, SuperBlock,
+    NO_PARENT,
+};
+
+use crate::cli::{commit_error, now, Args, ImageDevice};
+
+const LOST_AND_FOUND: &str = "lost+found";
+
+/// `hmfsprogs fsck <image> [--repair]`
+///
+/// Walks the record table from the root and from every snapshot, recomputing each file block's,
+/// entry's and root's checksum, and reports checksum mismatches, parent links that don't match
+/// any directory listing the entry, directories that contain themselves, file blocks that are
+/// out of place, hard links that don't lead to a file or symbolic link, link counts that don't
+/// match the names a tree has for an entry and entries no directory links to. `--repair`
+/// rewrites the table with the derived fields rebuilt, misplaced blocks and broken hard links
+/// dropped and orphans moved into `/lost+found`.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &[], &["--repair"])?;
+    let image = args.positional(0, "image")?;
+
+    let buf = std::fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let (superblock, records) =
+        format::read_records(&buf).map_err(|e| format!("{image}: unreadable volume: {e}"))?;
+    let data = format::data_area(&buf, &superblock)
+        .map_err(|e| format!("{image}: data area out of bounds: {e}"))?;
+    let snapshots = format::read_snapshots(&buf, &superblock);
+
+    let check = Check::run(&superblock, &records, snapshots, data)?;
+    for problem in check.problems.iter() {
+        println!("{image}: {problem}");
+    }
+
+    if check.problems.is_empty() {
+        println!(
+            "{image}: clean, {} entries, {} snapshot(s)",
+            records.len(),
+            check.snapshots.len()
+        );
+        return Ok(());
+    }
+
+    if !args.switch("--repair") {
+        return Err(format!(
+            "{image}: {} problem(s) found, run with --repair to fix",
+            check.problems.len()
+        ));
+    }
+
+    check
+        .rebuild(&superblock, &mut ImageDevice::open(image)?)
+        .map_err(|e| commit_error(image, e))?;
+
+    println!("{image}: repaired {} problem(s)", check.problems.len());
+    Ok(())
+}
+
+struct Check<'r, 'a> {
+    records: &'r [Record<'a>],
+    data: &'a [u8],
+    /// Snapshots whose root is a directory record
+    snapshots: Vec<SnapshotRecord<'a>>,
+    problems: Vec<String>,
+    /// Whether a walk has reached each record yet
+    seen: Vec<bool>,
+    /// Every directory listing each record; more than one when snapshots share it
+    linked_from: Vec<Vec<u64>>,
+    /// Directory listings with dangling, repeated, unreadable and cyclic links dropped
+    children: Vec<Vec<u64>>,
+    paths: Vec<String>,
+    /// Every record the walks reached, in the order they reached it
+    reached: Vec<u64>,
+    /// Unreachable records that aren't listed by any other unreachable directory
+    orphans: Vec<u64>,
+    /// Names the first tree to reach each file or symbolic link has for it
+    names: Vec<Option<u32>>,
+}
+
+impl<'r, 'a> Check<'r, 'a> {
+    fn run(
+        superblock: &SuperBlock,
+        records: &'r [Record<'a>],
+        snapshots: syscall::Result<Vec<SnapshotRecord<'a>>>,
+        data: &'a [u8],
+    ) -> Result<Self, String> {
+        let is_directory = |index: u64| {
+            matches!(
+                records.get(index as usize),
+                Some(Record {
+                    payload: Payload::Directory(_),
+                    ..
+                })
+            )
+        };
+
+        if !is_directory(0) {
+            return Err(String::from("no root directory, nothing to check"));
+        }
+
+        let mut check = Self {
+            records,
+            data,
+            snapshots: Vec::new(),
+            problems: Vec::new(),
+            seen: vec![false; records.len()],
+            linked_from: vec![Vec::new(); records.len()],
+            children: vec![Vec::new(); records.len()],
+            paths: vec![String::new(); records.len()],
+            reached: Vec::new(),
+            orphans: Vec::new(),
+            names: vec![None; records.len()],
+        };
+
+        match snapshots {
+            Ok(snapshots) => {
+                for snapshot in snapshots {
+                    if is_directory(snapshot.root) {
+                        check.snapshots.push(snapshot);
+                    } else {
+                        check.problems.push(format!(
+                            "snapshot {:?}: root #{} isn't a directory, dropping it",
+                            snapshot.name, snapshot.root
+                        ));
+                    }
+                }
+            }
+            Err(e) => check
+                .problems
+                .push(format!("unreadable snapshot table, dropping it: {e}")),
+        }
+
+        check.walk(0, String::from("/"));
+        for snapshot in check.snapshots.clone() {
+            check.walk(snapshot.root, format!("@{}:/", snapshot.name));
+        }
+
+        // a record listed by another unreachable directory comes along with that directory
+        let mut listed = vec![false; records.len()];
+        for (index, record) in records.iter().enumerate() {
+            if !check.seen[index] {
+                if let Payload::Directory(children) = &record.payload {
+                    for &child in children {
+                        if let Some(slot) = listed.get_mut(child as usize) {
+                            *slot = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        // cycles of unreachable directories list each other, so sweep up whatever is left too
+        for pass in [false, true] {
+            for index in 0..records.len() {
+                if check.seen[index] || (!pass && listed[index]) {
+                    continue;
+                }
+
+                let Ok(props) = PropertiesRecord::parse(records[index].properties) else {
+                    check.problems.push(format!(
+                        "#{index}: not linked from any directory and has unreadable properties, \
+                         dropping it"
+                    ));
+                    check.seen[index] = true;
+                    continue;
+                };
+
+                if let Payload::HardLink(target) = records[index].payload {
+                    if !check.links_to(target) {
+                        check.problems.push(format!(
+                            "#{index} ({}): not linked from any directory and a hard link to \
+                             #{target}, which isn't a file or symbolic link, dropping it",
+                            props.name
+                        ));
+                        check.seen[index] = true;
+                        continue;
+                    }
+                }
+
+                check.problems.push(format!(
+                    "#{index} ({}): not linked from any directory",
+                    props.name
+                ));
+                check.orphans.push(index as u64);
+                check.walk(index as u64, format!("/{LOST_AND_FOUND}/#{index}"));
+            }
+        }
+
+        check.break_cycles();
+        check.verify_parents();
+        check.verify_links();
+        check.verify_checksums(superblock);
+        Ok(check)
+    }
+
+    fn walk(&mut self, start: u64, path: String) {
+        let mut queue = VecDeque::new();
+        self.seen[start as usize] = true;
+        queue.push_back((start, path));
+
+        while let Some((index, path)) = queue.pop_front() {
+            let record = &self.records[index as usize];
+            self.paths[index as usize] = path.clone();
+            self.reached.push(index);
+
+            match &record.payload {
+                Payload::Directory(children) => {
+                    for &child in children {
+                        let Some(child_record) = self.records.get(child as usize) else {
+                            self.problems
+                                .push(format!("{path}: lists #{child}, which doesn't exist"));
+                            continue;
+                        };
+
+                        if self.children[index as usize].contains(&child) {
+                            self.problems
+                                .push(format!("{path}: lists #{child} more than once"));
+                            continue;
+                        }
+
+                        let Ok(props) = PropertiesRecord::parse(child_record.properties) else {
+                            self.problems
+                                .push(format!("{path}: #{child} has unreadable properties"));
+                            continue;
+                        };
+
+                        if let Payload::HardLink(target) = child_record.payload {
+                            if !self.links_to(target) {
+                                self.problems.push(format!(
+                                    "{}: hard link to #{target}, which isn't a file or symbolic \
+                                     link, dropping it",
+                                    join(&path, props.name)
+                                ));
+                                self.seen[child as usize] = true;
+                                continue;
+                            }
+                        }
+
+                        self.linked_from[child as usize].push(index);
+                        self.children[index as usize].push(child);
+
+                        if !core::mem::replace(&mut self.seen[child as usize], true) {
+                            queue.push_back((child, join(&path, props.name)));
+                        }
+                    }
+                }
+                Payload::File { len, blocks } => {
+                    let mut last = None;
+                    for block in blocks {
+                        if !fits(block, *len, last) {
+                            self.problems.push(format!(
+                                "{path}: block {} is out of order or past the end of the file",
+                                block.index
+                            ));
+                            continue;
+                        }
+                        last = Some(block.index);
+
+                        if self.contents(block.offset, block.len).is_none() {
+                            self.problems.push(format!(
+                                "{path}: block {} at {}+{} lies outside the data area",
+                                block.index, block.offset, block.len
+                            ));
+                        }
+                    }
+                }
+                Payload::Symlink(_) | Payload::HardLink(_) => {}
+            }
+        }
+    }
+
+    // whether a hard link can point at record `target`
+    fn links_to(&self, target: u64) -> bool {
+        self.records.get(target as usize).is_some_and(|record| {
+            matches!(record.payload, Payload::File { .. } | Payload::Symlink(_))
+                && PropertiesRecord::parse(record.properties).is_ok()
+        })
+    }
+
+    fn roots(&self) -> impl Iterator<Item = u64> + '_ {
+        [0].into_iter()
+            .chain(self.snapshots.iter().map(|snapshot| snapshot.root))
+            .chain(self.orphans.iter().copied())
+    }
+
+    // Shared records are fine, but a directory that (indirectly) lists itself has no checksum
+    // and no way out for anything walking it, so unlink whichever listing closes the loop
+    fn break_cycles(&mut self) {
+        const UNVISITED: u8 = 0;
+        const ON_STACK: u8 = 1;
+        const DONE: u8 = 2;
+
+        let mut state = vec![UNVISITED; self.records.len()];
+
+        for root in self.roots().collect::<Vec<_>>() {
+            if state[root as usize] != UNVISITED {
+                continue;
+            }
+
+            state[root as usize] = ON_STACK;
+            let mut stack = vec![(root, 0usize)];
+
+            while let Some((index, next)) = stack.last_mut() {
+                let index = *index as usize;
+
+                let Some(&child) = self.children[index].get(*next) else {
+                    state[index] = DONE;
+                    stack.pop();
+                    continue;
+                };
+
+                match state[child as usize] {
+                    UNVISITED => {
+                        *next += 1;
+                        state[child as usize] = ON_STACK;
+                        stack.push((child, 0));
+                    }
+                    ON_STACK => {
+                        self.problems.push(format!(
+                            "{}: lists #{child} ({}), which contains it",
+                            self.paths[index], self.paths[child as usize]
+                        ));
+                        self.children[index].remove(*next);
+                        self.linked_from[child as usize].retain(|&dir| dir as usize != index);
+                    }
+                    _ => *next += 1,
+                }
+            }
+        }
+    }
+
+    fn verify_parents(&mut self) {
+        for &index in self.reached.iter() {
+            let record = &self.records[index as usize];
+            let is_root = index == 0 || self.snapshots.iter().any(|s| s.root == index);
+
+            let expected = if is_root {
+                record.parent == NO_PARENT
+            } else {
+                // orphans' parents are already known to be wrong
+                self.orphans.contains(&index)
+                    || self.linked_from[index as usize].contains(&record.parent)
+            };
+
+            if !expected {
+                self.problems.push(format!(
+                    "{}: parent link points at #{}, which doesn't list it",
+                    self.paths[index as usize], record.parent
+                ));
+            }
+        }
+    }
+
+    // Counts the names each tree has for every file and symbolic link, hard links included,
+    // against the link count it was stored with. A record a snapshot shares with the live tree
+    // is counted in each; they only share it while it has the same names in both.
+    fn verify_links(&mut self) {
+        let trees = [(String::from("the live tree"), 0)]
+            .into_iter()
+            .chain(
+                self.snapshots
+                    .iter()
+                    .map(|snapshot| (format!("snapshot {:?}", snapshot.name), snapshot.root)),
+            )
+            .collect::<Vec<_>>();
+
+        for (tree, root) in trees {
+            let mut names = HashMap::<u64, u32>::new();
+            let mut entered = vec![false; self.records.len()];
+            let mut stack = vec![root];
+
+            while let Some(dir) = stack.pop() {
+                if core::mem::replace(&mut entered[dir as usize], true) {
+                    continue;
+                }
+
+                for &child in self.children[dir as usize].iter() {
+                    match self.records[child as usize].payload {
+                        Payload::Directory(_) => stack.push(child),
+                        Payload::HardLink(target) => *names.entry(target).or_default() += 1,
+                        _ => *names.entry(child).or_default() += 1,
+                    }
+                }
+            }
+
+            let mut names = names.into_iter().collect::<Vec<_>>();
+            names.sort();
+
+            for (index, count) in names {
+                let links = self.records[index as usize].links;
+                if links != count {
+                    self.problems.push(format!(
+                        "{}: link count is {links}, but {tree} has {count} name(s) for it",
+                        self.paths[index as usize]
+                    ));
+                }
+                self.names[index as usize].get_or_insert(count);
+            }
+        }
+    }
+
+    fn contents(&self, offset: u64, len: u64) -> Option<&'a [u8]> {
+        let start = usize::try_from(offset).ok()?;
+        let end = start.checked_add(usize::try_from(len).ok()?)?;
+        self.data.get(start..end)
+    }
+
+    // Checks each record against its own contents and its children's stored checksums, so a
+    // mismatch points at the record that actually changed
+    fn verify_checksums(&mut self, superblock: &SuperBlock) {
+        let mut computed = vec![None; self.records.len()];
+
+        for &index in self.reached.iter() {
+            let index = index as usize;
+            let record = &self.records[index];
+
+            if let Payload::File { blocks, .. } = &record.payload {
+                for block in blocks {
+                    let Some(contents) = self.contents(block.offset, block.len) else {
+                        continue;
+                    };
+
+                    let checksum = blocks::block_checksum(contents);
+                    if checksum != block.checksum {
+                        self.problems.push(format!(
+                            "{}: block {} checksum mismatch (stored {:#x}, computed \
+                             {checksum:#x})",
+                            self.paths[index], block.index, block.checksum
+                        ));
+                    }
+                }
+            }
+
+            let checksum = self.checksum(index, &|child| self.records[child as usize].checksum);
+
+            if checksum != record.checksum {
+                self.problems.push(format!(
+                    "{}: checksum mismatch (stored {:#x}, computed {checksum:#x})",
+                    self.paths[index], record.checksum
+                ));
+            }
+
+            computed[index] = Some(checksum);
+        }
+
+        let roots = [("root".to_string(), 0, superblock.checksum)]
+            .into_iter()
+            .chain(self.snapshots.iter().map(|snapshot| {
+                (
+                    format!("snapshot {:?}", snapshot.name),
+                    snapshot.root,
+                    snapshot.checksum,
+                )
+            }));
+
+        for (name, index, stored) in roots.collect::<Vec<_>>() {
+            if let Some(checksum) = computed[index as usize].filter(|&c| c != stored) {
+                self.problems.push(format!(
+                    "{name} checksum mismatch (stored {stored:#x}, computed {checksum:#x})"
+                ));
+            }
+        }
+    }
+
+    fn checksum(&self, index: usize, child_checksum: &dyn Fn(u64) -> Checksum) -> Checksum {
+        match &self.records[index].payload {
+            Payload::Directory(_) => {
+                let mut children = self.children[index]
+                    .iter()
+                    .map(|&child| {
+                        let properties = self.records[child as usize].properties;
+                        let name = PropertiesRecord::parse(properties).unwrap().name;
+                        (name, properties, child_checksum(child))
+                    })
+                    .collect::<Vec<_>>();
+                format::directory_checksum(&mut children, &self.records[index].xattrs)
+            }
+            Payload::File { len, blocks } => format::file_checksum(
+                *len,
+                blocks,
+                &self.records[index].xattrs,
+                self.records[index].links,
+            ),
+            Payload::Symlink(target) => format::symlink_checksum(
+                target,
+                &self.records[index].xattrs,
+                self.records[index].links,
+            ),
+            // the same entry as the record it points at
+            Payload::HardLink(target) => child_checksum(*target),
+        }
+    }
+
+    // the blocks of a file worth keeping, with their checksums recomputed from what's stored
+    fn usable_blocks(&self, len: u64, blocks: &[BlockRecord]) -> Vec<BlockRecord> {
+        let mut last = None;
+        let mut out = Vec::new();
+
+        for block in blocks {
+            if !fits(block, len, last) {
+                continue;
+            }
+            let Some(contents) = self.contents(block.offset, block.len) else {
+                continue;
+            };
+
+            last = Some(block.index);
+            out.push(BlockRecord {
+                checksum: blocks::block_checksum(contents),
+                ..*block
+            });
+        }
+
+        out
+    }
+    /// Commits the reachable trees to `device` again with parent links, checksums and the record
+    /// count rebuilt, orphans moved under `/lost+found` and misplaced or unreadable blocks
+    /// dropped, which leaves holes
+    fn rebuild(&self, superblock: &SuperBlock, device: &mut dyn Device) -> syscall::Result<()> {
+        let timestamp = now();
+        let lost_and_found_props = PropertiesRecord {
+            name: LOST_AND_FOUND,
+            mime_type: None,
+            mode: 0o700,
+            created_by: "root",
+            owner: "root",
+            date_created: timestamp,
+            date_modified: timestamp,
+            uid: 0,
+            gid: 0,
+        }
+        .to_bytes();
+
+        // reuse an existing /lost+found if there is one
+        let existing = self.children[0].iter().copied().find(|&child| {
+            let record = &self.records[child as usize];
+            matches!(record.payload, Payload::Directory(_))
+                && PropertiesRecord::parse(record.properties)
+                    .is_ok_and(|props| props.name == LOST_AND_FOUND)
+        });
+
+        #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+        enum Node {
+            Old(u64),
+            Orphan(u64),
+            LostAndFound,
+        }
+
+        let children_of = |node: Node| -> Vec<Node> {
+            let mut out = Vec::new();
+            match node {
+                Node::Old(index) | Node::Orphan(index) => {
+                    out.extend(self.children[index as usize].iter().map(|&c| Node::Old(c)));
+                    if Some(index) == existing && matches!(node, Node::Old(_)) {
+                        out.extend(self.orphans.iter().map(|&o| Node::Orphan(o)));
+                    }
+                    if node == Node::Old(0) && existing.is_none() && !self.orphans.is_empty() {
+                        out.push(Node::LostAndFound);
+                    }
+                }
+                Node::LostAndFound => out.extend(self.orphans.iter().map(|&o| Node::Orphan(o))),
+            }
+            out
+        };
+
+        // breadth-first from the live root and then each snapshot, as `format::encode` does,
+        // with records shared between trees kept shared
+        let mut order = vec![(NO_PARENT, Node::Old(0))];
+        order.extend(
+            self.snapshots
+                .iter()
+                .map(|snapshot| (NO_PARENT, Node::Old(snapshot.root))),
+        );
+        let mut placed = order
+            .iter()
+            .enumerate()
+            .map(|(index, &(_, node))| (node, index as u64))
+            .collect::<HashMap<_, _>>();
+        let mut nodes = Vec::new();
+
+        while let Some(&(parent, node)) = order.get(nodes.len()) {
+            let index = nodes.len() as u64;
+
+            let properties = match node {
+                Node::Old(old) => self.records[old as usize].properties.to_vec(),
+                Node::Orphan(old) => {
+                    let mut props =
+                        PropertiesRecord::parse(self.records[old as usize].properties).unwrap();
+                    let name = format!("#{old}");
+                    props.name = &name;
+                    props.to_bytes()
+                }
+                Node::LostAndFound => lost_and_found_props.clone(),
+            };
+
+            let old = match node {
+                Node::Old(old) | Node::Orphan(old) => Some(&self.records[old as usize]),
+                Node::LostAndFound => None,
+            };
+
+            // hard links still point at old records here, and are mapped once everything's placed
+            let payload = match old.map(|record| &record.payload) {
+                Some(Payload::File { len, blocks }) => Payload::File {
+                    len: *len,
+                    blocks: self.usable_blocks(*len, blocks),
+                },
+                Some(Payload::Symlink(target)) => Payload::Symlink(target.clone()),
+                Some(Payload::HardLink(target)) => Payload::HardLink(*target),
+                Some(Payload::Directory(_)) | None => Payload::Directory(
+                    children_of(node)
+                        .into_iter()
+                        .map(|child| {
+                            *placed.entry(child).or_insert_with(|| {
+                                order.push((index, child));
+                                order.len() as u64 - 1
+                            })
+                        })
+                        .collect(),
+                ),
+            };
+
+            let xattrs = old.map(|record| record.xattrs.clone()).unwrap_or_default();
+
+            let links = match (node, &payload) {
+                (
+                    Node::Old(old) | Node::Orphan(old),
+                    Payload::File { .. } | Payload::Symlink(_),
+                ) => self.names[old as usize].unwrap_or(self.records[old as usize].links),
+                _ => 1,
+            };
+
+            nodes.push((parent, properties, payload, xattrs, links));
+        }
+
+        // `walk` only let through hard links to records that end up placed one way or another
+        for (_, _, payload, _, _) in nodes.iter_mut() {
+            if let Payload::HardLink(target) = payload {
+                *target = placed
+                    .get(&Node::Old(*target))
+                    .or_else(|| placed.get(&Node::Orphan(*target)))
+                    .copied()
+                    .unwrap();
+            }
+        }
+
+        // shared records can come before some of the directories listing them, so checksum
+        // depth-first rather than just walking the table backwards
+        let mut checksums = vec![None; nodes.len()];
+        for start in 0..nodes.len() {
+            let mut stack = vec![start];
+
+            while let Some(&index) = stack.last() {
+                if checksums[index].is_some() {
+                    stack.pop();
+                    continue;
+                }
+
+                let children = match &nodes[index].2 {
+                    Payload::Directory(children) => children.as_slice(),
+                    Payload::HardLink(target) => core::slice::from_ref(target),
+                    _ => &[],
+                };
+                let pending = children
+                    .iter()
+                    .map(|&child| child as usize)
+                    .filter(|&child| checksums[child].is_none())
+                    .collect::<Vec<_>>();
+
+                if !pending.is_empty() {
+                    stack.extend(pending);
+                    continue;
+                }
+
+                let (xattrs, links) = (&nodes[index].3, nodes[index].4);
+                checksums[index] = Some(match &nodes[index].2 {
+                    Payload::File { len, blocks } => {
+                        format::file_checksum(*len, blocks, xattrs, links)
+                    }
+                    Payload::Symlink(target) => format::symlink_checksum(target, xattrs, links),
+                    Payload::HardLink(target) => checksums[*target as usize].unwrap(),
+                    Payload::Directory(_) => {
+                        let mut children = children
+                            .iter()
+                            .map(|&child| {
+                                let properties = nodes[child as usize].1.as_slice();
+                                let name = PropertiesRecord::parse(properties).unwrap().name;
+                                (name, properties, checksums[child as usize].unwrap())
+                            })
+                            .collect::<Vec<_>>();
+                        format::directory_checksum(&mut children, xattrs)
+                    }
+                });
+                stack.pop();
+            }
+        }
+        let checksums = checksums
+            .into_iter()
+            .map(Option::unwrap)
+            .collect::<Vec<_>>();
+
+        // blocks shared between files before stay shared
+        let mut data = Vec::new();
+        let mut moved = HashMap::new();
+        let records = nodes
+            .iter()
+            .zip(checksums.iter())
+            .map(
+                |((parent, properties, payload, xattrs, links), checksum)| Record {
+                    parent: *parent,
+                    checksum: *checksum,
+                    properties: properties.as_slice(),
+                    payload: match payload {
+                        Payload::File { len, blocks } => Payload::File {
+                            len: *len,
+                            blocks: blocks
+                                .iter()
+                                .map(|block| {
+                                    let offset = *moved
+                                        .entry((block.offset, block.len))
+                                        .or_insert_with(|| {
+                                            let offset = data.len() as u64;
+                                            data.extend_from_slice(
+                                                self.contents(block.offset, block.len).unwrap(),
+                                            );
+                                            offset
+                                        });
+                                    BlockRecord { offset, ..*block }
+                                })
+                                .collect(),
+                        },
+                        other => other.clone(),
+                    },
+                    xattrs: xattrs.clone(),
+                    links: *links,
+                },
+            )
+            .collect::<Vec<_>>();
+
+        let snapshots = self
+            .snapshots
+            .iter()
+            .map(|snapshot| {
+                let root = placed[&Node::Old(snapshot.root)];
+                SnapshotRecord {
+                    root,
+                    checksum: checksums[root as usize],
+                    ..*snapshot
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut repaired = *superblock;
+        repaired.checksum = checksums[0];
+        format::commit(device, repaired, &records, &snapshots, &data).map(drop)
+    }
+}
+
+// whether `block` can follow the block at index `last` in a file of `len` bytes
+fn fits(block: &BlockRecord, len: u64, last: Option<u64>) -> bool {
+    block.index < len.div_ceil(BLOCK_SIZE) && last.is_none_or(|last| last < block.index)
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{dir}{name}")
+    } else {
+        format!("{dir}/{name}")
+    }
+}
@@ -0,0 +1,307 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+
+use hmfs::blocks::FileBlocks;
+use hmfs::perm::Credential;
+use hmfs::sniff::sniff;
+use hmfs::{detached, format, new_map_shorthand, Entry, EntryKind, Properties, RootEntry};
+use mr_mime::Mime;
+use syscall::{ENOENT, EXDEV};
+use unix_path::Path as ImagePath;
+
+use crate::cli::{
+    commit, image_path, now, passphrase, to_time_t, unlock, unlock_along, Args, Users,
+};
+
+#[derive(Default)]
+struct Stats {
+    files: usize,
+    dirs: usize,
+    symlinks: usize,
+    hard_links: usize,
+    bytes: usize,
+    skipped: usize,
+}
+
+struct Import<'r, 'a> {
+    root: &'r mut RootEntry<'a>,
+    passphrase: Option<Vec<u8>>,
+    users: Users,
+    stats: Stats,
+    /// Where each host file with more than one link was imported to first, by device and inode
+    imported: HashMap<(u64, u64), String>,
+}
+
+/// `hmfsprogs import <host-dir> <image>:/path [--passphrase-file FILE]`
+///
+/// Copies a host directory tree into `/path` on an HMFS image, creating `/path` if needed and
+/// merging into directories that already exist. Mode bits, times and owners are carried over
+/// and each file's MIME type is guessed from its extension, or sniffed from its contents when
+/// the extension doesn't say. Symbolic links are copied as they are, and host files with several
+/// names inside the tree become hard links to one entry. Encrypted directories on the way are
+/// unlocked with the passphrase in FILE.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &["--passphrase-file"], &[])?;
+    let host = Path::new(args.positional(0, "host-dir")?);
+    let spec = args.positional(1, "image:/path")?;
+    let (image, dest) = image_path(spec)?;
+    let passphrase = passphrase(&args)?;
+
+    let buf = fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    unlock_along(&mut root, &dest, passphrase.as_deref()).map_err(|e| format!("{spec}: {e}"))?;
+
+    // mkdir -p
+    let timestamp = now();
+    let mut path = String::new();
+    for name in dest.iter() {
+        path = format!("{path}/{name}");
+
+        match root.resolve(
+            &Credential::ROOT,
+            ImagePath::new("/"),
+            ImagePath::new(&path),
+        ) {
+            Err(e) if e.errno == ENOENT => {
+                let props = Properties::new(
+                    String::from(*name),
+                    detached(),
+                    None,
+                    0o755,
+                    String::from("root"),
+                    timestamp,
+                    timestamp,
+                    String::from("root"),
+                    0,
+                    0,
+                );
+                root.link(
+                    &Credential::ROOT,
+                    ImagePath::new("/"),
+                    ImagePath::new(&path),
+                    props,
+                    empty_dir(),
+                )
+            }
+            other => other.map(drop),
+        }
+        .map_err(|e| format!("{spec}: {e}"))?;
+    }
+
+    let mut import = Import {
+        root: &mut root,
+        passphrase,
+        users: Users::load(),
+        stats: Stats::default(),
+        imported: HashMap::new(),
+    };
+
+    let meta = fs::metadata(host).map_err(|e| format!("{}: {e}", host.display()))?;
+    if meta.is_dir() {
+        import.dir(host, &path)?;
+    } else {
+        let name = host
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format!("{}: not a UTF-8 file name", host.display()))?;
+        let target = format!("{path}/{name}");
+        import.file(host, &meta, &target)?;
+    }
+
+    let stats = import.stats;
+    commit(image, &root)?;
+
+    println!(
+        "{spec}: imported {} files, {} directories, {} symbolic links and {} hard links ({} \
+         bytes){}",
+        stats.files,
+        stats.dirs,
+        stats.symlinks,
+        stats.hard_links,
+        stats.bytes,
+        match stats.skipped {
+            0 => String::new(),
+            n => format!(", skipped {n}"),
+        }
+    );
+    Ok(())
+}
+
+fn empty_dir<'a>() -> Entry<'a> {
+    Entry::new(EntryKind::Directory(Arc::new(new_map_shorthand())), None)
+}
+
+fn properties<'a>(
+    name: &str,
+    meta: &Metadata,
+    mime: Option<Mime<'a>>,
+    users: &Users,
+) -> Properties<'a> {
+    let modified = meta.modified().map(to_time_t).unwrap_or(0);
+    let created = meta.created().map(to_time_t).unwrap_or(modified);
+    let owner = users.name(meta.uid());
+
+    Properties::new(
+        String::from(name),
+        detached(),
+        mime,
+        meta.permissions().mode() & 0o7777,
+        owner.clone(),
+        created,
+        modified,
+        owner,
+        meta.uid(),
+        meta.gid(),
+    )
+}
+
+impl Import<'_, '_> {
+    fn dir(&mut self, host: &Path, dest: &str) -> Result<(), String> {
+        let mut entries = fs::read_dir(host)
+            .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("{}: {e}", host.display()))?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let Ok(name) = entry.file_name().into_string() else {
+                eprintln!("skipping {}: not a UTF-8 file name", path.display());
+                self.stats.skipped += 1;
+                continue;
+            };
+
+            let meta =
+                fs::symlink_metadata(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+            let target = format!("{dest}/{name}");
+
+            if meta.is_dir() {
+                let props = properties(&name, &meta, None, &self.users);
+
+                // keep what's already in an existing directory, but take the host's metadata
+                let dir = match self.root.resolve_nofollow(
+                    &Credential::ROOT,
+                    ImagePath::new("/"),
+                    ImagePath::new(&target),
+                ) {
+                    Ok((_, existing)) if existing.kind().is_dir() => existing,
+                    _ => empty_dir(),
+                };
+                self.root
+                    .link(
+                        &Credential::ROOT,
+                        ImagePath::new("/"),
+                        ImagePath::new(&target),
+                        props,
+                        dir.clone(),
+                    )
+                    .and_then(|_| unlock(self.root, &target, &dir, self.passphrase.as_deref()))
+                    .map_err(|e| format!("{}: {e}", path.display()))?;
+
+                self.stats.dirs += 1;
+                self.dir(&path, &target)?;
+            } else if meta.is_symlink() {
+                self.symlink(&path, &meta, &target)?;
+            } else if meta.is_file() {
+                self.file(&path, &meta, &target)?;
+            } else {
+                eprintln!(
+                    "skipping {}: not a regular file, directory or symbolic link",
+                    path.display()
+                );
+                self.stats.skipped += 1;
+            }
+        }
+
+        Ok(())
+    }
+    fn file(&mut self, host: &Path, meta: &Metadata, target: &str) -> Result<(), String> {
+        if meta.nlink() > 1 {
+            let inode = (meta.dev(), meta.ino());
+
+            match self.imported.get(&inode).cloned() {
+                Some(first) => match self.hard_link(&first, target) {
+                    Ok(()) => {
+                        self.stats.hard_links += 1;
+                        return Ok(());
+                    }
+                    // the names are in directories encrypted differently, so it takes a copy
+                    Err(e) if e.errno == EXDEV => {}
+                    Err(e) => return Err(format!("{}: {e}", host.display())),
+                },
+                None => {
+                    self.imported.insert(inode, String::from(target));
+                }
+            }
+        }
+
+        let data = fs::read(host).map_err(|e| format!("{}: {e}", host.display()))?;
+
+        let name = target.rsplit('/').next().unwrap_or(target);
+        let mime = Path::new(name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| Mime::guess(ext).next())
+            .or_else(|| sniff(&data));
+        let props = properties(name, meta, mime, &self.users);
+
+        self.stats.files += 1;
+        self.stats.bytes += data.len();
+
+        let file = Entry::new(EntryKind::File(FileBlocks::from_bytes(&data)), None);
+        self.root
+            .link(
+                &Credential::ROOT,
+                ImagePath::new("/"),
+                ImagePath::new(target),
+                props,
+                file,
+            )
+            .map_err(|e| format!("{}: {e}", host.display()))
+    }
+    // another name for the entry imported at `first`, replacing whatever isn't a directory
+    fn hard_link(&mut self, first: &str, target: &str) -> syscall::Result<()> {
+        let (first, target) = (ImagePath::new(first), ImagePath::new(target));
+
+        match self
+            .root
+            .resolve_nofollow(&Credential::ROOT, ImagePath::new("/"), target)
+        {
+            Ok((_, existing)) if !existing.kind().is_dir() => {
+                self.root
+                    .unlink(&Credential::ROOT, ImagePath::new("/"), target)?
+            }
+            _ => {}
+        }
+        self.root
+            .hard_link(&Credential::ROOT, ImagePath::new("/"), first, target)
+    }
+    fn symlink(&mut self, host: &Path, meta: &Metadata, target: &str) -> Result<(), String> {
+        let link = fs::read_link(host).map_err(|e| format!("{}: {e}", host.display()))?;
+        let Some(link) = link.to_str() else {
+            eprintln!("skipping {}: not a UTF-8 link target", host.display());
+            self.stats.skipped += 1;
+            return Ok(());
+        };
+
+        let name = target.rsplit('/').next().unwrap_or(target);
+        let props = properties(name, meta, None, &self.users);
+
+        self.stats.symlinks += 1;
+
+        let symlink = Entry::new(EntryKind::Symlink(String::from(link)), None);
+        self.root
+            .link(
+                &Credential::ROOT,
+                ImagePath::new("/"),
+                ImagePath::new(target),
+                props,
+                symlink,
+            )
+            .map_err(|e| format!("{}: {e}", host.display()))
+    }
+}
@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::fs::OpenOptions;
+
+use hmfs::perm::Credential;
+use hmfs::{format, RootEntry};
+
+use crate::cli::{commit_error, now, parse_size, Args, ImageDevice, Users};
+
+/// `hmfsprogs mkfs <image> --size N [--label LABEL] [--owner USER]`
+///
+/// Creates a sparse image file of the requested size holding an empty HMFS root, owned by USER
+/// (a host user name or a uid) and their primary group
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &["--size", "--label", "--owner"], &[])?;
+
+    let image = args.positional(0, "image")?;
+    let size = parse_size(args.value("--size").ok_or("--size is required")?)?;
+    let owner = args.value("--owner").unwrap_or("root");
+    let label = args.value("--label").unwrap_or_default();
+
+    let users = Users::load();
+    let uid = users
+        .id(owner)
+        .ok_or_else(|| format!("unknown user {owner:?}"))?;
+    let gid = users.primary_group(uid).unwrap_or(0);
+
+    let mut root = RootEntry::new_owned_by(now(), String::from(owner), uid, gid);
+    root.set_label(&Credential::ROOT, String::from(label))
+        .map_err(|_| format!("label is longer than {} bytes", format::LABEL_LEN))?;
+
+    // the superblock's second slot sits in the last bytes of the image
+    let needed = format::encode(&root).len() + format::SUPERBLOCK_SIZE;
+    if needed as u64 > size {
+        return Err(format!(
+            "{size} bytes is too small for an HMFS volume ({needed} needed)"
+        ));
+    }
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(image)
+        .map_err(|e| format!("{image}: {e}"))?;
+
+    // extending with `set_len` leaves everything past the superblock and tree as a hole
+    file.set_len(size).map_err(|e| format!("{image}: {e}"))?;
+    format::commit_root(&mut ImageDevice(file), &root).map_err(|e| commit_error(image, e))?;
+
+    println!(
+        "{image}: {size} bytes, HMFS v{}, label {label:?}, root owned by {owner}",
+        format::VERSION
+    );
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use hmfs::crypt::CRYPT_XATTR;
+use hmfs::index::Query;
+use hmfs::perm::Credential;
+use hmfs::{format, time_t, RootEntry};
+use unix_path::Path;
+
+use crate::cli::{passphrase, Args, Users};
+
+const TIME_FLAGS: [&str; 4] = [
+    "--created-since",
+    "--created-before",
+    "--modified-since",
+    "--modified-before",
+];
+
+/// `hmfsprogs query <image> [--mime TYPE] [--owner USER] [--created-since T] [--created-before T]
+/// [--modified-since T] [--modified-before T] [--xattr KEY[=VALUE]]... [--passphrase-file FILE]`
+///
+/// Prints the path of every entry in the live tree matching all of the criteria, found through
+/// the volume's attribute indexes the way the kernel finds them. Times are Unix times in
+/// seconds, `since` inclusive and `before` exclusive, and USER is a host user name or a uid.
+/// Each `--xattr` requires an extended attribute to be set, to VALUE if one is given. Encrypted
+/// directories the passphrase in FILE opens have their entries listed by their real names.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut value_flags = Vec::from(["--mime", "--owner", "--xattr", "--passphrase-file"]);
+    value_flags.extend(TIME_FLAGS);
+    let args = Args::parse(args, &value_flags, &[])?;
+    let image = args.positional(0, "image")?;
+
+    let [created_since, created_before, modified_since, modified_before] =
+        TIME_FLAGS.map(|flag| args.value(flag).map(parse_time).transpose());
+    let query = Query {
+        mime: args.value("--mime").map(String::from),
+        uid: args
+            .value("--owner")
+            .map(|owner| {
+                Users::load()
+                    .id(owner)
+                    .ok_or_else(|| format!("unknown user {owner:?}"))
+            })
+            .transpose()?,
+        created_since: created_since?,
+        created_before: created_before?,
+        modified_since: modified_since?,
+        modified_before: modified_before?,
+        xattrs: args
+            .values("--xattr")
+            .map(|xattr| match xattr.split_once('=') {
+                Some((key, value)) => (String::from(key), Some(value.as_bytes().to_vec())),
+                None => (String::from(xattr), None),
+            })
+            .collect(),
+    };
+
+    let buf = std::fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    if let Some(passphrase) = passphrase(&args)? {
+        unlock_all(&mut root, &passphrase);
+    }
+
+    for path in root.query(&Credential::ROOT, &query) {
+        println!("{path}");
+    }
+    Ok(())
+}
+
+fn parse_time(time: &str) -> Result<time_t, String> {
+    time.parse()
+        .map_err(|_| format!("invalid time {time:?}, expected Unix seconds"))
+}
+
+// Unlocks every encrypted directory `passphrase` opens. One inside another only shows up by
+// its real name once the outer one is open, so this goes round until nothing more opens.
+fn unlock_all(root: &mut RootEntry, passphrase: &[u8]) {
+    let encrypted = Query {
+        xattrs: Vec::from([(String::from(CRYPT_XATTR), None)]),
+        ..Query::default()
+    };
+
+    let mut opened = Vec::new();
+    loop {
+        let mut progress = false;
+        for path in root.query(&Credential::ROOT, &encrypted) {
+            if opened.contains(&path) {
+                continue;
+            }
+
+            let (cwd, dir) = (Path::new("/"), Path::new(&path));
+            if root.unlock(&Credential::ROOT, cwd, dir, passphrase).is_ok() {
+                opened.push(path);
+                progress = true;
+            }
+        }
+
+        if !progress {
+            break;
+        }
+    }
+}
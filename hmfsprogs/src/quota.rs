@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use hmfs::format;
+use hmfs::perm::Credential;
+use hmfs::quota::{Holder, Limits};
+use unix_path::Path;
+
+use crate::cli::{commit, image_path, parse_size, passphrase, unlock_along, Args, Users};
+
+/// `hmfsprogs quota <image>[:/path] [--owner USER] [--bytes SIZE] [--inodes N]
+/// [--passphrase-file FILE]`
+///
+/// Sets the limits on what an owner, given as a host user name or a uid, or the directory at the
+/// path can store, and reports every quota in the volume: what each owner has stored, what's
+/// below each directory with limits, and the limits themselves. A limit of 0 lifts it, one left
+/// out stays as it was, and `<image>:/` limits the whole volume. With neither limit given, only
+/// the report is printed. Inside an encrypted directory the entry is found with the passphrase
+/// in FILE.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(
+        args,
+        &["--owner", "--bytes", "--inodes", "--passphrase-file"],
+        &[],
+    )?;
+    let spec = args.positional(0, "image[:/path]")?;
+    let (image, components) = image_path(spec)?;
+    let users = Users::load();
+
+    let bytes = args.value("--bytes").map(parse_size).transpose()?;
+    let inodes = args
+        .value("--inodes")
+        .map(|n| n.parse::<u64>().map_err(|_| format!("invalid count {n:?}")))
+        .transpose()?;
+
+    let holder = match (args.value("--owner"), spec.contains(":/")) {
+        (Some(_), true) => return Err(String::from("--owner and a path don't go together")),
+        (Some(owner), false) => Some(Holder::Owner(
+            users
+                .id(owner)
+                .ok_or_else(|| format!("unknown user {owner:?}"))?,
+        )),
+        (None, true) => Some(Holder::Directory(format!("/{}", components.join("/")))),
+        (None, false) => None,
+    };
+
+    let buf = std::fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    let passphrase = passphrase(&args)?;
+    unlock_along(&mut root, &components, passphrase.as_deref())
+        .map_err(|e| format!("{spec}: {e}"))?;
+
+    if bytes.is_some() || inodes.is_some() {
+        let holder = holder.ok_or("--bytes and --inodes need --owner or a path")?;
+        let old = root
+            .quotas(&Credential::ROOT)
+            .into_iter()
+            .find(|quota| quota.holder == holder)
+            .map_or(Limits::default(), |quota| quota.limits);
+        let limits = Limits {
+            bytes: bytes.unwrap_or(old.bytes),
+            inodes: inodes.unwrap_or(old.inodes),
+        };
+
+        match &holder {
+            Holder::Owner(uid) => root.set_owner_quota(&Credential::ROOT, *uid, limits),
+            Holder::Directory(path) => {
+                root.set_quota(&Credential::ROOT, Path::new("/"), Path::new(path), limits)
+            }
+        }
+        .map_err(|e| format!("{spec}: {e}"))?;
+        commit(image, &root)?;
+    }
+
+    let limit = |limit: u64| match limit {
+        0 => String::from("-"),
+        limit => limit.to_string(),
+    };
+    println!(
+        "{:<24} {:>12} {:>12} {:>10} {:>10}",
+        "QUOTA", "BYTES", "LIMIT", "INODES", "LIMIT"
+    );
+    for quota in root.quotas(&Credential::ROOT) {
+        let holder = match quota.holder {
+            Holder::Owner(uid) => format!("owner {}", users.name(uid)),
+            Holder::Directory(path) => path,
+        };
+        println!(
+            "{holder:<24} {:>12} {:>12} {:>10} {:>10}",
+            quota.usage.bytes,
+            limit(quota.limits.bytes),
+            quota.usage.inodes,
+            limit(quota.limits.inodes)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::fs;
+use std::io::{self, Read};
+
+use hmfs::format;
+use hmfs::perm::Credential;
+use syscall::{EEXIST, EINVAL, EIO, ENOENT};
+
+use crate::cli::{commit, Args};
+
+/// `hmfsprogs receive <image> < stream`
+///
+/// Reads a stream made by `hmfsprogs send` from standard input and takes the snapshot it
+/// describes, starting from the snapshot in the volume it was made against. The new snapshot
+/// has to come out with the checksum it had where it was sent from, or nothing is kept.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &[], &[])?;
+    let image = args.positional(0, "image")?;
+
+    let mut stream = Vec::new();
+    io::stdin()
+        .read_to_end(&mut stream)
+        .map_err(|e| format!("reading the stream: {e}"))?;
+
+    let buf = fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    let stats = root
+        .receive(&Credential::ROOT, &stream)
+        .map_err(|e| match e.errno {
+            ENOENT => format!("{image}: no snapshot matches the one the stream was sent against"),
+            EEXIST => format!("{image}: already has a snapshot by the name the stream makes"),
+            EINVAL => String::from("not a stream from hmfsprogs send"),
+            EIO => String::from("the stream is damaged"),
+            _ => format!("{image}: {e}"),
+        })?;
+    commit(image, &root)?;
+
+    let snapshot = root.snapshots().last().expect("just received");
+    println!(
+        "{image}: received {:?}: {} changes, {} blocks ({} bytes) in full, {} by reference",
+        snapshot.name(),
+        stats.ops,
+        stats.blocks,
+        stats.bytes,
+        stats.shared
+    );
+    Ok(())
+}
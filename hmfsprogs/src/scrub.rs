@@ -0,0 +1,38 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use hmfs::format;
+use hmfs::scrub::Scrub;
+
+use crate::cli::Args;
+
+/// `hmfsprogs scrub <image>`
+///
+/// Re-verifies every entry and file block of the live tree and of each snapshot against its
+/// checksum, the same way the kernel's background scrub does, and prints the path of anything
+/// that doesn't match. Encrypted contents are verified as stored, so no passphrase is needed.
+/// Unlike `fsck` this only reads the volume; damage it finds is for `fsck --repair` or a backup
+/// to deal with.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &[], &[])?;
+    let image = args.positional(0, "image")?;
+
+    let buf = std::fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    let mut scrub = Scrub::new(&root);
+    let mut damaged = 0;
+    scrub.run(&mut |damage| {
+        println!("{image}: {damage}");
+        damaged += 1;
+    });
+
+    if damaged != 0 {
+        return Err(format!("{image}: {damaged} problem(s) found"));
+    }
+
+    println!(
+        "{image}: clean, {} entries and {} blocks verified",
+        scrub.entries(),
+        scrub.blocks()
+    );
+    Ok(())
+}
@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+
+use hmfs::format;
+use hmfs::perm::Credential;
+
+use crate::cli::Args;
+
+/// `hmfsprogs send <image> [--from SNAPSHOT] --to SNAPSHOT > stream`
+///
+/// Writes the stream that turns the snapshot named by `--from` into the one named by `--to` to
+/// standard output, carrying only the entries that differ between them and only the blocks the
+/// first doesn't already have. Without `--from` the stream holds the whole of `--to`. Encrypted
+/// entries go as they're stored, so no passphrase is needed at either end.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &["--from", "--to"], &[])?;
+    let image = args.positional(0, "image")?;
+    let to = args.value("--to").ok_or("--to is required")?;
+    let from = args.value("--from");
+
+    let mut stdout = io::stdout().lock();
+    if stdout.is_terminal() {
+        return Err(String::from("not writing a stream to a terminal"));
+    }
+
+    let buf = fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    for name in from.into_iter().chain([to]) {
+        if !root
+            .snapshots()
+            .iter()
+            .any(|snapshot| snapshot.name() == name)
+        {
+            return Err(format!("{image}: no snapshot {name:?}"));
+        }
+    }
+
+    let (stream, stats) = root
+        .send(&Credential::ROOT, from, to)
+        .map_err(|e| format!("{image}: {e}"))?;
+    stdout
+        .write_all(&stream)
+        .and_then(|()| stdout.flush())
+        .map_err(|e| format!("writing the stream: {e}"))?;
+
+    eprintln!(
+        "{image}: sent {to:?}{}: {} changes, {} blocks ({} bytes) in full, {} by reference",
+        from.map(|from| format!(" from {from:?}"))
+            .unwrap_or_default(),
+        stats.ops,
+        stats.blocks,
+        stats.bytes,
+        stats.shared
+    );
+    Ok(())
+}
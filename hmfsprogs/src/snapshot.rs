@@ -0,0 +1,46 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::fs;
+
+use hmfs::format;
+use hmfs::perm::Credential;
+
+use crate::cli::{commit, now, Args};
+
+/// `hmfsprogs snapshot <image> list|create|delete|rollback [NAME]`
+///
+/// Lists a volume's snapshots, takes a new one of the live tree, deletes one, or rolls the live
+/// tree back to one.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &[], &[])?;
+    let image = args.positional(0, "image")?;
+    let action = args.positional(1, "list|create|delete|rollback")?;
+
+    let buf = fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    if action == "list" {
+        for snapshot in root.snapshots() {
+            println!(
+                "{}\tcreated {}\tchecksum {:#x}",
+                snapshot.name(),
+                snapshot.created(),
+                snapshot.checksum()
+            );
+        }
+        return Ok(());
+    }
+
+    let name = args.positional(2, "name")?;
+    let result = match action {
+        "create" => root.snapshot(&Credential::ROOT, String::from(name), now()),
+        "delete" => root.delete_snapshot(&Credential::ROOT, name),
+        "rollback" => root.rollback(&Credential::ROOT, name),
+        _ => return Err(format!("unknown snapshot action {action:?}")),
+    };
+    result.map_err(|e| format!("{image}: snapshot {name:?}: {e}"))?;
+
+    commit(image, &root)?;
+
+    println!("{image}: {action} {name:?} done");
+    Ok(())
+}
@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::fs;
+use std::io::Write;
+
+use hmfs::format;
+use hmfs::perm::Credential;
+use unix_path::Path;
+
+use crate::cli::{commit, image_path, Args};
+
+/// `hmfsprogs xattr <image>:/path list|get|set|remove [KEY] [VALUE] [--file PATH]`
+///
+/// Lists, prints, sets or removes the extended attributes of one entry. `get` writes the raw
+/// value to stdout, and `set` takes the value from `--file` when it isn't given inline.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = Args::parse(args, &["--file"], &[])?;
+    let spec = args.positional(0, "image:/path")?;
+    let action = args.positional(1, "list|get|set|remove")?;
+    let (image, components) = image_path(spec)?;
+
+    let buf = fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    let mut root = format::decode(&buf).map_err(|e| format!("{image}: {e}"))?;
+
+    let path = format!("/{}", components.join("/"));
+    let (cwd, path) = (Path::new("/"), Path::new(&path));
+
+    let (_, entry) = root
+        .resolve(&Credential::ROOT, cwd, path)
+        .map_err(|e| format!("{spec}: {e}"))?;
+
+    if action == "list" {
+        for (key, value) in entry.xattrs() {
+            println!("{key}\t{} bytes", value.len());
+        }
+        return Ok(());
+    }
+
+    let key = args.positional(2, "key")?;
+    match action {
+        "get" => {
+            let value = entry
+                .xattr(key)
+                .ok_or_else(|| format!("{spec}: no attribute {key:?}"))?;
+            return std::io::stdout()
+                .write_all(value)
+                .map_err(|e| format!("stdout: {e}"));
+        }
+        "set" => {
+            let value = match (args.positional(3, "value"), args.value("--file")) {
+                (Ok(value), None) => value.as_bytes().to_vec(),
+                (Err(_), Some(file)) => fs::read(file).map_err(|e| format!("{file}: {e}"))?,
+                _ => return Err(String::from("set needs exactly one of <value> or --file")),
+            };
+            root.set_xattr(&Credential::ROOT, cwd, path, String::from(key), value)
+        }
+        "remove" => root
+            .remove_xattr(&Credential::ROOT, cwd, path, key)
+            .map(drop),
+        _ => return Err(format!("unknown xattr action {action:?}")),
+    }
+    .map_err(|e| format!("{spec}: {key:?}: {e}"))?;
+
+    commit(image, &root)
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use std::fs;
+
+use hmfs::format::{self, SUPERBLOCK_SIZE};
+
+use common::*;
+
+/// The slot the live superblock is in, and its generation
+fn live(image: &str) -> (usize, u64) {
+    let buf = fs::read(image).unwrap();
+    let len = buf.len() as u64;
+    let [first, second] = format::slot_offsets(len);
+    let (slot, superblock) =
+        format::newest_superblock([&buf[first as usize..], &buf[second as usize..]], len).unwrap();
+    (slot, superblock.generation)
+}
+
+#[test]
+fn commits_alternate_slots() {
+    let scratch = Scratch::new("commit");
+    let image = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    fs::create_dir(&host).unwrap();
+    fs::write(host.join("first"), b"1").unwrap();
+    run(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+    let (slot, generation) = live(&image);
+
+    fs::write(host.join("second"), b"2").unwrap();
+    run(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+    assert_eq!(live(&image), (1 - slot, generation + 1));
+
+    // a commit cut off as the superblock went down leaves the tree before it
+    let mut buf = fs::read(&image).unwrap();
+    let at = format::slot_offsets(buf.len() as u64)[1 - slot] as usize;
+    buf[at..at + SUPERBLOCK_SIZE / 2].fill(0);
+    fs::write(&image, &buf).unwrap();
+    assert_eq!(live(&image), (slot, generation));
+    with_root(&image, |root| assert_eq!(list(root, "/"), ["first"]));
+    assert!(run(&["fsck", &image]).contains("clean"));
+}
+
+#[test]
+fn full_volume_is_left_as_it_was() {
+    let scratch = Scratch::new("commit-full");
+    let image = scratch.join("image");
+    run(&["mkfs", &image, "--size", "64K"]);
+    let host = scratch.path().join("host");
+    fs::create_dir(&host).unwrap();
+    fs::write(host.join("big"), vec![7; 1 << 20]).unwrap();
+    let before = fs::read(&image).unwrap();
+
+    let (_, err) = run_fails(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+    assert!(err.contains("not enough free space"), "{err}");
+    assert_eq!(fs::read(&image).unwrap(), before);
+}
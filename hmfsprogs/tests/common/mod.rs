@@ -0,0 +1,192 @@
+// Helpers shared by the test files; not every file uses all of them
+#![allow(dead_code)]
+
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::os::unix::fs::{chown, lchown, symlink, FileExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output};
+use std::time::{Duration, UNIX_EPOCH};
+
+use hmfs::format::{self, Device, Record};
+use hmfs::perm::Credential;
+use hmfs::{Entry, Properties, RootEntry};
+use syscall::{Error, EIO};
+use unix_path::Path as ImagePath;
+
+/// A directory of a test's own, removed along with everything in it when dropped
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("hmfsprogs-test-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+    /// `name` in the directory, as the string hmfsprogs takes paths as
+    pub fn join(&self, name: &str) -> String {
+        self.0.join(name).to_str().unwrap().into()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs hmfsprogs with `args`
+pub fn hmfsprogs(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_hmfsprogs"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Runs hmfsprogs with `args`, which has to succeed, returning what it printed
+pub fn run(args: &[&str]) -> String {
+    let output = hmfsprogs(args);
+    assert!(
+        output.status.success(),
+        "hmfsprogs {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Runs hmfsprogs with `args`, which has to fail, returning what it printed and its error
+pub fn run_fails(args: &[&str]) -> (String, String) {
+    let output = hmfsprogs(args);
+    assert!(!output.status.success(), "hmfsprogs {args:?} succeeded");
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+/// Makes an empty 4 MiB image called `image` in `scratch`
+pub fn mkfs(scratch: &Scratch) -> String {
+    let image = scratch.join("image");
+    run(&["mkfs", &image, "--size", "4M"]);
+    image
+}
+
+/// When the files in `host_tree` were last modified
+pub const MODIFIED: u64 = 1_000_000_000;
+
+/// A host tree with something of each kind import maps: modes beyond the permission bits, an
+/// owner of its own, symbolic links and a file with two names; whether the owner could be set,
+/// which takes root, comes back with it
+pub fn host_tree(host: &Path) -> bool {
+    fs::create_dir_all(host.join("docs")).unwrap();
+    fs::write(host.join("docs/notes"), b"remember the milk\n").unwrap();
+    fs::write(host.join("docs/todo"), b"buy milk\n").unwrap();
+    fs::write(host.join("run"), b"#!/bin/sh\n").unwrap();
+    fs::hard_link(host.join("docs/todo"), host.join("todo")).unwrap();
+    symlink("docs/notes", host.join("notes")).unwrap();
+    symlink("/nowhere", host.join("dangling")).unwrap();
+
+    for file in ["docs/notes", "docs/todo", "run"] {
+        File::options()
+            .write(true)
+            .open(host.join(file))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(MODIFIED))
+            .unwrap();
+    }
+
+    let owned = chown(host.join("docs/notes"), Some(1234), Some(5678)).is_ok()
+        && lchown(host.join("notes"), Some(1234), Some(5678)).is_ok();
+
+    fs::set_permissions(host.join("run"), Permissions::from_mode(0o4751)).unwrap();
+    fs::set_permissions(host.join("docs/notes"), Permissions::from_mode(0o640)).unwrap();
+    fs::set_permissions(host.join("docs"), Permissions::from_mode(0o1750)).unwrap();
+    owned
+}
+
+/// Reads the volume in `image`, handing its root to `f`
+pub fn with_root<T>(image: &str, f: impl FnOnce(&RootEntry) -> T) -> T {
+    let buf = fs::read(image).unwrap();
+    let root = format::decode(&buf).unwrap();
+    f(&root)
+}
+
+/// The properties and entry at `path`, not following a symbolic link
+pub fn lookup<'a>(root: &RootEntry<'a>, path: &str) -> (Properties<'a>, Entry<'a>) {
+    root.resolve_nofollow(&Credential::ROOT, ImagePath::new("/"), ImagePath::new(path))
+        .unwrap()
+}
+
+/// Names in the directory at `path`, sorted; the root's own entry for its metadata isn't one
+pub fn list(root: &RootEntry, path: &str) -> Vec<String> {
+    let mut names = root
+        .read_dir(&Credential::ROOT, ImagePath::new("/"), ImagePath::new(path))
+        .unwrap()
+        .into_iter()
+        .map(|(props, _)| String::from(props.name()))
+        .filter(|name| name != "/")
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+pub fn read_file(root: &RootEntry, path: &str) -> Vec<u8> {
+    root.read_file(&Credential::ROOT, ImagePath::new("/"), ImagePath::new(path))
+        .unwrap()
+}
+
+/// An image file as a device, for changing a volume behind hmfsprogs' back
+struct ImageFile(File);
+
+impl Device for ImageFile {
+    fn size(&mut self) -> syscall::Result<u64> {
+        self.0
+            .metadata()
+            .map(|meta| meta.len())
+            .map_err(|_| Error::new(EIO))
+    }
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> syscall::Result<()> {
+        self.0
+            .read_exact_at(buf, offset)
+            .map_err(|_| Error::new(EIO))
+    }
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> syscall::Result<()> {
+        self.0
+            .write_all_at(buf, offset)
+            .map_err(|_| Error::new(EIO))
+    }
+    fn flush(&mut self) -> syscall::Result<()> {
+        Ok(())
+    }
+}
+
+/// Has `f` change the record table of the volume in `image`, which is then committed as it is,
+/// with nothing derived from it brought up to date
+pub fn damage(image: &str, f: impl FnOnce(&mut Vec<Record>)) {
+    let buf = fs::read(image).unwrap();
+    let (superblock, mut records) = format::read_records(&buf).unwrap();
+    let snapshots = format::read_snapshots(&buf, &superblock).unwrap();
+    let data = format::data_area(&buf, &superblock).unwrap();
+
+    f(&mut records);
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image)
+        .unwrap();
+    format::commit(&mut ImageFile(file), superblock, &records, &snapshots, data).unwrap();
+}
+
+/// Index of the record named `name`
+pub fn record(records: &[Record], name: &str) -> usize {
+    records
+        .iter()
+        .position(|record| {
+            format::PropertiesRecord::parse(record.properties).is_ok_and(|props| props.name == name)
+        })
+        .unwrap_or_else(|| panic!("no record is named {name:?}"))
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+
+/// The mark and the file count, length and bytes stored a report gives
+fn report(out: &str) -> (String, u64, u64, u64) {
+    let (_, rest) = out.split_once(": ").unwrap();
+    let rest = rest.replace(',', "");
+    let words = rest.split_whitespace().collect::<Vec<_>>();
+    let number = |at: usize| words[at].parse().unwrap();
+    (words[0].into(), number(1), number(3), number(7))
+}
+
+#[test]
+fn mark_report_and_read_back() {
+    let scratch = Scratch::new("compress");
+    let image = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    fs::create_dir_all(host.join("dir")).unwrap();
+    let text = b"all work and no play makes a dull volume\n".repeat(400);
+    fs::write(host.join("dir/text"), &text).unwrap();
+    run(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+
+    let file = format!("{image}:/dir/text");
+    let len = text.len() as u64;
+    assert_eq!(
+        report(&run(&["compress", &file])),
+        ("off".into(), 1, len, len)
+    );
+    let (mark, _, _, stored) = report(&run(&["compress", &file, "deflate"]));
+    assert_eq!(mark, "deflate");
+    assert!(stored < len / 4, "{stored} of {len}");
+
+    // a marked directory compresses what's put in it afterwards
+    let dir = format!("{image}:/dir");
+    run(&["compress", &dir, "deflate"]);
+    fs::write(host.join("dir/more"), &text).unwrap();
+    run(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+    let (mark, files, logical, stored) = report(&run(&["compress", &dir]));
+    assert_eq!((mark.as_str(), files, logical), ("deflate", 2, 2 * len));
+    assert!(stored < len / 2, "{stored} of {}", 2 * len);
+
+    with_root(&image, |root| {
+        assert_eq!(read_file(root, "/dir/text"), text);
+        assert_eq!(read_file(root, "/dir/more"), text);
+    });
+
+    // and turning it off stores the file as it is again
+    assert_eq!(
+        report(&run(&["compress", &file, "off"])),
+        ("off".into(), 1, len, len)
+    );
+    let (_, err) = run_fails(&["compress", &file, "zstd"]);
+    assert!(err.contains("unknown compression"), "{err}");
+    assert!(run(&["fsck", &image]).contains("clean"));
+}
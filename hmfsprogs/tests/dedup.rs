@@ -0,0 +1,41 @@
+mod common;
+
+use std::fs;
+
+use hmfs::blocks::BLOCK_SIZE;
+
+use common::*;
+
+#[test]
+fn merges_and_switches() {
+    let scratch = Scratch::new("dedup");
+    let image = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    fs::create_dir(&host).unwrap();
+    let block = BLOCK_SIZE as usize;
+    let data = (0..2 * block).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(host.join("a"), &data).unwrap();
+    fs::write(host.join("b"), &data).unwrap();
+    run(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+
+    let out = run(&["dedup", &image]);
+    assert!(out.contains("merged 2 duplicate blocks"), "{out}");
+    let freed = format!("{} bytes freed, 2 distinct blocks", 2 * block);
+    assert!(out.contains(&freed), "{out}");
+    assert!(out.ends_with("on write off\n"), "{out}");
+    // once it's done there's nothing left to merge
+    assert!(run(&["dedup", &image]).contains("merged 0 duplicate blocks"));
+    with_root(&image, |root| {
+        assert_eq!(read_file(root, "/a"), data);
+        assert_eq!(read_file(root, "/b"), data);
+    });
+
+    // the switch is kept with the volume
+    assert!(run(&["dedup", &image, "--on"]).ends_with("on write on\n"));
+    assert!(run(&["dedup", &image]).ends_with("on write on\n"));
+    with_root(&image, |root| assert!(root.dedups()));
+    assert!(run(&["dedup", &image, "--off"]).ends_with("on write off\n"));
+    let (_, err) = run_fails(&["dedup", &image, "--on", "--off"]);
+    assert!(err.contains("don't go together"), "{err}");
+    assert!(run(&["fsck", &image]).contains("clean"));
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+
+/// Whether `needle` appears anywhere in `haystack`
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+#[test]
+fn import_and_export_through_a_passphrase() {
+    let scratch = Scratch::new("encrypt");
+    let image = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    fs::create_dir(&host).unwrap();
+    fs::write(
+        host.join("diary-entry"),
+        b"dear diary, today I wrote tests\n",
+    )
+    .unwrap();
+
+    let passphrase = scratch.join("passphrase");
+    fs::write(&passphrase, b"correct horse\nignored after the newline").unwrap();
+    let wrong = scratch.join("wrong");
+    fs::write(&wrong, b"battery staple\n").unwrap();
+
+    let vault = format!("{image}:/vault");
+    run_fails(&["encrypt", &vault]);
+    run(&["encrypt", &vault, "--passphrase-file", &passphrase]);
+
+    // the key's needed to put anything in, and the right one at that
+    let host_dir = host.to_str().unwrap();
+    let (_, err) = run_fails(&["import", host_dir, &vault]);
+    assert!(err.contains("vault"), "{err}");
+    run_fails(&["import", host_dir, &vault, "--passphrase-file", &wrong]);
+    run(&["import", host_dir, &vault, "--passphrase-file", &passphrase]);
+
+    // neither the name nor the contents are on disk as they are
+    let stored = fs::read(&image).unwrap();
+    assert!(!contains(&stored, b"diary-entry"));
+    assert!(!contains(&stored, b"dear diary"));
+
+    // without the key it's skipped, with it it's all there
+    let out_dir = scratch.path().join("out");
+    let out = run(&["export", &format!("{image}:/"), out_dir.to_str().unwrap()]);
+    assert!(out.contains("skipped 1"), "{out}");
+    assert!(!out_dir.join("vault/diary-entry").exists());
+
+    let out_dir = scratch.path().join("unlocked");
+    run(&[
+        "export",
+        &format!("{image}:/"),
+        out_dir.to_str().unwrap(),
+        "--passphrase-file",
+        &passphrase,
+    ]);
+    assert_eq!(
+        fs::read(out_dir.join("vault/diary-entry")).unwrap(),
+        b"dear diary, today I wrote tests\n"
+    );
+
+    // an encrypted directory has to start out empty
+    let (_, err) = run_fails(&["encrypt", &vault, "--passphrase-file", &passphrase]);
+    assert!(err.contains("not empty"), "{err}");
+    assert!(run(&["fsck", &image]).contains("clean"));
+}
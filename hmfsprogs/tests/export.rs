@@ -0,0 +1,94 @@
+mod common;
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use common::*;
+
+/// Everything about the entry at `path` that import and export carry over, not following a
+/// symbolic link
+fn describe(path: &Path, owned: bool) -> String {
+    let meta = fs::symlink_metadata(path).unwrap();
+    let owner = if owned {
+        format!(" owned by {}:{}", meta.uid(), meta.gid())
+    } else {
+        String::new()
+    };
+
+    if meta.is_symlink() {
+        format!(
+            "symlink to {}{owner}",
+            fs::read_link(path).unwrap().display()
+        )
+    } else if meta.is_dir() {
+        format!("directory {:o}{owner}", meta.mode() & 0o7777)
+    } else {
+        format!(
+            "file {:o}{owner}, modified {}, {} link(s): {:?}",
+            meta.mode() & 0o7777,
+            meta.mtime(),
+            meta.nlink(),
+            fs::read(path).unwrap()
+        )
+    }
+}
+
+#[test]
+fn round_trip() {
+    let scratch = Scratch::new("export");
+    let image = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    let owned = host_tree(&host);
+
+    let dest = format!("{image}:/tree");
+    run(&["import", host.to_str().unwrap(), &dest]);
+
+    let out_dir = scratch.path().join("out");
+    let out = run(&["export", &dest, out_dir.to_str().unwrap()]);
+    assert!(
+        out.contains("exported 3 files, 1 directories, 2 symbolic links and 1 hard links"),
+        "{out}"
+    );
+
+    for path in [
+        "docs",
+        "docs/notes",
+        "docs/todo",
+        "run",
+        "todo",
+        "notes",
+        "dangling",
+    ] {
+        assert_eq!(
+            describe(&out_dir.join(path), owned),
+            describe(&host.join(path), owned),
+            "{path}"
+        );
+    }
+
+    // the two names are one file again
+    let ino = |path: &str| fs::metadata(out_dir.join(path)).unwrap().ino();
+    assert_eq!(ino("todo"), ino("docs/todo"));
+}
+
+#[test]
+fn single_file() {
+    let scratch = Scratch::new("export-file");
+    let image = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    host_tree(&host);
+    run(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+
+    let out_dir = scratch.path().join("out");
+    run(&[
+        "export",
+        &format!("{image}:/docs/notes"),
+        out_dir.to_str().unwrap(),
+    ]);
+    assert_eq!(
+        fs::read(out_dir.join("notes")).unwrap(),
+        b"remember the milk\n"
+    );
+    assert_eq!(fs::read_dir(&out_dir).unwrap().count(), 1);
+}
@@ -0,0 +1,43 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+
+#[test]
+fn types_from_contents() {
+    let scratch = Scratch::new("file");
+    let image = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    fs::create_dir_all(host.join("dir")).unwrap();
+    fs::write(host.join("picture"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+    fs::write(host.join("words"), b"just some words\n").unwrap();
+    fs::write(host.join("blob"), b"\0\x01\x02\x03").unwrap();
+
+    // the same on the host as in an image, where import filled in what it found
+    let host_path = |name: &str| host.join(name).to_str().unwrap().to_string();
+    run(&["import", &host_path(""), &format!("{image}:/")]);
+    for (name, detected) in [
+        ("picture", "image/png"),
+        ("words", "text/plain"),
+        ("blob", "data"),
+        ("dir", "directory"),
+    ] {
+        let on_host = host_path(name);
+        assert_eq!(run(&["file", &on_host]), format!("{on_host}: {detected}\n"));
+        let in_image = format!("{image}:/{name}");
+        assert_eq!(
+            run(&["file", &in_image]),
+            format!("{in_image}: {detected}\n")
+        );
+    }
+    with_root(&image, |root| {
+        let (props, _) = lookup(root, "/picture");
+        assert_eq!(props.mime_type().unwrap().to_string(), "image/png");
+        let (props, _) = lookup(root, "/blob");
+        assert!(props.mime_type().is_none());
+    });
+
+    run_fails(&["file", &host_path("nope")]);
+    run_fails(&["file", &format!("{image}:/nope")]);
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use std::fs;
+
+use hmfs::checksum::{Checksum, CHECKSUM_LEN};
+use hmfs::format::Payload;
+
+use common::*;
+
+/// An image holding a directory with two files, one of them also linked from the root
+fn populated(scratch: &Scratch) -> String {
+    let image = mkfs(scratch);
+
+    let host = scratch.path().join("host");
+    fs::create_dir_all(host.join("docs")).unwrap();
+    fs::write(host.join("docs/notes"), b"remember the milk\n").unwrap();
+    fs::write(host.join("docs/todo"), b"buy milk\n").unwrap();
+    fs::hard_link(host.join("docs/todo"), host.join("todo")).unwrap();
+
+    let dest = format!("{image}:/");
+    run(&["import", host.to_str().unwrap(), &dest]);
+    image
+}
+
+#[test]
+fn clean_volume() {
+    let scratch = Scratch::new("fsck-clean");
+    let image = populated(&scratch);
+
+    let out = run(&["fsck", &image]);
+    assert!(out.contains("clean"), "{out}");
+}
+
+#[test]
+fn checksums_and_link_counts() {
+    let scratch = Scratch::new("fsck-checksums");
+    let image = populated(&scratch);
+
+    damage(&image, |records| {
+        let notes = record(records, "notes");
+        records[notes].checksum = Checksum([0; CHECKSUM_LEN]);
+        let todo = record(records, "todo");
+        records[todo].links = 5;
+    });
+
+    // found, and left alone without --repair
+    let (out, err) = run_fails(&["fsck", &image]);
+    assert!(out.contains("/docs/notes: checksum mismatch"), "{out}");
+    assert!(
+        out.contains("link count is 5, but the live tree has 2"),
+        "{out}"
+    );
+    assert!(err.contains("run with --repair"), "{err}");
+    run_fails(&["fsck", &image]);
+
+    let out = run(&["fsck", &image, "--repair"]);
+    assert!(out.contains("repaired"), "{out}");
+    assert!(run(&["fsck", &image]).contains("clean"));
+
+    with_root(&image, |root| {
+        assert_eq!(read_file(root, "/docs/notes"), b"remember the milk\n");
+        assert_eq!(lookup(root, "/docs/todo").1.links(), 2);
+        assert_eq!(read_file(root, "/todo"), b"buy milk\n");
+    });
+}
+
+#[test]
+fn orphans_go_to_lost_and_found() {
+    let scratch = Scratch::new("fsck-orphans");
+    let image = populated(&scratch);
+
+    let mut orphan = 0;
+    damage(&image, |records| {
+        let docs = record(records, "docs");
+        orphan = record(records, "notes");
+        let Payload::Directory(children) = &mut records[docs].payload else {
+            panic!("docs isn't a directory");
+        };
+        children.retain(|&child| child != orphan as u64);
+    });
+
+    let (out, _) = run_fails(&["fsck", &image]);
+    assert!(
+        out.contains(&format!("#{orphan} (notes): not linked from any directory")),
+        "{out}"
+    );
+
+    run(&["fsck", &image, "--repair"]);
+    assert!(run(&["fsck", &image]).contains("clean"));
+
+    with_root(&image, |root| {
+        let (props, _) = lookup(root, "/lost+found");
+        assert_eq!(props.mode(), 0o700);
+        assert_eq!(
+            read_file(root, &format!("/lost+found/#{orphan}")),
+            b"remember the milk\n"
+        );
+        assert_eq!(read_file(root, "/docs/todo"), b"buy milk\n");
+    });
+}
+
+#[test]
+fn misplaced_blocks_are_dropped() {
+    let scratch = Scratch::new("fsck-blocks");
+    let image = populated(&scratch);
+
+    damage(&image, |records| {
+        let notes = record(records, "notes");
+        let Payload::File { blocks, .. } = &mut records[notes].payload else {
+            panic!("notes isn't a file");
+        };
+        blocks[0].offset = u64::MAX - 4;
+    });
+
+    let (out, _) = run_fails(&["fsck", &image]);
+    assert!(out.contains("/docs/notes"), "{out}");
+
+    run(&["fsck", &image, "--repair"]);
+    assert!(run(&["fsck", &image]).contains("clean"));
+
+    // the block is gone, which leaves a hole the length of the file
+    with_root(&image, |root| {
+        assert_eq!(read_file(root, "/docs/notes"), [0; 18]);
+    });
+}
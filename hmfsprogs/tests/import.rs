@@ -0,0 +1,87 @@
+mod common;
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+use hmfs::perm::Credential;
+use hmfs::EntryKind;
+use unix_path::Path as ImagePath;
+
+use common::*;
+
+#[test]
+fn maps_modes_owners_and_links() {
+    let scratch = Scratch::new("import");
+    let image = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    let owned = host_tree(&host);
+
+    let dest = format!("{image}:/imported");
+    let out = run(&["import", host.to_str().unwrap(), &dest]);
+    assert!(
+        out.contains("imported 3 files, 1 directories, 2 symbolic links and 1 hard links"),
+        "{out}"
+    );
+
+    with_root(&image, |root| {
+        for (path, mode) in [
+            ("/imported/run", 0o4751),
+            ("/imported/docs", 0o1750),
+            ("/imported/docs/notes", 0o640),
+        ] {
+            assert_eq!(lookup(root, path).0.mode(), mode, "{path}");
+        }
+
+        let (props, _) = lookup(root, "/imported/docs/notes");
+        assert_eq!(props.date_modified(), MODIFIED as i128);
+        let meta = fs::metadata(host.join("docs/notes")).unwrap();
+        assert_eq!((props.uid(), props.gid()), (meta.uid(), meta.gid()));
+        if owned {
+            // not a user the host has a name for
+            assert_eq!(props.owner(), "1234");
+            assert_eq!(lookup(root, "/imported/notes").0.uid(), 1234);
+        }
+
+        let (_, notes) = lookup(root, "/imported/notes");
+        assert!(matches!(notes.kind(), EntryKind::Symlink(_)));
+        let link = |path| {
+            root.read_link(&Credential::ROOT, ImagePath::new("/"), ImagePath::new(path))
+                .unwrap()
+        };
+        assert_eq!(link("/imported/notes"), "docs/notes");
+        assert_eq!(link("/imported/dangling"), "/nowhere");
+        assert_eq!(read_file(root, "/imported/notes"), b"remember the milk\n");
+
+        assert_eq!(lookup(root, "/imported/todo").1.links(), 2);
+        assert_eq!(lookup(root, "/imported/docs/todo").1.links(), 2);
+        assert_eq!(read_file(root, "/imported/todo"), b"buy milk\n");
+    });
+
+    assert!(run(&["fsck", &image]).contains("clean"));
+}
+
+#[test]
+fn merges_into_existing_directories() {
+    let scratch = Scratch::new("import-merge");
+    let image = mkfs(&scratch);
+
+    let first = scratch.path().join("first");
+    fs::create_dir_all(first.join("docs")).unwrap();
+    fs::write(first.join("docs/old"), b"old\n").unwrap();
+    fs::write(first.join("docs/both"), b"first\n").unwrap();
+
+    let second = scratch.path().join("second");
+    fs::create_dir_all(second.join("docs")).unwrap();
+    fs::write(second.join("docs/new"), b"new\n").unwrap();
+    fs::write(second.join("docs/both"), b"second\n").unwrap();
+
+    let dest = format!("{image}:/");
+    run(&["import", first.to_str().unwrap(), &dest]);
+    run(&["import", second.to_str().unwrap(), &dest]);
+
+    with_root(&image, |root| {
+        assert_eq!(read_file(root, "/docs/old"), b"old\n");
+        assert_eq!(read_file(root, "/docs/new"), b"new\n");
+        assert_eq!(read_file(root, "/docs/both"), b"second\n");
+    });
+}
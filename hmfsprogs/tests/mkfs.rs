@@ -0,0 +1,50 @@
+mod common;
+
+use std::fs;
+
+use hmfs::format;
+
+use common::*;
+
+#[test]
+fn empty_volume() {
+    let scratch = Scratch::new("mkfs");
+    let image = scratch.join("image");
+
+    let out = run(&[
+        "mkfs", &image, "--size", "1M", "--label", "stick", "--owner", "1234",
+    ]);
+    assert!(out.contains("1048576 bytes"), "{out}");
+    assert_eq!(fs::metadata(&image).unwrap().len(), 1 << 20);
+
+    let buf = fs::read(&image).unwrap();
+    let superblock = format::read_superblock(&buf).unwrap();
+    assert_eq!(superblock.version, format::VERSION);
+    assert_eq!(superblock.label(), "stick");
+
+    with_root(&image, |root| {
+        assert_eq!(root.label(), "stick");
+        let (props, _) = lookup(root, "/");
+        assert_eq!((props.owner(), props.uid()), ("1234", 1234));
+        assert!(list(root, "/").is_empty());
+    });
+
+    assert!(run(&["fsck", &image]).contains("clean"));
+}
+
+#[test]
+fn bad_arguments() {
+    let scratch = Scratch::new("mkfs-bad");
+    let image = scratch.join("image");
+
+    let (_, err) = run_fails(&["mkfs", &image]);
+    assert!(err.contains("--size is required"), "{err}");
+    let (_, err) = run_fails(&["mkfs", &image, "--size", "lots"]);
+    assert!(err.contains("invalid size"), "{err}");
+    let (_, err) = run_fails(&["mkfs", &image, "--size", "600"]);
+    assert!(err.contains("too small"), "{err}");
+    let (_, err) = run_fails(&["mkfs", &image, "--size", "1M", "--label", &"x".repeat(65)]);
+    assert!(err.contains("label is longer than 64 bytes"), "{err}");
+    let (_, err) = run_fails(&["mkfs", &image, "--size", "1M", "--owner", "no-such-user"]);
+    assert!(err.contains("unknown user"), "{err}");
+}
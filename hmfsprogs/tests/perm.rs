@@ -0,0 +1,44 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+
+#[test]
+fn chmod_and_chown() {
+    let scratch = Scratch::new("perm");
+    let image = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    fs::create_dir(&host).unwrap();
+    fs::write(host.join("file"), b"data").unwrap();
+    run(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+
+    let file = format!("{image}:/file");
+    run(&["chmod", &file, "4750"]);
+    run(&["chown", &file, "1234:5678"]);
+    with_root(&image, |root| {
+        let (props, _) = lookup(root, "/file");
+        assert_eq!(props.mode() & 0o7777, 0o4750);
+        assert_eq!((props.uid(), props.gid()), (1234, 5678));
+    });
+
+    // either half of an owner can be left as it is
+    run(&["chown", &file, ":42"]);
+    run(&["chown", &file, "0"]);
+    with_root(&image, |root| {
+        let (props, _) = lookup(root, "/file");
+        assert_eq!((props.uid(), props.gid()), (0, 42));
+    });
+
+    let (_, err) = run_fails(&["chmod", &file, "rwx"]);
+    assert!(err.contains("invalid mode"), "{err}");
+    let (_, err) = run_fails(&["chmod", &file, "17777"]);
+    assert!(err.contains("invalid mode"), "{err}");
+    let (_, err) = run_fails(&["chown", &file, ":"]);
+    assert!(err.contains("invalid owner"), "{err}");
+    let (_, err) = run_fails(&["chown", &file, "no-such-user"]);
+    assert!(err.contains("unknown user"), "{err}");
+    run_fails(&["chmod", &format!("{image}:/nope"), "644"]);
+
+    assert!(run(&["fsck", &image]).contains("clean"));
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use std::fs::{self, File};
+use std::os::unix::fs::chown;
+use std::time::{Duration, UNIX_EPOCH};
+
+use common::*;
+
+/// The paths `hmfsprogs query` prints for `args`, sorted
+fn query(image: &str, args: &[&str]) -> Vec<String> {
+    let mut paths = run(&[&["query", image][..], args].concat())
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn criteria() {
+    let scratch = Scratch::new("query");
+    let image = mkfs(&scratch);
+
+    let host = scratch.path().join("host");
+    fs::create_dir_all(host.join("docs")).unwrap();
+    fs::write(host.join("docs/notes.txt"), b"remember the milk\n").unwrap();
+    fs::write(host.join("docs/old.txt"), b"from long ago\n").unwrap();
+    fs::write(host.join("logo.png"), b"not really a picture").unwrap();
+    File::options()
+        .write(true)
+        .open(host.join("docs/old.txt"))
+        .unwrap()
+        .set_modified(UNIX_EPOCH + Duration::from_secs(MODIFIED))
+        .unwrap();
+    let owned = chown(host.join("logo.png"), Some(1234), None).is_ok();
+
+    run(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+    run(&[
+        "xattr",
+        &format!("{image}:/docs/notes.txt"),
+        "set",
+        "user.tag",
+        "groceries",
+    ]);
+
+    assert_eq!(
+        query(&image, &["--mime", "text/plain"]),
+        ["/docs/notes.txt", "/docs/old.txt"]
+    );
+    assert_eq!(query(&image, &["--mime", "image/png"]), ["/logo.png"]);
+    assert_eq!(
+        query(&image, &["--modified-before", &(MODIFIED + 1).to_string()]),
+        ["/docs/old.txt"]
+    );
+    assert_eq!(
+        query(
+            &image,
+            &[
+                "--mime",
+                "text/plain",
+                "--modified-since",
+                &(MODIFIED + 1).to_string()
+            ]
+        ),
+        ["/docs/notes.txt"]
+    );
+    assert_eq!(
+        query(&image, &["--xattr", "user.tag=groceries"]),
+        ["/docs/notes.txt"]
+    );
+    assert!(query(&image, &["--xattr", "user.tag=chores"]).is_empty());
+    if owned {
+        assert_eq!(query(&image, &["--owner", "1234"]), ["/logo.png"]);
+    }
+
+    let (_, err) = run_fails(&["query", &image, "--modified-since", "yesterday"]);
+    assert!(err.contains("invalid time"), "{err}");
+}
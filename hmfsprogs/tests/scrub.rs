@@ -0,0 +1,38 @@
+mod common;
+
+use std::fs;
+
+use hmfs::format;
+
+use common::*;
+
+#[test]
+fn finds_damaged_blocks() {
+    let scratch = Scratch::new("scrub");
+    let image = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    fs::create_dir(&host).unwrap();
+    fs::write(host.join("file"), b"precious bytes\n").unwrap();
+    run(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+
+    let out = run(&["scrub", &image]);
+    assert!(out.contains("clean"), "{out}");
+
+    // one bit flipped in the file's data, where the records can't see it
+    let mut buf = fs::read(&image).unwrap();
+    let (superblock, _) = format::read_records(&buf).unwrap();
+    let data = superblock.data_offset as usize;
+    let at = data
+        + buf[data..]
+            .windows(8)
+            .position(|w| w == b"precious")
+            .unwrap();
+    buf[at] ^= 1;
+    fs::write(&image, &buf).unwrap();
+
+    let (out, err) = run_fails(&["scrub", &image]);
+    assert!(out.contains("/file: block 0 checksum mismatch"), "{out}");
+    assert!(err.contains("1 problem(s) found"), "{err}");
+    // and it only looks
+    assert_eq!(fs::read(&image).unwrap(), buf);
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use common::*;
+
+/// Runs `hmfsprogs receive <image>` with `stream` on its standard input
+fn receive(image: &str, stream: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_hmfsprogs"))
+        .args(["receive", image])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stream).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn full_then_incremental() {
+    let scratch = Scratch::new("send");
+    let source = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    fs::create_dir(&host).unwrap();
+    let big = b"a block's worth of unchanging data\n".repeat(200);
+    fs::write(host.join("big"), &big).unwrap();
+    fs::write(host.join("small"), b"first\n").unwrap();
+    run(&["import", host.to_str().unwrap(), &format!("{source}:/")]);
+    run(&["snapshot", &source, "create", "one"]);
+    fs::write(host.join("small"), b"second\n").unwrap();
+    run(&["import", host.to_str().unwrap(), &format!("{source}:/")]);
+    run(&["snapshot", &source, "create", "two"]);
+
+    let full = hmfsprogs(&["send", &source, "--to", "one"]);
+    assert!(full.status.success(), "{}", stderr(&full));
+    let incremental = hmfsprogs(&["send", &source, "--from", "one", "--to", "two"]);
+    assert!(incremental.status.success(), "{}", stderr(&incremental));
+    // the second only carries what changed
+    assert!(incremental.stdout.len() < full.stdout.len() / 2);
+
+    let target = scratch.join("target");
+    run(&["mkfs", &target, "--size", "4M"]);
+    let out = receive(&target, &incremental.stdout);
+    assert!(
+        stderr(&out).contains("no snapshot matches"),
+        "{}",
+        stderr(&out)
+    );
+    for stream in [&full.stdout, &incremental.stdout] {
+        let out = receive(&target, stream);
+        assert!(out.status.success(), "{}", stderr(&out));
+    }
+
+    let list = run(&["snapshot", &target, "list"]);
+    let names = list
+        .lines()
+        .map(|line| line.split('\t').next().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["one", "two"]);
+    for (snapshot, small) in [("one", &b"first\n"[..]), ("two", b"second\n")] {
+        let out_dir = scratch.path().join(snapshot);
+        run(&[
+            "export",
+            &format!("{target}:/"),
+            out_dir.to_str().unwrap(),
+            "--snapshot",
+            snapshot,
+        ]);
+        assert_eq!(fs::read(out_dir.join("big")).unwrap(), big);
+        assert_eq!(fs::read(out_dir.join("small")).unwrap(), small);
+    }
+
+    let out = receive(&target, &incremental.stdout);
+    assert!(
+        stderr(&out).contains("already has a snapshot"),
+        "{}",
+        stderr(&out)
+    );
+    let out = receive(&target, b"not a stream at all");
+    assert!(stderr(&out).contains("not a stream"), "{}", stderr(&out));
+    let (_, err) = run_fails(&["send", &source, "--to", "three"]);
+    assert!(err.contains("no snapshot \"three\""), "{err}");
+    assert!(run(&["fsck", &target]).contains("clean"));
+}
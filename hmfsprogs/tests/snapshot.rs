@@ -0,0 +1,52 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+
+#[test]
+fn create_list_rollback_and_delete() {
+    let scratch = Scratch::new("snapshot");
+    let image = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    fs::create_dir(&host).unwrap();
+    fs::write(host.join("file"), b"first\n").unwrap();
+    run(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+
+    run(&["snapshot", &image, "create", "first"]);
+    let (_, err) = run_fails(&["snapshot", &image, "create", "first"]);
+    assert!(err.contains("snapshot \"first\""), "{err}");
+
+    fs::write(host.join("file"), b"second\n").unwrap();
+    run(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+    run(&["snapshot", &image, "create", "second"]);
+
+    let list = run(&["snapshot", &image, "list"]);
+    let names = list
+        .lines()
+        .map(|line| line.split('\t').next().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["first", "second"]);
+
+    // exported from the snapshot, it's as it was
+    let out_dir = scratch.path().join("out");
+    run(&[
+        "export",
+        &format!("{image}:/file"),
+        out_dir.to_str().unwrap(),
+        "--snapshot",
+        "first",
+    ]);
+    assert_eq!(fs::read(out_dir.join("file")).unwrap(), b"first\n");
+
+    run(&["snapshot", &image, "rollback", "first"]);
+    with_root(&image, |root| {
+        assert_eq!(read_file(root, "/file"), b"first\n");
+    });
+
+    run(&["snapshot", &image, "delete", "first"]);
+    assert!(run(&["snapshot", &image, "list"]).starts_with("second\t"));
+    run_fails(&["snapshot", &image, "rollback", "first"]);
+    run_fails(&["snapshot", &image, "squash", "second"]);
+    assert!(run(&["fsck", &image]).contains("clean"));
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use std::fs;
+
+use common::*;
+
+#[test]
+fn set_get_list_remove() {
+    let scratch = Scratch::new("xattr");
+    let image = mkfs(&scratch);
+    let host = scratch.path().join("host");
+    fs::create_dir(&host).unwrap();
+    fs::write(host.join("file"), b"data").unwrap();
+    run(&["import", host.to_str().unwrap(), &format!("{image}:/")]);
+
+    let file = format!("{image}:/file");
+    let value = scratch.join("value");
+    fs::write(&value, [0, 1, 2, 255]).unwrap();
+    run(&["xattr", &file, "set", "user.tag", "groceries"]);
+    run(&["xattr", &file, "set", "user.raw", "--file", &value]);
+
+    assert_eq!(
+        run(&["xattr", &file, "list"]),
+        "user.raw\t4 bytes\nuser.tag\t9 bytes\n"
+    );
+    assert_eq!(run(&["xattr", &file, "get", "user.tag"]), "groceries");
+    let raw = hmfsprogs(&["xattr", &file, "get", "user.raw"]);
+    assert_eq!(raw.stdout, [0, 1, 2, 255]);
+
+    run(&["xattr", &file, "remove", "user.tag"]);
+    assert_eq!(run(&["xattr", &file, "list"]), "user.raw\t4 bytes\n");
+    let (_, err) = run_fails(&["xattr", &file, "get", "user.tag"]);
+    assert!(err.contains("no attribute"), "{err}");
+    run_fails(&["xattr", &file, "remove", "user.tag"]);
+    run_fails(&["xattr", &file, "set", "user.both", "x", "--file", &value]);
+    run_fails(&["xattr", &file, "set", "hmfs.crypt", "x"]);
+
+    assert!(run(&["fsck", &image]).contains("clean"));
+}
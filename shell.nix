@@ -16,6 +16,9 @@ pkgs.mkShell rec {
     clang
     llvmPackages.bintools
     rustup
+  ] ++ pkgs.lib.optionals pkgs.stdenv.isLinux [
+    # mkfs.btrfs, which the btrfs crate's tests make their volumes with
+    pkgs.btrfs-progs
   ];
   RUSTC_VERSION = pkgs.lib.readFile ./rust-toolchain.toml;
   # https://github.com/rust-lang/rust-bindgen#environment-variables
@@ -29,8 +32,9 @@ pkgs.mkShell rec {
     fi
     '';
   
-  # Needed for testing
-  RUSTFLAGS = (builtins.map (a: ''-L ${a}/lib'') [
+  # Needed for testing; setting this overrides the kernel's rustflags in .cargo/config.toml, so
+  # they're repeated here
+  RUSTFLAGS = [ "--cfg aes_force_soft" ] ++ (builtins.map (a: ''-L ${a}/lib'') [
     pkgs.qemu
   ]);
 
@@ -3,13 +3,18 @@
 
 use core::sync::atomic::Ordering;
 
+use alloc::format;
 use alloc::string::String;
+use alloc::vec;
 use conquer_once::spin::OnceCell;
+use hmfs::format::Device;
 use pcics::header::HeaderType;
 use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use x86_64::{instructions::interrupts::without_interrupts, structures::paging::FrameAllocator};
 
-use crate::{get_phys_offset, map_page};
+use syscall::{Error, EINVAL, EIO, EROFS};
+
+use crate::{fs::volumes, get_phys_offset, map_page};
 
 use {
     crate::{
@@ -1099,6 +1104,58 @@ impl AhciPort {
     }
 }
 
+/// An AHCI port as storage an HMFS volume can be read from; the driver can't write yet
+pub(crate) struct AhciDevice {
+    port: Arc<AhciPort>,
+    size: u64,
+}
+
+impl AhciDevice {
+    pub(crate) fn new(port: Arc<AhciPort>) -> Option<Self> {
+        let size = port.identify()?;
+        Some(Self { port, size })
+    }
+}
+
+impl Device for AhciDevice {
+    fn size(&mut self) -> syscall::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> syscall::Result<()> {
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(Error::new(EIO))?;
+        if end > self.size {
+            return Err(Error::new(EIO));
+        }
+
+        // whole sectors at a time, no more than one command carries
+        let mut done = 0;
+        while done < buf.len() {
+            let at = offset + done as u64;
+            let skip = (at % 512) as usize;
+            let len = (buf.len() - done).min(128 * 512 - skip);
+
+            let mut sectors = vec![0; (skip + len).div_ceil(512) * 512];
+            self.port
+                .read((at / 512) as usize, &mut sectors)
+                .ok_or(Error::new(EIO))?;
+            buf[done..done + len].copy_from_slice(&sectors[skip..skip + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> syscall::Result<()> {
+        Err(Error::new(EROFS))
+    }
+
+    fn flush(&mut self) -> syscall::Result<()> {
+        Ok(())
+    }
+}
+
 pub(crate) struct AhciProtected {
     pub(crate) ports: [Option<Arc<AhciPort>>; 32],
     hba: VirtAddr,
@@ -1253,6 +1310,20 @@ impl AhciProtected {
                     unreachable!()
                 }
             }
+
+            // mount whatever HMFS volumes the ports hold
+            for (i, port) in self.ports.iter().enumerate() {
+                let Some(mut device) = port.clone().and_then(AhciDevice::new) else {
+                    continue;
+                };
+
+                match volumes::bring_up(format!("ahci{i}"), &mut device) {
+                    Ok(_) => {}
+                    // not an HMFS volume
+                    Err(e) if e.errno == EINVAL => {}
+                    Err(e) => error!("AHCI: couldn't mount the HMFS volume on port {i}: {e}"),
+                }
+            }
         } else {
             panic!("AHCI: Not a normal header")
         }
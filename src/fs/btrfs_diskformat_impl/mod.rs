@@ -1,96 +1,63 @@
-use core::sync::atomic::{AtomicU64, Ordering};
+// nothing mounts Btrfs volumes yet
+#![allow(dead_code)]
 
 use crate::drivers::ahci::get_ahci;
-use alloc::boxed::Box;
-use btrfs_diskformat::{constants::PRIMARY_SUPERBLOCK_ADDR, *};
-use zerocopy::{U32, U64};
+use alloc::vec;
+use btrfs_diskformat::{DevItem, SuperBlock};
+use syscall::{Error, EIO, ENODEV};
 
-// Define your driver struct
-struct BtrfsDriver {
-    dev_id: AtomicU64,
-}
-
-impl BtrfsDriver {
-    // code won't be dead for long
-    #[allow(dead_code)]
-    pub fn read_superblock(&self, _device: &mut DevItem) -> syscall::Result<SuperBlock> {
-        // Read the superblock from the device using the btrfs-diskformat crate
-        let mut superblock = unsafe { core::mem::zeroed::<SuperBlock>() };
-        let block_sz = core::mem::size_of::<SuperBlock>();
+pub use btrfs::*;
 
-        let _slice = unsafe {
-            core::slice::from_raw_parts_mut(&mut superblock as *mut _ as *mut u8, block_sz)
-        };
+/// Sectors are what AHCI reads in
+const AHCI_SECTOR_SIZE: usize = 512;
 
-        // TODO: use crate::drivers::ahci to read superblock from device
+/// The disk on an AHCI port
+pub struct AhciDisk {
+    port: usize,
+}
 
-        Ok(superblock)
+impl AhciDisk {
+    pub fn new(port: usize) -> Self {
+        Self { port }
     }
-    // code won't be dead for long
-    #[allow(dead_code)]
-    pub fn find_superblock_on_ahci_disk(&self, disk: usize) -> Result<DevItem, &'static str> {
-        // Use already-existing AHCI driver to find superblock location at offset 0x10000
-        let buffer = unsafe { core::slice::from_raw_parts_mut(Box::into_raw(Box::new(0u8)), 4096) };
-
-        if let Ok(port) = get_ahci().write().port_mut(disk) {
-            if port
-                .read(PRIMARY_SUPERBLOCK_ADDR as usize, buffer)
-                .is_some()
-            {
-                Ok(DevItem {
-                    devid: U64::new(self.dev_id.fetch_add(1, Ordering::SeqCst)),
-                    total_bytes: U64::new(port.identify().unwrap_or(0u64)),
-                    // TODO: use AHCI, XHCI, and planned NVMe drivers to detect this
-                    bytes_used: U64::new(0),
-
-                    // TODO: use AHCI, XHCI, and planned NVMe drivers to detect this
-                    io_align: U32::new(0),
-
-                    // TODO: use AHCI, XHCI, and planned NVMe drivers to detect this
-                    io_width: U32::new(0),
-                    sector_size: U32::new((port.identify().unwrap_or(0u64) % 512) as u32),
-
-                    // TODO: use AHCI, XHCI, and planned NVMe drivers to detect this
-                    r#type: U64::new(0),
-
-                    // TODO: use AHCI, XHCI, and planned NVMe drivers to detect this
-                    generation: U64::new(0),
-
-                    // TODO: use AHCI, XHCI, and planned NVMe drivers to detect this
-                    start_offset: U64::new(0),
+}
 
-                    // TODO: use AHCI, XHCI, and planned NVMe drivers to detect this
-                    dev_group: U32::new(0),
+impl Disk for AhciDisk {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> syscall::Result<()> {
+        let sector = offset as usize / AHCI_SECTOR_SIZE;
+        let skip = offset as usize % AHCI_SECTOR_SIZE;
+        let mut sectors = vec![0; (skip + buf.len()).div_ceil(AHCI_SECTOR_SIZE) * AHCI_SECTOR_SIZE];
 
-                    // TODO: use AHCI, XHCI, and planned NVMe drivers to detect this
-                    seek_speed: 0,
+        let mut ahci = get_ahci().write();
+        let port = ahci.port_mut(self.port).map_err(|_| Error::new(ENODEV))?;
+        port.read(sector, &mut sectors).ok_or(Error::new(EIO))?;
 
-                    // TODO: use AHCI, XHCI, and planned NVMe drivers to detect this
-                    bandwith: 0,
+        buf.copy_from_slice(&sectors[skip..skip + buf.len()]);
+        Ok(())
+    }
+    fn size(&mut self) -> syscall::Result<u64> {
+        let mut ahci = get_ahci().write();
+        let port = ahci.port_mut(self.port).map_err(|_| Error::new(ENODEV))?;
+        port.identify().ok_or(Error::new(EIO))
+    }
+}
 
-                    // TODO: use AHCI, XHCI, and planned NVMe drivers to detect this
-                    uuid: [0; 16],
+pub struct BtrfsDriver;
 
-                    // TODO: use AHCI, XHCI, and planned NVMe drivers to detect this
-                    fsid: [0; 16],
-                })
-            } else {
-                Err("Failed to read superblock")
-            }
-        } else {
-            Err("Failed to read superblock")
-        }
+impl BtrfsDriver {
+    /// Reads the newest valid superblock of `disk`, from the primary or a mirror
+    pub fn read_superblock(&self, disk: &mut dyn Disk) -> syscall::Result<SuperBlock> {
+        newest_superblock(disk)
     }
-
-    // code won't be dead for long
-    #[allow(dead_code)]
-    pub fn find_superblock_on_nvme_disk(&self, _disk: usize) -> Result<DevItem, &'static str> {
-        todo!()
+    /// The device item of the Btrfs volume on the AHCI disk at port `disk`, as its superblock
+    /// has it
+    pub fn find_superblock_on_ahci_disk(&self, disk: usize) -> Result<DevItem, &'static str> {
+        self.read_superblock(&mut AhciDisk::new(disk))
+            .map(|superblock| superblock.dev_item)
+            .map_err(|_| "Failed to read superblock")
     }
-
-    // code won't be dead for long
-    #[allow(dead_code)]
-    pub fn find_superblock_on_usb_stick(&self, _disk: usize) -> Result<DevItem, &'static str> {
-        todo!()
+    /// Opens the Btrfs volume on the AHCI disk at port `disk`
+    pub fn open_ahci_disk(&self, disk: usize) -> syscall::Result<BtrfsVolume<AhciDisk>> {
+        BtrfsVolume::open(AhciDisk::new(disk))
     }
 }
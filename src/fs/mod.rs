@@ -1,2 +1,3 @@
 pub mod btrfs_diskformat_impl;
-pub mod hmfs;
+pub mod scrub;
+pub mod volumes;
@@ -0,0 +1,50 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use hmfs::scrub::Scrub;
+use hmfs::RootEntry;
+use log::{error, info};
+
+use crate::common::RwLock;
+
+/// Most entries verified per call to `idle`, so the main loop never waits long on a scrub
+pub const SCRUB_BATCH: usize = 64;
+
+/// Scrubs in progress, oldest first, each with the name its volume is reported under
+static SCRUBS: RwLock<Vec<(String, Scrub<'static>)>> = RwLock::new(Vec::new());
+
+/// Queues a background scrub of the volume at `root`, as it is now
+///
+/// Damage turns up in the kernel log as the scrub gets to it, with `name` and the path of the
+/// entry that doesn't match its checksum.
+pub fn schedule(name: String, root: &RootEntry<'static>) {
+    info!("HMFS: scheduled a scrub of {name}");
+    SCRUBS.write().push((name, Scrub::new(root)));
+}
+
+/// Verifies a batch of the oldest scrub's entries; the main loop calls this once everything else
+/// has had its turn, which is what makes scrubbing the lowest-priority work the kernel does
+pub fn idle() {
+    let mut scrubs = SCRUBS.write();
+    let Some((name, scrub)) = scrubs.first_mut() else {
+        return;
+    };
+
+    let mut more = true;
+    for _ in 0..SCRUB_BATCH {
+        more = scrub.step(&mut |damage| error!("HMFS: {name}: {damage}"));
+        if !more {
+            break;
+        }
+    }
+
+    if !more {
+        info!(
+            "HMFS: finished scrubbing {name}, {} entries and {} blocks verified",
+            scrub.entries(),
+            scrub.blocks()
+        );
+        scrubs.remove(0);
+    }
+}
@@ -0,0 +1,62 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use hmfs::format::{self, Device};
+use hmfs::volumes::Volumes;
+use hmfs::RootEntry;
+use log::info;
+use syscall::{Error, EEXIST};
+
+use super::scrub;
+use crate::common::IrqRelaxStrategy;
+
+/// A mounted HMFS volume, shared by everything that reads, changes or watches it
+pub type Volume = hmfs::volumes::Volume<IrqRelaxStrategy>;
+
+/// Mounted volumes by name, which `watch:` reports changes to
+pub static VOLUMES: Volumes<IrqRelaxStrategy> = Volumes::new();
+
+/// Mounts `root` under `name`, failing with `EEXIST` if something already is
+pub fn mount(name: String, root: RootEntry<'static>) -> syscall::Result<Volume> {
+    let volume = VOLUMES.mount(name.clone(), root)?;
+    info!("HMFS: mounted {name}");
+    Ok(volume)
+}
+
+/// Mounts the HMFS volume on `device` under `name` and queues a scrub of it, failing with
+/// `EINVAL` if there's no volume there
+///
+/// Only the superblock slots and the regions the live superblock points to are read. The tree
+/// borrows from its record table for as long as the kernel runs, so that's kept once the volume
+/// is mounted; the rest is copied out of and freed.
+pub fn bring_up(name: String, device: &mut dyn Device) -> syscall::Result<Volume> {
+    if VOLUMES.volume(&name).is_some() {
+        return Err(Error::new(EEXIST));
+    }
+
+    let regions = format::read_device(device)?;
+    let table = Box::into_raw(regions.table.into_boxed_slice());
+    // `table` came from a box just now, and is only freed below once nothing borrows it
+    let decoded = format::decode_regions(
+        &regions.superblock,
+        unsafe { &*table },
+        &regions.snapshots,
+        &regions.data,
+    );
+    let mounted = decoded.and_then(|root| {
+        drop((regions.snapshots, regions.data));
+        mount(name.clone(), root)
+    });
+    let volume = match mounted {
+        Ok(volume) => volume,
+        Err(e) => {
+            // the tree was never built, or was dropped when mounting it failed, so nothing
+            // borrows the table any more
+            drop(unsafe { Box::from_raw(table) });
+            return Err(e);
+        }
+    };
+    scrub::schedule(name, &volume.read());
+    Ok(volume)
+}
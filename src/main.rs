@@ -233,6 +233,9 @@ pub fn maink(boot_info: &'static mut BootInfo) -> ! {
         &boot_info.memory_regions.first().unwrap() as *const _ as usize
     );
 
+    // before any driver can mount a volume for them to serve
+    scheme::init();
+
     match unsafe { AcpiTables::from_rsdp(KernelAcpi, rsdp as usize) } {
         Ok(tables) => {
             USER_ACPI.call_once(|| UserAcpi::new(&tables));
@@ -263,6 +266,9 @@ pub fn maink(boot_info: &'static mut BootInfo) -> ! {
                 canvas.merge_down(get_framebuffer());
             }
         }
+
+        // whatever time is left over goes to background filesystem scrubs
+        fs::scrub::idle();
     }
 }
 
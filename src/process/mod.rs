@@ -15,14 +15,12 @@ use alloc::{
     vec::Vec,
 };
 use conquer_once::spin::OnceCell;
+use hmfs::{Entry, FileData};
 use syscall::{Error, EBADF};
 use x86_64::structures::paging::{PageTableFlags, Size4KiB};
 use xmas_elf::ElfFile;
 
-use crate::{
-    fs::hmfs::{Entry, FileData},
-    int_like,
-};
+use crate::int_like;
 
 pub use self::signal::Signal;
 pub mod signal;
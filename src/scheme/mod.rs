@@ -1,3 +1,41 @@
 pub mod acpi;
 
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use hmfs::volumes::WatchScheme;
+use log::info;
+use syscall::{Error, EEXIST};
+
+use crate::common::RwLock;
+use crate::fs::volumes::VOLUMES;
+
 pub use syscall::scheme::Scheme;
+
+/// Schemes by the name that comes before the colon in the paths they're opened by
+static SCHEMES: RwLock<BTreeMap<String, Arc<dyn Scheme + Send + Sync>>> =
+    RwLock::new(BTreeMap::new());
+
+/// Makes `scheme` reachable as `name:`, failing with `EEXIST` if something already is
+pub fn register(name: &str, scheme: Arc<dyn Scheme + Send + Sync>) -> syscall::Result<()> {
+    let mut schemes = SCHEMES.write();
+    if schemes.contains_key(name) {
+        return Err(Error::new(EEXIST));
+    }
+
+    info!("Registered scheme {name}:");
+    schemes.insert(String::from(name), scheme);
+    Ok(())
+}
+
+/// The scheme registered as `name:`
+#[allow(dead_code)] // until paths can be opened from userspace
+pub fn scheme(name: &str) -> Option<Arc<dyn Scheme + Send + Sync>> {
+    SCHEMES.read().get(name).cloned()
+}
+
+/// Registers the schemes the kernel serves itself: so far `watch:`, over every mounted HMFS
+/// volume
+pub fn init() {
+    register("watch", Arc::new(WatchScheme::new(&VOLUMES))).expect("watch: registered twice");
+}